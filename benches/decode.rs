@@ -0,0 +1,211 @@
+// Bencher reports bytes per second, b.bytes is set to the number of samples
+// so that the MB/s it prints is millions of samples per second
+
+extern crate aurora;
+extern crate flac;
+extern crate test;
+
+use std::io::MemReader;
+
+use flac::bitstream::{BitRead, SliceReader};
+use flac::decoder::ParallelDecoder;
+use flac::demuxer::Demuxer;
+use flac::encoder;
+use flac::encoder::bitwriter::BitWriter;
+use flac::frame::header::Header;
+use flac::metadata::Metadata;
+use flac::reader::FlacReader;
+
+mod signal;
+
+fn header(block_size: u32) -> Header {
+  return Header {
+    variable_blocksize: false, block_size: block_size, sample_rate: 44100, channel_assignment: 0,
+    sample_size: 16, sample_number: None, frame_number: Some(0), crc: 0
+  };
+}
+
+// One 16 bit subframe of 4096 samples
+fn subframe(samples: &[i32], parameters: &encoder::Parameters, verbatim: bool) -> (Vec<u8>, encoder::subframe::Encoding) {
+  let mut subframe = encoder::subframe::analyze(samples, 16, parameters);
+
+  if verbatim {
+    subframe.encoding = encoder::subframe::Verbatim;
+  }
+
+  let mut writer = BitWriter::new();
+
+  encoder::subframe::write(&mut writer, samples, 16, &subframe);
+  writer.align();
+
+  return (writer.unwrap(), subframe.encoding);
+}
+
+fn bench_subframe(b: &mut test::Bencher, data: &[u8]) {
+  let header = header(4096);
+  let mut output = Vec::from_elem(4096, 0i32);
+
+  b.bytes = 4096;
+
+  b.iter(|| {
+    flac::subframe::read_into(&header, 16, &mut SliceReader::new(data), output.as_mut_slice()).unwrap();
+  });
+}
+
+fn mono() -> Vec<i32> {
+  return signal::music(4096, 1, 16, 1);
+}
+
+#[bench]
+fn bench_frame_header(b: &mut test::Bencher) {
+  let flac = signal::encode(encoder::Parameters::new(44100, 2, 16), signal::music(4096, 2, 16, 2).as_slice());
+
+  // STREAMINFO is the only block
+  let frame = flac.slice_from(4 + 4 + 34);
+
+  b.bytes = 1;
+
+  b.iter(|| {
+    Header::from(&mut SliceReader::new(frame))
+  });
+}
+
+#[bench]
+fn bench_subframe_constant(b: &mut test::Bencher) {
+  let (data, _) = subframe(Vec::from_elem(4096, 1000i32).as_slice(), &encoder::Parameters::new(44100, 1, 16), false);
+
+  bench_subframe(b, data.as_slice());
+}
+
+#[bench]
+fn bench_subframe_verbatim(b: &mut test::Bencher) {
+  let (data, _) = subframe(mono().as_slice(), &encoder::Parameters::new(44100, 1, 16), true);
+
+  bench_subframe(b, data.as_slice());
+}
+
+#[bench]
+fn bench_subframe_fixed(b: &mut test::Bencher) {
+  let mut parameters = encoder::Parameters::new(44100, 1, 16);
+  parameters.max_lpc_order = 0;
+
+  let (data, encoding) = subframe(mono().as_slice(), &parameters, false);
+
+  match encoding {
+    encoder::subframe::Fixed(..) => (),
+    _ => panic!("Signal didn't encode to a fixed subframe")
+  }
+
+  bench_subframe(b, data.as_slice());
+}
+
+#[bench]
+fn bench_subframe_lpc(b: &mut test::Bencher) {
+  let mut parameters = encoder::Parameters::new(44100, 1, 16);
+  parameters.max_lpc_order = 12;
+
+  let (data, encoding) = subframe(mono().as_slice(), &parameters, false);
+
+  match encoding {
+    encoder::subframe::LPC(..) => (),
+    _ => panic!("Signal didn't encode to an LPC subframe")
+  }
+
+  bench_subframe(b, data.as_slice());
+}
+
+#[bench]
+fn bench_residual(b: &mut test::Bencher) {
+  let residual: Vec<i32> = signal::noise(4096, 16, 3).iter().map(|&n| n >> 6).collect();
+
+  let mut writer = BitWriter::new();
+
+  for &value in residual.iter() {
+    writer.write_rice(value, 8);
+  }
+
+  writer.align();
+
+  let data = writer.unwrap();
+  let mut output = Vec::from_elem(4096, 0i32);
+
+  b.bytes = 4096;
+
+  b.iter(|| {
+    SliceReader::new(data.as_slice()).read_rice(8, output.as_mut_slice())
+  });
+}
+
+#[bench]
+fn bench_file(b: &mut test::Bencher) {
+  let frames = 10 * 44100;
+  let flac = signal::encode(encoder::Parameters::new(44100, 2, 16), signal::music(frames, 2, 16, 4).as_slice());
+
+  b.bytes = 2 * frames as u64;
+
+  b.iter(|| {
+    let mut reader = FlacReader::new(MemReader::new(flac.clone())).unwrap();
+
+    for block in reader.blocks() {
+      block.unwrap();
+    }
+  });
+}
+
+// The whole pipeline, with the parallel decoder on a number of threads, to
+// see how throughput scales with them
+fn bench_parallel(b: &mut test::Bencher, threads: uint) {
+  let frames = 10 * 44100;
+  let flac = signal::encode(encoder::Parameters::new(44100, 2, 16), signal::music(frames, 2, 16, 4).as_slice());
+
+  b.bytes = 2 * frames as u64;
+
+  b.iter(|| {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<Metadata>(4);
+    let (sink_a, mut source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    let data = flac.clone();
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      Demuxer::new(source_0, sink_1, sink_md).run();
+    });
+
+    spawn(proc() {
+      ParallelDecoder::new(source_1, source_md, sink_a, threads).run();
+    });
+
+    let mut last = false;
+
+    while !last {
+      source_a.read(|audio| {
+        last = audio.last;
+      });
+    }
+  });
+}
+
+#[bench]
+fn bench_parallel_1(b: &mut test::Bencher) {
+  bench_parallel(b, 1);
+}
+
+#[bench]
+fn bench_parallel_2(b: &mut test::Bencher) {
+  bench_parallel(b, 2);
+}
+
+#[bench]
+fn bench_parallel_4(b: &mut test::Bencher) {
+  bench_parallel(b, 4);
+}
+
+#[bench]
+fn bench_parallel_8(b: &mut test::Bencher) {
+  bench_parallel(b, 8);
+}
@@ -0,0 +1,33 @@
+// Bencher reports bytes per second, b.bytes is set to the number of samples
+// so that the MB/s it prints is millions of samples per second
+
+extern crate flac;
+extern crate test;
+
+use flac::encoder;
+
+mod signal;
+
+fn bench_file(b: &mut test::Bencher, parameters: encoder::Parameters) {
+  let frames = 10 * 44100;
+  let samples = signal::music(frames, 2, 16, 5);
+
+  b.bytes = samples.len() as u64;
+
+  b.iter(|| {
+    signal::encode(parameters.clone(), samples.as_slice())
+  });
+}
+
+#[bench]
+fn bench_file_fixed(b: &mut test::Bencher) {
+  let mut parameters = encoder::Parameters::new(44100, 2, 16);
+  parameters.max_lpc_order = 0;
+
+  bench_file(b, parameters);
+}
+
+#[bench]
+fn bench_file_lpc(b: &mut test::Bencher) {
+  bench_file(b, encoder::Parameters::new(44100, 2, 16));
+}
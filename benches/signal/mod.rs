@@ -0,0 +1,37 @@
+// Deterministic signals to benchmark with, so nothing has to be downloaded.
+// Each benchmark uses some of them.
+#![allow(dead_code)]
+
+use std;
+
+pub use self::test_util::{noise, encode};
+
+// The tests' signals, from the crate's sources
+#[path = "../../src/test_util.rs"]
+mod test_util;
+
+// Something like music, a few sines that fade in and out under a little
+// noise, interleaved and scaled to bits
+pub fn music(frames: uint, channels: uint, bits: u8, seed: u32) -> Vec<i32> {
+  let noise = noise(frames * channels, 16, seed);
+  let scale = (1u64 << (bits as uint - 1)) as f64 - 1.0;
+
+  let tones = [(220.0f64, 0.3f64), (331.0, 0.2), (587.5, 0.15), (1203.0, 0.05)];
+
+  return Vec::from_fn(frames * channels, |i| {
+    let t = (i / channels) as f64 / 44100.0;
+    let c = (i % channels) as f64;
+
+    let mut value = 0.0;
+
+    for &(frequency, amplitude) in tones.iter() {
+      let fade = 0.5 + 0.5 * (t * 0.7 + frequency).sin();
+
+      value += amplitude * fade * (2.0 * std::f64::consts::PI * frequency * t + c).sin();
+    }
+
+    value += 0.01 * noise[i] as f64 / 32768.0;
+
+    (value * scale) as i32
+  });
+}
@@ -0,0 +1,174 @@
+// Mutation fuzzer for the parsers. The parsers reject bad input by returning
+// an error, so any panic is a crash, and the input that caused it is saved to
+// artifacts/TARGET.
+//
+//   fuzz TARGET [RUNS [SEED]]   Mutates corpus/TARGET
+//   fuzz TARGET FILE            Runs one input, to reproduce a crash
+//
+// Targets are metadata, frame_header, subframe and file. The file target
+// also starts from every conformance stream, from tests/streams.
+
+extern crate flac;
+
+use std::any::{Any, AnyRefExt};
+use std::io::{fs, File, USER_RWX};
+use std::io::util::NullWriter;
+use std::os;
+use std::task::TaskBuilder;
+
+#[path = "../../tests/streams/mod.rs"]
+mod streams;
+mod targets;
+
+fn random(seed: &mut u32, n: uint) -> uint {
+  *seed = *seed * 1103515245 + 12345;
+
+  return (((*seed >> 16) & 0x7FFF) as uint) % n;
+}
+
+// Values that tend to sit on edges, in lengths, codes and counts
+static INTERESTING: [u8, ..8] = [0x00, 0x01, 0x7F, 0x80, 0xF8, 0xFE, 0xFF, 0x10];
+
+fn mutate(data: &mut Vec<u8>, seed: &mut u32) {
+  if data.is_empty() {
+    data.push(random(seed, 256) as u8);
+    return;
+  }
+
+  let i = random(seed, data.len());
+
+  match random(seed, 6) {
+    0 => data.as_mut_slice()[i] ^= 1 << random(seed, 8),
+    1 => data.as_mut_slice()[i] = random(seed, 256) as u8,
+    2 => data.as_mut_slice()[i] = INTERESTING[random(seed, INTERESTING.len())],
+    3 => {
+      let end = i + random(seed, data.len() - i) + 1;
+      let rest = data.slice_from(end).to_vec();
+
+      data.truncate(i);
+      data.push_all(rest.as_slice());
+    },
+    4 => {
+      let end = i + random(seed, data.len() - i) + 1;
+      let copy = data.slice(i, end).to_vec();
+      let at = random(seed, data.len() + 1);
+      let rest = data.slice_from(at).to_vec();
+
+      data.truncate(at);
+      data.push_all(copy.as_slice());
+      data.push_all(rest.as_slice());
+    },
+    _ => data.truncate(i)
+  }
+}
+
+fn message(error: &Box<Any + Send>) -> String {
+  match error.downcast_ref::<&'static str>() {
+    Some(s) => return s.to_string(),
+    None => ()
+  }
+
+  return match error.downcast_ref::<String>() {
+    Some(s) => s.clone(),
+    None => "Panicked with something other than a message".to_string()
+  };
+}
+
+// The message of the panic, if the target panicked
+fn run(target: fn(Vec<u8>), data: Vec<u8>) -> Option<String> {
+  let result = TaskBuilder::new().stderr(box NullWriter).try(proc() {
+    target(data);
+  });
+
+  return match result {
+    Ok(()) => None,
+    Err(error) => Some(message(&error))
+  };
+}
+
+fn corpus(name: &str) -> Vec<Vec<u8>> {
+  let mut inputs = Vec::new();
+  let directory = Path::new("corpus").join(name);
+
+  match fs::readdir(&directory) {
+    Ok(paths) => {
+      for path in paths.iter() {
+        inputs.push(File::open(path).read_to_end().unwrap());
+      }
+    },
+    Err(_) => ()
+  }
+
+  if name == "file" {
+    for stream in streams::streams().into_iter() {
+      inputs.push(stream.flac);
+    }
+  }
+
+  if inputs.is_empty() {
+    inputs.push(Vec::new());
+  }
+
+  return inputs;
+}
+
+fn main() {
+  let args = os::args();
+
+  if args.len() < 2 {
+    panic!("Usage: fuzz TARGET [RUNS [SEED]] or fuzz TARGET FILE");
+  }
+
+  let name = args[1].as_slice();
+
+  let target = match targets::find(name) {
+    Some(target) => target,
+    None => panic!("No target {}, there are {}", name, targets::NAMES.as_slice())
+  };
+
+  if args.len() == 3 && Path::new(args[2].as_slice()).exists() {
+    let data = File::open(&Path::new(args[2].as_slice())).read_to_end().unwrap();
+
+    match run(target, data) {
+      Some(message) => println!("Crash: {}", message),
+      None => println!("No crash")
+    }
+
+    return;
+  }
+
+  let runs: uint = if args.len() > 2 { from_str(args[2].as_slice()).unwrap() } else { 100000 };
+  let mut seed: u32 = if args.len() > 3 { from_str(args[3].as_slice()).unwrap() } else { 1 };
+
+  let inputs = corpus(name);
+  let artifacts = Path::new("artifacts").join(name);
+  let mut crashes = 0u;
+
+  for run_number in range(0, runs) {
+    let mut data = inputs[random(&mut seed, inputs.len())].clone();
+
+    for _ in range(0, 1 + random(&mut seed, 4)) {
+      mutate(&mut data, &mut seed);
+    }
+
+    match run(target, data.clone()) {
+      Some(message) => {
+        let path = artifacts.join(format!("crash-{}", run_number));
+
+        fs::mkdir_recursive(&artifacts, USER_RWX).unwrap();
+        File::create(&path).write(data.as_slice()).unwrap();
+
+        println!("{}: {}", path.display(), message);
+
+        crashes += 1;
+      },
+      None => ()
+    }
+  }
+
+  println!("{} runs of {}, {} crashes", runs, name, crashes);
+
+  if crashes > 0 {
+    os::set_exit_status(1);
+  }
+}
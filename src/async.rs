@@ -0,0 +1,470 @@
+// The toolchain has no futures Stream or AsyncRead to build on, so this is a
+// reader that's polled over a plain std::io::Reader, which takes TimedOut and
+// ResourceUnavailable errors to mean that no data is there yet.
+
+use std::cmp;
+use std::io::{IoError, IoResult, EndOfFile, InvalidInput, TimedOut, ResourceUnavailable};
+
+use bitstream::SliceReader;
+use crc;
+use decoder::parallel::Splitter;
+use frame;
+use frame::header;
+use limits::Limits;
+use metadata;
+use metadata::id3;
+use metadata::stream_info;
+use metadata::stream_info::StreamInfo;
+use reader::{Block, Error, Io, Limit, invalid_input};
+
+// Whether a value is there yet, or the input has to be polled again once it
+// has more data
+#[deriving(Show,PartialEq)]
+pub enum Poll<T> {
+  Ready(T), NotReady
+}
+
+// Decodes from input that isn't always ready, like a non-blocking socket or
+// one with a read timeout. A read that times out, would block or returns
+// nothing makes a poll return NotReady, and everything parsed so far is kept
+// for the next one, so no thread ever waits on the input.
+//
+// Only one frame, and the header after it that says where it ends, is
+// buffered at a time. Its size comes from STREAMINFO. Errors are the same as
+// FlacReader's.
+pub struct AsyncFlacReader<R> {
+  input: R,
+  buffer: Vec<u8>, // Metadata that isn't parsed yet
+  started: bool,
+  metadata: Vec<metadata::Metadata>,
+  stream_info: Option<StreamInfo>,
+  splitter: Splitter,
+  limit: uint,
+  finished: bool,
+  limits: Limits,
+  total: uint // Bytes of tags and blocks so far
+}
+
+// Largest frame a stream can have, when STREAMINFO doesn't know it
+fn max_frame_size(stream_info: &StreamInfo) -> uint {
+  return frame::max_size(stream_info::max_block_size(stream_info), stream_info.channels as uint, stream_info.bits_per_sample);
+}
+
+impl<R: Reader> AsyncFlacReader<R> {
+  pub fn new(input: R) -> AsyncFlacReader<R> {
+    return AsyncFlacReader::with_limits(input, Limits::new());
+  }
+
+  // Same as new, but every tag and block is checked against the limits as
+  // soon as its header is in, and STREAMINFO and every frame header before
+  // anything else is buffered
+  pub fn with_limits(input: R, limits: Limits) -> AsyncFlacReader<R> {
+    return AsyncFlacReader {
+      input: input,
+      buffer: Vec::new(),
+      started: false,
+      metadata: Vec::new(),
+      stream_info: None,
+      splitter: Splitter::new(&[], 1),
+      limit: 0,
+      finished: false,
+      limits: limits,
+      total: 0
+    };
+  }
+
+  // None until poll_metadata is Ready
+  pub fn stream_info(&self) -> Option<&StreamInfo> {
+    return self.stream_info.as_ref();
+  }
+
+  // All metadata blocks, STREAMINFO first, once poll_metadata is Ready
+  pub fn metadata(&self) -> &[metadata::Metadata] {
+    return self.metadata.as_slice();
+  }
+
+  fn consume(&mut self, n: uint) {
+    self.buffer = self.buffer.slice_from(n).to_vec();
+  }
+
+  // Parses what it can of the buffered metadata, true once it's all there
+  fn parse_metadata(&mut self) -> Result<bool, Error> {
+    loop {
+      if !self.started {
+        if id3::is_tag(self.buffer.as_slice()) {
+          if self.buffer.len() < id3::HEADER_LENGTH {
+            return Ok(false);
+          }
+
+          let length = try!(id3::length(self.buffer.as_slice()).map_err(invalid_input));
+
+          try!(self.limits.check_metadata(length, self.total).map_err(Limit));
+
+          if self.buffer.len() < length {
+            return Ok(false);
+          }
+
+          self.total += length;
+          self.consume(length);
+          continue;
+        }
+
+        if self.buffer.len() < 4 {
+          return Ok(false);
+        }
+
+        if self.buffer.slice_to(4) != b"fLaC" {
+          return Err(Io(IoError { kind: InvalidInput, desc: "flac::AsyncFlacReader: Input doesn't start with fLaC", detail: None }));
+        }
+
+        self.consume(4);
+        self.started = true;
+      }
+
+      if self.buffer.len() < 4 {
+        return Ok(false);
+      }
+
+      if self.metadata.is_empty() && self.buffer[0] & 0x7F != 0 {
+        return Err(Io(IoError { kind: InvalidInput, desc: "flac::AsyncFlacReader: Metadata doesn't start with STREAMINFO", detail: None }));
+      }
+
+      let length = (self.buffer[1] as uint << 16) | (self.buffer[2] as uint << 8) | self.buffer[3] as uint;
+
+      try!(self.limits.check_block(self.buffer[0] & 0x7F, length, self.total).map_err(Limit));
+
+      if self.buffer.len() < 4 + length {
+        return Ok(false);
+      }
+
+      let mut block = metadata::Metadata { ty: metadata::Unknown, data: Vec::new(), last: false };
+
+      let last = try!(metadata::from_bytes(self.buffer.slice_to(4 + length), &mut block).map_err(invalid_input));
+
+      match block.ty {
+        metadata::StreamInfo(ref stream_info) if self.metadata.is_empty() => try!(self.limits.check_stream_info(stream_info).map_err(Limit)),
+        _ => ()
+      }
+
+      self.metadata.push(block);
+      self.total += length;
+      self.consume(4 + length);
+
+      if last {
+        let stream_info = match self.metadata[0].ty {
+          metadata::StreamInfo(ref stream_info) => stream_info.clone(),
+          _ => panic!("flac::AsyncFlacReader: Block of type 0 isn't STREAMINFO (BUG)")
+        };
+
+        let frame_size = match stream_info.frame_size.1 {
+          0 => max_frame_size(&stream_info),
+          n => n as uint
+        };
+
+        self.limit = frame_size + header::MAX_LENGTH;
+        self.stream_info = Some(stream_info);
+
+        self.splitter.push(self.buffer.as_slice());
+        self.buffer.truncate(0);
+
+        return Ok(true);
+      }
+    }
+  }
+
+  // Reads one chunk, and never more than the frame buffer has room for
+  fn read_chunk(&mut self) -> IoResult<Poll<()>> {
+    let room = match self.stream_info {
+      Some(_) if self.splitter.len() >= self.limit => {
+        return Err(IoError { kind: InvalidInput, desc: "flac::AsyncFlacReader: Frame is larger than STREAMINFO allows", detail: None });
+      },
+      Some(_) => self.limit - self.splitter.len(),
+      None => 4096
+    };
+
+    let mut chunk = [0x00u8, ..4096];
+    let n = cmp::min(room, chunk.len());
+
+    match self.input.read(chunk.slice_to_mut(n)) {
+      Ok(0) => return Ok(NotReady),
+      Ok(n) => {
+        match self.stream_info {
+          Some(_) => self.splitter.push(chunk.slice_to(n)),
+          None => self.buffer.push_all(chunk.slice_to(n))
+        }
+      },
+      Err(ref error) if error.kind == EndOfFile => self.finished = true,
+      Err(ref error) if error.kind == TimedOut || error.kind == ResourceUnavailable => return Ok(NotReady),
+      Err(error) => return Err(error)
+    }
+
+    return Ok(Ready(()));
+  }
+
+  // Ready once all metadata is parsed
+  pub fn poll_metadata(&mut self) -> Result<Poll<()>, Error> {
+    while self.stream_info.is_none() && !try!(self.parse_metadata()) {
+      if self.finished {
+        return Err(Io(IoError { kind: EndOfFile, desc: "flac::AsyncFlacReader: Input ended in the metadata", detail: None }));
+      }
+
+      match try!(self.read_chunk().map_err(Io)) {
+        Ready(()) => (),
+        NotReady => return Ok(NotReady)
+      }
+    }
+
+    return Ok(Ready(()));
+  }
+
+  // Ready(None) once the input ends
+  pub fn poll_block(&mut self) -> Result<Poll<Option<Block>>, Error> {
+    match try!(self.poll_metadata()) {
+      Ready(()) => (),
+      NotReady => return Ok(NotReady)
+    }
+
+    loop {
+      match self.splitter.next(self.finished) {
+        Some(frame) => {
+          if crc::crc16(frame.as_slice()) != 0 {
+            let last = self.finished && self.splitter.is_empty();
+
+            return Err(Io(if frame::is_cut_off(frame.as_slice(), last) {
+              IoError { kind: EndOfFile, desc: "flac::AsyncFlacReader: Input ended in the middle of a frame", detail: None }
+            } else {
+              IoError { kind: InvalidInput, desc: "flac::AsyncFlacReader: Frame CRC-16 doesn't match", detail: None }
+            }));
+          }
+
+          let header = try!(header::Header::from(&mut SliceReader::new(frame.as_slice())).map_err(invalid_input));
+
+          try!(self.limits.check_frame(frame::channels(header.channel_assignment) as u8, header.block_size).map_err(Limit));
+
+          let (header, channels) = try!(frame::decode(&mut SliceReader::new(frame.as_slice()), self.stream_info.as_ref()).map_err(invalid_input));
+
+          return Ok(Ready(Some(Block { header: header, channels: channels })));
+        },
+        None if self.finished => return Ok(Ready(None)),
+        None => {
+          match try!(self.read_chunk().map_err(Io)) {
+            Ready(()) => (),
+            NotReady => return Ok(NotReady)
+          }
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std;
+  use std::cmp;
+  use std::io::{IoError, IoResult};
+
+  use frame;
+  use frame::header;
+  use limits;
+  use reader;
+
+  use metadata::id3;
+  use test_util;
+  use test_util::noise;
+
+  // Hands out a few bytes at a time, and times out every other read
+  struct Trickle {
+    data: Vec<u8>,
+    position: uint,
+    ready: bool
+  }
+
+  impl Reader for Trickle {
+    fn read(&mut self, buffer: &mut [u8]) -> IoResult<uint> {
+      self.ready = !self.ready;
+
+      if !self.ready {
+        return Err(IoError { kind: std::io::TimedOut, desc: "Not ready", detail: None });
+      }
+
+      if self.position == self.data.len() {
+        return Err(IoError { kind: std::io::EndOfFile, desc: "End of file", detail: None });
+      }
+
+      let n = cmp::min(cmp::min(buffer.len(), 100), self.data.len() - self.position);
+
+      for i in range(0, n) {
+        buffer[i] = self.data[self.position + i];
+      }
+
+      self.position += n;
+
+      return Ok(n);
+    }
+  }
+
+  fn encode(samples: &[i32]) -> Vec<u8> {
+    let mut parameters = test_util::parameters(2, 1152);
+    parameters.samples = Some(samples.len() as u64 / 2);
+
+    return test_util::encode(parameters, samples);
+  }
+
+  #[test]
+  fn test_matches_reader() {
+    let flac = encode(noise(2 * 10000, 16, 45).as_slice());
+
+    let mut data = id3::tests::tag("Trickle", false);
+    data.push_all(flac.as_slice());
+
+    let mut reader = super::AsyncFlacReader::new(Trickle { data: data, position: 0, ready: false });
+
+    let mut not_ready = 0u;
+
+    while reader.poll_metadata().unwrap() == super::NotReady {
+      not_ready += 1;
+    }
+
+    assert!(not_ready > 0);
+    assert_eq!(reader.stream_info().unwrap().samples, 10000);
+
+    let mut expected = reader::FlacReader::new(std::io::MemReader::new(flac)).unwrap();
+    let mut n = 0u;
+
+    loop {
+      match reader.poll_block().unwrap() {
+        super::Ready(Some(block)) => {
+          let expected = expected.next_block().unwrap().unwrap();
+
+          assert_eq!(block.header, expected.header);
+          assert_eq!(block.channels, expected.channels);
+
+          n += 1;
+        },
+        super::Ready(None) => break,
+        super::NotReady => ()
+      }
+
+      assert!(reader.splitter.len() <= reader.limit);
+    }
+
+    assert_eq!(n, (10000 + 1151) / 1152);
+    assert!(expected.next_block().is_none());
+  }
+
+  fn poll_error(data: Vec<u8>, limits: limits::Limits) -> reader::Error {
+    let mut reader = super::AsyncFlacReader::with_limits(Trickle { data: data, position: 0, ready: false }, limits);
+
+    loop {
+      match reader.poll_block() {
+        Ok(super::Ready(None)) => panic!("Input ended without an error"),
+        Ok(_) => (),
+        Err(error) => return error
+      }
+    }
+  }
+
+  fn io_error(data: Vec<u8>) -> std::io::IoErrorKind {
+    return match poll_error(data, limits::Limits::new()) {
+      reader::Io(error) => error.kind,
+      reader::Limit(exceeded) => panic!("Limits::new() exceeded with {}", exceeded)
+    };
+  }
+
+  #[test]
+  fn test_invalid_input() {
+    let flac = encode(noise(2 * 10000, 16, 46).as_slice());
+
+    let mut not_flac = flac.clone();
+    not_flac.as_mut_slice()[0] = b'F';
+
+    assert_eq!(io_error(not_flac), std::io::InvalidInput);
+
+    let mut padding_first = b"fLaC".to_vec();
+    padding_first.push_all(&::metadata::header(false, 1, 0));
+    padding_first.push_all(flac.slice_from(4));
+
+    assert_eq!(io_error(padding_first), std::io::InvalidInput);
+
+    let mut corrupt = flac.clone();
+    let i = corrupt.len() / 2;
+    corrupt.as_mut_slice()[i] ^= 0x10;
+
+    assert_eq!(io_error(corrupt), std::io::InvalidInput);
+
+    // A reserved subframe type in the first frame, which is right after
+    // STREAMINFO, under CRCs that still match
+    let mut reserved = flac.clone();
+    let length = header::check(reserved.slice_from(4 + 4 + 34)).unwrap();
+
+    frame::tests::rewrite(reserved.slice_from_mut(4 + 4 + 34), length, 0x02 << 1);
+
+    assert_eq!(io_error(reserved), std::io::InvalidInput);
+
+    // STREAMINFO that says it's 33 bytes
+    let mut short = flac.slice_to(4 + 4 + 33).to_vec();
+    short.as_mut_slice()[7] = 33;
+    short.push_all(flac.slice_from(4 + 4 + 34));
+
+    assert_eq!(io_error(short), std::io::InvalidInput);
+
+    assert_eq!(io_error(flac.slice_to(flac.len() - 100).to_vec()), std::io::EndOfFile);
+  }
+
+  // A frame that doesn't end, with no frame header after it, is only read up
+  // to the largest frame STREAMINFO allows and the header after it
+  #[test]
+  fn test_buffer_bound() {
+    let flac = encode(noise(2 * 10000, 16, 48).as_slice());
+    let start = 4 + 4 + 34;
+
+    let max_frame = (flac[4 + 4 + 7] as uint << 16) | (flac[4 + 4 + 8] as uint << 8) | flac[4 + 4 + 9] as uint;
+    // Only the first frame's header, then zeros
+    let length = header::check(flac.slice_from(start)).unwrap();
+
+    let mut data = flac.slice_to(start + length).to_vec();
+    data.push_all(Vec::from_elem(1 << 20, 0x00u8).as_slice());
+
+    let mut reader = super::AsyncFlacReader::new(Trickle { data: data, position: 0, ready: false });
+
+    loop {
+      match reader.poll_block() {
+        Ok(super::Ready(None)) => panic!("Input ended without an error"),
+        Ok(_) => assert!(reader.splitter.len() <= max_frame + header::MAX_LENGTH),
+        Err(reader::Io(error)) => {
+          assert_eq!(error.kind, std::io::InvalidInput);
+          break;
+        },
+        Err(reader::Limit(exceeded)) => panic!("Limits::new() exceeded with {}", exceeded)
+      }
+    }
+
+    assert_eq!(reader.input.position - start, max_frame + header::MAX_LENGTH);
+  }
+
+  fn exceeded(data: Vec<u8>, limits: limits::Limits) -> limits::Exceeded {
+    return match poll_error(data, limits) {
+      reader::Limit(exceeded) => exceeded,
+      reader::Io(error) => panic!("{}", error)
+    };
+  }
+
+  #[test]
+  fn test_limits() {
+    let flac = encode(noise(2 * 10000, 16, 47).as_slice());
+
+    let mut tagged = id3::tests::tag("Limits", false);
+    let tag = tagged.len();
+    tagged.push_all(flac.as_slice());
+
+    assert_eq!(exceeded(tagged.clone(), limits::Limits { max_metadata_block: tag - 1, ..limits::Limits::new() }), limits::MetadataBlock(tag));
+    assert_eq!(exceeded(tagged.clone(), limits::Limits { max_metadata: tag + 33, ..limits::Limits::new() }), limits::Metadata(tag + 34));
+    assert_eq!(exceeded(tagged, limits::Limits { max_channels: 1, ..limits::Limits::new() }), limits::Channels(2));
+
+    // A block that says it's 16 MiB, with nothing after it
+    let mut huge = b"fLaC".to_vec();
+    huge.push_all(&::metadata::header(true, 4, (1 << 24) - 1));
+
+    assert_eq!(exceeded(huge, limits::Limits { max_metadata_block: 1 << 20, ..limits::Limits::new() }), limits::MetadataBlock((1 << 24) - 1));
+  }
+}
@@ -0,0 +1,686 @@
+use prelude::*;
+
+#[cfg(feature = "std")]
+use aurora;
+
+use crc;
+use invalid::Invalid;
+use simd;
+use simd::{Kernel, Scalar};
+
+static PAST_END: Invalid = Invalid("flac::bitstream: Read past the end of the data");
+
+// Reading past the end of the input is an error, so that every parser
+// built on these fails the same way on input that's cut off
+pub trait BitRead {
+  fn read_n(&mut self, n: uint) -> Result<u32, Invalid>;
+  fn read_n_signed(&mut self, n: uint) -> Result<i32, Invalid>;
+
+  // Number of 0 bits before the next 1 bit, which is read as well
+  fn read_unary(&mut self) -> Result<u32, Invalid> {
+    let mut n = 0u32;
+
+    while try!(self.read_n(1)) == 0 {
+      n += 1;
+    }
+
+    return Ok(n);
+  }
+
+  // Fills output with Rice coded values, and returns how many bits that took
+  fn read_rice(&mut self, parameter: uint, output: &mut [i32]) -> Result<u64, Invalid> {
+    let mut bits = 0u64;
+
+    for value in output.iter_mut() {
+      let quotient = try!(self.read_unary());
+      let remainder = if parameter > 0 { try!(self.read_n(parameter)) } else { 0 };
+
+      *value = unfold((quotient << parameter) | remainder);
+      bits += quotient as u64 + 1 + parameter as u64;
+    }
+
+    return Ok(bits);
+  }
+}
+
+// Rice codes zig-zag signed values, 0, -1, 1, -2, ...
+#[inline]
+fn unfold(folded: u32) -> i32 {
+  return (folded >> 1) as i32 ^ -((folded & 1) as i32);
+}
+
+// Unfolds values in place, as many at a time as the kernel's vectors hold and
+// the rest one by one. The kernel has to be one of simd::kernels().
+fn unfold_with(kernel: Kernel, values: &mut [i32]) {
+  let lanes = match kernel { Scalar => 1, simd::Avx2 => 8, _ => 4 };
+  let n = values.len() - values.len() % lanes;
+
+  if lanes > 1 && n > 0 {
+    unsafe { unfold_vector(kernel, values.as_mut_ptr(), n) };
+  }
+
+  for value in values.slice_from_mut(n).iter_mut() {
+    *value = unfold(*value as u32);
+  }
+}
+
+// SSE2 does for the Sse41 kernel, as every x86_64 CPU has it
+#[cfg(target_arch = "x86_64")]
+unsafe fn unfold_vector(kernel: Kernel, values: *mut i32, n: uint) {
+  let mut values = values;
+  let mut n = n;
+
+  match kernel {
+    simd::Sse41 => {
+      asm!("1:
+            movdqu ($0), %xmm0
+            movdqa %xmm0, %xmm1
+            psrld $$1, %xmm0
+            pslld $$31, %xmm1
+            psrad $$31, %xmm1
+            pxor %xmm1, %xmm0
+            movdqu %xmm0, ($0)
+            add $$16, $0
+            sub $$4, $1
+            jnz 1b"
+        : "+r"(values), "+r"(n)
+        :
+        : "xmm0", "xmm1", "cc", "memory");
+    },
+    simd::Avx2 => {
+      asm!("1:
+            vmovdqu ($0), %ymm0
+            vpslld $$31, %ymm0, %ymm1
+            vpsrad $$31, %ymm1, %ymm1
+            vpsrld $$1, %ymm0, %ymm0
+            vpxor %ymm1, %ymm0, %ymm0
+            vmovdqu %ymm0, ($0)
+            add $$32, $0
+            sub $$8, $1
+            jnz 1b
+            vzeroupper"
+        : "+r"(values), "+r"(n)
+        :
+        : "xmm0", "xmm1", "cc", "memory");
+    },
+    _ => panic!("flac::bitstream: No {} kernel on x86_64 (BUG)", kernel)
+  }
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn unfold_vector(kernel: Kernel, values: *mut i32, n: uint) {
+  let mut values = values;
+  let mut n = n;
+
+  match kernel {
+    simd::Neon => {
+      asm!("1:
+            ld1 {v0.4s}, [$0]
+            shl v1.4s, v0.4s, #31
+            sshr v1.4s, v1.4s, #31
+            ushr v0.4s, v0.4s, #1
+            eor v0.16b, v0.16b, v1.16b
+            st1 {v0.4s}, [$0], #16
+            subs $1, $1, #4
+            b.ne 1b"
+        : "+r"(values), "+r"(n)
+        :
+        : "v0", "v1", "cc", "memory");
+    },
+    _ => panic!("flac::bitstream: No {} kernel on aarch64 (BUG)", kernel)
+  }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+unsafe fn unfold_vector(kernel: Kernel, _: *mut i32, _: uint) {
+  panic!("flac::bitstream: No {} kernel on this target (BUG)", kernel);
+}
+
+#[cfg(feature = "std")]
+impl<'a> BitRead for aurora::stream::Bitstream<'a> {
+  fn read_n(&mut self, n: uint) -> Result<u32, Invalid> {
+    return Ok(self.read_n(n));
+  }
+
+  fn read_n_signed(&mut self, n: uint) -> Result<i32, Invalid> {
+    return Ok(self.read_n_signed(n));
+  }
+}
+
+// Reads from a byte slice already in memory.
+// Up to 64 bits at a time are kept in a cache, so most reads are a shift,
+// and unary codes are counted with leading zeros instead of bit by bit.
+pub struct SliceReader<'a> {
+  data: &'a [u8],
+  position: uint, // Next byte to load into the cache
+  cache: u64, // From the most significant bit, bits after the valid ones are 0
+  bits: uint
+}
+
+impl<'a> SliceReader<'a> {
+  pub fn new(data: &'a [u8]) -> SliceReader<'a> {
+    return SliceReader { data: data, position: 0, cache: 0, bits: 0 };
+  }
+
+  fn refill(&mut self) {
+    if self.position + 8 <= self.data.len() {
+      let word = self.data.slice(self.position, self.position + 8).iter().fold(0u64, |a, &b| (a << 8) | b as u64);
+      let bytes = (64 - self.bits) / 8;
+
+      self.cache |= word >> self.bits;
+      self.bits += 8 * bytes;
+      self.position += bytes;
+
+      if self.bits < 64 {
+        self.cache &= !(0xFFFFFFFFFFFFFFFFu64 >> self.bits);
+      }
+    } else {
+      while self.bits <= 56 && self.position < self.data.len() {
+        self.cache |= self.data[self.position] as u64 << (56 - self.bits);
+        self.position += 1;
+        self.bits += 8;
+      }
+    }
+  }
+
+  fn skip(&mut self, n: uint) {
+    self.cache = if n < 64 { self.cache << n } else { 0 };
+    self.bits -= n;
+  }
+}
+
+impl<'a> BitRead for SliceReader<'a> {
+  fn read_n(&mut self, n: uint) -> Result<u32, Invalid> {
+    if n == 0 {
+      return Ok(0);
+    }
+
+    if self.bits < n {
+      self.refill();
+
+      if self.bits < n {
+        return Err(PAST_END);
+      }
+    }
+
+    let value = (self.cache >> (64 - n)) as u32;
+
+    self.skip(n);
+
+    return Ok(value);
+  }
+
+  fn read_n_signed(&mut self, n: uint) -> Result<i32, Invalid> {
+    if n == 0 {
+      return Ok(0);
+    }
+
+    let shift = 32 - n;
+
+    return Ok((try!(self.read_n(n)) << shift) as i32 >> shift);
+  }
+
+  fn read_unary(&mut self) -> Result<u32, Invalid> {
+    let mut n = 0u32;
+
+    loop {
+      if self.bits == 0 {
+        self.refill();
+
+        if self.bits == 0 {
+          return Err(PAST_END);
+        }
+      }
+
+      let zeros = self.cache.leading_zeros() as uint;
+
+      if zeros < self.bits {
+        self.skip(zeros + 1);
+
+        return Ok(n + zeros as u32);
+      }
+
+      n += self.bits as u32;
+      self.skip(self.bits);
+    }
+  }
+
+  fn read_rice(&mut self, parameter: uint, output: &mut [i32]) -> Result<u64, Invalid> {
+    let start = 8 * self.position as u64 - self.bits as u64;
+
+    for value in output.iter_mut() {
+      let quotient = try!(self.read_unary());
+
+      // Most remainders are in the cache already
+      let remainder = if parameter == 0 {
+        0
+      } else if self.bits >= parameter {
+        let remainder = (self.cache >> (64 - parameter)) as u32;
+
+        self.skip(parameter);
+
+        remainder
+      } else {
+        try!(self.read_n(parameter))
+      };
+
+      *value = ((quotient << parameter) | remainder) as i32;
+    }
+
+    // Unfolded all at once, so that it's vectorized
+    unfold_with(simd::kernel(), output);
+
+    return Ok(8 * self.position as u64 - self.bits as u64 - start);
+  }
+}
+
+// Keeps track of how many bits have been read, frames are padded to a byte
+// boundary before the CRC-16 and the bit readers can't tell us where we are.
+// The CRC-8 and CRC-16 of what's been read are kept as well, from the bits
+// each read had to have been, since readers don't hand out their bytes.
+pub struct Counter<'a, B: 'a> {
+  inner: &'a mut B,
+  bits: u64,
+  pending: u64, // Bits that don't make up a byte yet, at the bottom
+  crc8: u8,
+  crc16: u16
+}
+
+impl<'a, B: BitRead> Counter<'a, B> {
+  pub fn new(inner: &'a mut B) -> Counter<'a, B> {
+    return Counter { inner: inner, bits: 0, pending: 0, crc8: 0, crc16: 0 };
+  }
+
+  pub fn bits(&self) -> u64 {
+    return self.bits;
+  }
+
+  pub fn align(&mut self) -> Result<(), Invalid> {
+    let padding = (8 - self.bits % 8) % 8;
+
+    if padding > 0 {
+      try!(self.read_n(padding as uint));
+    }
+
+    return Ok(());
+  }
+
+  // Of the whole bytes read, which is 0 once the CRC itself has been read
+  pub fn crc8(&self) -> u8 {
+    return self.crc8;
+  }
+
+  pub fn crc16(&self) -> u16 {
+    return self.crc16;
+  }
+
+  // Counts n bits of value, up to 32 at a time
+  fn feed(&mut self, value: u32, n: uint) {
+    let pending_bits = (self.bits % 8) as uint;
+
+    self.pending = (self.pending << n) | value as u64;
+    self.bits += n as u64;
+
+    let mut bits = pending_bits + n;
+
+    while bits >= 8 {
+      bits -= 8;
+
+      let byte = (self.pending >> bits) as u8;
+
+      self.crc8 = crc::update8(self.crc8, byte);
+      self.crc16 = crc::update16(self.crc16, byte);
+    }
+
+    self.pending &= (1 << bits) - 1;
+  }
+
+  // Counts the zeros and the one of a unary code
+  fn feed_unary(&mut self, n: u32) {
+    let mut zeros = n;
+
+    while zeros >= 32 {
+      self.feed(0, 32);
+      zeros -= 32;
+    }
+
+    self.feed(1, zeros as uint + 1);
+  }
+}
+
+impl<'a, B: BitRead> BitRead for Counter<'a, B> {
+  fn read_n(&mut self, n: uint) -> Result<u32, Invalid> {
+    let value = try!(self.inner.read_n(n));
+
+    self.feed(value, n);
+
+    return Ok(value);
+  }
+
+  fn read_n_signed(&mut self, n: uint) -> Result<i32, Invalid> {
+    let value = try!(self.inner.read_n_signed(n));
+
+    if n > 0 {
+      self.feed(value as u32 & (0xFFFFFFFF >> (32 - n)), n);
+    }
+
+    return Ok(value);
+  }
+
+  fn read_unary(&mut self) -> Result<u32, Invalid> {
+    let n = try!(self.inner.read_unary());
+
+    self.feed_unary(n);
+
+    return Ok(n);
+  }
+
+  // Rice codes are the same bits for the same value, so they're worked out
+  // again from the values
+  fn read_rice(&mut self, parameter: uint, output: &mut [i32]) -> Result<u64, Invalid> {
+    let bits = try!(self.inner.read_rice(parameter, output));
+
+    for &value in output.iter() {
+      let folded = ((value << 1) ^ (value >> 31)) as u32;
+
+      self.feed_unary(folded >> parameter);
+
+      if parameter > 0 {
+        self.feed(folded & (0xFFFFFFFF >> (32 - parameter)), parameter);
+      }
+    }
+
+    return Ok(bits);
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use test;
+
+  use test_util::noise;
+
+  use invalid::Invalid;
+
+  use super::BitRead;
+
+  // Packs bits from the most significant, padded to whole bytes with zeros.
+  // Apart from the encoder's BitWriter so that these tests don't need it.
+  struct BitWriter {
+    data: Vec<u8>,
+    bits: uint
+  }
+
+  impl BitWriter {
+    fn new() -> BitWriter {
+      return BitWriter { data: Vec::new(), bits: 0 };
+    }
+
+    fn write_bit(&mut self, one: bool) {
+      if self.bits % 8 == 0 {
+        self.data.push(0x00);
+      }
+
+      if one {
+        let last = self.data.len() - 1;
+        *self.data.get_mut(last) |= 0x80 >> (self.bits % 8);
+      }
+
+      self.bits += 1;
+    }
+
+    fn write_n(&mut self, value: u32, n: uint) {
+      for i in range(0, n).rev() {
+        self.write_bit((value >> i) & 1 != 0);
+      }
+    }
+
+    fn write_unary(&mut self, n: u32) {
+      for _ in range(0, n) {
+        self.write_bit(false);
+      }
+
+      self.write_bit(true);
+    }
+
+    fn write_rice(&mut self, value: i32, parameter: uint) {
+      let folded = ((value << 1) ^ (value >> 31)) as u32;
+
+      self.write_unary(folded >> parameter);
+      self.write_n(folded & ((1 << parameter) - 1), parameter);
+    }
+
+    fn unwrap(self) -> Vec<u8> {
+      return self.data;
+    }
+  }
+
+  // Reads one bit at a time, with the default unary and Rice decoding
+  struct BitByBit<'a> {
+    data: &'a [u8],
+    position: uint
+  }
+
+  impl<'a> BitRead for BitByBit<'a> {
+    fn read_n(&mut self, n: uint) -> Result<u32, Invalid> {
+      let mut value = 0u32;
+
+      for _ in range(0, n) {
+        value = (value << 1) | ((self.data[self.position / 8] >> (7 - self.position % 8)) & 1) as u32;
+        self.position += 1;
+      }
+
+      return Ok(value);
+    }
+
+    fn read_n_signed(&mut self, n: uint) -> Result<i32, Invalid> {
+      return Ok(if n == 0 { 0 } else { (try!(self.read_n(n)) << (32 - n)) as i32 >> (32 - n) });
+    }
+  }
+
+  fn rice(values: &[i32], parameter: uint) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+
+    for &value in values.iter() {
+      writer.write_rice(value, parameter);
+    }
+
+    return writer.unwrap();
+  }
+
+  #[test]
+  fn test_read_n() {
+    let data: Vec<u8> = noise(1000, 16, 47).iter().map(|&n| n as u8).collect();
+
+    let mut expected = BitByBit { data: data.as_slice(), position: 0 };
+    let mut reader = super::SliceReader::new(data.as_slice());
+
+    let mut n = 0u;
+
+    while 8 * data.len() - expected.position >= n + n % 17 {
+      assert_eq!(reader.read_n(n), expected.read_n(n));
+      assert_eq!(reader.read_n_signed(n % 17), expected.read_n_signed(n % 17));
+
+      n = (n + 5) % 33;
+    }
+  }
+
+  #[test]
+  fn test_read_past_end() {
+    let mut reader = super::SliceReader::new(&[0x00, 0x01]);
+
+    assert_eq!(reader.read_n(17), Err(super::PAST_END));
+    assert_eq!(reader.read_unary(), Ok(15));
+    assert_eq!(reader.read_unary(), Err(super::PAST_END));
+  }
+
+  #[test]
+  fn test_unary_and_rice() {
+    let values: Vec<i32> = noise(4096, 16, 48).iter().map(|&n| n >> 6).collect();
+
+    for &parameter in [0u, 3, 9, 14].iter() {
+      let values: Vec<i32> = values.iter().map(|&v| v >> (14 - parameter)).collect();
+      let data = rice(values.as_slice(), parameter);
+
+      let mut output = Vec::from_elem(values.len(), 0i32);
+      let mut expected = Vec::from_elem(values.len(), 0i32);
+
+      let mut reader = super::SliceReader::new(data.as_slice());
+      let mut bit_by_bit = BitByBit { data: data.as_slice(), position: 0 };
+
+      let bits = reader.read_rice(parameter, output.as_mut_slice()).unwrap();
+
+      assert_eq!(bit_by_bit.read_rice(parameter, expected.as_mut_slice()), Ok(bits));
+      assert_eq!(bits, bit_by_bit.position as u64);
+
+      assert_eq!(output, values);
+      assert_eq!(expected, values);
+    }
+
+    // Longer than the cache
+    let mut writer = BitWriter::new();
+    writer.write_unary(100);
+    writer.write_n(0b101, 3);
+
+    let data = writer.unwrap();
+    let mut reader = super::SliceReader::new(data.as_slice());
+
+    assert_eq!(reader.read_unary(), Ok(100));
+    assert_eq!(reader.read_n(3), Ok(0b101));
+  }
+
+  #[test]
+  fn test_unfold_kernels() {
+    let mut folded: Vec<i32> = noise(1000, 32, 51);
+    folded.push_all(&[0, 1, 2, -1, -2, 0x7FFFFFFF, 0x80000000u32 as i32]);
+
+    let expected: Vec<i32> = folded.iter().map(|&v| super::unfold(v as u32)).collect();
+
+    for &kernel in ::simd::kernels().iter() {
+      // Lengths that leave each number of values after the vectors
+      for n in range(folded.len() - 9, folded.len() + 1) {
+        let mut values = folded.slice_to(n).to_vec();
+
+        super::unfold_with(kernel, values.as_mut_slice());
+
+        assert_eq!(values.as_slice(), expected.slice_to(n));
+      }
+    }
+  }
+
+  #[test]
+  fn test_counter_crc() {
+    let values: Vec<i32> = noise(100, 16, 50).iter().map(|&n| n >> 20).collect();
+
+    let mut writer = BitWriter::new();
+    writer.write_n(0xFFF8, 16);
+    writer.write_unary(70);
+    writer.write_n(0x15, 5);
+
+    for &value in values.iter() {
+      writer.write_rice(value, 6);
+    }
+
+    writer.write_n(0x7FFFFFFF, 31);
+
+    let data = writer.unwrap();
+
+    let mut reader = super::SliceReader::new(data.as_slice());
+    let mut counter = super::Counter::new(&mut reader);
+    let mut output = Vec::from_elem(values.len(), 0i32);
+
+    counter.read_n(16).unwrap();
+    assert_eq!(counter.crc8(), ::crc::crc8(data.slice_to(2)));
+
+    counter.read_unary().unwrap();
+    counter.read_n_signed(5).unwrap();
+    counter.read_rice(6, output.as_mut_slice()).unwrap();
+    counter.read_n(31).unwrap();
+    counter.align().unwrap();
+
+    assert_eq!(output, values);
+    assert_eq!(counter.crc8(), ::crc::crc8(data.as_slice()));
+    assert_eq!(counter.crc16(), ::crc::crc16(data.as_slice()));
+  }
+
+  // 4096 residual sized values, Rice coded with parameter 8
+  pub fn residual() -> (Vec<u8>, Vec<i32>) {
+    let values: Vec<i32> = noise(4096, 16, 49).iter().map(|&n| n >> 20).collect();
+
+    return (rice(values.as_slice(), 8), values);
+  }
+
+  #[bench]
+  fn bench_rice_cached(b: &mut test::Bencher) {
+    let (data, mut output) = residual();
+
+    b.bytes = 4 * output.len() as u64;
+
+    b.iter(|| {
+      super::SliceReader::new(data.as_slice()).read_rice(8, output.as_mut_slice())
+    });
+  }
+}
+
+// aurora's bitstreams read the same as a SliceReader, one field at a time
+#[cfg(all(test, feature = "std"))]
+mod aurora_tests {
+  use test;
+
+  use aurora;
+
+  use super::{BitRead, SliceReader};
+  use super::tests::residual;
+
+  static FRAME: &'static [u8] = include_bin!("../test-vectors/frames/bad_apple.1");
+
+  fn read<B: BitRead>(bitstream: &mut B, n: uint) -> (u32, i32) {
+    let unsigned = bitstream.read_n(n).unwrap();
+
+    return (unsigned, bitstream.read_n_signed(n).unwrap());
+  }
+
+  #[test]
+  fn test_matches_slice_reader() {
+    let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(FRAME.to_vec(), 4096, sink_0).run();
+    });
+
+    let mut stream = aurora::stream::Stream::new(&mut source_0);
+    let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+    let mut reader = SliceReader::new(FRAME);
+
+    let mut position = 0u;
+    let mut n = 1u;
+
+    while position + 2 * n <= 8 * FRAME.len() {
+      assert_eq!(read(&mut bitstream, n), read(&mut reader, n));
+
+      position += 2 * n;
+      n = 1 + (n + 6) % 32;
+    }
+  }
+
+  // The path the decoder took before SliceReader, for bench_rice_cached to
+  // compare against. The channel holds all of the data, so the buffer runs
+  // to the end before reading starts and no task is spawned.
+  #[bench]
+  fn bench_rice_aurora(b: &mut test::Bencher) {
+    let (data, mut output) = residual();
+
+    b.bytes = 4 * output.len() as u64;
+
+    b.iter(|| {
+      let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(data.len() / 4096 + 2);
+
+      aurora::buffer::Buffer::new(data.clone(), 4096, sink_0).run();
+
+      let mut stream = aurora::stream::Stream::new(&mut source_0);
+      let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+
+      bitstream.read_rice(8, output.as_mut_slice())
+    });
+  }
+}
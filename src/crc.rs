@@ -0,0 +1,62 @@
+use prelude::*;
+
+// CRC-8 protects the frame header, polynomial x^8 + x^2 + x^1 + x^0
+pub fn crc8(data: &[u8]) -> u8 {
+  return data.iter().fold(0u8, |crc, &byte| update8(crc, byte));
+}
+
+pub fn update8(crc: u8, byte: u8) -> u8 {
+  let mut crc = crc ^ byte;
+
+  for _ in range(0u, 8) {
+    crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+  }
+
+  return crc;
+}
+
+// CRC-16 protects the whole frame, polynomial x^16 + x^15 + x^2 + x^0
+pub fn crc16(data: &[u8]) -> u16 {
+  return data.iter().fold(0u16, |crc, &byte| update16(crc, byte));
+}
+
+pub fn update16(crc: u16, byte: u8) -> u16 {
+  let mut crc = crc ^ (byte as u16) << 8;
+
+  for _ in range(0u, 8) {
+    crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+  }
+
+  return crc;
+}
+
+#[cfg(test)]
+mod tests {
+  // The first frames of Bad Apple, and the first stored verbatim
+  static FRAMES: [&'static [u8], ..4] = [
+    include_bin!("../test-vectors/frames/bad_apple.1"),
+    include_bin!("../test-vectors/frames/bad_apple.2"),
+    include_bin!("../test-vectors/frames/bad_apple.3"),
+    include_bin!("../test-vectors/frames/bad_apple_verbatim.1")
+  ];
+
+  #[test]
+  fn test_crc8_of_frame_headers() {
+    assert_eq!(super::crc8(FRAMES[0].slice(0, 5)), 0xC2);
+    assert_eq!(super::crc8(FRAMES[1].slice(0, 5)), 0xC5);
+    assert_eq!(super::crc8(FRAMES[2].slice(0, 5)), 0xCC);
+    assert_eq!(super::crc8(FRAMES[3].slice(0, 5)), 0xAE);
+  }
+
+  #[test]
+  fn test_crc16_of_frames() {
+    for frame in FRAMES.iter() {
+      let n = frame.len();
+
+      let expected = (frame[n - 2] as u16 << 8) | frame[n - 1] as u16;
+
+      assert_eq!(super::crc16(frame.slice(0, n - 2)), expected);
+      assert_eq!(super::crc16(*frame), 0);
+    }
+  }
+}
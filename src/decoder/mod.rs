@@ -0,0 +1,301 @@
+use std;
+
+use aurora;
+
+use bitstream::{BitRead, SliceReader};
+use frame::header::Header;
+use invalid::Invalid;
+use metadata::stream_info::StreamInfo;
+
+pub use self::parallel::ParallelDecoder;
+
+use self::parallel::Splitter;
+
+pub mod parallel;
+
+pub struct Decoder {
+  source: aurora::channel::Source<aurora::Binary>,
+  metadata_source: aurora::channel::Source<::metadata::Metadata>,
+  sink: aurora::channel::Sink<aurora::Audio>,
+  chained: bool
+}
+
+impl Decoder {
+  pub fn new(source: aurora::channel::Source<aurora::Binary>, metadata_source: aurora::channel::Source<::metadata::Metadata>, sink: aurora::channel::Sink<aurora::Audio>) -> Decoder {
+    return Decoder { source: source, metadata_source: metadata_source, sink: sink, chained: false };
+  }
+
+  // For demuxers that can have one stream after another, like chained Ogg
+  // files. The metadata of each stream follows the last frame of the one
+  // before, and an Unknown block that is the last one, with no stream info
+  // before it, marks the end.
+  pub fn chained(source: aurora::channel::Source<aurora::Binary>, metadata_source: aurora::channel::Source<::metadata::Metadata>, sink: aurora::channel::Sink<aurora::Audio>) -> Decoder {
+    return Decoder { source: source, metadata_source: metadata_source, sink: sink, chained: true };
+  }
+
+  // Streams that don't say how many samples they have, like raw frames, end
+  // with the input, so frames are split out of it to see which one is last
+  fn run_to_end(&mut self, stream_info: &StreamInfo) {
+    let mut splitter = Splitter::new(&[], 1);
+    let mut frames = FrameDecoder::new(stream_info.clone());
+    let mut finished = false;
+    let mut done = false;
+
+    // Up to the largest frame there can be, and the header after it
+    let max_size = ::frame::max_size(::metadata::stream_info::max_block_size(stream_info), stream_info.channels as uint, stream_info.bits_per_sample) + ::frame::header::MAX_LENGTH;
+
+    while !done {
+      let decoded = match splitter.next_slice(finished) {
+        Some(frame) => Some(valid!(frames.decode(&mut SliceReader::new(frame)))),
+        None => None
+      };
+
+      match decoded {
+        Some((header, subframes)) => {
+          done = finished && splitter.is_empty();
+
+          self.sink.write(|audio| {
+            ::frame::fill(&header, subframes, audio);
+
+            audio.last = done;
+          });
+        },
+        None if finished => {
+          self.sink.write(|audio| {
+            describe(stream_info, audio);
+
+            audio.last = true;
+          });
+
+          done = true;
+        },
+        None if splitter.len() > max_size => {
+          panic!("flac::Decoder: Frame is larger than STREAMINFO allows (INPUT)");
+        },
+        None => {
+          self.source.read(|binary| {
+            splitter.push(binary.data.as_slice());
+            finished = binary.last;
+          });
+        }
+      }
+    }
+  }
+
+  pub fn run(&mut self) {
+    let chained = self.chained;
+
+    let mut stream_info = match next_stream_info(&mut self.metadata_source, chained) {
+      Some(stream_info) => stream_info,
+      None => {
+        self.sink.write(|audio| {
+          audio.last = true;
+        });
+
+        return;
+      }
+    };
+
+    if stream_info.samples == 0 && !chained {
+      return self.run_to_end(&stream_info);
+    }
+
+    let mut stream = aurora::stream::Stream::new(&mut self.source);
+    let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+
+    loop {
+      if stream_info.samples == 0 {
+        panic!("flac::Decoder: Chained streams need to say how many samples they have (INPUT)");
+      }
+
+      let mut last = false;
+      let mut samples_remaining = stream_info.samples;
+      let mut frames = FrameDecoder::new(stream_info.clone());
+
+      while !last {
+        let bs = &mut bitstream;
+        let sink = &mut self.sink;
+        let frames = &mut frames;
+
+        sink.write(|audio| {
+          let (header, subframes) = valid!(frames.decode(bs));
+
+          ::frame::fill(&header, subframes, audio);
+
+          samples_remaining -= header.block_size as u64;
+
+          last = samples_remaining == 0;
+
+          audio.last = last && !chained;
+        });
+      }
+
+      if !chained {
+        return;
+      }
+
+      stream_info = match next_stream_info(&mut self.metadata_source, chained) {
+        Some(stream_info) => stream_info,
+        None => {
+          self.sink.write(|audio| {
+            audio.last = true;
+          });
+
+          return;
+        }
+      };
+    }
+  }
+}
+
+// Decodes frames into buffers sized once from the stream info, so that after
+// new it doesn't allocate
+pub struct FrameDecoder {
+  stream_info: StreamInfo,
+  subframes: Vec<Vec<i32>>
+}
+
+impl FrameDecoder {
+  pub fn new(stream_info: StreamInfo) -> FrameDecoder {
+    let block_size = ::metadata::stream_info::max_block_size(&stream_info);
+    let subframes = Vec::from_fn(stream_info.channels as uint, |_| Vec::from_elem(block_size, 0i32));
+
+    return FrameDecoder { stream_info: stream_info, subframes: subframes };
+  }
+
+  // The samples of each channel, valid up to the block size in the header and
+  // until the next frame is decoded
+  pub fn decode<'a, B: BitRead>(&'a mut self, bitstream: &mut B) -> Result<(Header, &'a [Vec<i32>]), Invalid> {
+    let header = try!(::frame::decode_into(bitstream, Some(&self.stream_info), self.subframes.as_mut_slice()));
+    let channels = ::frame::channels(header.channel_assignment);
+
+    return Ok((header, self.subframes.slice_to(channels)));
+  }
+}
+
+// Gives audio without samples the format of the stream, so a stream with no
+// frames at all still says what it would have been
+fn describe(stream_info: &StreamInfo, audio: &mut aurora::Audio) {
+  audio.channels = stream_info.channels as uint;
+  audio.sample_rate = stream_info.sample_rate as f64;
+  audio.endian = aurora::endian::Big;
+  audio.sample_type = aurora::sample_type::Signed(stream_info.bits_per_sample as uint);
+}
+
+// Reads the metadata of the next stream, None for the Unknown last block that
+// ends chained streams
+fn next_stream_info(metadata_source: &mut aurora::channel::Source<::metadata::Metadata>, chained: bool) -> Option<StreamInfo> {
+  let mut ty = ::metadata::Unknown;
+  let mut last = false;
+
+  metadata_source.read(|metadata| { ty = metadata.ty.clone(); last = metadata.last });
+
+  let stream_info = match ty {
+    ::metadata::StreamInfo(si) => si,
+    ::metadata::Unknown if chained && last => return None,
+    _ => panic!("Metadata didn't start with a stream info, and it has to according to the spec")
+  };
+
+  while !last {
+    metadata_source.read(|metadata| { last = metadata.last });
+  }
+
+  return Some(stream_info);
+}
+
+// The allocation count comes from jemalloc, which is what these use
+#[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+mod tests {
+  use std;
+
+  use bitstream::SliceReader;
+  use frame;
+  use metadata;
+
+  use decoder::parallel::Splitter;
+  use test_util::{encode, noise, parameters};
+
+  #[test]
+  fn test_frame_decoder_does_not_allocate() {
+    let mut parameters = parameters(2, 4096);
+    parameters.samples = Some(20000);
+
+    let buffer = encode(parameters, noise(2 * 20000, 16, 43).as_slice());
+
+    let mut block = metadata::Metadata { ty: metadata::Unknown, data: Vec::new(), last: false };
+    metadata::from_bytes(buffer.slice(4, 4 + 4 + 34), &mut block).unwrap();
+
+    let stream_info = match block.ty {
+      metadata::StreamInfo(stream_info) => stream_info,
+      _ => panic!("First block isn't the stream info")
+    };
+
+    let mut start = 4;
+
+    loop {
+      let header = buffer.slice(start, start + 4);
+
+      start += 4 + ((header[1] as uint << 16) | (header[2] as uint << 8) | header[3] as uint);
+
+      if header[0] & 0x80 != 0 {
+        break;
+      }
+    }
+
+    let mut splitter = Splitter::new(&[], 1);
+    splitter.push(buffer.slice_from(start));
+    let mut frames = super::FrameDecoder::new(stream_info.clone());
+
+    let mut n = 0u;
+
+    loop {
+      let data = match splitter.next(true) {
+        Some(data) => data,
+        None => break
+      };
+
+      let (expected_header, expected) = frame::decode(&mut SliceReader::new(data.as_slice()), Some(&stream_info)).unwrap();
+
+      let before = allocated();
+      let (header, subframes) = frames.decode(&mut SliceReader::new(data.as_slice())).unwrap();
+
+      assert_eq!(allocated(), before);
+      assert_eq!(header, expected_header);
+
+      for (subframe, expected) in subframes.iter().zip(expected.iter()) {
+        assert_eq!(subframe.slice_to(header.block_size as uint), expected.as_slice());
+      }
+
+      n += 1;
+    }
+
+    assert_eq!(n, (20000 + 4095) / 4096);
+  }
+
+  // Bytes the allocator has handed out to this thread so far, which jemalloc
+  // keeps count of
+  fn allocated() -> u64 {
+    extern {
+      fn je_mallctl(name: *const u8, old: *mut u8, old_length: *mut uint, new: *mut u8, new_length: uint) -> i32;
+    }
+
+    let mut allocated = 0u64;
+    let mut length = std::mem::size_of::<u64>();
+
+    let result = unsafe {
+      je_mallctl(b"thread.allocated\0".as_ptr(), &mut allocated as *mut u64 as *mut u8, &mut length, std::ptr::null_mut(), 0)
+    };
+
+    assert_eq!(result, 0);
+
+    return allocated;
+  }
+
+  #[test]
+  fn test_allocated_counts() {
+    let before = allocated();
+    let buffer = Vec::from_elem(1000, 0u8);
+
+    assert!(allocated() >= before + buffer.len() as u64);
+  }
+}
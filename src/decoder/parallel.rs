@@ -0,0 +1,612 @@
+use std::cmp;
+use std::comm::{sync_channel, SyncSender};
+use std::task;
+
+use aurora;
+
+use bitstream::{Counter, SliceReader};
+use crc;
+use frame;
+use frame::header;
+use invalid::Invalid;
+use metadata;
+
+// Cuts frame data into segments of whole frames that decode independently,
+// at seek point offsets when there are any and by scanning for frame headers
+// otherwise. Seek points are only taken at their word where a frame header
+// starts and the CRC-16 of the frames before it matches.
+//
+// Segments are cut out by moving past them, and the buffer is only moved to
+// the front once what's cut out is as long as what's left, so data is copied
+// about once.
+pub struct Splitter {
+  data: Vec<u8>,
+  start: uint, // What's before it is cut out already
+  offset: u64, // Of start in the stream
+  boundaries: Vec<u64>,
+  frames: uint,
+  ends: Vec<uint>,
+  scan: uint
+}
+
+impl Splitter {
+  pub fn new(seek_points: &[metadata::seek_table::SeekPoint], frames: uint) -> Splitter {
+    let mut points: Vec<&metadata::seek_table::SeekPoint> = seek_points.iter().filter(|p| !p.is_placeholder() && p.offset > 0).collect();
+
+    points.sort_by(|a, b| a.sample_number.cmp(&b.sample_number));
+
+    // Later samples can't be at earlier offsets, so those points are wrong
+    let mut boundaries: Vec<u64> = Vec::with_capacity(points.len());
+
+    for point in points.iter() {
+      if boundaries.last().map_or(true, |&offset| point.offset > offset) {
+        boundaries.push(point.offset);
+      }
+    }
+
+    return Splitter { data: Vec::new(), start: 0, offset: 0, boundaries: boundaries, frames: frames, ends: Vec::with_capacity(frames), scan: 0 };
+  }
+
+  pub fn push(&mut self, data: &[u8]) {
+    let remaining = self.data.len() - self.start;
+
+    if self.start > 0 && self.start >= remaining {
+      for i in range(0, remaining) {
+        self.data.as_mut_slice()[i] = self.data[self.start + i];
+      }
+
+      self.data.truncate(remaining);
+      self.start = 0;
+    }
+
+    self.data.push_all(data);
+  }
+
+  pub fn is_empty(&self) -> bool {
+    return self.data.len() == self.start;
+  }
+
+  // Bytes pushed that aren't cut out yet
+  pub fn len(&self) -> uint {
+    return self.data.len() - self.start;
+  }
+
+  fn rest(&self) -> &[u8] {
+    return self.data.slice_from(self.start);
+  }
+
+  fn cut(&mut self, n: uint) -> Option<(uint, uint)> {
+    let segment = (self.start, self.start + n);
+
+    self.start += n;
+    self.offset += n as u64;
+    self.ends.truncate(0);
+    self.scan = 0;
+
+    return Some(segment);
+  }
+
+  // Start of the frame following the one at start
+  fn find(&mut self, start: uint, finished: bool) -> Option<uint> {
+    let from = cmp::max(self.scan, start + 1) - start;
+
+    return match frame::find_next(self.rest().slice_from(start), from, finished) {
+      Ok(i) => {
+        self.scan = start + i + 1;
+        Some(start + i)
+      },
+      Err(i) => {
+        self.scan = start + i;
+        None
+      }
+    };
+  }
+
+  // Where the next segment is in data
+  fn next_range(&mut self, finished: bool) -> Option<(uint, uint)> {
+    if self.is_empty() {
+      return None;
+    }
+
+    while !self.boundaries.is_empty() && self.boundaries[0] <= self.offset {
+      self.boundaries.remove(0);
+    }
+
+    let length = self.len();
+
+    if !self.boundaries.is_empty() {
+      let end = (self.boundaries[0] - self.offset) as uint;
+
+      if end == length && finished {
+        return self.cut(end);
+      }
+
+      if end + header::MAX_LENGTH <= length || (finished && end < length) {
+        if header::check(self.rest().slice_from(end)).is_some() && crc::crc16(self.rest().slice_to(end)) == 0 {
+          return self.cut(end);
+        }
+
+        // Not where a frame starts after all
+        self.boundaries.remove(0);
+
+        return self.next_range(finished);
+      }
+    } else {
+      while self.ends.len() < self.frames {
+        let start = match self.ends.last() {
+          Some(&end) => end,
+          None => 0
+        };
+
+        match self.find(start, finished) {
+          Some(end) => self.ends.push(end),
+          None => break
+        }
+      }
+
+      if self.ends.len() == self.frames {
+        let end = self.ends[self.frames - 1];
+
+        return self.cut(end);
+      }
+    }
+
+    if finished {
+      return self.cut(length);
+    }
+
+    return None;
+  }
+
+  pub fn next(&mut self, finished: bool) -> Option<Vec<u8>> {
+    return match self.next_range(finished) {
+      Some((start, end)) => Some(self.data.slice(start, end).to_vec()),
+      None => None
+    };
+  }
+
+  // Same as next, without copying the segment out. It's there until the next
+  // push.
+  pub fn next_slice(&mut self, finished: bool) -> Option<&[u8]> {
+    return match self.next_range(finished) {
+      Some((start, end)) => Some(self.data.slice(start, end)),
+      None => None
+    };
+  }
+}
+
+fn decode_segment(data: Vec<u8>, stream_info: &metadata::stream_info::StreamInfo) -> Result<Vec<(header::Header, Vec<Vec<i32>>)>, Invalid> {
+  let bits = 8 * data.len() as u64;
+
+  let mut reader = SliceReader::new(data.as_slice());
+  let mut counter = Counter::new(&mut reader);
+
+  let mut frames = Vec::new();
+
+  while counter.bits() < bits {
+    frames.push(try!(frame::decode(&mut counter, Some(stream_info))));
+  }
+
+  return Ok(frames);
+}
+
+// Decodes segments of frames on worker threads, and writes them to the sink
+// in stream order
+pub struct ParallelDecoder {
+  source: aurora::channel::Source<aurora::Binary>,
+  metadata_source: aurora::channel::Source<metadata::Metadata>,
+  sink: aurora::channel::Sink<aurora::Audio>,
+  threads: uint
+}
+
+impl ParallelDecoder {
+  pub fn new(source: aurora::channel::Source<aurora::Binary>, metadata_source: aurora::channel::Source<metadata::Metadata>, sink: aurora::channel::Sink<aurora::Audio>, threads: uint) -> ParallelDecoder {
+    if threads < 1 {
+      panic!("flac::ParallelDecoder: Needs at least one thread (INPUT)");
+    }
+
+    return ParallelDecoder { source: source, metadata_source: metadata_source, sink: sink, threads: threads };
+  }
+
+  pub fn run(self) {
+    valid!(self.try_run());
+  }
+
+  // Same as run, but a segment that doesn't decode stops it with an error
+  // instead of a panic, after the audio before it. The sink is dropped
+  // without a last audio then.
+  //
+  // Reading, decoding and output run at the same time, connected by bounded
+  // channels. The input is split on a task of its own, which hands segments
+  // to the workers in turn, and the results are put back in order here, so
+  // only a few segments per worker are ever held.
+  pub fn try_run(self) -> Result<(), Invalid> {
+    let ParallelDecoder { source, mut metadata_source, mut sink, threads } = self;
+
+    let mut stream_info = None;
+    let mut seek_points = Vec::new();
+    let mut last = false;
+
+    while !last {
+      metadata_source.read(|block| {
+        match block.ty {
+          metadata::StreamInfo(si) => stream_info = Some(si),
+          metadata::SeekTable(ref points) => seek_points = points.clone(),
+          _ => ()
+        }
+
+        last = block.last;
+      });
+    }
+
+    let stream_info = match stream_info {
+      Some(stream_info) => stream_info,
+      None => panic!("Metadata didn't contain a stream info, and it has to according to the spec")
+    };
+
+    let (result_sender, results) = sync_channel(threads);
+
+    let mut workers = Vec::with_capacity(threads);
+
+    for _ in range(0, threads) {
+      let (job_sender, job_receiver) = sync_channel::<(uint, Vec<u8>, bool)>(1);
+      let result_sender = result_sender.clone();
+      let stream_info = stream_info.clone();
+
+      // A segment that panics only takes its own task down, and the error
+      // goes out like any other. Workers stop once results aren't wanted.
+      spawn(proc() {
+        for (index, segment, last) in job_receiver.iter() {
+          let stream_info = stream_info.clone();
+
+          let frames = match task::try(proc() decode_segment(segment, &stream_info)) {
+            Ok(frames) => frames,
+            Err(_) => Err(Invalid("flac::ParallelDecoder: Decoding a segment panicked"))
+          };
+
+          if result_sender.send_opt((index, frames, last)).is_err() {
+            return;
+          }
+        }
+      });
+
+      workers.push(job_sender);
+    }
+
+    // Only the workers send results, so receiving fails once they're all gone
+    drop(result_sender);
+
+    spawn(proc() {
+      split(source, seek_points, workers);
+    });
+
+    // Results that came in ahead of the next one, by how far ahead
+    let mut pending: Vec<Option<(Result<Vec<(header::Header, Vec<Vec<i32>>)>, Invalid>, bool)>> = Vec::new();
+    let mut next = 0u;
+
+    loop {
+      match results.recv_opt() {
+        Ok((index, frames, last)) => {
+          while pending.len() <= index - next {
+            pending.push(None);
+          }
+
+          pending.as_mut_slice()[index - next] = Some((frames, last));
+        },
+        Err(()) => panic!("flac::ParallelDecoder: Workers stopped before every segment was decoded (BUG)")
+      }
+
+      while !pending.is_empty() && pending[0].is_some() {
+        let (frames, last) = match pending.remove(0) {
+          Some(Some(result)) => result,
+          _ => panic!("flac::ParallelDecoder: Result that was there is gone (BUG)")
+        };
+
+        let frames = try!(frames);
+        let n = frames.len();
+
+        for (i, &(ref header, ref subframes)) in frames.iter().enumerate() {
+          sink.write(|audio| {
+            frame::fill(header, subframes.as_slice(), audio);
+
+            audio.last = last && i == n - 1;
+          });
+        }
+
+        if last {
+          // A stream without frames ends with a segment without any
+          if n == 0 {
+            sink.write(|audio| {
+              audio.last = true;
+            });
+          }
+
+          return Ok(());
+        }
+
+        next += 1;
+      }
+    }
+  }
+}
+
+// Splits the input into segments for the workers, in turn. Each segment is
+// held back until the next one is cut, so the last goes out marked as last,
+// and a stream without frames still has one. Stops once the workers are gone.
+fn split(mut source: aurora::channel::Source<aurora::Binary>, seek_points: Vec<metadata::seek_table::SeekPoint>, workers: Vec<SyncSender<(uint, Vec<u8>, bool)>>) {
+  let mut splitter = Splitter::new(seek_points.as_slice(), 16);
+  let mut finished = false;
+  let mut held = None;
+  let mut index = 0u;
+
+  loop {
+    match splitter.next(finished) {
+      Some(segment) => {
+        match held.take() {
+          Some(previous) => {
+            if workers[index % workers.len()].send_opt((index, previous, false)).is_err() {
+              return;
+            }
+
+            index += 1;
+          },
+          None => ()
+        }
+
+        held = Some(segment);
+      },
+      None if finished => {
+        let _ = workers[index % workers.len()].send_opt((index, held.unwrap_or(Vec::new()), true));
+
+        return;
+      },
+      None => {
+        source.read(|binary| {
+          splitter.push(binary.data.as_slice());
+          finished = binary.last;
+        });
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std;
+  use aurora;
+
+  use demuxer;
+  use decoder;
+  use encoder;
+  use metadata;
+  use metadata::seek_table::{SeekPoint, PLACEHOLDER};
+  use test_util;
+  use test_util::noise;
+
+  fn frames() -> Vec<Vec<u8>> {
+    let parameters = test_util::parameters(1, 1024);
+    let samples = noise(5 * 1024, 10, 3);
+
+    return range(0, 5u).map(|n| {
+      encoder::frame::encode(&parameters, n as u64, &[samples.slice(1024 * n, 1024 * (n + 1))])
+    }).collect();
+  }
+
+  fn concat(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    for frame in frames.iter() {
+      data.push_all(frame.as_slice());
+    }
+
+    return data;
+  }
+
+  #[test]
+  fn test_splitter_with_seek_points() {
+    let frames = frames();
+
+    let offset_2 = (frames[0].len() + frames[1].len()) as u64;
+    let offset_4 = offset_2 + (frames[2].len() + frames[3].len()) as u64;
+
+    let points = [
+      SeekPoint { sample_number: 0, offset: 0, samples: 1024 },
+      SeekPoint { sample_number: 2048, offset: offset_2, samples: 1024 },
+      SeekPoint { sample_number: 4096, offset: offset_4, samples: 1024 },
+      SeekPoint { sample_number: PLACEHOLDER, offset: 0, samples: 0 }
+    ];
+
+    let mut splitter = super::Splitter::new(&points, 16);
+
+    splitter.push(concat(frames.as_slice()).as_slice());
+
+    assert_eq!(splitter.next(true), Some(concat(frames.slice(0, 2))));
+    assert_eq!(splitter.next(true), Some(concat(frames.slice(2, 4))));
+    assert_eq!(splitter.next(true), Some(concat(frames.slice(4, 5))));
+    assert_eq!(splitter.next(true), None);
+  }
+
+  #[test]
+  fn test_splitter_skips_wrong_seek_points() {
+    let frames = frames();
+
+    let offset_2 = (frames[0].len() + frames[1].len()) as u64;
+    let offset_4 = offset_2 + (frames[2].len() + frames[3].len()) as u64;
+
+    // In the middle of the third frame, and a later sample before it
+    let points = [
+      SeekPoint { sample_number: 2048, offset: offset_2 + 1, samples: 1024 },
+      SeekPoint { sample_number: 4096, offset: offset_4, samples: 1024 },
+      SeekPoint { sample_number: 3072, offset: offset_2 + 2, samples: 1024 }
+    ];
+
+    let mut splitter = super::Splitter::new(&points, 16);
+
+    splitter.push(concat(frames.as_slice()).as_slice());
+
+    assert_eq!(splitter.next(true), Some(concat(frames.slice(0, 4))));
+    assert_eq!(splitter.next(true), Some(concat(frames.slice(4, 5))));
+    assert_eq!(splitter.next(true), None);
+  }
+
+  #[test]
+  fn test_splitter_cuts_in_place() {
+    let frames = frames();
+    let data = concat(frames.as_slice());
+
+    let mut splitter = super::Splitter::new(&[], 1);
+    splitter.push(data.as_slice());
+
+    let mut pointers = Vec::new();
+
+    for frame in frames.iter() {
+      let segment = splitter.next_slice(true).unwrap();
+
+      assert_eq!(segment, frame.as_slice());
+      pointers.push(segment.as_ptr() as uint);
+    }
+
+    // One after the other in the buffer that was pushed to
+    for (i, frame) in frames.slice_to(frames.len() - 1).iter().enumerate() {
+      assert_eq!(pointers[i + 1], pointers[i] + frame.len());
+    }
+
+    assert!(splitter.is_empty());
+  }
+
+  #[test]
+  fn test_splitter_scans_for_frames() {
+    let frames = frames();
+    let data = concat(frames.as_slice());
+
+    let mut splitter = super::Splitter::new(&[], 2);
+    let mut segments = Vec::new();
+
+    for chunk in data.as_slice().chunks(100) {
+      splitter.push(chunk);
+
+      loop {
+        match splitter.next(false) {
+          Some(segment) => segments.push(segment),
+          None => break
+        }
+      }
+    }
+
+    loop {
+      match splitter.next(true) {
+        Some(segment) => segments.push(segment),
+        None => break
+      }
+    }
+
+    assert_eq!(segments, vec![concat(frames.slice(0, 2)), concat(frames.slice(2, 4)), concat(frames.slice(4, 5))]);
+  }
+
+  fn run(data: Vec<u8>, threads: uint) -> Vec<u8> {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, mut source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+    });
+
+    spawn(proc() {
+      if threads > 1 {
+        super::ParallelDecoder::new(source_1, source_md, sink_a, threads).run();
+      } else {
+        decoder::Decoder::new(source_1, source_md, sink_a).run();
+      }
+    });
+
+    let mut output = Vec::new();
+    let mut last = false;
+
+    while !last {
+      source_a.read(|audio| {
+        output.push_all(audio.data.as_slice());
+        last = audio.last;
+      });
+    }
+
+    return output;
+  }
+
+  fn encode(seek_table: bool) -> (Vec<u8>, uint) {
+    let noise = noise(200000, 8, 11);
+    let samples: Vec<i32> = range(0, 200000i32).zip(noise.iter()).map(|(i, &n)| (i % 300) * 50 + n).collect();
+
+    let mut parameters = encoder::Parameters::new(44100, 2, 16);
+
+    if seek_table {
+      parameters.seek_table = Some(metadata::seek_table::EverySamples(16384));
+      parameters.samples = Some(100000);
+    }
+
+    return (test_util::encode(parameters, samples.as_slice()), samples.len());
+  }
+
+  #[test]
+  fn test_matches_sequential_decoder() {
+    let (buffer, samples) = encode(false);
+
+    let sequential = run(buffer.clone(), 1);
+    let parallel = run(buffer, 4);
+
+    assert_eq!(sequential.len(), 2 * samples);
+    assert_eq!(parallel, sequential);
+  }
+
+  #[test]
+  fn test_wrong_seek_point() {
+    let (mut buffer, _) = encode(true);
+
+    let sequential = run(buffer.clone(), 1);
+
+    // The offset of the second point, after STREAMINFO and the first one
+    let i = 4 + 4 + 34 + 4 + 18 + 15;
+    buffer.as_mut_slice()[i] ^= 0x01;
+
+    assert_eq!(run(buffer, 4), sequential);
+  }
+
+  #[test]
+  fn test_corrupt_frame() {
+    let (mut buffer, _) = encode(false);
+
+    let i = buffer.len() / 2;
+    buffer.as_mut_slice()[i] ^= 0x01;
+
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(buffer, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+    });
+
+    // Takes audio until the sink is dropped
+    spawn(proc() {
+      let mut source_a = source_a;
+
+      loop {
+        source_a.read(|_| ());
+      }
+    });
+
+    // Comes back with an error instead of waiting on the segment forever, or
+    // panicking
+    assert!(super::ParallelDecoder::new(source_1, source_md, sink_a, 4).try_run().is_err());
+  }
+}
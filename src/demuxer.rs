@@ -1,11 +1,26 @@
+use std;
+use std::cmp;
+
 use aurora;
 
+use bitstream::SliceReader;
+use crc;
+use frame;
+use frame::header;
+use limits::{Limits, Exceeded};
 use metadata;
+use metadata::id3;
+use metadata::stream_info;
+use metadata::stream_info::StreamInfo;
 
 pub struct Demuxer {
   source: aurora::channel::Source<aurora::Binary>,
   sink: aurora::channel::Sink<aurora::Binary>,
-  metadata_sink: aurora::channel::Sink<metadata::Metadata>
+  metadata_sink: aurora::channel::Sink<metadata::Metadata>,
+  raw: bool,
+  stream_info: Option<StreamInfo>,
+  id3: bool,
+  limits: Limits
 }
 
 impl Demuxer {
@@ -13,26 +28,243 @@ impl Demuxer {
     return Demuxer {
       source: source,
       sink: sink,
-      metadata_sink: metadata_sink
+      metadata_sink: metadata_sink,
+      raw: false,
+      stream_info: None,
+      id3: false,
+      limits: Limits::new()
+    }
+  }
+
+  // For frames without the fLaC header and metadata, like RTP payloads or
+  // files cut in the middle. Demuxing starts at the first frame header, and
+  // sends a STREAMINFO made from it unless one is given, which frames that
+  // leave their sample rate or size to STREAMINFO need.
+  pub fn raw(source: aurora::channel::Source<aurora::Binary>, sink: aurora::channel::Sink<aurora::Binary>, metadata_sink: aurora::channel::Sink<metadata::Metadata>, stream_info: Option<StreamInfo>) -> Demuxer {
+    return Demuxer {
+      source: source,
+      sink: sink,
+      metadata_sink: metadata_sink,
+      raw: true,
+      stream_info: stream_info,
+      id3: false,
+      limits: Limits::new()
     }
   }
 
+  // ID3v2 tags in front of the fLaC marker are always skipped, this sends
+  // their frames on as an Id3 block after the FLAC metadata as well
+  pub fn expose_id3(&mut self) {
+    self.id3 = true;
+  }
+
+  // Tags, metadata blocks and STREAMINFO that exceed the limits fail the
+  // demuxer before they're read, see try_run
+  pub fn limit(&mut self, limits: Limits) {
+    self.limits = limits;
+  }
+
+  // A header with a valid CRC-8 can still turn up in the middle of a frame, so
+  // the first frame is only taken as one when its CRC-16 matches up to the
+  // next header or the end of the input. A frame is never longer than its
+  // samples verbatim, so that's as far as the next header is looked for.
+  fn run_raw(&mut self) -> Result<(), Exceeded> {
+    let mut data = Vec::new();
+    let mut finished = false;
+    let mut start = None;
+    let mut i = 0;
+    let mut scan = 0;
+
+    while start.is_none() {
+      let mut more = false;
+
+      if i + header::MAX_LENGTH <= data.len() || (finished && i < data.len()) {
+        if data[i] != 0xFF || header::check(data.slice_from(i)).is_none() {
+          i += 1;
+          continue;
+        }
+
+        let candidate = data.slice_from(i);
+        let header = match header::Header::from(&mut SliceReader::new(candidate)) {
+          Ok(header) => header,
+          Err(_) => {
+            i += 1;
+            continue;
+          }
+        };
+        let bits = if header.sample_size == 0 { 32 } else { header.sample_size };
+        let max_size = frame::max_size(header.block_size as uint, frame::channels(header.channel_assignment), bits);
+
+        match frame::find_next(candidate, scan, finished) {
+          Ok(_) => start = Some(i),
+          Err(_) if finished && crc::crc16(candidate) == 0 => start = Some(i),
+          Err(end) if finished || end > max_size => {
+            i += 1;
+            scan = 0;
+          },
+          Err(end) => {
+            scan = end;
+            more = true;
+          }
+        }
+      } else if finished {
+        panic!("flac::Demuxer: Input has no frame header (INPUT)");
+      } else {
+        more = true;
+      }
+
+      if more {
+        self.source.read(|binary| {
+          data.push_all(binary.data.as_slice());
+          finished = binary.last;
+        });
+      }
+    }
+
+    let header = valid!(header::Header::from(&mut SliceReader::new(data.slice_from(i))));
+
+    let mut stream_info = match self.stream_info.take() {
+      Some(stream_info) => stream_info,
+      None => {
+        if header.sample_rate == 0 || header.sample_size == 0 {
+          panic!("flac::Demuxer: First frame leaves its sample rate or size to STREAMINFO, which has to be given for raw frames (INPUT)");
+        }
+
+        // STREAMINFO can't say 65536, and 65535 stands for it
+        let block_size = if header.variable_blocksize { (16, 65535) } else { (cmp::min(header.block_size, 65535) as u16, cmp::min(header.block_size, 65535) as u16) };
+
+        StreamInfo {
+          block_size: block_size,
+          frame_size: (0, 0),
+          sample_rate: header.sample_rate,
+          channels: frame::channels(header.channel_assignment) as u8,
+          bits_per_sample: header.sample_size,
+          samples: 0,
+          signature: stream_info::MD5([0x00, ..16])
+        }
+      }
+    };
+
+    // There's no telling how many samples are left from the middle of a stream
+    stream_info.samples = 0;
+
+    try!(self.limits.check_stream_info(&stream_info));
+
+    self.metadata_sink.write(|metadata| {
+      metadata.ty = metadata::StreamInfo(stream_info.clone());
+      metadata.data.truncate(0);
+      metadata.data.push_all(stream_info::write(&stream_info).as_slice());
+      metadata.last = true;
+    });
+
+    let first = data.slice_from(i);
+
+    self.sink.write(|binary| {
+      binary.data.truncate(0);
+      binary.data.push_all(first);
+      binary.last = finished;
+    });
+
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    while !finished {
+      source.read(|input| {
+        sink.write(|binary| {
+          binary.data.truncate(0);
+          binary.data.push_all(input.data.as_slice());
+          binary.last = input.last;
+        });
+
+        finished = input.last;
+      });
+    }
+
+    return Ok(());
+  }
+
   pub fn run(&mut self) {
+    match self.try_run() {
+      Ok(()) => (),
+      Err(exceeded) => panic!("flac::Demuxer: Input exceeds the limits, {} (INPUT)", exceeded)
+    }
+  }
+
+  // Same as run, but a tag or block that exceeds the limits stops it with an
+  // error instead of a panic. The sinks are dropped without a last buffer
+  // then.
+  pub fn try_run(&mut self) -> Result<(), Exceeded> {
+    if self.raw {
+      return self.run_raw();
+    }
+
     let mut stream = aurora::stream::Stream::new(&mut self.source);
 
     let mut fourcc = [0x00, ..4];
 
     stream.read(fourcc);
 
+    let mut frames = Vec::new();
+    let mut total = 0;
+
+    while id3::is_tag(&fourcc) {
+      let mut tag = Vec::from_elem(id3::HEADER_LENGTH, 0x00u8);
+
+      std::slice::bytes::copy_memory(tag.slice_mut(0, 4), &fourcc);
+      stream.read(tag.slice_mut(4, id3::HEADER_LENGTH));
+
+      let length = valid!(id3::length(tag.as_slice()));
+
+      try!(self.limits.check_metadata(length, total));
+      total += length;
+
+      tag.grow(length - id3::HEADER_LENGTH, 0x00);
+      stream.read(tag.slice_mut(id3::HEADER_LENGTH, length));
+
+      if self.id3 {
+        frames.push_all(valid!(id3::frames(tag.as_slice())).as_slice());
+      }
+
+      stream.read(fourcc);
+    }
+
     if fourcc != b"fLaC" {
       panic!("flac::Demuxer: Stream did not start with fourcc 'fLaC' had bytes {:x}{:x}{:x}{:x} (INPUT)", fourcc[0], fourcc[1], fourcc[2], fourcc[3]);
     }
 
+    let expose = !frames.is_empty();
+    let limits = &self.limits;
     let mut last = false;
+    let mut result = Ok(());
 
-    while !last {
+    while !last && result.is_ok() {
       self.metadata_sink.write(|metadata| {
-        last = metadata::transfer(&mut stream, metadata);
+        result = match metadata::transfer_within(&mut stream, metadata, limits, &mut total) {
+          Ok(is_last) => {
+            last = is_last;
+
+            match metadata.ty {
+              metadata::StreamInfo(ref stream_info) => limits.check_stream_info(stream_info),
+              _ => Ok(())
+            }
+          },
+          Err(exceeded) => Err(exceeded)
+        };
+
+        // The Id3 block comes last instead
+        if last && expose {
+          metadata.last = false;
+        }
+      });
+    }
+
+    try!(result);
+
+    if expose {
+      self.metadata_sink.write(|metadata| {
+        metadata.ty = metadata::Id3(frames.clone());
+        metadata.data.truncate(0);
+        metadata.last = true;
       });
     }
 
@@ -55,48 +287,218 @@ impl Demuxer {
         binary.last = last;
       });
     }
+
+    return Ok(());
   }
 }
 
-#[cfg(all(test, feature = "complete-tests"))]
-mod complete_tests {
-  use std;
+#[cfg(test)]
+mod tests {
   use aurora;
+
+  use decoder;
+  use encoder;
   use metadata;
+  use metadata::id3;
+  use frame;
+  use frame::header;
+  use limits;
+  use metadata::stream_info::StreamInfo;
+
+  use test_util::{encode, noise, parameters};
+
+  fn decode(data: Vec<u8>, raw: bool, stream_info: Option<StreamInfo>) -> Vec<u8> {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, mut source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      if raw {
+        super::Demuxer::raw(source_0, sink_1, sink_md, stream_info).run();
+      } else {
+        super::Demuxer::new(source_0, sink_1, sink_md).run();
+      }
+    });
+
+    spawn(proc() {
+      decoder::Decoder::new(source_1, source_md, sink_a).run();
+    });
+
+    let mut output = Vec::new();
+    let mut last = false;
+
+    while !last {
+      source_a.read(|audio| {
+        output.push_all(audio.data.as_slice());
+        last = audio.last;
+      });
+    }
+
+    return output;
+  }
+
+  // 32 bit mono, in blocks of 1024
+  fn parameters_32() -> encoder::Parameters {
+    let mut parameters = encoder::Parameters::new(44100, 1, 32);
+
+    parameters.block_size = encoder::Fixed(1024);
+
+    return parameters;
+  }
+
+  #[test]
+  fn test_raw_frames() {
+    let flac = encode(parameters(2, 1152), noise(2 * 10000, 16, 31).as_slice());
+    let expected = decode(flac.clone(), false, None);
+
+    // Whole frames, and the same with garbage in front and the first frame cut
+    let mut cut = vec![0x00, 0xFF, 0x12];
+    cut.push_all(flac.slice_from(42 + 3));
+
+    assert_eq!(decode(flac.slice_from(42).to_vec(), true, None), expected);
+    assert_eq!(decode(cut, true, None), expected.slice_from(1152 * 2 * 2).to_vec());
+  }
+
+  // A frame header with nothing after it, as in a stream cut in the middle of
+  // a frame that happens to contain one, is skipped
+  #[test]
+  fn test_raw_false_sync() {
+    let flac = encode(parameters(2, 1152), noise(2 * 10000, 16, 31).as_slice());
+    let expected = decode(flac.clone(), false, None);
+
+    let frames = flac.slice_from(42);
+    let length = header::check(frames).unwrap();
+    let second = frame::find_next(frames, 1, true).unwrap();
+
+    let mut cut = frames.slice_to(length).to_vec();
+    cut.grow(100, 0x00);
+    cut.push_all(frames.slice_from(second));
+
+    assert_eq!(decode(cut, true, None), expected.slice_from(1152 * 2 * 2).to_vec());
+  }
+
+  // STREAMINFO can't say 65536, which a fixed block size can be
+  #[test]
+  fn test_raw_frames_of_65536() {
+    let parameters = encoder::Parameters::new(44100, 1, 16);
+    let samples = noise(2 * 65536, 16, 37);
+
+    let mut frames = encoder::frame::encode(&parameters, 0, &[samples.slice_to(65536)]);
+    frames.push_all(encoder::frame::encode(&parameters, 1, &[samples.slice_from(65536)]).as_slice());
+
+    let mut expected = Vec::new();
+
+    for &sample in samples.iter() {
+      expected.push((sample >> 8) as u8);
+      expected.push(sample as u8);
+    }
+
+    assert_eq!(decode(frames, true, None), expected);
+  }
+
+  #[test]
+  fn test_raw_frames_with_stream_info() {
+    let samples = noise(5000, 32, 5);
+
+    let flac = encode(parameters_32(), samples.as_slice());
+    let expected = decode(flac.clone(), false, None);
+
+    let stream_info = metadata::stream_info::read(&flac.slice(8, 42).to_vec()).unwrap();
+
+    assert_eq!(expected.len(), 4 * 5000);
+    assert_eq!(decode(flac.slice_from(42).to_vec(), true, Some(stream_info)), expected);
+  }
 
   #[test]
-  fn test_new() {
+  #[should_fail]
+  fn test_raw_frames_need_stream_info() {
+    let flac = encode(parameters_32(), Vec::from_elem(2048, 1 << 30).as_slice());
+
     let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
-    let (sink_1, _) = aurora::channel::create::<aurora::Binary>(1);
-    let (sink_md, mut source_md) = aurora::channel::create::<metadata::Metadata>(1);
+    let (sink_1, _source_1) = aurora::channel::create::<aurora::Binary>(16);
+    let (sink_md, _source_md) = aurora::channel::create::<metadata::Metadata>(16);
 
     spawn(proc() {
-      let path = std::path::Path::new("./test-vectors/complete/bad_apple.flac");
-      let file = std::io::File::open(&path).unwrap();
+      aurora::buffer::Buffer::new(flac.slice_from(42).to_vec(), 4096, sink_0).run();
+    });
+
+    super::Demuxer::raw(source_0, sink_1, sink_md, None).run();
+  }
 
-      aurora::file::Input::new(file, 4096, sink_0).run();
+  #[test]
+  fn test_id3_tags() {
+    let flac = encode(parameters(2, 1152), noise(2 * 5000, 16, 33).as_slice());
+
+    let mut tagged = id3::tests::tag("One", true);
+    tagged.push_all(id3::tests::tag("Two", false).as_slice());
+    tagged.push_all(flac.as_slice());
+
+    assert_eq!(decode(tagged.clone(), false, None), decode(flac, false, None));
+
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, _source_1) = aurora::channel::create::<aurora::Binary>(16);
+    let (sink_md, mut source_md) = aurora::channel::create::<metadata::Metadata>(16);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(tagged, 4096, sink_0).run();
     });
 
     spawn(proc() {
-      super::Demuxer::new(source_0, sink_1, sink_md).run();
+      let mut demuxer = super::Demuxer::new(source_0, sink_1, sink_md);
+
+      demuxer.expose_id3();
+      demuxer.run();
     });
 
-    source_md.read(|metadata| {
-      let canonical = &metadata::stream_info::StreamInfo {
-        block_size: (4096, 4096),
-        frame_size: (1324, 13848),
-        sample_rate: 44100,
-        channels: 2,
-        bits_per_sample: 16,
-        samples: 13940634,
-        signature: metadata::stream_info::MD5([0x07, 0x02, 0x55, 0xE5, 0xCE, 0x94, 0x69, 0xED, 0xC6, 0x23, 0xCD, 0x9E, 0x8E, 0xB3, 0xE2, 0x21])
-      };
-
-      assert_eq!(stream_info.ty, metadata::StreamInfo(canonical));
+    let mut blocks = Vec::new();
+    let mut last = false;
+
+    while !last {
+      source_md.read(|metadata| {
+        blocks.push(metadata.ty.clone());
+        last = metadata.last;
+      });
+    }
+
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[1], metadata::Id3(vec![
+      id3::Frame { id: b"TIT2".to_vec(), flags: 0, data: b"\x03One".to_vec() },
+      id3::Frame { id: b"TIT2".to_vec(), flags: 0, data: b"\x03Two".to_vec() }
+    ]));
+  }
+
+  fn try_run(data: Vec<u8>, raw: bool, limits: limits::Limits) -> Result<(), limits::Exceeded> {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, _source_1) = aurora::channel::create::<aurora::Binary>(64);
+    let (sink_md, _source_md) = aurora::channel::create::<metadata::Metadata>(16);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
     });
 
-    // let audio = flac.audioStream();
-    //
-    // assert_eq!(audio.read_be_u16(), 0xFFF8)
+    let mut demuxer = if raw { super::Demuxer::raw(source_0, sink_1, sink_md, None) } else { super::Demuxer::new(source_0, sink_1, sink_md) };
+
+    demuxer.limit(limits);
+
+    return demuxer.try_run();
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_limits() {
+    let flac = encode(parameters(2, 1152), noise(2 * 5000, 16, 34).as_slice());
+
+    let mut tagged = id3::tests::tag("Limits", false);
+    let tag = tagged.len();
+    tagged.push_all(flac.as_slice());
+
+    assert_eq!(try_run(tagged.clone(), false, limits::Limits::new()), Ok(()));
+    assert_eq!(try_run(tagged.clone(), false, limits::Limits { max_metadata_block: tag - 1, ..limits::Limits::new() }), Err(limits::MetadataBlock(tag)));
+    assert_eq!(try_run(tagged, false, limits::Limits { max_channels: 1, ..limits::Limits::new() }), Err(limits::Channels(2)));
+    assert_eq!(try_run(flac.slice_from(42).to_vec(), true, limits::Limits { max_block_size: 1024, ..limits::Limits::new() }), Err(limits::BlockSize(1152)));
+  }
+}
@@ -0,0 +1,147 @@
+pub struct BitWriter {
+  data: Vec<u8>,
+  cache: u64,
+  bits: uint
+}
+
+impl BitWriter {
+  pub fn new() -> BitWriter {
+    return BitWriter { data: Vec::new(), cache: 0, bits: 0 };
+  }
+
+  pub fn with_capacity(bytes: uint) -> BitWriter {
+    return BitWriter { data: Vec::with_capacity(bytes), cache: 0, bits: 0 };
+  }
+
+  pub fn write_n(&mut self, value: u32, n: uint) {
+    if n == 0 {
+      return;
+    }
+
+    self.cache = (self.cache << n) | (value as u64 & ((1u64 << n) - 1));
+    self.bits += n;
+
+    while self.bits >= 8 {
+      self.bits -= 8;
+      self.data.push((self.cache >> self.bits) as u8);
+    }
+
+    self.cache &= (1u64 << self.bits) - 1;
+  }
+
+  pub fn write_n_signed(&mut self, value: i32, n: uint) {
+    self.write_n(value as u32, n);
+  }
+
+  // Unary is n zeros terminated by a one
+  pub fn write_unary(&mut self, n: u32) {
+    let mut n = n;
+
+    while n >= 32 {
+      self.write_n(0, 32);
+      n -= 32;
+    }
+
+    self.write_n(1, n as uint + 1);
+  }
+
+  pub fn write_rice(&mut self, value: i32, parameter: uint) {
+    let folded = ((value << 1) ^ (value >> 31)) as u32;
+
+    self.write_unary(folded >> parameter);
+    self.write_n(folded, parameter);
+  }
+
+  // See http://en.wikipedia.org/wiki/UTF-8, extended to 36 bits
+  pub fn write_utf8(&mut self, value: u64) {
+    if value < 0x80 {
+      return self.write_n(value as u32, 8);
+    }
+
+    let mut total_bytes = 2u;
+
+    while value >> (5 * total_bytes + 1) != 0 {
+      total_bytes += 1;
+    }
+
+    let prefix = (0xFF00u32 >> total_bytes) & 0xFF;
+
+    self.write_n(prefix | (value >> (6 * (total_bytes - 1))) as u32, 8);
+
+    for i in range(0, total_bytes - 1).rev() {
+      self.write_n(0x80 | ((value >> (6 * i)) as u32 & 0x3F), 8);
+    }
+  }
+
+  pub fn align(&mut self) {
+    if self.bits > 0 {
+      let padding = 8 - self.bits;
+      self.write_n(0, padding);
+    }
+  }
+
+  pub fn bits(&self) -> uint {
+    return 8 * self.data.len() + self.bits;
+  }
+
+  // Only whole bytes, call align first to include the last partial byte
+  pub fn as_slice(&self) -> &[u8] {
+    return self.data.as_slice();
+  }
+
+  pub fn unwrap(self) -> Vec<u8> {
+    return self.data;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::BitWriter;
+
+  #[test]
+  fn test_write_n() {
+    let mut writer = BitWriter::new();
+
+    writer.write_n(0b11111111111110, 14);
+    writer.write_n(0, 1);
+    writer.write_n(0, 1);
+    writer.write_n(0b1100, 4);
+    writer.write_n(0b1001, 4);
+    writer.write_n(0b1, 5);
+
+    assert_eq!(writer.bits(), 29);
+
+    writer.align();
+
+    assert_eq!(writer.unwrap(), vec![0xFF, 0xF8, 0xC9, 0x08]);
+  }
+
+  #[test]
+  fn test_write_rice() {
+    let mut writer = BitWriter::new();
+
+    writer.write_rice(-3, 2); // Folds to 5, 0b01 01
+    writer.write_rice(2, 0); // Folds to 4, 0b00001
+    writer.align();
+
+    assert_eq!(writer.unwrap(), vec![0b01010000, 0b10000000]);
+  }
+
+  #[test]
+  fn test_utf8_encoding_of_one_byte() {
+    let mut writer = BitWriter::new();
+
+    writer.write_utf8(0b0100100);
+
+    assert_eq!(writer.unwrap(), vec![0b00100100]);
+  }
+
+  #[test]
+  fn test_utf8_encoding_of_four_bytes() {
+    let mut writer = BitWriter::new();
+
+    writer.write_utf8(0b000100100101101100010);
+
+    assert_eq!(writer.unwrap(), vec![0b11110000, 0b10100100, 0b10101101, 0b10100010]);
+  }
+}
@@ -0,0 +1,180 @@
+use crc;
+use frame::header::Header;
+
+use super::Parameters;
+use super::bitwriter::BitWriter;
+use super::subframe;
+
+const SYNC_CODE: u32 = 0b11111111111110;
+
+fn block_size_code(block_size: u32) -> u32 {
+  return match block_size {
+    192 => 0b0001,
+    576 => 0b0010,
+    1152 => 0b0011,
+    2304 => 0b0100,
+    4608 => 0b0101,
+    256 => 0b1000,
+    512 => 0b1001,
+    1024 => 0b1010,
+    2048 => 0b1011,
+    4096 => 0b1100,
+    8192 => 0b1101,
+    16384 => 0b1110,
+    32768 => 0b1111,
+    1...256 => 0b0110,
+    257...65536 => 0b0111,
+    _ => panic!("flac::Encoder: Block size {} is too large (INPUT)", block_size)
+  };
+}
+
+fn sample_rate_code(sample_rate: u32) -> u32 {
+  return match sample_rate {
+    88_200 => 0b0001,
+    176_400 => 0b0010,
+    192_000 => 0b0011,
+    8_000 => 0b0100,
+    16_000 => 0b0101,
+    22_050 => 0b0110,
+    24_000 => 0b0111,
+    32_000 => 0b1000,
+    44_100 => 0b1001,
+    48_000 => 0b1010,
+    96_000 => 0b1011,
+    r if r % 1000 == 0 && r / 1000 <= 255 => 0b1100,
+    r if r <= 65535 => 0b1101,
+    r if r % 10 == 0 && r / 10 <= 65535 => 0b1110,
+    _ => 0b0000
+  };
+}
+
+fn sample_size_code(sample_size: u8) -> u32 {
+  return match sample_size {
+    8 => 0b001,
+    12 => 0b010,
+    16 => 0b100,
+    20 => 0b101,
+    24 => 0b110,
+    _ => 0b000
+  };
+}
+
+pub fn write_header(writer: &mut BitWriter, header: &Header) {
+  let block_size_code = block_size_code(header.block_size);
+  let sample_rate_code = sample_rate_code(header.sample_rate);
+
+  writer.write_n(SYNC_CODE, 14);
+  writer.write_n(0, 1);
+  writer.write_n(if header.variable_blocksize { 1 } else { 0 }, 1);
+  writer.write_n(block_size_code, 4);
+  writer.write_n(sample_rate_code, 4);
+  writer.write_n(header.channel_assignment as u32, 4);
+  writer.write_n(sample_size_code(header.sample_size), 3);
+  writer.write_n(0, 1);
+
+  match (header.sample_number, header.frame_number) {
+    (Some(n), None) if header.variable_blocksize => writer.write_utf8(n),
+    (None, Some(n)) if !header.variable_blocksize => writer.write_utf8(n as u64),
+    _ => panic!("flac::Encoder: Frame header needs exactly one of sample and frame number (BUG)")
+  }
+
+  match block_size_code {
+    0b0110 => writer.write_n(header.block_size - 1, 8),
+    0b0111 => writer.write_n(header.block_size - 1, 16),
+    _ => ()
+  }
+
+  match sample_rate_code {
+    0b1100 => writer.write_n(header.sample_rate / 1000, 8),
+    0b1101 => writer.write_n(header.sample_rate, 16),
+    0b1110 => writer.write_n(header.sample_rate / 10, 16),
+    _ => ()
+  }
+
+  let crc = crc::crc8(writer.as_slice());
+
+  writer.write_n(crc as u32, 8);
+}
+
+// Encodes one frame, number is the sample number for variable block size
+// streams and the frame number otherwise
+pub fn encode(parameters: &Parameters, number: u64, channels: &[&[i32]]) -> Vec<u8> {
+  let block_size = channels[0].len();
+  let bits = parameters.bits_per_sample;
+
+  let independent: Vec<subframe::Subframe> = channels.iter().map(|c| subframe::analyze(*c, bits, parameters)).collect();
+
+  let mut channel_assignment = channels.len() as u8 - 1;
+
+  let mut side = Vec::new();
+  let mut mid = Vec::new();
+  let mut side_subframe = None;
+  let mut mid_subframe = None;
+
+  if channels.len() == 2 && parameters.stereo_decorrelation && bits < 32 {
+    for (&l, &r) in channels[0].iter().zip(channels[1].iter()) {
+      side.push(l - r);
+      mid.push(((l as i64 + r as i64) >> 1) as i32);
+    }
+
+    let s = subframe::analyze(side.as_slice(), bits + 1, parameters);
+    let m = subframe::analyze(mid.as_slice(), bits, parameters);
+
+    let costs = [
+      (0b0001u8, independent[0].bits + independent[1].bits),
+      (0b1000, independent[0].bits + s.bits),
+      (0b1001, s.bits + independent[1].bits),
+      (0b1010, m.bits + s.bits)
+    ];
+
+    let (mut best, mut best_cost) = costs[0];
+
+    for &(assignment, cost) in costs.iter() {
+      if cost < best_cost {
+        best = assignment;
+        best_cost = cost;
+      }
+    }
+
+    channel_assignment = best;
+    side_subframe = Some(s);
+    mid_subframe = Some(m);
+  }
+
+  let chosen: Vec<(&[i32], u8, &subframe::Subframe)> = match channel_assignment {
+    0b1000 => vec![(channels[0], bits, &independent[0]), (side.as_slice(), bits + 1, side_subframe.as_ref().unwrap())],
+    0b1001 => vec![(side.as_slice(), bits + 1, side_subframe.as_ref().unwrap()), (channels[1], bits, &independent[1])],
+    0b1010 => vec![(mid.as_slice(), bits, mid_subframe.as_ref().unwrap()), (side.as_slice(), bits + 1, side_subframe.as_ref().unwrap())],
+    _ => channels.iter().zip(independent.iter()).map(|(c, s)| (*c, bits, s)).collect()
+  };
+
+  let variable = parameters.block_size.is_variable();
+
+  let header = Header {
+    variable_blocksize: variable,
+    block_size: block_size as u32,
+    sample_rate: parameters.sample_rate,
+    channel_assignment: channel_assignment,
+    sample_size: bits,
+    sample_number: if variable { Some(number) } else { None },
+    frame_number: if variable { None } else { Some(number as u32) },
+    crc: 0
+  };
+
+  let estimate = chosen.iter().fold(0, |a, &(_, _, s)| a + s.bits) / 8 + 32;
+  let mut writer = BitWriter::with_capacity(estimate);
+
+  write_header(&mut writer, &header);
+
+  for &(samples, bits, subframe) in chosen.iter() {
+    subframe::write(&mut writer, samples, bits, subframe);
+  }
+
+  writer.align();
+
+  let crc = crc::crc16(writer.as_slice());
+
+  writer.write_n(crc as u32, 16);
+
+  return writer.unwrap();
+}
@@ -0,0 +1,716 @@
+use std::cmp;
+use std::u32;
+use std::io::{IoResult, SeekSet, SeekEnd};
+
+use md5;
+use metadata;
+use metadata::stream_info;
+use metadata::seek_table;
+
+pub mod bitwriter;
+pub mod frame;
+pub mod subframe;
+
+mod pool;
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum BlockSize {
+  // Every frame but the last has this many samples
+  Fixed(u32),
+  // Windows of the first size are split in halves, down to the second size,
+  // wherever the halves encode smaller than the whole
+  Variable(u32, u32)
+}
+
+impl BlockSize {
+  pub fn is_variable(&self) -> bool {
+    return match *self {
+      Variable(_, _) => true,
+      Fixed(_) => false
+    };
+  }
+
+  fn window(&self) -> u32 {
+    return match *self {
+      Variable(window, _) => window,
+      Fixed(block_size) => block_size
+    };
+  }
+}
+
+#[deriving(Show,Clone)]
+pub struct Parameters {
+  pub sample_rate: u32,
+  pub channels: u8,
+  pub bits_per_sample: u8,
+  pub block_size: BlockSize,
+  pub max_fixed_order: u8,
+  pub max_lpc_order: u8,
+  pub lpc_precision: u8, // 0 picks one from the block size
+  pub max_partition_order: u8,
+  pub stereo_decorrelation: bool,
+  pub threads: uint,
+  pub seek_table: Option<seek_table::Spacing>,
+  pub samples: Option<u64> // Total expected, evenly spaced seek points need it
+}
+
+impl Parameters {
+  pub fn new(sample_rate: u32, channels: u8, bits_per_sample: u8) -> Parameters {
+    return Parameters {
+      sample_rate: sample_rate,
+      channels: channels,
+      bits_per_sample: bits_per_sample,
+      block_size: Fixed(4096),
+      max_fixed_order: 4,
+      max_lpc_order: 8,
+      lpc_precision: 0,
+      max_partition_order: 6,
+      stereo_decorrelation: true,
+      threads: 1,
+      seek_table: None,
+      samples: None
+    };
+  }
+
+  fn validate(&self) {
+    if self.sample_rate == 0 || self.sample_rate > 655_350 {
+      panic!("flac::Encoder: Sample rate {} is out of range (INPUT)", self.sample_rate);
+    }
+
+    if self.channels < 1 || self.channels > 8 {
+      panic!("flac::Encoder: {} channels is out of range (INPUT)", self.channels);
+    }
+
+    if self.bits_per_sample < 4 || self.bits_per_sample > 32 {
+      panic!("flac::Encoder: {} bits per sample is out of range (INPUT)", self.bits_per_sample);
+    }
+
+    let (largest, smallest) = match self.block_size {
+      Fixed(block_size) => (block_size, block_size),
+      Variable(window, minimum) => (window, minimum)
+    };
+
+    if smallest < 16 || largest > 65535 || smallest > largest {
+      panic!("flac::Encoder: Block size {} is out of range (INPUT)", self.block_size);
+    }
+
+    if self.max_fixed_order > 4 || self.max_lpc_order > 32 || self.lpc_precision > 15 || self.max_partition_order > 15 {
+      panic!("flac::Encoder: Predictor parameters are out of range (INPUT)");
+    }
+
+    if self.threads < 1 {
+      panic!("flac::Encoder: Needs at least one thread (INPUT)");
+    }
+  }
+}
+
+fn split(parameters: &Parameters, sample_number: u64, channels: &[&[i32]], minimum: uint) -> Vec<(u32, Vec<u8>)> {
+  let length = channels[0].len();
+  let whole = frame::encode(parameters, sample_number, channels);
+
+  if length < 2 * minimum {
+    return vec![(length as u32, whole)];
+  }
+
+  let half = length / 2;
+
+  let left: Vec<&[i32]> = channels.iter().map(|c| c.slice_to(half)).collect();
+  let right: Vec<&[i32]> = channels.iter().map(|c| c.slice_from(half)).collect();
+
+  let mut frames = split(parameters, sample_number, left.as_slice(), minimum);
+  frames.extend(split(parameters, sample_number + half as u64, right.as_slice(), minimum).into_iter());
+
+  let split_size = frames.iter().fold(0, |a, &(_, ref data)| a + data.len());
+
+  if whole.len() <= split_size {
+    return vec![(length as u32, whole)];
+  }
+
+  return frames;
+}
+
+// Encodes a window of samples into one or more frames, number is the frame
+// number of the first one in fixed block size streams
+fn encode_window(parameters: &Parameters, number: u64, sample_number: u64, channels: &[&[i32]]) -> Vec<(u32, Vec<u8>)> {
+  return match parameters.block_size {
+    Fixed(_) => vec![(channels[0].len() as u32, frame::encode(parameters, number, channels))],
+    Variable(_, minimum) => split(parameters, sample_number, channels, minimum as uint)
+  };
+}
+
+pub struct Encoder<W> {
+  writer: W,
+  parameters: Parameters,
+  buffer: Vec<Vec<i32>>,
+  frame_number: u64,
+  sample_number: u64,
+  block_size: (u32, u32),
+  last_block_size: Option<u32>,
+  frame_size: (u32, u32),
+  pool: Option<pool::Pool>,
+  seek_table: Option<seek_table::Builder>,
+  offset: u64,
+  md5: md5::Context
+}
+
+impl<W: Writer + Seek> Encoder<W> {
+  pub fn new(writer: W, parameters: Parameters) -> IoResult<Encoder<W>> {
+    return Encoder::with_metadata(writer, parameters, Vec::new());
+  }
+
+  // Writes the blocks, as block types and contents, after STREAMINFO and the
+  // seek table
+  pub fn with_metadata(writer: W, parameters: Parameters, blocks: Vec<(u8, Vec<u8>)>) -> IoResult<Encoder<W>> {
+    parameters.validate();
+
+    let mut encoder = Encoder {
+      writer: writer,
+      buffer: Vec::from_fn(parameters.channels as uint, |_| Vec::new()),
+      parameters: parameters,
+      frame_number: 0,
+      sample_number: 0,
+      block_size: (u32::MAX, 0),
+      last_block_size: None,
+      frame_size: (u32::MAX, 0),
+      pool: None,
+      seek_table: None,
+      offset: 0,
+      md5: md5::Context::new()
+    };
+
+    if encoder.parameters.threads > 1 {
+      encoder.pool = Some(pool::Pool::new(encoder.parameters.threads, &encoder.parameters));
+    }
+
+    encoder.seek_table = encoder.parameters.seek_table.as_ref().map(|spacing| {
+      seek_table::Builder::new(spacing, encoder.parameters.sample_rate, encoder.parameters.samples)
+    });
+
+    // STREAMINFO is rewritten with the real statistics by finish, and the
+    // seek table with the real offsets
+    let stream_info = encoder.stream_info();
+
+    try!(encoder.writer.write(b"fLaC"));
+    try!(encoder.writer.write(&metadata::header(encoder.seek_table.is_none() && blocks.is_empty(), 0, 34)));
+    try!(encoder.writer.write(stream_info::write(&stream_info).as_slice()));
+
+    match encoder.seek_table {
+      Some(ref builder) => {
+        try!(encoder.writer.write(&metadata::header(blocks.is_empty(), 3, 18 * builder.len())));
+        try!(encoder.writer.write(seek_table::write(builder.points().as_slice()).as_slice()));
+      },
+      None => ()
+    }
+
+    for (i, &(ty, ref data)) in blocks.iter().enumerate() {
+      if ty == 0 || ty == 3 || ty == 127 || data.len() >= 1 << 24 {
+        panic!("flac::Encoder: Can't write a metadata block of type {} and length {} (INPUT)", ty, data.len());
+      }
+
+      try!(encoder.writer.write(&metadata::header(i == blocks.len() - 1, ty, data.len())));
+      try!(encoder.writer.write(data.as_slice()));
+    }
+
+    return Ok(encoder);
+  }
+
+  // Takes interleaved samples, any number of whole sample frames at a time
+  pub fn write(&mut self, samples: &[i32]) -> IoResult<()> {
+    let channels = self.parameters.channels as uint;
+
+    if samples.len() % channels != 0 {
+      panic!("flac::Encoder: {} samples don't divide into {} channels (INPUT)", samples.len(), channels);
+    }
+
+    self.md5.update_samples(samples, self.parameters.bits_per_sample);
+
+    for (i, &sample) in samples.iter().enumerate() {
+      self.buffer.as_mut_slice()[i % channels].push(sample);
+    }
+
+    let window = self.parameters.block_size.window() as uint;
+
+    // A few windows per thread at a time keeps every worker busy
+    let batch = 4 * self.parameters.threads;
+
+    loop {
+      let windows = cmp::min(self.buffer[0].len() / window, batch);
+
+      if windows == 0 {
+        return Ok(());
+      }
+
+      try!(self.encode(windows, window));
+    }
+  }
+
+  fn encode(&mut self, windows: uint, length: uint) -> IoResult<()> {
+    let frames = if self.pool.is_some() {
+      let mut jobs = Vec::with_capacity(windows);
+
+      for w in range(0, windows) {
+        let channels = self.buffer.iter().map(|c| c.slice(w * length, (w + 1) * length).to_vec()).collect();
+
+        jobs.push((self.frame_number + w as u64, self.sample_number + (w * length) as u64, channels));
+      }
+
+      self.pool.as_mut().unwrap().encode(jobs)
+    } else {
+      let mut frames = Vec::with_capacity(windows);
+
+      for w in range(0, windows) {
+        let channels: Vec<&[i32]> = self.buffer.iter().map(|c| c.slice(w * length, (w + 1) * length)).collect();
+
+        frames.push(encode_window(&self.parameters, self.frame_number + w as u64, self.sample_number + (w * length) as u64, channels.as_slice()));
+      }
+
+      frames
+    };
+
+    for window in frames.iter() {
+      for &(block_size, ref data) in window.iter() {
+        try!(self.write_frame(block_size, data.as_slice()));
+      }
+    }
+
+    for channel in self.buffer.iter_mut() {
+      let remaining = channel.slice_from(windows * length).to_vec();
+      *channel = remaining;
+    }
+
+    return Ok(());
+  }
+
+  fn write_frame(&mut self, block_size: u32, data: &[u8]) -> IoResult<()> {
+    try!(self.writer.write(data));
+
+    match self.seek_table {
+      Some(ref mut builder) => builder.frame(self.sample_number, self.offset, block_size),
+      None => ()
+    }
+
+    self.offset += data.len() as u64;
+
+    // Only the last frame may be smaller than the minimum block size, so a
+    // frame counts towards it once another one follows
+    match self.last_block_size {
+      Some(previous) => {
+        let (min, max) = self.block_size;
+        self.block_size = (cmp::min(min, previous), cmp::max(max, previous));
+      },
+      None => ()
+    }
+
+    let (min, max) = self.frame_size;
+    self.frame_size = (cmp::min(min, data.len() as u32), cmp::max(max, data.len() as u32));
+
+    self.last_block_size = Some(block_size);
+    self.sample_number += block_size as u64;
+    self.frame_number += 1;
+
+    return Ok(());
+  }
+
+  pub fn stream_info(&self) -> stream_info::StreamInfo {
+    let (min_block_size, max_block_size) = match self.parameters.block_size {
+      Fixed(block_size) => (block_size, block_size),
+      Variable(_, _) => {
+        let (min, max) = self.block_size;
+
+        match self.last_block_size {
+          Some(last) if min == u32::MAX => (last, last),
+          Some(last) => (min, cmp::max(max, last)),
+          None => (0, 0)
+        }
+      }
+    };
+
+    let frame_size = match self.frame_size {
+      (u32::MAX, _) => (0, 0),
+      frame_size => frame_size
+    };
+
+    return stream_info::StreamInfo {
+      block_size: (min_block_size as u16, max_block_size as u16),
+      frame_size: frame_size,
+      sample_rate: self.parameters.sample_rate,
+      channels: self.parameters.channels,
+      bits_per_sample: self.parameters.bits_per_sample,
+      samples: self.sample_number,
+      signature: stream_info::MD5(self.md5.clone().finish())
+    };
+  }
+
+  pub fn finish(mut self) -> IoResult<W> {
+    let remaining = self.buffer[0].len();
+
+    if remaining > 0 {
+      try!(self.encode(1, remaining));
+    }
+
+    let stream_info = self.stream_info();
+
+    try!(self.writer.seek(8, SeekSet));
+    try!(self.writer.write(stream_info::write(&stream_info).as_slice()));
+
+    match self.seek_table {
+      Some(ref builder) => {
+        try!(self.writer.seek(8 + 34 + 4, SeekSet));
+        try!(self.writer.write(seek_table::write(builder.points().as_slice()).as_slice()));
+      },
+      None => ()
+    }
+
+    try!(self.writer.seek(0, SeekEnd));
+
+    return Ok(self.writer);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std;
+  use aurora;
+
+  use bitstream;
+  use frame;
+  use md5;
+  use metadata;
+  use metadata::seek_table;
+  use test_util::{encode, noise};
+
+  fn decode(data: Vec<u8>) -> (metadata::stream_info::StreamInfo, Vec<frame::header::Header>, Vec<i32>) {
+    let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    let mut stream = aurora::stream::Stream::new(&mut source_0);
+
+    let mut fourcc = [0x00, ..4];
+
+    stream.read(&mut fourcc);
+
+    assert_eq!(fourcc.as_slice(), b"fLaC");
+
+    let mut block: metadata::Metadata = aurora::Initialize::initialize();
+
+    let last = metadata::transfer(&mut stream, &mut block);
+
+    let stream_info = match block.ty {
+      metadata::StreamInfo(stream_info) => stream_info,
+      _ => panic!("Encoded stream didn't start with a stream info")
+    };
+
+    if !last {
+      while !metadata::transfer(&mut stream, &mut block) {}
+    }
+
+    let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+
+    let mut headers = Vec::new();
+    let mut samples = Vec::new();
+    let mut decoded = 0;
+
+    while decoded < stream_info.samples {
+      let (header, subframes) = frame::decode(&mut bitstream, Some(&stream_info)).unwrap();
+
+      for s in range(0, header.block_size as uint) {
+        for subframe in subframes.iter() {
+          samples.push(subframe[s]);
+        }
+      }
+
+      decoded += header.block_size as u64;
+      headers.push(header);
+    }
+
+    return (stream_info, headers, samples);
+  }
+
+  #[test]
+  fn test_fixed_block_size() {
+    let noise = noise(2 * 10000, 6, 1);
+    let mut samples = Vec::new();
+
+    for i in range(0, 10000i32) {
+      let triangle = (i % 200 - 100) * 150;
+
+      samples.push(triangle + noise[2 * i as uint]);
+      samples.push(-triangle / 2 + noise[2 * i as uint + 1]);
+    }
+
+    let mut parameters = super::Parameters::new(44100, 2, 16);
+    parameters.block_size = super::Fixed(1152);
+
+    let data = encode(parameters, samples.as_slice());
+
+    assert!(data.len() < 2 * samples.len());
+
+    let (stream_info, headers, decoded) = decode(data);
+
+    assert_eq!(stream_info.block_size, (1152, 1152));
+    assert_eq!(stream_info.samples, 10000);
+    assert_eq!(headers.len(), 9);
+
+    for (i, header) in headers.iter().enumerate() {
+      assert_eq!(header.variable_blocksize, false);
+      assert_eq!(header.frame_number, Some(i as u32));
+    }
+
+    assert_eq!(headers[8].block_size, 10000 - 8 * 1152);
+    assert_eq!(decoded, samples);
+  }
+
+  #[test]
+  fn test_variable_block_size() {
+    let noise = noise(3 * 4608 + 1000, 15, 1);
+    let mut samples = Vec::new();
+
+    // Silence until a burst of noise half way into the second window
+    for i in range(0, 3 * 4608 + 1000) {
+      let sample = if i < 4608 + 2304 { 0 } else { noise[i] };
+
+      samples.push(sample);
+    }
+
+    let mut parameters = super::Parameters::new(48000, 1, 16);
+    parameters.block_size = super::Variable(4608, 576);
+
+    let data = encode(parameters, samples.as_slice());
+
+    let (stream_info, headers, decoded) = decode(data);
+
+    let mut sample_number = 0;
+
+    for header in headers.iter() {
+      assert_eq!(header.variable_blocksize, true);
+      assert_eq!(header.sample_number, Some(sample_number));
+
+      sample_number += header.block_size as u64;
+    }
+
+    assert!(headers.iter().any(|h| h.sample_number == Some(4608 + 2304)));
+
+    let (min, max) = stream_info.block_size;
+    let last = headers.len() - 1;
+
+    assert_eq!(min as u32, headers.slice_to(last).iter().map(|h| h.block_size).min().unwrap());
+    assert_eq!(max as u32, headers.iter().map(|h| h.block_size).max().unwrap());
+    assert!(min < max);
+
+    assert_eq!(stream_info.samples, samples.len() as u64);
+    assert_eq!(decoded, samples);
+  }
+
+  #[test]
+  fn test_threads_match_single_threaded_output() {
+    let noise = noise(2 * 50000, 14, 7);
+    let mut samples = Vec::new();
+
+    // Quiet and loud stretches
+    for i in range(0, 50000u) {
+      let shift = if (i / 3000) % 2 == 0 { 9 } else { 0 };
+
+      samples.push(noise[2 * i] >> shift);
+      samples.push(noise[2 * i + 1] >> shift);
+    }
+
+    for block_size in [super::Fixed(4096), super::Fixed(1000), super::Variable(4608, 576)].iter() {
+      let mut parameters = super::Parameters::new(96000, 2, 24);
+      parameters.block_size = *block_size;
+
+      let single = encode(parameters.clone(), samples.as_slice());
+
+      parameters.threads = 4;
+
+      let threaded = encode(parameters.clone(), samples.as_slice());
+
+      assert_eq!(single, threaded);
+    }
+  }
+
+  #[test]
+  fn test_seek_table() {
+    let samples = noise(50000, 13, 5);
+
+    let mut parameters = super::Parameters::new(44100, 1, 16);
+    parameters.seek_table = Some(seek_table::EverySamples(10000));
+    parameters.samples = Some(50000);
+
+    let data = encode(parameters, samples.as_slice());
+
+    // STREAMINFO is followed by the seek table, which is the last block
+    assert_eq!(data[4], 0x00);
+    assert_eq!(data.slice(42, 46), metadata::header(true, 3, 5 * 18).as_slice());
+
+    let points = seek_table::read(&data.slice(46, 46 + 5 * 18).to_vec()).unwrap();
+    let frames = 46 + 5 * 18;
+
+    let sample_numbers: Vec<u64> = points.iter().map(|p| p.sample_number).collect();
+
+    assert_eq!(sample_numbers, vec![0, 8192, 16384, 28672, 36864]);
+
+    for point in points.iter() {
+      let frame = data.slice_from(frames + point.offset as uint);
+
+      assert!(frame::header::check(frame).is_some());
+
+      let header = frame::header::Header::from(&mut bitstream::SliceReader::new(frame)).unwrap();
+
+      assert_eq!(header.frame_number, Some((point.sample_number / 4096) as u32));
+      assert_eq!(point.samples, 4096);
+    }
+
+    let (_, _, decoded) = decode(data);
+
+    assert_eq!(decoded, samples);
+  }
+
+  // Uniform in 0 to n
+  fn random(seed: &mut u32, n: uint) -> uint {
+    *seed = *seed * 1103515245 + 12345;
+
+    return ((*seed >> 16) & 0x7FFF) as uint % n;
+  }
+
+  // Uniform over every sample bits can hold
+  fn full_range(seed: &mut u32, bits: u8) -> i32 {
+    let value = (random(seed, 1 << 15) << 17) | (random(seed, 1 << 15) << 2) | random(seed, 4);
+
+    return (value as u32 as i32) >> (32 - bits as uint);
+  }
+
+  // One channel of noise, sines, silence, full-scale squares or silence with
+  // full-scale bursts in it
+  fn signal(seed: &mut u32, kind: uint, n: uint, bits: u8) -> Vec<i32> {
+    let max = ((1i64 << (bits as uint - 1)) - 1) as i32;
+    let min = (-(1i64 << (bits as uint - 1))) as i32;
+
+    return match kind {
+      0 => range(0, n).map(|_| full_range(seed, bits)).collect(),
+      1 => {
+        let amplitude = max as f64 * (1 + random(seed, 1000)) as f64 / 1000.0;
+        let period = 2.0 + random(seed, 2000) as f64;
+
+        range(0, n).map(|i| (amplitude * (2.0 * std::f64::consts::PI * i as f64 / period).sin()) as i32).collect()
+      },
+      2 => Vec::from_elem(n, 0i32),
+      3 => {
+        let half = 1 + random(seed, 50);
+
+        range(0, n).map(|i| if (i / half) % 2 == 0 { max } else { min }).collect()
+      },
+      _ => {
+        let start = random(seed, n);
+        let end = start + random(seed, n - start + 1);
+
+        range(0, n).map(|i| if i >= start && i < end { full_range(seed, bits) } else { 0 }).collect()
+      }
+    };
+  }
+
+  // The parameters, the kind of signal in each channel and the length of a
+  // round trip case, everything about it comes from its seed
+  fn round_trip_case(seed: u32) -> (super::Parameters, Vec<uint>, uint) {
+    let mut seed = seed;
+
+    let channels = 1 + random(&mut seed, 8) as u8;
+    let bits = 4 + random(&mut seed, 29) as u8;
+
+    let mut parameters = super::Parameters::new(44100, channels, bits);
+
+    parameters.block_size = match random(&mut seed, 3) {
+      0 => super::Fixed(16 + random(&mut seed, 64) as u32),
+      1 => super::Fixed(16 + random(&mut seed, 8192) as u32),
+      _ => {
+        let minimum = 16 + random(&mut seed, 1024) as u32;
+
+        super::Variable(minimum << random(&mut seed, 4), minimum)
+      }
+    };
+
+    parameters.max_fixed_order = random(&mut seed, 5) as u8;
+    parameters.max_lpc_order = random(&mut seed, 33) as u8;
+    parameters.lpc_precision = if random(&mut seed, 2) == 0 { 0 } else { 5 + random(&mut seed, 11) as u8 };
+    parameters.max_partition_order = random(&mut seed, 9) as u8;
+    parameters.stereo_decorrelation = random(&mut seed, 2) == 0;
+    parameters.threads = 1 + random(&mut seed, 3);
+
+    let kinds = range(0, channels).map(|_| random(&mut seed, 5)).collect();
+
+    // Some streams are shorter than a block
+    let n = if random(&mut seed, 4) == 0 { 1 + random(&mut seed, 20) } else { 1 + random(&mut seed, 12000) };
+
+    return (parameters, kinds, n);
+  }
+
+  // What went wrong in a round trip of n samples, if anything
+  fn round_trip(parameters: &super::Parameters, kinds: &[uint], n: uint, seed: u32) -> Option<String> {
+    let mut seed = seed;
+    let bits = parameters.bits_per_sample;
+
+    let signals: Vec<Vec<i32>> = kinds.iter().map(|&kind| signal(&mut seed, kind, n, bits)).collect();
+
+    let mut samples = Vec::with_capacity(n * kinds.len());
+
+    for i in range(0, n) {
+      for signal in signals.iter() {
+        samples.push(signal[i]);
+      }
+    }
+
+    let (stream_info, _, decoded) = decode(encode(parameters.clone(), samples.as_slice()));
+
+    if decoded != samples {
+      let i = range(0, samples.len()).find(|&i| i >= decoded.len() || decoded[i] != samples[i]).unwrap_or(samples.len());
+
+      return Some(format!("sample {} changed, {} decoded of {}", i, decoded.len(), samples.len()));
+    }
+
+    let mut context = md5::Context::new();
+    context.update_samples(samples.as_slice(), bits);
+
+    if stream_info.signature != metadata::stream_info::MD5(context.finish()) {
+      return Some("the MD5 signature is wrong".to_string());
+    }
+
+    if stream_info.samples != n as u64 {
+      return Some(format!("STREAMINFO says {} samples", stream_info.samples));
+    }
+
+    return None;
+  }
+
+  // A failing case is shortened for as long as it keeps failing, and reported
+  // with its seed so it can be run again on its own with round_trip_case
+  #[test]
+  fn test_round_trip() {
+    for seed in range(0u32, 60) {
+      let (parameters, kinds, n) = round_trip_case(seed);
+
+      let mut failure = match round_trip(&parameters, kinds.as_slice(), n, seed) {
+        Some(failure) => (n, failure),
+        None => continue
+      };
+
+      loop {
+        let (n, _) = failure;
+
+        if n == 1 {
+          break;
+        }
+
+        match round_trip(&parameters, kinds.as_slice(), n / 2, seed) {
+          Some(shorter) => failure = (n / 2, shorter),
+          None => break
+        }
+      }
+
+      let (n, message) = failure;
+
+      panic!("Round trip case {} failed, {} in {} samples of signals {} with {}", seed, message, n, kinds, parameters);
+    }
+  }
+}
@@ -0,0 +1,65 @@
+use super::Parameters;
+
+struct Job {
+  index: uint,
+  number: u64,
+  sample_number: u64,
+  channels: Vec<Vec<i32>>
+}
+
+// Workers encoding windows independently, frame and sample numbers are
+// assigned up front so the frames are the same as from a single thread
+pub struct Pool {
+  workers: Vec<Sender<Job>>,
+  results: Receiver<(uint, Vec<(u32, Vec<u8>)>)>
+}
+
+impl Pool {
+  pub fn new(threads: uint, parameters: &Parameters) -> Pool {
+    let (result_sender, results) = channel();
+
+    let mut workers = Vec::with_capacity(threads);
+
+    for _ in range(0, threads) {
+      let (job_sender, job_receiver) = channel::<Job>();
+      let result_sender = result_sender.clone();
+      let parameters = parameters.clone();
+
+      spawn(proc() {
+        for job in job_receiver.iter() {
+          let channels: Vec<&[i32]> = job.channels.iter().map(|c| c.as_slice()).collect();
+
+          let frames = super::encode_window(&parameters, job.number, job.sample_number, channels.as_slice());
+
+          result_sender.send((job.index, frames));
+        }
+      });
+
+      workers.push(job_sender);
+    }
+
+    return Pool { workers: workers, results: results };
+  }
+
+  // Takes (number, sample number, channels) for each window, and returns
+  // their frames in the same order
+  pub fn encode(&mut self, windows: Vec<(u64, u64, Vec<Vec<i32>>)>) -> Vec<Vec<(u32, Vec<u8>)>> {
+    let count = windows.len();
+
+    for (index, (number, sample_number, channels)) in windows.into_iter().enumerate() {
+      let job = Job { index: index, number: number, sample_number: sample_number, channels: channels };
+
+      self.workers[index % self.workers.len()].send(job);
+    }
+
+    let mut frames: Vec<Option<Vec<(u32, Vec<u8>)>>> = Vec::from_fn(count, |_| None);
+
+    for _ in range(0, count) {
+      let (index, result) = self.results.recv();
+
+      frames.as_mut_slice()[index] = Some(result);
+    }
+
+    return frames.into_iter().map(|f| f.unwrap()).collect();
+  }
+}
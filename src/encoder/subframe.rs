@@ -0,0 +1,490 @@
+use std::cmp;
+use std::i32;
+
+use super::Parameters;
+use super::bitwriter::BitWriter;
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum Partition {
+  Rice(u8), Escape(u8)
+}
+
+#[deriving(Show,Clone,PartialEq)]
+pub struct Residual {
+  pub partition_order: u8,
+  pub partitions: Vec<Partition>
+}
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum Encoding {
+  Constant,
+  Verbatim,
+  Fixed(u8, Residual),
+  LPC(u8, i8, Vec<i32>, Residual)
+}
+
+#[deriving(Show,Clone,PartialEq)]
+pub struct Subframe {
+  pub encoding: Encoding,
+  pub wasted_bits: u8,
+  pub bits: uint
+}
+
+// Number of bits needed to hold value as a two's complement integer
+fn signed_bits(value: i32) -> uint {
+  if value == 0 {
+    return 0;
+  }
+
+  let mut magnitude = (if value < 0 { !value } else { value }) as u32;
+  let mut bits = 1u;
+
+  while magnitude != 0 {
+    magnitude >>= 1;
+    bits += 1;
+  }
+
+  return bits;
+}
+
+fn wasted_bits(samples: &[i32], bits_per_sample: u8) -> u8 {
+  let mut combined = samples.iter().fold(0i32, |a, &s| a | s);
+
+  if combined == 0 {
+    return 0;
+  }
+
+  let mut wasted = 0u8;
+
+  while combined & 1 == 0 && wasted < bits_per_sample - 1 {
+    combined >>= 1;
+    wasted += 1;
+  }
+
+  return wasted;
+}
+
+fn narrow(residual: Vec<i64>) -> Option<Vec<i32>> {
+  if residual.iter().any(|&r| r < i32::MIN as i64 || r > i32::MAX as i64) {
+    return None;
+  }
+
+  return Some(residual.iter().map(|&r| r as i32).collect());
+}
+
+pub fn fixed_residual(samples: &[i32], order: u8) -> Option<Vec<i32>> {
+  let order = order as uint;
+  let mut residual = Vec::with_capacity(samples.len() - order);
+
+  for i in range(order, samples.len()) {
+    let s = |n: uint| samples[i - n] as i64;
+
+    let prediction = match order {
+      0 => 0,
+      1 => s(1),
+      2 => 2 * s(1) - s(2),
+      3 => 3 * s(1) - 3 * s(2) + s(3),
+      4 => 4 * s(1) - 6 * s(2) + 4 * s(3) - s(4),
+      _ => panic!("flac::Encoder: Fixed predictor order {} does not exist (BUG)", order)
+    };
+
+    residual.push(samples[i] as i64 - prediction);
+  }
+
+  return narrow(residual);
+}
+
+pub fn lpc_residual(samples: &[i32], coefficients: &[i32], shift: i8) -> Option<Vec<i32>> {
+  let order = coefficients.len();
+  let mut residual = Vec::with_capacity(samples.len() - order);
+
+  for i in range(order, samples.len()) {
+    let mut sum = 0i64;
+
+    for (j, &c) in coefficients.iter().enumerate() {
+      sum += c as i64 * samples[i - 1 - j] as i64;
+    }
+
+    residual.push(samples[i] as i64 - (sum >> shift as uint));
+  }
+
+  return narrow(residual);
+}
+
+// Predictors of every order up to max_order, from the Welch windowed
+// autocorrelation using Levinson-Durbin recursion
+fn lpc_coefficients(samples: &[i32], max_order: uint) -> Vec<Vec<f64>> {
+  let n = samples.len();
+  let half = (n as f64 - 1.0) / 2.0;
+
+  let windowed: Vec<f64> = samples.iter().enumerate().map(|(i, &s)| {
+    let x = (i as f64 - half) / (half + 1.0);
+    s as f64 * (1.0 - x * x)
+  }).collect();
+
+  let mut autocorrelation = Vec::with_capacity(max_order + 1);
+
+  for lag in range(0, max_order + 1) {
+    let mut sum = 0.0f64;
+
+    for i in range(lag, n) {
+      sum += windowed[i] * windowed[i - lag];
+    }
+
+    autocorrelation.push(sum);
+  }
+
+  let mut result = Vec::new();
+
+  if autocorrelation[0] == 0.0 {
+    return result;
+  }
+
+  let mut error = autocorrelation[0];
+  let mut lpc = Vec::from_elem(max_order, 0.0f64);
+
+  for i in range(0, max_order) {
+    let mut r = -autocorrelation[i + 1];
+
+    for j in range(0, i) {
+      r -= lpc[j] * autocorrelation[i - j];
+    }
+
+    r /= error;
+
+    let mut next = lpc.clone();
+
+    {
+      let next = next.as_mut_slice();
+
+      next[i] = r;
+
+      for j in range(0, i) {
+        next[j] = lpc[j] + r * lpc[i - 1 - j];
+      }
+    }
+
+    lpc = next;
+    error *= 1.0 - r * r;
+
+    result.push(lpc.slice(0, i + 1).iter().map(|&c| -c).collect());
+
+    if error <= 0.0 {
+      break;
+    }
+  }
+
+  return result;
+}
+
+fn round(x: f64) -> i64 {
+  return if x >= 0.0 { (x + 0.5) as i64 } else { -((0.5 - x) as i64) };
+}
+
+// Quantizes coefficients to precision bits with error feedback, the shift is
+// chosen so the largest coefficient uses all of them
+fn quantize(coefficients: &[f64], precision: u8) -> Option<(Vec<i32>, i8)> {
+  let cmax = coefficients.iter().fold(0.0f64, |a, &c| {
+    let magnitude = if c < 0.0 { -c } else { c };
+    if magnitude > a { magnitude } else { a }
+  });
+
+  if cmax <= 0.0 {
+    return None;
+  }
+
+  let mut log2cmax = 0i;
+  let mut c = cmax;
+
+  while c >= 2.0 {
+    c /= 2.0;
+    log2cmax += 1;
+  }
+
+  while c < 1.0 {
+    c *= 2.0;
+    log2cmax -= 1;
+  }
+
+  let shift = cmp::min(precision as int - 2 - log2cmax, 15);
+
+  if shift < 0 {
+    return None;
+  }
+
+  let max = (1i64 << (precision as uint - 1)) - 1;
+  let min = -(1i64 << (precision as uint - 1));
+
+  let mut error = 0.0f64;
+  let mut quantized = Vec::with_capacity(coefficients.len());
+
+  for &c in coefficients.iter() {
+    error += c * (1i64 << shift as uint) as f64;
+
+    let q = cmp::max(min, cmp::min(max, round(error)));
+
+    error -= q as f64;
+    quantized.push(q as i32);
+  }
+
+  return Some((quantized, shift as i8));
+}
+
+fn default_precision(block_size: uint) -> u8 {
+  return match block_size {
+    0...192 => 7,
+    193...384 => 8,
+    385...576 => 9,
+    577...1152 => 10,
+    1153...2304 => 11,
+    2305...4608 => 12,
+    _ => 13
+  };
+}
+
+fn rice_partition(residual: &[i32]) -> (Partition, uint) {
+  let n = residual.len();
+  let sum = residual.iter().fold(0u64, |a, &r| a + ((r << 1) ^ (r >> 31)) as u32 as u64);
+
+  let mut best = Rice(0);
+  let mut best_bits = n + sum as uint;
+
+  for k in range(1u, 31) {
+    let bits = n * (k + 1) + (sum >> k) as uint;
+
+    if bits < best_bits {
+      best = Rice(k as u8);
+      best_bits = bits;
+    }
+  }
+
+  let escape = residual.iter().fold(0u, |a, &r| cmp::max(a, signed_bits(r)));
+
+  // Escaped samples are at most 31 bits, the size is written in 5
+  if escape < 32 && 5 + n * escape < best_bits {
+    best = Escape(escape as u8);
+    best_bits = 5 + n * escape;
+  }
+
+  return (best, best_bits);
+}
+
+fn needs_wide_parameters(partitions: &[Partition]) -> bool {
+  return partitions.iter().any(|p| match *p { Rice(k) => k > 14, _ => false });
+}
+
+fn choose_residual(residual: &[i32], block_size: uint, order: uint, max_partition_order: u8) -> (Residual, uint) {
+  let mut best: Option<(Residual, uint)> = None;
+
+  for partition_order in range(0, max_partition_order as uint + 1) {
+    let partition_size = block_size >> partition_order;
+
+    if partition_size << partition_order != block_size || partition_size < order {
+      break;
+    }
+
+    let mut partitions = Vec::with_capacity(1 << partition_order);
+    let mut bits = 2 + 4;
+    let mut start = 0;
+
+    for p in range(0, 1u << partition_order) {
+      let end = start + partition_size - (if p == 0 { order } else { 0 });
+      let (partition, partition_bits) = rice_partition(residual.slice(start, end));
+
+      partitions.push(partition);
+      bits += partition_bits;
+      start = end;
+    }
+
+    bits += partitions.len() * (if needs_wide_parameters(partitions.as_slice()) { 5 } else { 4 });
+
+    let better = match best {
+      Some((_, b)) => bits < b,
+      None => true
+    };
+
+    if better {
+      best = Some((Residual { partition_order: partition_order as u8, partitions: partitions }, bits));
+    }
+  }
+
+  return best.unwrap();
+}
+
+pub fn analyze(samples: &[i32], bits_per_sample: u8, parameters: &Parameters) -> Subframe {
+  let n = samples.len();
+
+  if samples.iter().all(|&s| s == samples[0]) {
+    return Subframe { encoding: Constant, wasted_bits: 0, bits: 8 + bits_per_sample as uint };
+  }
+
+  let wasted = wasted_bits(samples, bits_per_sample);
+  let shifted: Vec<i32> = samples.iter().map(|&s| s >> wasted as uint).collect();
+  let samples = shifted.as_slice();
+
+  let bits = (bits_per_sample - wasted) as uint;
+  let header = 8 + wasted as uint;
+
+  let mut best = Verbatim;
+  let mut best_bits = header + n * bits;
+
+  for order in range(0, cmp::min(parameters.max_fixed_order as uint, n - 1) + 1) {
+    let residual = match fixed_residual(samples, order as u8) {
+      Some(residual) => residual,
+      None => continue
+    };
+
+    let (coding, coding_bits) = choose_residual(residual.as_slice(), n, order, parameters.max_partition_order);
+    let total = header + order * bits + coding_bits;
+
+    if total < best_bits {
+      best = Fixed(order as u8, coding);
+      best_bits = total;
+    }
+  }
+
+  let precision = if parameters.lpc_precision > 0 { parameters.lpc_precision } else { default_precision(n) };
+  let max_lpc_order = cmp::min(parameters.max_lpc_order as uint, n - 1);
+
+  let predictors = lpc_coefficients(samples, max_lpc_order);
+
+  for coefficients in predictors.iter() {
+    let (quantized, shift) = match quantize(coefficients.as_slice(), precision) {
+      Some(q) => q,
+      None => continue
+    };
+
+    let order = quantized.len();
+
+    let residual = match lpc_residual(samples, quantized.as_slice(), shift) {
+      Some(residual) => residual,
+      None => continue
+    };
+
+    let (coding, coding_bits) = choose_residual(residual.as_slice(), n, order, parameters.max_partition_order);
+    let total = header + order * bits + 4 + 5 + order * precision as uint + coding_bits;
+
+    if total < best_bits {
+      best = LPC(precision, shift, quantized, coding);
+      best_bits = total;
+    }
+  }
+
+  return Subframe { encoding: best, wasted_bits: wasted, bits: best_bits };
+}
+
+fn write_residual(writer: &mut BitWriter, residual: &[i32], block_size: uint, order: uint, coding: &Residual) {
+  let wide = needs_wide_parameters(coding.partitions.as_slice());
+  let (parameter_bits, escape) = if wide { (5, 0b11111) } else { (4, 0b1111) };
+
+  writer.write_n(if wide { 0b01 } else { 0b00 }, 2);
+  writer.write_n(coding.partition_order as u32, 4);
+
+  let partition_size = block_size >> coding.partition_order as uint;
+  let mut start = 0;
+
+  for (p, partition) in coding.partitions.iter().enumerate() {
+    let end = start + partition_size - (if p == 0 { order } else { 0 });
+
+    match *partition {
+      Rice(k) => {
+        writer.write_n(k as u32, parameter_bits);
+
+        for &r in residual.slice(start, end).iter() {
+          writer.write_rice(r, k as uint);
+        }
+      },
+      Escape(bits) => {
+        writer.write_n(escape, parameter_bits);
+        writer.write_n(bits as u32, 5);
+
+        for &r in residual.slice(start, end).iter() {
+          writer.write_n_signed(r, bits as uint);
+        }
+      }
+    }
+
+    start = end;
+  }
+}
+
+pub fn write(writer: &mut BitWriter, samples: &[i32], bits_per_sample: u8, subframe: &Subframe) {
+  let wasted = subframe.wasted_bits;
+  let shifted: Vec<i32> = samples.iter().map(|&s| s >> wasted as uint).collect();
+  let samples = shifted.as_slice();
+  let bits = (bits_per_sample - wasted) as uint;
+
+  let ty_code = match subframe.encoding {
+    Constant => 0b000000,
+    Verbatim => 0b000001,
+    Fixed(order, _) => 0b001000 | order as u32,
+    LPC(_, _, ref coefficients, _) => 0b100000 | (coefficients.len() as u32 - 1)
+  };
+
+  writer.write_n(0, 1);
+  writer.write_n(ty_code, 6);
+
+  if wasted > 0 {
+    writer.write_n(1, 1);
+    writer.write_unary(wasted as u32 - 1);
+  } else {
+    writer.write_n(0, 1);
+  }
+
+  match subframe.encoding {
+    Constant => {
+      writer.write_n_signed(samples[0], bits);
+    },
+    Verbatim => {
+      for &s in samples.iter() {
+        writer.write_n_signed(s, bits);
+      }
+    },
+    Fixed(order, ref coding) => {
+      let order = order as uint;
+
+      for &s in samples.slice_to(order).iter() {
+        writer.write_n_signed(s, bits);
+      }
+
+      let residual = fixed_residual(samples, order as u8).expect("flac::Encoder: Fixed residual overflows (BUG)");
+
+      write_residual(writer, residual.as_slice(), samples.len(), order, coding);
+    },
+    LPC(precision, shift, ref coefficients, ref coding) => {
+      let order = coefficients.len();
+
+      for &s in samples.slice_to(order).iter() {
+        writer.write_n_signed(s, bits);
+      }
+
+      writer.write_n(precision as u32 - 1, 4);
+      writer.write_n_signed(shift as i32, 5);
+
+      for &c in coefficients.iter() {
+        writer.write_n_signed(c, precision as uint);
+      }
+
+      let residual = lpc_residual(samples, coefficients.as_slice(), shift).expect("flac::Encoder: LPC residual overflows (BUG)");
+
+      write_residual(writer, residual.as_slice(), samples.len(), order, coding);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::i32;
+
+  // Samples that need all 32 bits are cheaper escaped, but the escape size
+  // only has room for 31
+  #[test]
+  fn test_rice_partition_escape() {
+    let residual = Vec::from_fn(16, |i| if i % 2 == 0 { i32::MIN } else { i32::MAX });
+
+    match super::rice_partition(residual.as_slice()) {
+      (super::Escape(bits), _) => assert!(bits < 32),
+      (super::Rice(k), _) => assert!(k < 31)
+    }
+  }
+}
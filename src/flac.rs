@@ -1,9 +1,82 @@
 // pub mod demuxer;
 
+#![feature(phase, globs, asm)]
+
+// Without the std feature only the parsers are built, on core and alloc
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
 extern crate aurora;
 
+#[cfg(test)]
+extern crate test;
+
+#[cfg(not(feature = "std"))]
+#[phase(plugin, link)]
+extern crate core;
+
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+#[cfg(not(feature = "std"))]
+extern crate collections;
+
+// What deriving and the macros expand to
+#[cfg(not(feature = "std"))]
+mod std {
+  pub use core::{clone, cmp, fmt, iter, kinds, option};
+}
+
+// Imported by the parsers, so they build with and without std
+#[cfg(not(feature = "std"))]
+mod prelude {
+  pub use core::prelude::*;
+  pub use collections::vec::Vec;
+  pub use collections::slice::CloneableVector;
+}
+
+#[cfg(feature = "std")]
+mod prelude {
+  pub use std::prelude::*;
+}
+
+// Unwraps what a parser returned, or rejects the input by panicking
+macro_rules! valid(
+  ($e:expr) => (match $e { Ok(value) => value, Err(invalid) => panic!("{} (INPUT)", invalid) })
+)
+
+pub mod invalid;
+pub mod bitstream;
+pub mod crc;
+pub mod simd;
 pub mod metadata;
+pub mod frame;
+pub mod subframe;
+pub mod md5;
+pub mod limits;
+
+#[cfg(feature = "std")]
 pub mod demuxer;
+#[cfg(feature = "std")]
 pub mod decoder;
-pub mod frame;
-pub mod subframe;
\ No newline at end of file
+#[cfg(feature = "std")]
+pub mod encoder;
+#[cfg(feature = "std")]
+pub mod wav;
+#[cfg(feature = "std")]
+pub mod foreign;
+#[cfg(feature = "std")]
+pub mod ogg;
+#[cfg(feature = "std")]
+pub mod mp4;
+#[cfg(feature = "std")]
+pub mod matroska;
+#[cfg(feature = "std")]
+pub mod reader;
+#[cfg(feature = "std")]
+pub mod async;
+#[cfg(feature = "std")]
+pub mod parser;
+
+#[cfg(test)]
+mod test_util;
\ No newline at end of file
@@ -0,0 +1,943 @@
+use std::cmp;
+use std::io::{IoError, IoResult, BufReader, EndOfFile, Seek, SeekSet, SeekCur, SeekEnd};
+
+use aurora;
+
+use encoder;
+use limits::Limits;
+use reader::{Error, Io, Limit};
+
+// PCM input from RIFF WAVE, AIFF, AIFF-C and Sony Wave64 files. Everything
+// but the audio is kept as it is, the way flac --keep-foreign-metadata does,
+// in APPLICATION blocks that start with the id of the container followed by
+// one chunk each, the chunks after the audio go into one last block, along
+// with the bytes at the end of the audio that don't make a whole sample. Output
+// puts them back around the decoded audio, which gives back the same file.
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum Container {
+  Riff, Aiff, Wave64
+}
+
+impl Container {
+  // Id of the APPLICATION blocks the chunks are kept in
+  pub fn id(&self) -> &'static [u8] {
+    return match *self {
+      Riff => b"riff",
+      Aiff => b"aiff",
+      Wave64 => b"w64 "
+    };
+  }
+}
+
+static RIFF_GUID: [u8, ..16] = [0x72, 0x69, 0x66, 0x66, 0x2E, 0x91, 0xCF, 0x11, 0xA5, 0xD6, 0x28, 0xDB, 0x04, 0xC1, 0x00, 0x00];
+static WAVE_GUID: [u8, ..16] = [0x77, 0x61, 0x76, 0x65, 0xF3, 0xAC, 0xD3, 0x11, 0x8C, 0xD1, 0x00, 0xC0, 0x4F, 0x8E, 0xDB, 0x8A];
+static FMT_GUID: [u8, ..16] = [0x66, 0x6D, 0x74, 0x20, 0xF3, 0xAC, 0xD3, 0x11, 0x8C, 0xD1, 0x00, 0xC0, 0x4F, 0x8E, 0xDB, 0x8A];
+static DATA_GUID: [u8, ..16] = [0x64, 0x61, 0x74, 0x61, 0xF3, 0xAC, 0xD3, 0x11, 0x8C, 0xD1, 0x00, 0xC0, 0x4F, 0x8E, 0xDB, 0x8A];
+
+// How samples are stored, the used bits are at the top of the bytes
+#[deriving(Show,Clone)]
+struct Layout {
+  bytes: uint,
+  bits: uint,
+  big_endian: bool,
+  unsigned: bool
+}
+
+impl Layout {
+  fn decode(&self, data: &[u8]) -> i32 {
+    let mut raw = (if self.big_endian { be(data) } else { le(data) }) as u32;
+
+    if self.unsigned {
+      raw ^= 0x80;
+    }
+
+    return (raw << (32 - 8 * self.bytes)) as i32 >> (32 - self.bits);
+  }
+
+  fn encode(&self, sample: i32, output: &mut Vec<u8>) {
+    let mut raw = (sample << (32 - self.bits)) as u32 >> (32 - 8 * self.bytes);
+
+    if self.unsigned {
+      raw ^= 0x80;
+    }
+
+    for i in range(0, self.bytes) {
+      let shift = if self.big_endian { 8 * (self.bytes - 1 - i) } else { 8 * i };
+
+      output.push((raw >> shift) as u8);
+    }
+  }
+}
+
+struct Header {
+  container: Container,
+  sample_rate: u32,
+  channels: uint,
+  layout: Layout,
+  audio_size: u64,
+  blocks: Vec<Vec<u8>>
+}
+
+fn le(data: &[u8]) -> u64 {
+  return data.iter().rev().fold(0u64, |a, &b| (a << 8) | b as u64);
+}
+
+fn be(data: &[u8]) -> u64 {
+  return data.iter().fold(0u64, |a, &b| (a << 8) | b as u64);
+}
+
+fn check_layout(channels: uint, layout: &Layout) {
+  if channels < 1 || channels > 8 {
+    panic!("flac::foreign: {} channels can't be encoded (INPUT)", channels);
+  }
+
+  if layout.bits < 4 || layout.bits > 32 || layout.bytes > 4 || layout.bits > 8 * layout.bytes {
+    panic!("flac::foreign: {} bit samples in {} bytes can't be encoded (INPUT)", layout.bits, layout.bytes);
+  }
+}
+
+// The fmt chunk of RIFF WAVE and Wave64 files
+fn wave_format(data: &[u8]) -> (u32, uint, Layout) {
+  if data.len() < 16 {
+    panic!("flac::foreign: fmt chunk is too short (INPUT)");
+  }
+
+  let tag = le(data.slice(0, 2));
+  let channels = le(data.slice(2, 4)) as uint;
+  let sample_rate = le(data.slice(4, 8)) as u32;
+  let block_align = le(data.slice(12, 14)) as uint;
+  let container_bits = le(data.slice(14, 16)) as uint;
+
+  let bits = match tag {
+    0x0001 => container_bits,
+    0xFFFE if data.len() >= 40 && le(data.slice(24, 26)) == 0x0001 => {
+      match le(data.slice(18, 20)) as uint {
+        0 => container_bits,
+        valid => valid
+      }
+    },
+    _ => panic!("flac::foreign: Only PCM WAVE files can be encoded, not format {:x} (INPUT)", tag)
+  };
+
+  if channels == 0 || block_align % channels != 0 {
+    panic!("flac::foreign: Block align {} doesn't fit {} channels (INPUT)", block_align, channels);
+  }
+
+  let bytes = block_align / channels;
+
+  // 8 bit WAVE samples are the only unsigned ones
+  let layout = Layout { bytes: bytes, bits: bits, big_endian: false, unsigned: bytes == 1 };
+
+  check_layout(channels, &layout);
+
+  return (sample_rate, channels, layout);
+}
+
+// 80 bit extended precision, which AIFF uses for the sample rate
+fn extended(data: &[u8]) -> u32 {
+  let exponent = (((data[0] as int & 0x7F) << 8) | data[1] as int) - 16383;
+  let mantissa = be(data.slice(2, 10));
+
+  if data[0] & 0x80 != 0 || exponent < 0 || exponent > 31 {
+    panic!("flac::foreign: Sample rate is out of range (INPUT)");
+  }
+
+  return (mantissa >> (63 - exponent) as uint) as u32;
+}
+
+// The COMM chunk of AIFF and AIFF-C files
+fn aiff_format(data: &[u8], aifc: bool) -> (u32, uint, Layout) {
+  if data.len() < 18 || (aifc && data.len() < 22) {
+    panic!("flac::foreign: COMM chunk is too short (INPUT)");
+  }
+
+  let channels = be(data.slice(0, 2)) as uint;
+  let bits = be(data.slice(6, 8)) as uint;
+  let sample_rate = extended(data.slice(8, 18));
+
+  let big_endian = if aifc {
+    let compression = data.slice(18, 22);
+
+    if compression == b"NONE" || compression == b"twos" {
+      true
+    } else if compression == b"sowt" {
+      false
+    } else {
+      panic!("flac::foreign: Only uncompressed AIFF-C files can be encoded (INPUT)");
+    }
+  } else {
+    true
+  };
+
+  let layout = Layout { bytes: (bits + 7) / 8, bits: bits, big_endian: big_endian, unsigned: false };
+
+  check_layout(channels, &layout);
+
+  return (sample_rate, channels, layout);
+}
+
+// Of a chunk in one APPLICATION block, after the id, longer ones are split
+const MAX_PIECE: uint = (1 << 24) - 1 - 4;
+
+// Input that chunks are kept from. Every read is checked against what's left
+// of the input and against the limits before anything is allocated for it.
+struct Chunks<'a, R: 'a> {
+  input: &'a mut R,
+  end: u64,
+  limits: &'a Limits,
+  total: uint // Bytes kept so far
+}
+
+impl<'a, R: Reader + Seek> Chunks<'a, R> {
+  fn read(&mut self, n: u64) -> Result<Vec<u8>, Error> {
+    let position = try!(self.input.tell().map_err(Io));
+
+    if n > self.end - cmp::min(position, self.end) {
+      return Err(Io(IoError { kind: EndOfFile, desc: "flac::foreign: Chunk is longer than what's left of the input", detail: None }));
+    }
+
+    try!(self.limits.check_metadata(n as uint, self.total).map_err(Limit));
+    self.total += n as uint;
+
+    return self.input.read_exact(n as uint).map_err(Io);
+  }
+}
+
+fn finish_header(container: Container, format: Option<(u32, uint, Layout)>, audio_size: u64, blocks: Vec<Vec<u8>>) -> Header {
+  let (sample_rate, channels, layout) = match format {
+    Some(format) => format,
+    None => panic!("flac::foreign: Audio came before its format (INPUT)")
+  };
+
+  return Header { container: container, sample_rate: sample_rate, channels: channels, layout: layout, audio_size: audio_size, blocks: blocks };
+}
+
+fn read_riff<R: Reader + Seek>(input: &mut Chunks<R>, mut head: Vec<u8>) -> Result<Header, Error> {
+  head.push_all(try!(input.read(8)).as_slice());
+
+  if head.slice(8, 12) != b"WAVE" {
+    panic!("flac::foreign: RIFF file isn't a WAVE file (INPUT)");
+  }
+
+  let mut blocks = vec![head];
+  let mut format = None;
+
+  loop {
+    let mut chunk = try!(input.read(8));
+    let size = le(chunk.slice(4, 8));
+
+    if chunk.slice_to(4) == b"data" {
+      blocks.push(chunk);
+
+      return Ok(finish_header(Riff, format, size, blocks));
+    }
+
+    chunk.push_all(try!(input.read(size + (size & 1))).as_slice());
+
+    if chunk.slice_to(4) == b"fmt " {
+      format = Some(wave_format(chunk.slice_from(8)));
+    }
+
+    blocks.push(chunk);
+  }
+}
+
+fn read_aiff<R: Reader + Seek>(input: &mut Chunks<R>, mut head: Vec<u8>) -> Result<Header, Error> {
+  head.push_all(try!(input.read(8)).as_slice());
+
+  let aifc = head.slice(8, 12) == b"AIFC";
+
+  if !aifc && head.slice(8, 12) != b"AIFF" {
+    panic!("flac::foreign: FORM file isn't an AIFF file (INPUT)");
+  }
+
+  let mut blocks = vec![head];
+  let mut format = None;
+
+  loop {
+    let mut chunk = try!(input.read(8));
+    let size = be(chunk.slice(4, 8));
+
+    if chunk.slice_to(4) == b"SSND" {
+      let offsets = try!(input.read(8));
+      let offset = be(offsets.slice_to(4));
+
+      if size < 8 + offset {
+        panic!("flac::foreign: SSND chunk is too short (INPUT)");
+      }
+
+      chunk.push_all(offsets.as_slice());
+      chunk.push_all(try!(input.read(offset)).as_slice());
+
+      blocks.push(chunk);
+
+      return Ok(finish_header(Aiff, format, size - 8 - offset, blocks));
+    }
+
+    chunk.push_all(try!(input.read(size + (size & 1))).as_slice());
+
+    if chunk.slice_to(4) == b"COMM" {
+      format = Some(aiff_format(chunk.slice_from(8), aifc));
+    }
+
+    blocks.push(chunk);
+  }
+}
+
+fn read_wave64<R: Reader + Seek>(input: &mut Chunks<R>, mut head: Vec<u8>) -> Result<Header, Error> {
+  head.push_all(try!(input.read(36)).as_slice());
+
+  if head.slice(0, 16) != RIFF_GUID.as_slice() || head.slice(24, 40) != WAVE_GUID.as_slice() {
+    panic!("flac::foreign: File isn't a Wave64 file (INPUT)");
+  }
+
+  let mut blocks = vec![head];
+  let mut format = None;
+
+  loop {
+    let mut chunk = try!(input.read(24));
+    let size = le(chunk.slice(16, 24));
+
+    // Sizes include the chunk header, and chunks are padded to 8 bytes
+    if size < 24 {
+      panic!("flac::foreign: Wave64 chunk is too short (INPUT)");
+    }
+
+    if chunk.slice_to(16) == DATA_GUID.as_slice() {
+      blocks.push(chunk);
+
+      return Ok(finish_header(Wave64, format, size - 24, blocks));
+    }
+
+    chunk.push_all(try!(input.read(size - 24 + (8 - size % 8) % 8)).as_slice());
+
+    if chunk.slice_to(16) == FMT_GUID.as_slice() {
+      format = Some(wave_format(chunk.slice_from(24)));
+    }
+
+    blocks.push(chunk);
+  }
+}
+
+// Reads everything up to the audio
+fn read_header<R: Reader + Seek>(input: &mut Chunks<R>) -> Result<Header, Error> {
+  let magic = try!(input.read(4));
+
+  if magic.as_slice() == b"RIFF" {
+    return read_riff(input, magic);
+  } else if magic.as_slice() == b"FORM" {
+    return read_aiff(input, magic);
+  } else if magic.as_slice() == b"riff" {
+    return read_wave64(input, magic);
+  }
+
+  panic!("flac::foreign: Input is neither a WAVE, an AIFF nor a Wave64 file (INPUT)");
+}
+
+pub struct Input<R> {
+  input: R,
+  pub container: Container,
+  pub sample_rate: u32,
+  pub channels: u8,
+  pub bits_per_sample: u8,
+  pub samples: u64,
+  layout: Layout,
+  remaining: u64,
+  blocks: Vec<Vec<u8>>
+}
+
+impl<R: Reader + Seek> Input<R> {
+  pub fn new(input: R) -> IoResult<Input<R>> {
+    return match Input::with_limits(input, Limits::new()) {
+      Ok(input) => Ok(input),
+      Err(Io(error)) => Err(error),
+      Err(Limit(exceeded)) => panic!("flac::foreign: Limits::new() was exceeded with {} (BUG)", exceeded)
+    };
+  }
+
+  // Same as new, but every chunk that's kept is checked against the limits
+  // on metadata before it's read
+  pub fn with_limits(mut input: R, limits: Limits) -> Result<Input<R>, Error> {
+    let start = try!(input.tell().map_err(Io));
+
+    try!(input.seek(0, SeekEnd).map_err(Io));
+
+    let end = try!(input.tell().map_err(Io));
+
+    try!(input.seek(start as i64, SeekSet).map_err(Io));
+
+    let (header, audio_size, trailing) = {
+      let mut chunks = Chunks { input: &mut input, end: end, limits: &limits, total: 0 };
+
+      let header = try!(read_header(&mut chunks));
+
+      let block_align = (header.channels * header.layout.bytes) as u64;
+
+      // What's after the whole sample frames has to be known before the
+      // encoder writes its metadata
+      let position = try!(chunks.input.tell().map_err(Io));
+
+      if header.audio_size > end - position {
+        return Err(Io(IoError { kind: EndOfFile, desc: "flac::foreign: Audio is longer than what's left of the input", detail: None }));
+      }
+
+      let audio_size = header.audio_size - header.audio_size % block_align;
+
+      try!(chunks.input.seek(audio_size as i64, SeekCur).map_err(Io));
+
+      let trailing = try!(chunks.read(end - position - audio_size));
+
+      try!(chunks.input.seek(position as i64, SeekSet).map_err(Io));
+
+      (header, audio_size, trailing)
+    };
+
+    let block_align = (header.channels * header.layout.bytes) as u64;
+
+    let mut blocks = header.blocks;
+
+    if !trailing.is_empty() {
+      blocks.push(trailing);
+    }
+
+    return Ok(Input {
+      input: input,
+      container: header.container,
+      sample_rate: header.sample_rate,
+      channels: header.channels as u8,
+      bits_per_sample: header.layout.bits as u8,
+      samples: audio_size / block_align,
+      layout: header.layout,
+      remaining: audio_size,
+      blocks: blocks
+    });
+  }
+
+  pub fn parameters(&self) -> encoder::Parameters {
+    let mut parameters = encoder::Parameters::new(self.sample_rate, self.channels, self.bits_per_sample);
+
+    parameters.samples = Some(self.samples);
+
+    return parameters;
+  }
+
+  // APPLICATION blocks for Encoder::with_metadata, chunks that are too long
+  // for one go into several in a row
+  pub fn metadata(&self) -> Vec<(u8, Vec<u8>)> {
+    let id = self.container.id();
+    let mut blocks = Vec::new();
+
+    for chunk in self.blocks.iter() {
+      for piece in chunk.as_slice().chunks(MAX_PIECE) {
+        let mut data = id.to_vec();
+        data.push_all(piece);
+
+        blocks.push((2, data));
+      }
+    }
+
+    return blocks;
+  }
+
+  // Interleaved samples, up to frames of them per channel, none at the end
+  pub fn read(&mut self, frames: uint) -> IoResult<Vec<i32>> {
+    let block_align = self.channels as uint * self.layout.bytes;
+    let n = cmp::min((frames * block_align) as u64, self.remaining) as uint;
+
+    let data = try!(self.input.read_exact(n));
+
+    self.remaining -= n as u64;
+
+    return Ok(data.as_slice().chunks(self.layout.bytes).map(|s| self.layout.decode(s)).collect());
+  }
+}
+
+// Writes the audio from a source between the chunks from the APPLICATION
+// blocks of the stream it was decoded from
+pub struct Output<W> {
+  source: aurora::channel::Source<aurora::Audio>,
+  blocks: Vec<Vec<u8>>,
+  writer: W
+}
+
+impl<W: Writer> Output<W> {
+  // Takes the contents of the stream's APPLICATION blocks, ids included,
+  // ones that aren't foreign chunks are left out
+  pub fn new(source: aurora::channel::Source<aurora::Audio>, blocks: Vec<Vec<u8>>, writer: W) -> Output<W> {
+    return Output { source: source, blocks: blocks, writer: writer };
+  }
+
+  pub fn run(mut self) -> IoResult<W> {
+    let id = match self.blocks.iter().find(|b| b.len() >= 4 && [Riff, Aiff, Wave64].iter().any(|c| c.id() == b.slice_to(4))) {
+      Some(block) => block.slice_to(4).to_vec(),
+      None => panic!("flac::foreign::Output: There are no foreign chunks to restore (INPUT)")
+    };
+
+    let chunks: Vec<&[u8]> = self.blocks.iter().filter(|b| b.len() >= 4 && b.slice_to(4) == id.as_slice()).map(|b| b.slice_from(4)).collect();
+
+    let mut joined = Vec::new();
+
+    for chunk in chunks.iter() {
+      joined.push_all(*chunk);
+    }
+
+    let header = {
+      let mut reader = BufReader::new(joined.as_slice());
+
+      match read_header(&mut Chunks { input: &mut reader, end: joined.len() as u64, limits: &Limits::new(), total: 0 }) {
+        Ok(header) => header,
+        Err(Io(error)) => return Err(error),
+        Err(Limit(exceeded)) => panic!("flac::foreign::Output: Limits::new() was exceeded with {} (BUG)", exceeded)
+      }
+    };
+
+    // Chunks can be split over blocks, so what goes before the audio is
+    // counted in bytes
+    let length = header.blocks.iter().fold(0, |n, chunk| n + chunk.len());
+
+    try!(self.writer.write(joined.slice_to(length)));
+
+    let mut buffer = Vec::new();
+    let mut result = Ok(());
+    let mut last = false;
+
+    while !last && result.is_ok() {
+      let writer = &mut self.writer;
+
+      self.source.read(|audio| {
+        last = audio.last;
+
+        let bytes = match audio.sample_type {
+          aurora::sample_type::Signed(bits) => (bits + 7) / 8,
+          _ => panic!("flac::foreign::Output: Only signed samples can be written (INPUT)")
+        };
+
+        buffer.truncate(0);
+
+        for sample in audio.data.as_slice().chunks(bytes) {
+          let value = (be(sample) << (64 - 8 * bytes)) as i64 >> (64 - 8 * bytes);
+
+          header.layout.encode(value as i32, &mut buffer);
+        }
+
+        result = writer.write(buffer.as_slice());
+      });
+    }
+
+    try!(result);
+    try!(self.writer.write(joined.slice_from(length)));
+
+    return Ok(self.writer);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std;
+  use aurora;
+
+  use demuxer;
+  use decoder;
+  use encoder;
+  use limits;
+  use metadata;
+  use reader;
+  use test_util;
+  use test_util::noise;
+
+  fn push_le(data: &mut Vec<u8>, value: u64, bytes: uint) {
+    for i in range(0, bytes) {
+      data.push((value >> (8 * i)) as u8);
+    }
+  }
+
+  fn push_be(data: &mut Vec<u8>, value: u64, bytes: uint) {
+    for i in range(0, bytes).rev() {
+      data.push((value >> (8 * i)) as u8);
+    }
+  }
+
+  // 16 bit stereo, or 20 bits in 24 with WAVE_FORMAT_EXTENSIBLE
+  fn wave(samples: &[i32], extensible: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    body.push_all(b"WAVE");
+    body.push_all(b"bext");
+    push_le(&mut body, 5, 4);
+    body.push_all(b"hello\x00");
+
+    body.push_all(b"fmt ");
+
+    if extensible {
+      push_le(&mut body, 40, 4);
+      push_le(&mut body, 0xFFFE, 2);
+      push_le(&mut body, 2, 2);
+      push_le(&mut body, 96000, 4);
+      push_le(&mut body, 96000 * 6, 4);
+      push_le(&mut body, 6, 2);
+      push_le(&mut body, 24, 2);
+      push_le(&mut body, 22, 2);
+      push_le(&mut body, 20, 2);
+      push_le(&mut body, 3, 4);
+      body.push_all(&[0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71]);
+    } else {
+      push_le(&mut body, 16, 4);
+      push_le(&mut body, 0x0001, 2);
+      push_le(&mut body, 2, 2);
+      push_le(&mut body, 44100, 4);
+      push_le(&mut body, 44100 * 4, 4);
+      push_le(&mut body, 4, 2);
+      push_le(&mut body, 16, 2);
+    }
+
+    let bytes = if extensible { 3 } else { 2 };
+
+    body.push_all(b"data");
+    push_le(&mut body, (bytes * samples.len()) as u64, 4);
+
+    for &sample in samples.iter() {
+      let shift = if extensible { 4 } else { 0 };
+
+      push_le(&mut body, (sample << shift) as u64, bytes);
+    }
+
+    body.push_all(b"ID3 ");
+    push_le(&mut body, 3, 4);
+    body.push_all(b"abc\x00");
+
+    let mut data = Vec::new();
+
+    data.push_all(b"RIFF");
+    push_le(&mut data, body.len() as u64, 4);
+    data.push_all(body.as_slice());
+
+    return data;
+  }
+
+  // 12 bit stereo AIFF, or 16 bit mono little-endian AIFF-C
+  fn aiff(samples: &[i32], aifc: bool) -> Vec<u8> {
+    let (channels, bits) = if aifc { (1, 16) } else { (2, 12) };
+
+    let mut body = Vec::new();
+
+    body.push_all(if aifc { b"AIFC" } else { b"AIFF" });
+
+    if aifc {
+      body.push_all(b"FVER");
+      push_be(&mut body, 4, 4);
+      push_be(&mut body, 0xA2805140, 4);
+    }
+
+    body.push_all(b"COMM");
+    push_be(&mut body, if aifc { 24 } else { 18 }, 4);
+    push_be(&mut body, channels, 2);
+    push_be(&mut body, samples.len() as u64 / channels, 4);
+    push_be(&mut body, bits, 2);
+    body.push_all(&[0x40, 0x0E, 0xAC, 0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+    if aifc {
+      body.push_all(b"sowt\x00\x00");
+    }
+
+    body.push_all(b"ANNO");
+    push_be(&mut body, 7, 4);
+    body.push_all(b"comment\x00");
+
+    body.push_all(b"SSND");
+    push_be(&mut body, 8 + 2 * samples.len() as u64, 4);
+    push_be(&mut body, 0, 8);
+
+    for &sample in samples.iter() {
+      if aifc {
+        push_le(&mut body, sample as u64, 2);
+      } else {
+        push_be(&mut body, (sample << 4) as u64, 2);
+      }
+    }
+
+    let mut data = Vec::new();
+
+    data.push_all(b"FORM");
+    push_be(&mut data, body.len() as u64, 4);
+    data.push_all(body.as_slice());
+
+    return data;
+  }
+
+  // 16 bit stereo with a chunk after the audio
+  fn wave64(samples: &[i32]) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    body.push_all(&super::WAVE_GUID);
+
+    body.push_all(&super::FMT_GUID);
+    push_le(&mut body, 24 + 16, 8);
+    push_le(&mut body, 0x0001, 2);
+    push_le(&mut body, 2, 2);
+    push_le(&mut body, 48000, 4);
+    push_le(&mut body, 48000 * 4, 4);
+    push_le(&mut body, 4, 2);
+    push_le(&mut body, 16, 2);
+
+    body.push_all(&super::DATA_GUID);
+    push_le(&mut body, 24 + 2 * samples.len() as u64, 8);
+
+    for &sample in samples.iter() {
+      push_le(&mut body, sample as u64, 2);
+    }
+
+    body.grow((8 - 2 * samples.len() % 8) % 8, 0x00);
+
+    body.push_all(b"levl\xF3\xAC\xD3\x11\x8C\xD1\x00\xC0\x4F\x8E\xDB\x8A");
+    push_le(&mut body, 24 + 3, 8);
+    body.push_all(b"xyz\x00\x00\x00\x00\x00");
+
+    let mut data = Vec::new();
+
+    data.push_all(&super::RIFF_GUID);
+    push_le(&mut data, 24 + body.len() as u64, 8);
+    data.push_all(body.as_slice());
+
+    return data;
+  }
+
+  fn read_all(data: Vec<u8>) -> (super::Input<std::io::MemReader>, Vec<i32>) {
+    let mut input = super::Input::new(std::io::MemReader::new(data)).unwrap();
+    let mut samples = Vec::new();
+
+    loop {
+      let read = input.read(1000).unwrap();
+
+      if read.is_empty() {
+        return (input, samples);
+      }
+
+      samples.push_all(read.as_slice());
+    }
+  }
+
+  #[test]
+  fn test_read_wave() {
+    let expected = noise(2 * 1001, 16, 17);
+    let (input, samples) = read_all(wave(expected.as_slice(), false));
+
+    assert_eq!(input.container, super::Riff);
+    assert_eq!((input.sample_rate, input.channels, input.bits_per_sample, input.samples), (44100, 2, 16, 1001));
+    assert_eq!(samples, expected);
+
+    let blocks: Vec<Vec<u8>> = input.metadata().into_iter().map(|(ty, data)| {
+      assert_eq!(ty, 2);
+
+      data
+    }).collect();
+
+    assert_eq!(blocks.len(), 5);
+    assert_eq!(blocks[0].slice_to(8), b"riffRIFF");
+    assert_eq!(blocks[1].as_slice(), b"riffbext\x05\x00\x00\x00hello\x00");
+    assert_eq!(blocks[2].slice_to(8), b"rifffmt ");
+    assert_eq!(blocks[3].slice_to(8), b"riffdata");
+    assert_eq!(blocks[4].as_slice(), b"riffID3 \x03\x00\x00\x00abc\x00");
+  }
+
+  #[test]
+  fn test_read_wave_extensible() {
+    let expected = noise(2 * 1000, 20, 17);
+    let (input, samples) = read_all(wave(expected.as_slice(), true));
+
+    assert_eq!((input.sample_rate, input.channels, input.bits_per_sample), (96000, 2, 20));
+    assert_eq!(samples, expected);
+  }
+
+  #[test]
+  fn test_read_aiff() {
+    let expected = noise(2 * 500, 12, 17);
+    let (input, samples) = read_all(aiff(expected.as_slice(), false));
+
+    assert_eq!(input.container, super::Aiff);
+    assert_eq!((input.sample_rate, input.channels, input.bits_per_sample), (44100, 2, 12));
+    assert_eq!(samples, expected);
+
+    let expected = noise(777, 16, 17);
+    let (input, samples) = read_all(aiff(expected.as_slice(), true));
+
+    assert_eq!((input.sample_rate, input.channels, input.bits_per_sample), (44100, 1, 16));
+    assert_eq!(samples, expected);
+  }
+
+  #[test]
+  fn test_read_wave64() {
+    let expected = noise(2 * 999, 16, 17);
+    let (input, samples) = read_all(wave64(expected.as_slice()));
+
+    assert_eq!(input.container, super::Wave64);
+    assert_eq!((input.sample_rate, input.channels, input.bits_per_sample), (48000, 2, 16));
+    assert_eq!(samples, expected);
+  }
+
+  fn round_trip(file: Vec<u8>) -> Vec<u8> {
+    let mut input = super::Input::new(std::io::MemReader::new(file)).unwrap();
+
+    let mut encoder = encoder::Encoder::with_metadata(test_util::Output::new(), input.parameters(), input.metadata()).unwrap();
+
+    loop {
+      let samples = input.read(1000).unwrap();
+
+      if samples.is_empty() {
+        break;
+      }
+
+      encoder.write(samples.as_slice()).unwrap();
+    }
+
+    let flac = encoder.finish().unwrap().unwrap();
+
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, mut source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (mut sink_md2, source_md2) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(flac, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+    });
+
+    spawn(proc() {
+      decoder::Decoder::new(source_1, source_md2, sink_a).run();
+    });
+
+    // The decoder only needs the metadata passed on, the APPLICATION blocks
+    // are kept on the way
+    let mut blocks = Vec::new();
+    let mut last = false;
+
+    while !last {
+      let mut copy = None;
+
+      source_md.read(|block| copy = Some((block.ty.clone(), block.data.clone(), block.last)));
+
+      let (ty, data, is_last) = copy.unwrap();
+
+      match ty {
+        metadata::Application(_) => blocks.push(data.clone()),
+        _ => ()
+      }
+
+      sink_md2.write(|block| {
+        block.ty = ty.clone();
+        block.data = data.clone();
+        block.last = is_last;
+      });
+
+      last = is_last;
+    }
+
+    let writer = super::Output::new(source_a, blocks, std::io::MemWriter::new()).run().unwrap();
+
+    return writer.get_ref().to_vec();
+  }
+
+  #[test]
+  fn test_round_trip_is_byte_exact() {
+    let files = vec![
+      wave(noise(2 * 5001, 16, 17).as_slice(), false),
+      wave(noise(2 * 3000, 20, 17).as_slice(), true),
+      aiff(noise(2 * 4000, 12, 17).as_slice(), false),
+      aiff(noise(4097, 16, 17).as_slice(), true),
+      wave64(noise(2 * 4999, 16, 17).as_slice())
+    ];
+
+    for file in files.into_iter() {
+      assert_eq!(round_trip(file.clone()), file);
+    }
+  }
+
+  fn find(data: &[u8], id: &[u8]) -> uint {
+    return data.windows(id.len()).position(|w| w == id).unwrap();
+  }
+
+  fn add_le(data: &mut Vec<u8>, at: uint, n: u64) {
+    let value = super::le(data.slice(at, at + 4)) + n;
+
+    for i in range(0, 4u) {
+      data.as_mut_slice()[at + i] = (value >> (8 * i)) as u8;
+    }
+  }
+
+  #[test]
+  fn test_round_trip_keeps_partial_samples() {
+    // A byte after the last whole sample frame, and the pad byte after it
+    let mut file = wave(noise(2 * 3000, 16, 18).as_slice(), false);
+    let data = find(file.as_slice(), b"data");
+    let end = data + 8 + 4 * 3000;
+
+    let rest = file.slice_from(end).to_vec();
+    file.truncate(end);
+    file.push_all(&[0xAB, 0x00]);
+    file.push_all(rest.as_slice());
+
+    add_le(&mut file, data + 4, 1);
+    add_le(&mut file, 4, 2);
+
+    let (input, _) = read_all(file.clone());
+
+    assert_eq!(input.samples, 3000);
+    assert_eq!(round_trip(file.clone()), file);
+  }
+
+  #[test]
+  fn test_round_trip_of_a_long_chunk() {
+    // Longer than one APPLICATION block can be
+    let mut file = wave(noise(2 * 1000, 16, 19).as_slice(), false);
+    let rest = file.slice_from(12).to_vec();
+
+    file.truncate(12);
+    file.push_all(b"junk");
+    push_le(&mut file, 1 << 24, 4);
+    file.grow(1 << 24, 0x55);
+    file.push_all(rest.as_slice());
+
+    add_le(&mut file, 4, 8 + (1 << 24));
+
+    let (input, _) = read_all(file.clone());
+
+    assert!(input.metadata().iter().all(|&(_, ref data)| data.len() < 1 << 24));
+    assert_eq!(round_trip(file.clone()), file);
+  }
+
+  fn error(file: Vec<u8>, limits: limits::Limits) -> reader::Error {
+    return match super::Input::with_limits(std::io::MemReader::new(file), limits) {
+      Ok(_) => panic!("Input opened"),
+      Err(error) => error
+    };
+  }
+
+  #[test]
+  fn test_chunks_are_checked() {
+    let file = wave(noise(2 * 1000, 16, 20).as_slice(), false);
+
+    // The fmt chunk is the first to read more than 10 bytes at once
+    match error(file.clone(), limits::Limits { max_metadata_block: 10, ..limits::Limits::new() }) {
+      reader::Limit(exceeded) => assert_eq!(exceeded, limits::MetadataBlock(16)),
+      reader::Io(error) => panic!("{}", error)
+    }
+
+    // A chunk, and audio, that say they're longer than the file, which
+    // nothing is allocated for
+    for &id in [b"bext", b"data"].iter() {
+      let mut long = file.clone();
+      let at = find(long.as_slice(), id) + 4;
+
+      add_le(&mut long, at, 0x7FFFFFF0);
+
+      match error(long, limits::Limits::new()) {
+        reader::Io(error) => assert_eq!(error.kind, std::io::EndOfFile),
+        reader::Limit(exceeded) => panic!("Limits::new() exceeded with {}", exceeded)
+      }
+    }
+  }
+}
@@ -1,9 +1,16 @@
-use std;
-use aurora;
+use prelude::*;
+
+#[cfg(test)]
+use bitstream::SliceReader;
+
+use crc;
+use bitstream::BitRead;
+use invalid::Invalid;
 
 const SYNC_CODE: u16 = 0b11111111111110;
 
-#[deriving(Show)]
+// Sample rate and sample size are 0 when the frame leaves them to STREAMINFO
+#[deriving(Show,PartialEq)]
 pub struct Header {
   pub variable_blocksize: bool,
   pub block_size: u32,
@@ -17,30 +24,35 @@ pub struct Header {
 
 impl Header {
 
-  pub fn from(stream: &mut aurora::stream::Bitstream) -> Header {
-    if stream.read_n(14) as u16 != SYNC_CODE {
-      panic!("Failed to sync frame");
+  pub fn from<B: BitRead>(stream: &mut B) -> Result<Header, Invalid> {
+    if try!(stream.read_n(14)) as u16 != SYNC_CODE {
+      return Err(Invalid("flac::Decoder: Frame doesn't start with a sync code"));
     }
 
-    if stream.read_n(1) != 0 {
-      panic!("Reserved bit in frame header must be 0");
+    if try!(stream.read_n(1)) != 0 {
+      return Err(Invalid("flac::Decoder: Reserved bit in frame header is set"));
     }
 
-    let variable_blocksize = stream.read_n(1) != 0;
+    let variable_blocksize = try!(stream.read_n(1)) != 0;
+
+    let block_size_code = try!(stream.read_n(4)) as u8;
 
-    let block_size_code = stream.read_n(4) as u8;
+    let sample_rate_code = try!(stream.read_n(4)) as u8;
 
-    let sample_rate_code = stream.read_n(4) as u8;
+    let channel_assignment = try!(stream.read_n(4)) as u8;
 
-    let channel_assignment = stream.read_n(4) as u8;
+    if channel_assignment > 0b1010 {
+      return Err(Invalid("flac::Decoder: Reserved channel assignment"));
+    }
 
-    let sample_size = Header::finalize_sample_size(stream.read_n(3) as u8);
+    let sample_size = try!(Header::finalize_sample_size(try!(stream.read_n(3)) as u8));
 
-    if stream.read_n(1) != 0 {
-      panic!("Reserved bit in frame header must be 0");
+    if try!(stream.read_n(1)) != 0 {
+      return Err(Invalid("flac::Decoder: Reserved bit in frame header is set"));
     }
 
-    let decoded_number = decode_sample_or_frame_number(stream);
+    // Frame numbers are 31 bits at most, which takes up to six bytes
+    let decoded_number = try!(decode_number(stream, if variable_blocksize { 7 } else { 6 }));
 
     let mut frame_number: Option<u32> = None;
     let mut sample_number: Option<u64> = None;
@@ -51,13 +63,13 @@ impl Header {
       frame_number = Some(decoded_number as u32);
     }
 
-    let block_size = Header::finalize_block_size(block_size_code, stream);
+    let block_size = try!(Header::finalize_block_size(block_size_code, stream));
 
-    let sample_rate = Header::finalize_sample_rate(sample_rate_code, stream);
+    let sample_rate = try!(Header::finalize_sample_rate(sample_rate_code, stream));
 
-    let crc = stream.read_n(8) as u8;
+    let crc = try!(stream.read_n(8)) as u8;
 
-    return Header {
+    return Ok(Header {
       variable_blocksize: variable_blocksize,
       block_size: block_size,
       sample_rate: sample_rate,
@@ -66,21 +78,21 @@ impl Header {
       sample_number: sample_number,
       frame_number: frame_number,
       crc: crc
-    };
+    });
   }
 
-  fn finalize_block_size(block_size_code: u8, stream: &mut aurora::stream::Bitstream) -> u32 {
+  fn finalize_block_size<B: BitRead>(block_size_code: u8, stream: &mut B) -> Result<u32, Invalid> {
     let n = block_size_code as uint;
 
-    return match n {
-      0b0000 => panic!("Block size 0000 is reserved"),
+    return Ok(match n {
+      0b0000 => return Err(Invalid("flac::Decoder: Reserved block size")),
       0b0001 => 192,
       0b0010 => 576 << (n - 2),
       0b0011 => 576 << (n - 2),
       0b0100 => 576 << (n - 2),
       0b0101 => 576 << (n - 2),
-      0b0110 => stream.read_n(8) + 1,
-      0b0111 => stream.read_n(16) + 1,
+      0b0110 => try!(stream.read_n(8)) + 1,
+      0b0111 => try!(stream.read_n(16)) + 1,
       0b1000 => 256 << (n - 8),
       0b1001 => 256 << (n - 8),
       0b1010 => 256 << (n - 8),
@@ -89,13 +101,13 @@ impl Header {
       0b1101 => 256 << (n - 8),
       0b1110 => 256 << (n - 8),
       0b1111 => 256 << (n - 8),
-      _ => panic!("Invalid block size")
-    };
+      _ => panic!("flac::Decoder: Undefined input?! (BUG)")
+    });
   }
 
-  fn finalize_sample_rate(sample_rate_code: u8, stream: &mut aurora::stream::Bitstream) -> u32 {
-    match sample_rate_code {
-      0b0000 => panic!("TODO: get from STREAMINFO metadata block"),
+  fn finalize_sample_rate<B: BitRead>(sample_rate_code: u8, stream: &mut B) -> Result<u32, Invalid> {
+    return Ok(match sample_rate_code {
+      0b0000 => 0,
       0b0001 => 88_200,
       0b0010 => 176_400,
       0b0011 => 192_000,
@@ -107,177 +119,208 @@ impl Header {
       0b1001 => 44_100,
       0b1010 => 48_000,
       0b1011 => 96_000,
-      0b1100 => stream.read_n(8) * 1000,
-      0b1101 => stream.read_n(16),
-      0b1110 => stream.read_n(16) * 10,
-      _ => panic!("Invalid sample rate")
-    }
+      0b1100 => try!(stream.read_n(8)) * 1000,
+      0b1101 => try!(stream.read_n(16)),
+      0b1110 => try!(stream.read_n(16)) * 10,
+      _ => return Err(Invalid("flac::Decoder: Invalid sample rate"))
+    });
   }
 
-  fn finalize_sample_size(sample_size_code: u8) -> u8 {
-    match sample_size_code {
-      0b000 => panic!("TODO: get from STREAMINFO metadata block"),
+  fn finalize_sample_size(sample_size_code: u8) -> Result<u8, Invalid> {
+    return Ok(match sample_size_code {
+      0b000 => 0,
       0b001 => 8,
       0b010 => 12,
-      0b011 => panic!("flac::Decoder: Reserved sample size (INPUT)"),
+      0b011 => return Err(Invalid("flac::Decoder: Reserved sample size")),
       0b100 => 16,
       0b101 => 20,
       0b110 => 24,
-      0b111 => panic!("flac::Decoder: Reserved sample size (INPUT)"),
+      0b111 => return Err(Invalid("flac::Decoder: Reserved sample size")),
       _ => panic!("flac::Decoder: Undefined input?! (BUG)")
-    }
+    });
   }
 
 }
 
 // See http://en.wikipedia.org/wiki/UTF-8
-fn decode_sample_or_frame_number(stream: &mut aurora::stream::Bitstream) -> u64 {
+#[cfg(test)]
+fn decode_sample_or_frame_number<B: BitRead>(stream: &mut B) -> Result<u64, Invalid> {
+  return decode_number(stream, 7);
+}
+
+static INVALID_NUMBER: Invalid = Invalid("flac::Decoder: Invalid sample or frame number");
+
+// Decodes a number of up to longest bytes
+fn decode_number<B: BitRead>(stream: &mut B, longest: uint) -> Result<u64, Invalid> {
   let mut total_bytes = 0;
 
-  while stream.read_n(1) == 1 {
+  while total_bytes < 8 && try!(stream.read_n(1)) == 1 {
     total_bytes += 1;
   }
 
-  let mut decoded = stream.read_n(7 - total_bytes) as u64;
+  // One leading bit is a continuation byte
+  if total_bytes == 1 || total_bytes > longest {
+    return Err(INVALID_NUMBER);
+  }
+
+  let mut decoded = try!(stream.read_n(7 - total_bytes)) as u64;
 
   for _ in range(1, total_bytes) {
-    assert_eq!(stream.read_n(2), 0b10u32);
-    decoded = (decoded << 6) + stream.read_n(6) as u64;
+    if try!(stream.read_n(2)) != 0b10 {
+      return Err(INVALID_NUMBER);
+    }
+
+    decoded = (decoded << 6) + try!(stream.read_n(6)) as u64;
   }
 
-  return decoded;
+  return Ok(decoded);
 }
 
-#[test]
-fn test_utf8_decoding_of_one_byte() {
-  let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
+// Length of the frame header at the start of data, if there is a valid one,
+// needs MAX_LENGTH bytes to be sure about it
+pub fn check(data: &[u8]) -> Option<uint> {
+  if data.len() < 6 || data[0] != 0xFF || data[1] & 0xFE != 0xF8 {
+    return None;
+  }
 
-  spawn(proc() {
-    let buffer = vec![0b00100100];
-    aurora::buffer::Buffer::new(buffer, 4096, sink_0).run();
-  });
+  let block_size_code = data[2] >> 4;
+  let sample_rate_code = data[2] & 0x0F;
+  let channel_assignment = data[3] >> 4;
+  let sample_size_code = (data[3] >> 1) & 0x07;
 
-  let mut stream = aurora::stream::Stream::new(&mut source_0);
-  let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+  if block_size_code == 0 || sample_rate_code == 0b1111 || channel_assignment > 0b1010 || sample_size_code == 0b011 || data[3] & 0x01 != 0 {
+    return None;
+  }
 
-  let decoded = decode_sample_or_frame_number(&mut bitstream);
+  let number_length = match data[4] {
+    0x00...0x7F => 1,
+    0xC0...0xDF => 2,
+    0xE0...0xEF => 3,
+    0xF0...0xF7 => 4,
+    0xF8...0xFB => 5,
+    0xFC...0xFD => 6,
+    0xFE if data[1] & 0x01 != 0 => 7,
+    _ => return None
+  };
+
+  let mut length = 4 + number_length;
+
+  length += match block_size_code {
+    0b0110 => 1,
+    0b0111 => 2,
+    _ => 0
+  };
+
+  length += match sample_rate_code {
+    0b1100 => 1,
+    0b1101 | 0b1110 => 2,
+    _ => 0
+  };
+
+  if data.len() <= length {
+    return None;
+  }
 
-  assert_eq!(decoded, 0b0100100);
-}
+  if data.slice(5, 4 + number_length).iter().any(|&b| b & 0xC0 != 0x80) {
+    return None;
+  }
 
-#[test]
-fn test_utf8_decoding_of_four_bytes() {
-  let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
+  if crc::crc8(data.slice_to(length)) != data[length] {
+    return None;
+  }
 
-  spawn(proc() {
-    let buffer = vec![0b11110000, 0b10100100, 0b10101101, 0b10100010];
-    aurora::buffer::Buffer::new(buffer, 4096, sink_0).run();
-  });
+  return Some(length + 1);
+}
 
-  let mut stream = aurora::stream::Stream::new(&mut source_0);
-  let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+pub const MAX_LENGTH: uint = 16;
 
-  let decoded = decode_sample_or_frame_number(&mut bitstream);
+#[test]
+fn test_utf8_decoding_of_one_byte() {
+  let decoded = decode_sample_or_frame_number(&mut SliceReader::new(&[0b00100100]));
 
-  assert_eq!(decoded, 0b000100100101101100010);
+  assert_eq!(decoded, Ok(0b0100100));
 }
 
 #[test]
-fn test_header_from_1() {
-  let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
-
-  spawn(proc() {
-    let path = std::path::Path::new("./test-vectors/frames/bad_apple.1");
-    let file = std::io::File::open(&path).unwrap();
-
-    aurora::file::Input::new(file, 4096, sink_0).run();
-  });
-
-  let mut stream = aurora::stream::Stream::new(&mut source_0);
-  let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
-
-  let header = Header::from(&mut bitstream);
+fn test_utf8_decoding_of_four_bytes() {
+  let decoded = decode_sample_or_frame_number(&mut SliceReader::new(&[0b11110000, 0b10100100, 0b10101101, 0b10100010]));
 
-  assert_eq!(header.variable_blocksize, false);
-  assert_eq!(header.block_size, 4096);
-  assert_eq!(header.sample_rate, 44100);
-  assert_eq!(header.channel_assignment, 1);
-  assert_eq!(header.sample_size, 16);
-  assert_eq!(header.frame_number, Some(0));
-  assert_eq!(header.crc, 0xC2);
+  assert_eq!(decoded, Ok(0b000100100101101100010));
 }
 
 #[test]
-fn test_header_from_2() {
-  let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
-
-  spawn(proc() {
-    let path = std::path::Path::new("./test-vectors/frames/bad_apple.2");
-    let file = std::io::File::open(&path).unwrap();
+fn test_utf8_decoding_of_eight_leading_ones() {
+  let decoded = decode_sample_or_frame_number(&mut SliceReader::new(&[0xFF, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80]));
 
-    aurora::file::Input::new(file, 4096, sink_0).run();
-  });
-
-  let mut stream = aurora::stream::Stream::new(&mut source_0);
-  let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+  assert_eq!(decoded, Err(INVALID_NUMBER));
+}
 
-  let header = Header::from(&mut bitstream);
+#[test]
+fn test_utf8_decoding_of_a_continuation_byte() {
+  let decoded = decode_sample_or_frame_number(&mut SliceReader::new(&[0x80, 0x00]));
 
-  assert_eq!(header.variable_blocksize, false);
-  assert_eq!(header.block_size, 4096);
-  assert_eq!(header.sample_rate, 44100);
-  assert_eq!(header.channel_assignment, 1);
-  assert_eq!(header.sample_size, 16);
-  assert_eq!(header.frame_number, Some(1));
-  assert_eq!(header.crc, 0xC5);
+  assert_eq!(decoded, Err(INVALID_NUMBER));
 }
 
 #[test]
-fn test_header_from_3() {
-  let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
+fn test_frame_number_of_seven_bytes() {
+  let data = [0xFF, 0xF8, 0x19, 0x08, 0xFE, 0x80, 0x80, 0x80, 0x80, 0x80, 0x81, 0x00];
 
-  spawn(proc() {
-    let path = std::path::Path::new("./test-vectors/frames/bad_apple.3");
-    let file = std::io::File::open(&path).unwrap();
+  assert_eq!(check(&data), None);
+  assert_eq!(Header::from(&mut SliceReader::new(&data)), Err(INVALID_NUMBER));
+}
 
-    aurora::file::Input::new(file, 4096, sink_0).run();
-  });
+#[test]
+fn test_reserved_codes() {
+  // The first header of Bad Apple with a reserved channel assignment, sample
+  // size, bit, block size, sample rate and bit in turn
+  for &(i, bits) in [(3u, 0xF0u8), (3, 0x06), (3, 0x01), (2, 0xC0), (2, 0x06), (1, 0x02)].iter() {
+    let mut data = BAD_APPLE[0];
+    data[i] ^= bits;
+
+    assert_eq!(check(data.as_slice()), None);
+    assert!(Header::from(&mut SliceReader::new(data.as_slice())).is_err());
+  }
 
-  let mut stream = aurora::stream::Stream::new(&mut source_0);
-  let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+  // Cut off
+  assert!(Header::from(&mut SliceReader::new(BAD_APPLE[0].slice_to(4))).is_err());
+}
 
-  let header = Header::from(&mut bitstream);
+// The headers of the first frames of Bad Apple, and of the first frame stored
+// verbatim
+#[cfg(test)]
+static BAD_APPLE: [[u8, ..6], ..4] = [
+  [0xFF, 0xF8, 0xC9, 0x18, 0x00, 0xC2],
+  [0xFF, 0xF8, 0xC9, 0x18, 0x01, 0xC5],
+  [0xFF, 0xF8, 0xC9, 0x18, 0x02, 0xCC],
+  [0xFF, 0xF8, 0x39, 0x18, 0x00, 0xAE]
+];
 
-  assert_eq!(header.variable_blocksize, false);
-  assert_eq!(header.block_size, 4096);
-  assert_eq!(header.sample_rate, 44100);
-  assert_eq!(header.channel_assignment, 1);
-  assert_eq!(header.sample_size, 16);
-  assert_eq!(header.frame_number, Some(2));
-  assert_eq!(header.crc, 0xCC);
+#[test]
+fn test_header_from() {
+  for (i, data) in BAD_APPLE.iter().enumerate() {
+    let header = Header::from(&mut SliceReader::new(data.as_slice())).unwrap();
+    let verbatim = i == 3;
+
+    assert_eq!(header.variable_blocksize, false);
+    assert_eq!(header.block_size, if verbatim { 1152 } else { 4096 });
+    assert_eq!(header.sample_rate, 44100);
+    assert_eq!(header.channel_assignment, 1);
+    assert_eq!(header.sample_size, 16);
+    assert_eq!(header.frame_number, Some(if verbatim { 0 } else { i as u32 }));
+    assert_eq!(header.crc, data[5]);
+  }
 }
 
 #[test]
-fn test_header_from_bad_apple_verbatim_1() {
-  let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
+fn test_check() {
+  for data in BAD_APPLE.iter() {
+    assert_eq!(check(data.as_slice()), Some(6));
+    assert_eq!(check(data.slice_from(1)), None);
 
-  spawn(proc() {
-    let path = std::path::Path::new("./test-vectors/frames/bad_apple_verbatim.1");
-    let file = std::io::File::open(&path).unwrap();
+    let mut corrupt = *data;
+    corrupt[4] ^= 0x01;
 
-    aurora::file::Input::new(file, 4096, sink_0).run();
-  });
-
-  let mut stream = aurora::stream::Stream::new(&mut source_0);
-  let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
-
-  let header = Header::from(&mut bitstream);
-
-  assert_eq!(header.variable_blocksize, false);
-  assert_eq!(header.block_size, 1152);
-  assert_eq!(header.sample_rate, 44100);
-  assert_eq!(header.channel_assignment, 1);
-  assert_eq!(header.sample_size, 16);
-  assert_eq!(header.frame_number, Some(0));
-  assert_eq!(header.crc, 0xAE);
-}
\ No newline at end of file
+    assert_eq!(check(corrupt.as_slice()), None);
+  }
+}
@@ -1,57 +1,435 @@
-use std;
-use std::mem;
+use prelude::*;
 
+use std::cmp;
+
+#[cfg(feature = "std")]
 use aurora;
 
+use bitstream::{BitRead, Counter, SliceReader};
+use crc;
+use invalid::Invalid;
+use metadata::stream_info::StreamInfo;
+
 pub mod header;
 
-fn extend_sign_bits(value: u32, n: u8) -> i32 {
-  let shift = 32 - n;
+// Of a channel assignment that Header::from accepted, which rejects the
+// reserved ones
+pub fn channels(channel_assignment: u8) -> uint {
+  return match channel_assignment {
+    0b0000...0b0111 => channel_assignment as uint + 1,
+    0b1000...0b1010 => 2,
+    _ => panic!("flac::Decoder: Reserved channel assignment {} (BUG)", channel_assignment)
+  };
+}
+
+// Largest frame there can be of block size samples in each channel. Encoders
+// fall back to verbatim subframes, with side channels one bit wider.
+pub fn max_size(block_size: uint, channels: uint, bits: u8) -> uint {
+  let bits = bits as uint + 1;
 
-  return (value << shift as uint) as i32 >> (shift as uint);
+  return header::MAX_LENGTH + channels * (1 + (block_size * bits + 7) / 8) + 2;
 }
 
-pub fn read(bitstream: &mut aurora::stream::Bitstream, audio: &mut aurora::Audio) -> uint {
-  let header = header::Header::from(bitstream);
+// Start of the frame after the one at the start of data, looking from from on.
+// A header with a valid CRC-8 can still turn up inside audio data, so the
+// CRC-16 of the frame before it has to match as well. Otherwise it's where to
+// look from once more data has come in.
+pub fn find_next(data: &[u8], from: uint, finished: bool) -> Result<uint, uint> {
+  let mut i = cmp::max(from, 1);
 
-  let channels = match header.channel_assignment {
-    1 => 2,
-    _ => panic!("Not implemented")
-  };
+  while i < data.len() {
+    if !finished && i + header::MAX_LENGTH > data.len() {
+      break;
+    }
+
+    if data[i] == 0xFF && header::check(data.slice_from(i)).is_some() && crc::crc16(data.slice_to(i)) == 0 {
+      return Ok(i);
+    }
 
-  let mut subframes = Vec::new();
-  for _ in range(0, channels) {
-    subframes.push(super::subframe::read(&header, bitstream))
+    i += 1;
   }
 
-  match header.channel_assignment {
-    1 => {
-      audio.channels = channels;
-      audio.sample_rate = header.sample_rate as f64;
-      audio.endian = aurora::endian::Big;
-      audio.sample_type = aurora::sample_type::Signed(header.sample_size as uint);
+  return Err(i);
+}
 
-      let bytes_per_sample = header.sample_size as uint / 8;
+// Whether data, a frame without a matching CRC-16, is one the input ended in
+// the middle of. Only the last data can be, and only if no other frame starts
+// in it, anything else is corrupt.
+pub fn is_cut_off(data: &[u8], last: bool) -> bool {
+  return last && range(1, data.len()).all(|i| data[i] != 0xFF || header::check(data.slice_from(i)).is_none());
+}
 
-      audio.data.grow(bytes_per_sample * channels * header.block_size as uint, 0);
+// Side channels carry one extra bit of precision
+pub fn bits_per_sample(header: &header::Header, channel: uint) -> u8 {
+  return match (header.channel_assignment, channel) {
+    (0b1000, 1) | (0b1001, 0) | (0b1010, 1) => header.sample_size + 1,
+    _ => header.sample_size
+  };
+}
 
-      for s in range(0, header.block_size as uint) {
-        for c in range(0, channels) {
-          let sample = unsafe { mem::transmute::<i32, [u8, ..4]>(extend_sign_bits(subframes[c][s], header.sample_size).to_be()) };
+fn decorrelate(channel_assignment: u8, block_size: uint, subframes: &mut [Vec<i32>]) {
+  if channel_assignment < 0b1000 {
+    return;
+  }
 
-          let index = bytes_per_sample * (s * channels + c);
+  let (firsts, seconds) = subframes.split_at_mut(1);
 
-          let input = sample.slice(4 - bytes_per_sample, 4);
-          let output =  audio.data.slice_mut(index, index + bytes_per_sample);
+  let first = firsts[0].slice_to_mut(block_size);
+  let second = seconds[0].slice_to_mut(block_size);
 
-          std::slice::bytes::copy_memory(output, input);
-        }
+  match channel_assignment {
+    0b1000 => {
+      for (left, side) in first.iter().zip(second.iter_mut()) {
+        *side = *left - *side;
+      }
+    },
+    0b1001 => {
+      for (side, right) in first.iter_mut().zip(second.iter()) {
+        *side = *side + *right;
       }
     },
-    _ => panic!("Not implemented")
+    _ => {
+      for (mid, side) in first.iter_mut().zip(second.iter_mut()) {
+        let m = (*mid << 1) | (*side & 1);
+        let s = *side;
+
+        *mid = (m + s) >> 1;
+        *side = (m - s) >> 1;
+      }
+    }
+  }
+}
+
+fn read_header<B: BitRead>(counter: &mut Counter<B>, stream_info: Option<&StreamInfo>) -> Result<header::Header, Invalid> {
+  let mut header = try!(header::Header::from(counter));
+
+  // The CRC of everything up to and including the CRC itself comes out 0
+  if counter.crc8() != 0 {
+    return Err(Invalid("flac::Decoder: Frame header CRC-8 doesn't match"));
+  }
+
+  if header.sample_rate == 0 || header.sample_size == 0 {
+    let stream_info = match stream_info {
+      Some(stream_info) => stream_info,
+      None => return Err(Invalid("flac::Decoder: Frame takes its sample rate or size from STREAMINFO, but there is none"))
+    };
+
+    if header.sample_rate == 0 {
+      header.sample_rate = stream_info.sample_rate;
+    }
+
+    if header.sample_size == 0 {
+      header.sample_size = stream_info.bits_per_sample;
+    }
+  }
+
+  return Ok(header);
+}
+
+// Reads the subframes, the padding and the CRC-16 of a frame after its header
+fn read_body<B: BitRead>(header: &header::Header, counter: &mut Counter<B>, subframes: &mut [Vec<i32>]) -> Result<(), Invalid> {
+  for c in range(0, channels(header.channel_assignment)) {
+    let bits = bits_per_sample(header, c);
+
+    if bits > 32 {
+      return Err(Invalid("flac::Decoder: Side channels of 32 bit streams are not supported"));
+    }
+
+    try!(super::subframe::read_into(header, bits, counter, subframes[c].as_mut_slice()));
+  }
+
+  decorrelate(header.channel_assignment, header.block_size as uint, subframes);
+
+  try!(counter.align());
+  try!(counter.read_n(16));
+
+  if counter.crc16() != 0 {
+    return Err(Invalid("flac::Decoder: Frame CRC-16 doesn't match"));
+  }
+
+  return Ok(());
+}
+
+pub fn decode<B: BitRead>(bitstream: &mut B, stream_info: Option<&StreamInfo>) -> Result<(header::Header, Vec<Vec<i32>>), Invalid> {
+  let mut counter = Counter::new(bitstream);
+
+  let header = try!(read_header(&mut counter, stream_info));
+
+  let block_size = header.block_size as uint;
+  let mut subframes = Vec::from_fn(channels(header.channel_assignment), |_| Vec::from_elem(block_size, 0i32));
+
+  try!(read_body(&header, &mut counter, subframes.as_mut_slice()));
+
+  return Ok((header, subframes));
+}
+
+// Same as decode, into buffers that have room for a frame's channels and
+// samples. Only the first block size samples of each are written.
+pub fn decode_into<B: BitRead>(bitstream: &mut B, stream_info: Option<&StreamInfo>, subframes: &mut [Vec<i32>]) -> Result<header::Header, Invalid> {
+  let mut counter = Counter::new(bitstream);
+
+  return read_into(&mut counter, stream_info, subframes);
+}
+
+// Decodes the frame at the start of data into buffers like decode_into, and
+// returns its header and length in bytes. Fails if data ends before the
+// frame does.
+pub fn decode_slice(data: &[u8], stream_info: Option<&StreamInfo>, subframes: &mut [Vec<i32>]) -> Result<(header::Header, uint), Invalid> {
+  let mut reader = SliceReader::new(data);
+  let mut counter = Counter::new(&mut reader);
+
+  let header = try!(read_into(&mut counter, stream_info, subframes));
+
+  return Ok((header, (counter.bits() / 8) as uint));
+}
+
+fn read_into<B: BitRead>(counter: &mut Counter<B>, stream_info: Option<&StreamInfo>, subframes: &mut [Vec<i32>]) -> Result<header::Header, Invalid> {
+  let header = try!(read_header(counter, stream_info));
+
+  let channels = channels(header.channel_assignment);
+
+  if subframes.len() < channels || subframes.iter().take(channels).any(|s| s.len() < header.block_size as uint) {
+    return Err(Invalid("flac::Decoder: Frame has more channels or samples than the buffers have room for"));
   }
 
-  let _ = bitstream.read_n(16); // CRC
+  try!(read_body(&header, counter, subframes));
+
+  return Ok(header);
+}
+
+#[cfg(feature = "std")]
+pub fn read<B: BitRead>(bitstream: &mut B, stream_info: Option<&StreamInfo>, audio: &mut aurora::Audio) -> uint {
+  let (header, subframes) = valid!(decode(bitstream, stream_info));
+
+  fill(&header, subframes.as_slice(), audio);
 
   return header.block_size as uint;
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "std")]
+pub fn fill(header: &header::Header, subframes: &[Vec<i32>], audio: &mut aurora::Audio) {
+  let channels = subframes.len();
+
+  audio.channels = channels;
+  audio.sample_rate = header.sample_rate as f64;
+  audio.endian = aurora::endian::Big;
+  audio.sample_type = aurora::sample_type::Signed(header.sample_size as uint);
+
+  let bytes_per_sample = (header.sample_size as uint + 7) / 8;
+
+  audio.data.grow(bytes_per_sample * channels * header.block_size as uint, 0);
+
+  for s in range(0, header.block_size as uint) {
+    for c in range(0, channels) {
+      let sample = subframes[c][s];
+      let index = bytes_per_sample * (s * channels + c);
+
+      for b in range(0, bytes_per_sample) {
+        audio.data.as_mut_slice()[index + b] = (sample >> (8 * (bytes_per_sample - 1 - b))) as u8;
+      }
+    }
+  }
+}
+
+// Types that decoded samples can be written out as, given their bit depth
+pub trait Sample {
+  fn from_sample(sample: i32, bits: u8) -> Self;
+}
+
+impl Sample for i32 {
+  fn from_sample(sample: i32, _: u8) -> i32 {
+    return sample;
+  }
+}
+
+impl Sample for i16 {
+  fn from_sample(sample: i32, bits: u8) -> i16 {
+    if bits > 16 {
+      panic!("flac::Decoder: {} bit samples don't fit in an i16 (INPUT)", bits);
+    }
+
+    return sample as i16;
+  }
+}
+
+// Normalized to [-1, 1) by the bit depth
+impl Sample for f32 {
+  fn from_sample(sample: i32, bits: u8) -> f32 {
+    return sample as f32 / (1u64 << (bits as uint - 1)) as f32;
+  }
+}
+
+// Writes the samples of a frame to a buffer for each channel, returns the
+// number of samples in each
+pub fn planar<S: Sample>(header: &header::Header, subframes: &[Vec<i32>], buffers: &mut [&mut [S]]) -> uint {
+  let block_size = header.block_size as uint;
+
+  if buffers.len() < subframes.len() || buffers.iter().any(|b| b.len() < block_size) {
+    panic!("flac::Decoder: Buffers need room for {} channels of {} samples (INPUT)", subframes.len(), block_size);
+  }
+
+  for (buffer, subframe) in buffers.iter_mut().zip(subframes.iter()) {
+    for (output, &sample) in buffer.iter_mut().zip(subframe.slice_to(block_size).iter()) {
+      *output = Sample::from_sample(sample, header.sample_size);
+    }
+  }
+
+  return block_size;
+}
+
+// Writes the samples of a frame interleaved, returns how many it wrote
+pub fn interleaved<S: Sample>(header: &header::Header, subframes: &[Vec<i32>], output: &mut [S]) -> uint {
+  let channels = subframes.len();
+  let length = channels * header.block_size as uint;
+
+  if output.len() < length {
+    panic!("flac::Decoder: Output needs room for {} samples (INPUT)", length);
+  }
+
+  for s in range(0, header.block_size as uint) {
+    for c in range(0, channels) {
+      output[s * channels + c] = Sample::from_sample(subframes[c][s], header.sample_size);
+    }
+  }
+
+  return length;
+}
+
+#[cfg(test)]
+pub mod tests {
+  use bitstream::SliceReader;
+  use crc;
+  use invalid::Invalid;
+
+  use super::header::Header;
+
+  // The first frame of Bad Apple, and the same stored verbatim, which only has
+  // its first 1152 samples
+  static BAD_APPLE_1: &'static [u8] = include_bin!("../../test-vectors/frames/bad_apple.1");
+  static BAD_APPLE_VERBATIM_1: &'static [u8] = include_bin!("../../test-vectors/frames/bad_apple_verbatim.1");
+
+  // Sets byte i of the frame at the start of data, which is followed by
+  // another, and makes both of its CRCs match again
+  pub fn rewrite(data: &mut [u8], i: uint, value: u8) {
+    let length = super::header::check(data).unwrap();
+    let end = super::find_next(data, 1, true).unwrap();
+
+    data[i] = value;
+    data[length - 1] = crc::crc8(data.slice_to(length - 1));
+
+    let crc = crc::crc16(data.slice_to(end - 2));
+
+    data[end - 2] = (crc >> 8) as u8;
+    data[end - 1] = crc as u8;
+  }
+
+  fn frame(sample_size: u8) -> (Header, Vec<Vec<i32>>) {
+    let header = Header {
+      variable_blocksize: false, block_size: 3, sample_rate: 44100, channel_assignment: 1,
+      sample_size: sample_size, sample_number: None, frame_number: Some(0), crc: 0
+    };
+
+    let top = 1 << (sample_size as uint - 1);
+
+    return (header, vec![vec![0, -top, top - 1], vec![1, -1, top / 2]]);
+  }
+
+  #[test]
+  fn test_planar() {
+    let (header, subframes) = frame(24);
+
+    let mut left = [0i32, ..4];
+    let mut right = [0i32, ..3];
+
+    assert_eq!(super::planar(&header, subframes.as_slice(), &mut [left.as_mut_slice(), right.as_mut_slice()]), 3);
+    assert_eq!(left.as_slice(), [0, -8388608, 8388607, 0].as_slice());
+    assert_eq!(right.as_slice(), [1, -1, 4194304].as_slice());
+  }
+
+  #[test]
+  fn test_interleaved() {
+    let (header, subframes) = frame(16);
+
+    let mut i16s = [0i16, ..6];
+    let mut i32s = [0i32, ..6];
+    let mut f32s = [0f32, ..6];
+
+    assert_eq!(super::interleaved(&header, subframes.as_slice(), i16s.as_mut_slice()), 6);
+    assert_eq!(super::interleaved(&header, subframes.as_slice(), i32s.as_mut_slice()), 6);
+    assert_eq!(super::interleaved(&header, subframes.as_slice(), f32s.as_mut_slice()), 6);
+
+    assert_eq!(i16s.as_slice(), [0, 1, -32768, -1, 32767, 16384].as_slice());
+    assert_eq!(i32s.as_slice(), [0, 1, -32768, -1, 32767, 16384].as_slice());
+    assert_eq!(f32s.as_slice(), [0.0, 1.0 / 32768.0, -1.0, -1.0 / 32768.0, 32767.0 / 32768.0, 0.5].as_slice());
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_i16_needs_16_bits() {
+    let (header, subframes) = frame(20);
+
+    let mut output = [0i16, ..6];
+
+    super::interleaved(&header, subframes.as_slice(), output.as_mut_slice());
+  }
+
+  #[test]
+  fn test_decode_slice() {
+    let mut data = BAD_APPLE_1.to_vec();
+    data.push_all(BAD_APPLE_VERBATIM_1);
+
+    let mut subframes = vec![Vec::from_elem(4096, 0i32), Vec::from_elem(4096, 0i32)];
+
+    let (header, n) = super::decode_slice(data.as_slice(), None, subframes.as_mut_slice()).unwrap();
+    let (expected_header, expected) = super::decode(&mut SliceReader::new(data.as_slice()), None).unwrap();
+
+    assert_eq!(header, expected_header);
+    assert_eq!(subframes, expected);
+    assert_eq!(n, BAD_APPLE_1.len());
+
+    let first = subframes[0].slice_to(1152).to_vec();
+    let (header, m) = super::decode_slice(data.slice_from(n), None, subframes.as_mut_slice()).unwrap();
+
+    assert_eq!(header.block_size, 1152);
+    assert_eq!(n + m, data.len());
+    assert_eq!(subframes[0].slice_to(1152), first.as_slice());
+  }
+
+  fn corrupt(header: bool) -> Result<(), Invalid> {
+    let mut frame = BAD_APPLE_1.to_vec();
+
+    // Flipping a bit of either CRC changes nothing else about how it reads
+    let i = if header { super::header::check(frame.as_slice()).unwrap() - 1 } else { frame.len() - 1 };
+    frame.as_mut_slice()[i] ^= 0x01;
+
+    return super::decode(&mut SliceReader::new(frame.as_slice()), None).map(|_| ());
+  }
+
+  #[test]
+  fn test_header_crc_mismatch() {
+    assert_eq!(corrupt(true), Err(Invalid("flac::Decoder: Frame header CRC-8 doesn't match")));
+  }
+
+  #[test]
+  fn test_frame_crc_mismatch() {
+    assert_eq!(corrupt(false), Err(Invalid("flac::Decoder: Frame CRC-16 doesn't match")));
+  }
+
+  #[test]
+  fn test_cut_off_and_bad_content() {
+    let mut subframes = vec![Vec::from_elem(4096, 0i32), Vec::from_elem(4096, 0i32)];
+
+    assert!(super::decode_slice(BAD_APPLE_1.slice_to(BAD_APPLE_1.len() / 2), None, subframes.as_mut_slice()).is_err());
+    assert!(super::decode_slice(BAD_APPLE_1, None, subframes.slice_to_mut(1)).is_err());
+
+    // A reserved subframe type in place of the first one, under CRCs that
+    // match
+    let mut frame = BAD_APPLE_1.to_vec();
+    frame.push_all(BAD_APPLE_1);
+
+    let i = super::header::check(frame.as_slice()).unwrap();
+    rewrite(frame.as_mut_slice(), i, 0x02 << 1);
+
+    assert_eq!(super::decode(&mut SliceReader::new(frame.as_slice()), None).err(), Some(Invalid("flac::Decoder: Subframe type is reserved")));
+  }
+}
@@ -0,0 +1,23 @@
+// What the parsers return for input they can't read, instead of panicking, so
+// a stream that can't be trusted can't take its caller down with it. Decoders
+// that have no other way to fail, like aurora tasks, panic with the message
+// and (INPUT) through valid!.
+
+use std::fmt;
+
+#[deriving(PartialEq,Clone)]
+pub struct Invalid(pub &'static str);
+
+impl Invalid {
+  pub fn desc(&self) -> &'static str {
+    let Invalid(desc) = *self;
+
+    return desc;
+  }
+}
+
+impl fmt::Show for Invalid {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    return write!(f, "{}", self.desc());
+  }
+}
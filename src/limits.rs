@@ -0,0 +1,135 @@
+// Bounds on what a stream may make the decoder allocate, for input that can't
+// be trusted. Lengths are checked as soon as they're read, before anything is
+// allocated for them.
+
+use prelude::*;
+
+use std::cmp;
+
+#[cfg(feature = "std")]
+use std::uint;
+#[cfg(not(feature = "std"))]
+use core::uint;
+
+use metadata::stream_info::StreamInfo;
+
+// Metadata block type of pictures
+const PICTURE: u8 = 6;
+
+#[deriving(Show,PartialEq,Clone)]
+pub struct Limits {
+  pub max_metadata_block: uint, // Bytes in one block or ID3v2 tag
+  pub max_metadata: uint,       // Bytes in all of them together
+  pub max_picture: uint,        // Bytes in one PICTURE block
+  pub max_channels: u8,
+  pub max_block_size: u32
+}
+
+// Which limit was exceeded, and by how much
+#[deriving(Show,PartialEq,Clone)]
+pub enum Exceeded {
+  MetadataBlock(uint),
+  Metadata(uint),
+  Picture(uint),
+  Channels(u8),
+  BlockSize(u32)
+}
+
+impl Limits {
+  // As much as the format allows, which is no limit at all
+  pub fn new() -> Limits {
+    return Limits {
+      max_metadata_block: uint::MAX,
+      max_metadata: uint::MAX,
+      max_picture: uint::MAX,
+      max_channels: 8,
+      max_block_size: 65536
+    };
+  }
+
+  // A metadata block of the type and length, after total bytes of metadata
+  // before it
+  pub fn check_block(&self, ty: u8, length: uint, total: uint) -> Result<(), Exceeded> {
+    if ty == PICTURE && length > self.max_picture {
+      return Err(Picture(length));
+    }
+
+    return self.check_metadata(length, total);
+  }
+
+  // Metadata that isn't a FLAC block, like an ID3v2 tag
+  pub fn check_metadata(&self, length: uint, total: uint) -> Result<(), Exceeded> {
+    if length > self.max_metadata_block {
+      return Err(MetadataBlock(length));
+    }
+
+    if length > self.max_metadata - cmp::min(total, self.max_metadata) {
+      return Err(Metadata(if total > uint::MAX - length { uint::MAX } else { total + length }));
+    }
+
+    return Ok(());
+  }
+
+  pub fn check_frame(&self, channels: u8, block_size: u32) -> Result<(), Exceeded> {
+    if channels > self.max_channels {
+      return Err(Channels(channels));
+    }
+
+    if block_size > self.max_block_size {
+      return Err(BlockSize(block_size));
+    }
+
+    return Ok(());
+  }
+
+  // STREAMINFO bounds every frame after it
+  pub fn check_stream_info(&self, stream_info: &StreamInfo) -> Result<(), Exceeded> {
+    let (_, max_block_size) = stream_info.block_size;
+
+    return self.check_frame(stream_info.channels, max_block_size as u32);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::uint;
+
+  use metadata::stream_info::{StreamInfo, MD5};
+
+  fn limits() -> super::Limits {
+    return super::Limits { max_metadata_block: 1000, max_metadata: 1500, max_picture: 500, max_channels: 2, max_block_size: 4096 };
+  }
+
+  #[test]
+  fn test_metadata() {
+    let limits = limits();
+
+    assert_eq!(limits.check_block(4, 1000, 0), Ok(()));
+    assert_eq!(limits.check_block(4, 1001, 0), Err(super::MetadataBlock(1001)));
+    assert_eq!(limits.check_block(4, 600, 900), Ok(()));
+    assert_eq!(limits.check_block(4, 601, 900), Err(super::Metadata(1501)));
+    assert_eq!(limits.check_block(6, 501, 0), Err(super::Picture(501)));
+    assert_eq!(limits.check_metadata(1, uint::MAX), Err(super::Metadata(uint::MAX)));
+  }
+
+  #[test]
+  fn test_stream_info() {
+    let limits = limits();
+
+    let mut stream_info = StreamInfo {
+      block_size: (4096, 4096), frame_size: (0, 0), sample_rate: 44100, channels: 2,
+      bits_per_sample: 16, samples: 0, signature: MD5([0x00, ..16])
+    };
+
+    assert_eq!(limits.check_stream_info(&stream_info), Ok(()));
+
+    stream_info.channels = 3;
+    assert_eq!(limits.check_stream_info(&stream_info), Err(super::Channels(3)));
+
+    stream_info.channels = 2;
+    stream_info.block_size = (4096, 8192);
+    assert_eq!(limits.check_stream_info(&stream_info), Err(super::BlockSize(8192)));
+
+    assert_eq!(super::Limits::new().check_frame(8, 65536), Ok(()));
+  }
+}
@@ -0,0 +1,933 @@
+use std::cmp;
+use std::io::{IoResult, Seek, SeekSet, SeekCur, SeekEnd, EndOfFile};
+
+use aurora;
+
+use limits::{Limits, Exceeded};
+use metadata;
+use metadata::stream_info;
+
+// Demuxes the first A_FLAC track of a Matroska or WebM file. CodecPrivate has
+// "fLaC" and the metadata blocks, and every frame of a SimpleBlock or Block is
+// one FLAC frame. Like the MP4 demuxer it reads all of the input first.
+
+static EBML: u64 = 0x1A45DFA3;
+static DOC_TYPE: u64 = 0x4282;
+static SEGMENT: u64 = 0x18538067;
+static SEEK_HEAD: u64 = 0x114D9B74;
+static SEEK: u64 = 0x4DBB;
+static SEEK_ID: u64 = 0x53AB;
+static SEEK_POSITION: u64 = 0x53AC;
+static INFO: u64 = 0x1549A966;
+static TIMECODE_SCALE: u64 = 0x2AD7B1;
+static TRACKS: u64 = 0x1654AE6B;
+static TRACK_ENTRY: u64 = 0xAE;
+static TRACK_NUMBER: u64 = 0xD7;
+static CODEC_ID: u64 = 0x86;
+static CODEC_PRIVATE: u64 = 0x63A2;
+static CLUSTER: u64 = 0x1F43B675;
+static SIMPLE_BLOCK: u64 = 0xA3;
+static BLOCK_GROUP: u64 = 0xA0;
+static BLOCK: u64 = 0xA1;
+static CUES: u64 = 0x1C53BB6B;
+static CUE_POINT: u64 = 0xBB;
+static CUE_TIME: u64 = 0xB3;
+static CUE_TRACK_POSITIONS: u64 = 0xB7;
+static CUE_TRACK: u64 = 0xF7;
+static CUE_CLUSTER_POSITION: u64 = 0xF1;
+
+// Children of a Segment, anything else ends a Cluster of unknown size
+static TOP_LEVEL: [u64, ..8] = [SEEK_HEAD, INFO, TRACKS, CLUSTER, CUES, 0x1254C367, 0x1043A770, 0x1941A469];
+
+fn be(data: &[u8]) -> u64 {
+  return data.iter().fold(0u64, |a, &b| (a << 8) | b as u64);
+}
+
+// Variable size integer at the start of data, and its length. IDs keep the
+// length marker and sizes don't.
+fn vint(data: &[u8], marker: bool) -> (u64, uint) {
+  if data.is_empty() || data[0] == 0 {
+    panic!("flac::matroska: Invalid variable size integer (INPUT)");
+  }
+
+  let length = data[0].leading_zeros() as uint + 1;
+
+  if data.len() < length {
+    panic!("flac::matroska: Variable size integer is cut off (INPUT)");
+  }
+
+  let first = if marker { data[0] } else { data[0] & (0xFFu >> length) as u8 };
+
+  return (data.slice(1, length).iter().fold(first as u64, |a, &b| (a << 8) | b as u64), length);
+}
+
+// A size with all of its bits set is unknown
+fn size_of(size: u64, length: uint) -> Option<u64> {
+  return if size == (1 << (7 * length)) - 1 { None } else { Some(size) };
+}
+
+// ID, header length and size of the element at the start of data
+fn header(data: &[u8]) -> (u64, uint, Option<u64>) {
+  let (id, id_length) = vint(data, true);
+  let (size, size_length) = vint(data.slice_from(id_length), false);
+
+  return (id, id_length + size_length, size_of(size, size_length));
+}
+
+// Where an element with its body at body ends, unless that's past end. Sizes
+// come from the file and can be up to 2^56, so they're compared to what's
+// left instead of added to body.
+fn end_of(body: uint, size: u64, end: uint) -> Option<uint> {
+  return if body <= end && size <= (end - body) as u64 { Some(body + size as uint) } else { None };
+}
+
+fn read_vint<R: Reader>(input: &mut R, marker: bool) -> IoResult<(u64, uint)> {
+  let first = try!(input.read_u8());
+  let mut data = vec![first];
+
+  if first != 0 {
+    data.push_all(try!(input.read_exact(first.leading_zeros() as uint)).as_slice());
+  }
+
+  return Ok(vint(data.as_slice(), marker));
+}
+
+fn read_header<R: Reader>(input: &mut R) -> IoResult<(u64, Option<u64>)> {
+  let (id, _) = try!(read_vint(input, true));
+  let (size, length) = try!(read_vint(input, false));
+
+  return Ok((id, size_of(size, length)));
+}
+
+// The body of an element, if there's that much left of input of length bytes
+fn read_body<R: Reader + Seek>(input: &mut R, size: u64, length: u64) -> IoResult<Vec<u8>> {
+  let position = try!(input.tell());
+
+  if size > length - cmp::min(position, length) {
+    panic!("flac::matroska::seek: Element is longer than the input (INPUT)");
+  }
+
+  return input.read_exact(size as uint);
+}
+
+// Elements in data, which all need to have a known size
+fn children(data: &[u8]) -> Vec<(u64, &[u8])> {
+  let mut children = Vec::new();
+  let mut i = 0;
+
+  while i < data.len() {
+    let (id, length, size) = header(data.slice_from(i));
+
+    let end = match size.map(|size| end_of(i + length, size, data.len())) {
+      Some(Some(end)) => end,
+      Some(None) => panic!("flac::matroska: Element {:X} is longer than its parent (INPUT)", id),
+      None => panic!("flac::matroska: Element {:X} has an unknown size (INPUT)", id)
+    };
+
+    children.push((id, data.slice(i + length, end)));
+    i = end;
+  }
+
+  return children;
+}
+
+// Length of a Cluster of unknown size, which goes on until an element that
+// can't be in a Cluster
+fn cluster_length(data: &[u8]) -> uint {
+  let mut i = 0;
+
+  while i < data.len() {
+    let (id, length, size) = header(data.slice_from(i));
+
+    if TOP_LEVEL.contains(&id) {
+      break;
+    }
+
+    i = match size.map(|size| end_of(i + length, size, data.len())) {
+      Some(Some(end)) => end,
+      Some(None) => panic!("flac::matroska: Element {:X} is longer than the Cluster (INPUT)", id),
+      None => panic!("flac::matroska: Element {:X} in a Cluster has an unknown size (INPUT)", id)
+    };
+  }
+
+  return i;
+}
+
+// Number and CodecPrivate of a track entry, if it is FLAC
+fn flac_track(entry: &[u8]) -> Option<(u64, Vec<u8>)> {
+  let mut number = None;
+  let mut codec = false;
+  let mut private = None;
+
+  for &(id, data) in children(entry).iter() {
+    if id == TRACK_NUMBER {
+      number = Some(be(data));
+    } else if id == CODEC_ID {
+      codec = data == b"A_FLAC";
+    } else if id == CODEC_PRIVATE {
+      private = Some(data.to_vec());
+    }
+  }
+
+  return match (number, codec, private) {
+    (Some(number), true, Some(private)) => {
+      if private.len() < 4 + 4 + 34 || private.slice_to(4) != b"fLaC" {
+        panic!("flac::matroska: CodecPrivate of the FLAC track doesn't start with fLaC and STREAMINFO (INPUT)");
+      }
+
+      Some((number, private))
+    },
+    (_, true, _) => panic!("flac::matroska: FLAC track without a number or CodecPrivate (INPUT)"),
+    _ => None
+  };
+}
+
+// Splits a laced block into frames
+fn frames(flags: u8, data: &[u8]) -> Vec<&[u8]> {
+  let lacing = (flags >> 1) & 0x03;
+
+  if lacing == 0 {
+    return vec![data];
+  }
+
+  if data.is_empty() {
+    panic!("flac::matroska: Laced block without a frame count (INPUT)");
+  }
+
+  let count = data[0] as uint + 1;
+  let mut sizes = Vec::with_capacity(count);
+  let mut i = 1;
+
+  for n in range(0, count - 1) {
+    match lacing {
+      // Xiph, sums of bytes up to one that isn't 255
+      1 => {
+        let mut size = 0;
+
+        loop {
+          if i == data.len() {
+            panic!("flac::matroska: Lacing is cut off (INPUT)");
+          }
+
+          size += data[i] as uint;
+          i += 1;
+
+          if data[i - 1] != 255 {
+            break;
+          }
+        }
+
+        sizes.push(size);
+      },
+      // EBML, a size and then signed differences to the one before
+      3 => {
+        let (value, length) = vint(data.slice_from(i), false);
+
+        i += length;
+
+        let size = if n == 0 {
+          value as i64
+        } else {
+          let bias = (1i64 << (7 * length - 1)) - 1;
+
+          sizes[n - 1] as i64 + value as i64 - bias
+        };
+
+        if size < 0 || size > data.len() as i64 {
+          panic!("flac::matroska: Laced frame size is out of range (INPUT)");
+        }
+
+        sizes.push(size as uint);
+      },
+      // Fixed, all the same size
+      _ => sizes.push((data.len() - 1) / count)
+    }
+  }
+
+  // Every size is at most 255 times the length of the block, so this can't wrap
+  let total = sizes.iter().fold(i, |a, &s| a + s);
+
+  if total > data.len() {
+    panic!("flac::matroska: Laced frames are longer than their block (INPUT)");
+  }
+
+  sizes.push(data.len() - total);
+
+  let mut frames = Vec::with_capacity(count);
+
+  for &size in sizes.iter() {
+    frames.push(data.slice(i, i + size));
+    i += size;
+  }
+
+  return frames;
+}
+
+// Track number and flags of a SimpleBlock or Block, and what comes after them
+fn block(data: &[u8]) -> (u64, u8, &[u8]) {
+  let (track, length) = vint(data, false);
+
+  if data.len() < length + 3 {
+    panic!("flac::matroska: Block is too short (INPUT)");
+  }
+
+  return (track, data[length + 2], data.slice_from(length + 3));
+}
+
+pub struct Demuxer {
+  source: aurora::channel::Source<aurora::Binary>,
+  sink: aurora::channel::Sink<aurora::Binary>,
+  metadata_sink: aurora::channel::Sink<metadata::Metadata>,
+  limits: Limits
+}
+
+impl Demuxer {
+  pub fn new(source: aurora::channel::Source<aurora::Binary>, sink: aurora::channel::Sink<aurora::Binary>, metadata_sink: aurora::channel::Sink<metadata::Metadata>) -> Demuxer {
+    return Demuxer {
+      source: source,
+      sink: sink,
+      metadata_sink: metadata_sink,
+      limits: Limits::new()
+    }
+  }
+
+  // Metadata blocks and STREAMINFO that exceed the limits fail the demuxer,
+  // see try_run
+  pub fn limit(&mut self, limits: Limits) {
+    self.limits = limits;
+  }
+
+  fn write_metadata(&mut self, private: &[u8]) -> Result<(), Exceeded> {
+    let mut offset = 4;
+    let mut total = 0;
+    let mut last = false;
+
+    while !last {
+      if private.len() < offset + 4 {
+        panic!("flac::matroska::Demuxer: CodecPrivate ended before the last metadata block (INPUT)");
+      }
+
+      let length = 4 + be(private.slice(offset + 1, offset + 4)) as uint;
+
+      if private.len() < offset + length {
+        panic!("flac::matroska::Demuxer: Metadata block is longer than CodecPrivate (INPUT)");
+      }
+
+      let block = private.slice(offset, offset + length);
+
+      try!(self.limits.check_block(block[0] & 0x7F, length - 4, total));
+      total += length - 4;
+
+      let limits = &self.limits;
+      let mut result = Ok(());
+
+      self.metadata_sink.write(|metadata| {
+        last = valid!(metadata::from_bytes(block, metadata));
+
+        result = match metadata.ty {
+          metadata::StreamInfo(ref stream_info) => limits.check_stream_info(stream_info),
+          _ => Ok(())
+        };
+      });
+
+      try!(result);
+
+      offset += length;
+    }
+
+    return Ok(());
+  }
+
+  fn write_frames(&mut self, flags: u8, data: &[u8]) {
+    for frame in frames(flags, data).iter() {
+      self.sink.write(|binary| {
+        binary.data.truncate(0);
+        binary.data.push_all(*frame);
+        binary.last = false;
+      });
+    }
+  }
+
+  fn write_cluster(&mut self, cluster: &[u8], track: u64) {
+    for &(id, data) in children(cluster).iter() {
+      if id == SIMPLE_BLOCK {
+        let (number, flags, data) = block(data);
+
+        if number == track {
+          self.write_frames(flags, data);
+        }
+      } else if id == BLOCK_GROUP {
+        for &(_, data) in children(data).iter().filter(|&&(id, _)| id == BLOCK) {
+          let (number, flags, data) = block(data);
+
+          if number == track {
+            self.write_frames(flags, data);
+          }
+        }
+      }
+    }
+  }
+
+  pub fn run(&mut self) {
+    match self.try_run() {
+      Ok(()) => (),
+      Err(exceeded) => panic!("flac::matroska::Demuxer: Input exceeds the limits, {} (INPUT)", exceeded)
+    }
+  }
+
+  // Same as run, but metadata that exceeds the limits stops it with an error
+  // instead of a panic. The sinks are dropped without a last buffer then.
+  pub fn try_run(&mut self) -> Result<(), Exceeded> {
+    let mut data = Vec::new();
+    let mut last = false;
+
+    while !last {
+      self.source.read(|binary| {
+        data.push_all(binary.data.as_slice());
+        last = binary.last;
+      });
+    }
+
+    let data = data.as_slice();
+
+    let (id, length, size) = header(data);
+
+    let start = match size.and_then(|size| end_of(length, size, data.len())) {
+      Some(start) if id == EBML => start,
+      _ => panic!("flac::matroska::Demuxer: Input doesn't start with an EBML header (INPUT)")
+    };
+
+    match children(data.slice(length, start)).iter().find(|&&(id, _)| id == DOC_TYPE) {
+      Some(&(_, doc_type)) if doc_type == b"matroska" || doc_type == b"webm" => (),
+      _ => panic!("flac::matroska::Demuxer: Document type isn't matroska or webm (INPUT)")
+    }
+
+    let (id, length, size) = header(data.slice_from(start));
+
+    if id != SEGMENT {
+      panic!("flac::matroska::Demuxer: EBML header isn't followed by a Segment (INPUT)");
+    }
+
+    let mut i = start + length;
+
+    let end = match size.map(|size| end_of(i, size, data.len())) {
+      Some(Some(end)) => end,
+      Some(None) => panic!("flac::matroska::Demuxer: Segment is longer than the input (INPUT)"),
+      None => data.len()
+    };
+
+    let mut track = None;
+
+    while i < end {
+      let (id, length, size) = header(data.slice(i, end));
+
+      let body = i + length;
+
+      let next = match size.map(|size| end_of(body, size, end)) {
+        Some(Some(next)) => next,
+        Some(None) => panic!("flac::matroska::Demuxer: Element {:X} is longer than the Segment (INPUT)", id),
+        None if id == CLUSTER => body + cluster_length(data.slice(body, end)),
+        None => panic!("flac::matroska::Demuxer: Element {:X} has an unknown size (INPUT)", id)
+      };
+
+      if id == TRACKS && track.is_none() {
+        let entries = children(data.slice(body, next));
+
+        match entries.iter().filter(|&&(id, _)| id == TRACK_ENTRY).filter_map(|&(_, entry)| flac_track(entry)).next() {
+          Some((number, private)) => {
+            try!(self.write_metadata(private.as_slice()));
+            track = Some(number);
+          },
+          None => panic!("flac::matroska::Demuxer: File has no A_FLAC track (INPUT)")
+        }
+      } else if id == CLUSTER {
+        match track {
+          Some(track) => self.write_cluster(data.slice(body, next), track),
+          None => panic!("flac::matroska::Demuxer: Cluster came before the tracks (INPUT)")
+        }
+      }
+
+      i = next;
+    }
+
+    if track.is_none() {
+      panic!("flac::matroska::Demuxer: File has no tracks (INPUT)");
+    }
+
+    self.sink.write(|binary| {
+      binary.data.truncate(0);
+      binary.last = true;
+    });
+
+    return Ok(());
+  }
+}
+
+// Finds where to start demuxing to get to a sample, from the Cues. This is
+// the offset of the Cluster of the last cue point of the FLAC track at or
+// before the sample, and the sample that cue point is at. Clusters are
+// skipped over, and Cues after a Cluster of unknown size are found through
+// the SeekHead.
+pub fn seek<R: Reader + Seek>(input: &mut R, sample: u64) -> IoResult<Option<(u64, u64)>> {
+  try!(input.seek(0, SeekEnd));
+
+  let input_length = try!(input.tell());
+
+  try!(input.seek(0, SeekSet));
+
+  match try!(read_header(input)) {
+    (id, Some(size)) if id == EBML => try!(input.seek(size as i64, SeekCur)),
+    _ => panic!("flac::matroska::seek: Input doesn't start with an EBML header (INPUT)")
+  }
+
+  let size = match try!(read_header(input)) {
+    (id, size) if id == SEGMENT => size,
+    _ => panic!("flac::matroska::seek: EBML header isn't followed by a Segment (INPUT)")
+  };
+
+  let start = try!(input.tell());
+  let end = size.map(|size| start + size);
+
+  let mut scale = 1000000;
+  let mut track = None;
+  let mut cues = None;
+  let mut cues_position = None;
+
+  loop {
+    let position = try!(input.tell());
+
+    if end.map_or(false, |end| position >= end) {
+      break;
+    }
+
+    let (id, size) = match read_header(input) {
+      Ok(header) => header,
+      Err(ref error) if error.kind == EndOfFile => break,
+      Err(error) => return Err(error)
+    };
+
+    let size = match size {
+      Some(size) => size,
+      None => break
+    };
+
+    if id != INFO && id != TRACKS && id != SEEK_HEAD && id != CUES {
+      try!(input.seek(size as i64, SeekCur));
+      continue;
+    }
+
+    let body = try!(read_body(input, size, input_length));
+
+    for &(child, data) in children(body.as_slice()).iter() {
+      if id == INFO && child == TIMECODE_SCALE {
+        scale = be(data);
+      } else if id == TRACKS && child == TRACK_ENTRY && track.is_none() {
+        track = flac_track(data).map(|(number, private)| {
+          (number, valid!(stream_info::read(&private.slice(8, 42).to_vec())).sample_rate as u64)
+        });
+      } else if id == SEEK_HEAD && child == SEEK {
+        let seek = children(data);
+
+        let target = seek.iter().find(|&&(id, _)| id == SEEK_ID).map(|&(_, id)| be(id));
+        let position = seek.iter().find(|&&(id, _)| id == SEEK_POSITION).map(|&(_, position)| be(position));
+
+        if target == Some(CUES) {
+          cues_position = position;
+        }
+      }
+    }
+
+    if id == CUES {
+      cues = Some(body);
+    }
+  }
+
+  if cues.is_none() {
+    match cues_position {
+      Some(position) if position < input_length - start => {
+        try!(input.seek((start + position) as i64, SeekSet));
+
+        cues = match try!(read_header(input)) {
+          (id, Some(size)) if id == CUES => Some(try!(read_body(input, size, input_length))),
+          _ => panic!("flac::matroska::seek: SeekHead doesn't point at the Cues (INPUT)")
+        };
+      },
+      Some(_) => panic!("flac::matroska::seek: SeekHead points past the end of the input (INPUT)"),
+      None => return Ok(None)
+    }
+  }
+
+  let (number, sample_rate) = match track {
+    Some(track) => track,
+    None => panic!("flac::matroska::seek: File has no A_FLAC track (INPUT)")
+  };
+
+  let mut best = None;
+
+  for &(id, point) in children(cues.unwrap().as_slice()).iter() {
+    if id != CUE_POINT {
+      continue;
+    }
+
+    let point = children(point);
+
+    let time = match point.iter().find(|&&(id, _)| id == CUE_TIME) {
+      Some(&(_, time)) => be(time),
+      None => continue
+    };
+
+    let cue_sample = time * scale / 1000 * sample_rate / 1000000;
+
+    if cue_sample > sample || best.map_or(false, |(_, s)| s > cue_sample) {
+      continue;
+    }
+
+    for &(id, positions) in point.iter() {
+      if id != CUE_TRACK_POSITIONS {
+        continue;
+      }
+
+      let positions = children(positions);
+
+      let cue_track = positions.iter().find(|&&(id, _)| id == CUE_TRACK).map(|&(_, track)| be(track));
+      let cluster = positions.iter().find(|&&(id, _)| id == CUE_CLUSTER_POSITION).map(|&(_, position)| be(position));
+
+      match (cue_track, cluster) {
+        (Some(cue_track), Some(cluster)) if cue_track == number && cluster < input_length - start => best = Some((start + cluster, cue_sample)),
+        _ => ()
+      }
+    }
+  }
+
+  return Ok(best);
+}
+
+#[cfg(test)]
+mod tests {
+  use std;
+  use aurora;
+
+  use decoder;
+  use demuxer;
+  use encoder;
+  use limits;
+  use metadata;
+
+  use decoder::parallel::Splitter;
+  use test_util;
+  use test_util::noise;
+
+  fn element(id: u64, body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    for i in range(0, 4u).rev() {
+      if id >> (8 * i) != 0 {
+        data.push((id >> (8 * i)) as u8);
+      }
+    }
+
+    // Sizes are always eight bytes, so that they don't change with the body
+    data.push(0x01);
+
+    for i in range(0, 7u).rev() {
+      data.push((body.len() as u64 >> (8 * i)) as u8);
+    }
+
+    data.push_all(body);
+
+    return data;
+  }
+
+  fn unsigned(id: u64, value: u64) -> Vec<u8> {
+    return element(id, Vec::from_fn(8, |i| (value >> (8 * (7 - i))) as u8).as_slice());
+  }
+
+  fn master(id: u64, children: &[Vec<u8>], known: bool) -> Vec<u8> {
+    let body = children.iter().fold(Vec::new(), |mut a, c| { a.push_all(c.as_slice()); a });
+
+    let mut data = element(id, body.as_slice());
+
+    if !known {
+      let length = data.len() - body.len();
+
+      for i in range(length - 7, length) {
+        data.as_mut_slice()[i] = 0xFF;
+      }
+    }
+
+    return data;
+  }
+
+  fn encode() -> Vec<u8> {
+    let mut parameters = encoder::Parameters::new(48000, 2, 16);
+    parameters.block_size = encoder::Fixed(4800);
+
+    return test_util::encode(parameters, noise(2 * 96000, 14, 23).as_slice());
+  }
+
+  fn block(track: u8, lacing: u8, frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = vec![0x80 | track, 0x00, 0x00, 0x80 | (lacing << 1)];
+
+    if lacing != 0 {
+      data.push((frames.len() - 1) as u8);
+    }
+
+    for (n, frame) in frames.slice_to(frames.len() - 1).iter().enumerate() {
+      match lacing {
+        1 => {
+          data.grow(frame.len() / 255, 255);
+          data.push((frame.len() % 255) as u8);
+        },
+        _ => {
+          let bias = (1i64 << 55) - 1;
+          let value = if n == 0 { frame.len() as i64 } else { frame.len() as i64 - frames[n - 1].len() as i64 + bias };
+
+          data.push(0x01);
+
+          for i in range(0, 7u).rev() {
+            data.push((value >> (8 * i)) as u8);
+          }
+        }
+      }
+    }
+
+    for frame in frames.iter() {
+      data.push_all(frame.as_slice());
+    }
+
+    return data;
+  }
+
+  // Matroska with the FLAC stream as track 1 next to another track, five
+  // frames to a Cluster and Cues at the end. Returns the file and the offsets
+  // of the Clusters with the samples they start at.
+  fn mkv(flac: &[u8], known: bool) -> (Vec<u8>, Vec<(u64, u64)>) {
+    let mut splitter = Splitter::new(&[], 1);
+    let mut frames = Vec::new();
+
+    splitter.push(flac.slice_from(42));
+
+    loop {
+      match splitter.next(true) {
+        Some(frame) => frames.push(frame),
+        None => break
+      }
+    }
+
+    let header = master(super::EBML, &[element(super::DOC_TYPE, b"matroska")], true);
+
+    let tracks = master(super::TRACKS, &[
+      master(super::TRACK_ENTRY, &[unsigned(super::TRACK_NUMBER, 2), element(super::CODEC_ID, b"A_OPUS"), element(super::CODEC_PRIVATE, b"OpusHead")], true),
+      master(super::TRACK_ENTRY, &[unsigned(super::TRACK_NUMBER, 1), element(super::CODEC_ID, b"A_FLAC"), element(super::CODEC_PRIVATE, flac.slice_to(42))], true)
+    ], true);
+
+    let info = master(super::INFO, &[unsigned(super::TIMECODE_SCALE, 1000000)], true);
+
+    let seek_head = |position: u64| {
+      master(super::SEEK_HEAD, &[master(super::SEEK, &[element(super::SEEK_ID, &[0x1C, 0x53, 0xBB, 0x6B]), unsigned(super::SEEK_POSITION, position)], true)], true)
+    };
+
+    let mut body = seek_head(0);
+    body.push_all(info.as_slice());
+    body.push_all(tracks.as_slice());
+
+    let mut clusters = Vec::new();
+
+    for (n, group) in frames.as_slice().chunks(5).enumerate() {
+      let sample = 5 * 4800 * n as u64;
+
+      let mut children = vec![unsigned(0xE7, sample / 48), element(super::SIMPLE_BLOCK, block(1, 0, group.slice_to(1)).as_slice())];
+
+      children.push(element(super::SIMPLE_BLOCK, block(2, 0, &[vec![0xFF, 0xF8, 0x00]]).as_slice()));
+
+      if group.len() > 1 {
+        children.push(master(super::BLOCK_GROUP, &[element(super::BLOCK, block(1, 0, group.slice(1, 2)).as_slice())], true));
+      }
+
+      if group.len() > 2 {
+        let lacing = if n % 2 == 0 { 1 } else { 3 };
+
+        children.push(element(super::SIMPLE_BLOCK, block(1, lacing, group.slice_from(2)).as_slice()));
+      }
+
+      clusters.push((body.len() as u64, sample));
+      body.push_all(master(super::CLUSTER, children.as_slice(), known).as_slice());
+    }
+
+    let points: Vec<Vec<u8>> = clusters.iter().map(|&(position, sample)| {
+      master(super::CUE_POINT, &[
+        unsigned(super::CUE_TIME, sample / 48),
+        master(super::CUE_TRACK_POSITIONS, &[unsigned(super::CUE_TRACK, 1), unsigned(super::CUE_CLUSTER_POSITION, position)], true)
+      ], true)
+    }).collect();
+
+    let cues = body.len() as u64;
+
+    body.push_all(master(super::CUES, points.as_slice(), true).as_slice());
+
+    let seek_head = seek_head(cues);
+
+    for (i, &byte) in seek_head.iter().enumerate() {
+      body.as_mut_slice()[i] = byte;
+    }
+
+    let length = body.len();
+
+    let mut data = header;
+    data.push_all(master(super::SEGMENT, &[body], known).as_slice());
+
+    let start = (data.len() - length) as u64;
+
+    return (data, clusters.iter().map(|&(position, sample)| (start + position, sample)).collect());
+  }
+
+  fn decode(data: Vec<u8>, is_mkv: bool) -> Vec<u8> {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, mut source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      if is_mkv {
+        super::Demuxer::new(source_0, sink_1, sink_md).run();
+      } else {
+        demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+      }
+    });
+
+    spawn(proc() {
+      decoder::Decoder::new(source_1, source_md, sink_a).run();
+    });
+
+    let mut output = Vec::new();
+    let mut last = false;
+
+    while !last {
+      source_a.read(|audio| {
+        output.push_all(audio.data.as_slice());
+        last = audio.last;
+      });
+    }
+
+    return output;
+  }
+
+  #[test]
+  fn test_lacing() {
+    let mut xiph = vec![0x02, 0x03, 0xFF, 0x01];
+    xiph.push_all(Vec::from_fn(3 + 256 + 5, |i| i as u8).as_slice());
+
+    let laced = super::frames(0x02, xiph.as_slice());
+
+    assert_eq!(laced.len(), 3);
+    assert_eq!(laced[0], xiph.slice(4, 7));
+    assert_eq!(laced[1], xiph.slice(7, 263));
+    assert_eq!(laced[2], xiph.slice(263, 268));
+
+    // Sizes 4, then 4 - 1 = 3, then the rest
+    let mut ebml = vec![0x02, 0x84, 0xBE];
+    ebml.push_all(Vec::from_fn(4 + 3 + 2, |i| i as u8).as_slice());
+
+    let laced = super::frames(0x06, ebml.as_slice());
+
+    assert_eq!(laced, vec![ebml.slice(3, 7), ebml.slice(7, 10), ebml.slice(10, 12)]);
+
+    let fixed = [0x01, 1, 2, 3, 4, 5, 6];
+
+    assert_eq!(super::frames(0x04, &fixed), vec![fixed.slice(1, 4), fixed.slice(4, 7)]);
+  }
+
+  #[test]
+  fn test_matches_native_stream() {
+    let flac = encode();
+    let expected = decode(flac.clone(), false);
+
+    for &known in [true, false].iter() {
+      let (mkv, _) = mkv(flac.as_slice(), known);
+
+      assert_eq!(decode(mkv, true), expected);
+    }
+  }
+
+  #[test]
+  fn test_seek() {
+    let flac = encode();
+
+    for &known in [true, false].iter() {
+      let (mkv, clusters) = mkv(flac.as_slice(), known);
+
+      for &(offset, _) in clusters.iter() {
+        assert_eq!(mkv.slice(offset as uint, offset as uint + 4), [0x1F, 0x43, 0xB6, 0x75].as_slice());
+      }
+
+      let mut input = std::io::MemReader::new(mkv);
+
+      for &sample in [0u64, 4799, 24000, 30000, 95999, 1000000].iter() {
+        let expected = clusters.iter().filter(|&&(_, s)| s <= sample).last().map(|&c| c);
+
+        assert_eq!(super::seek(&mut input, sample).unwrap(), expected);
+      }
+    }
+  }
+
+  fn try_run(data: Vec<u8>, limits: limits::Limits) -> Result<(), limits::Exceeded> {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, _source_1) = aurora::channel::create::<aurora::Binary>(256);
+    let (sink_md, _source_md) = aurora::channel::create::<metadata::Metadata>(16);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    let mut demuxer = super::Demuxer::new(source_0, sink_1, sink_md);
+
+    demuxer.limit(limits);
+
+    return demuxer.try_run();
+  }
+
+  #[test]
+  fn test_limits() {
+    let (mkv, _) = mkv(encode().as_slice(), true);
+
+    assert_eq!(try_run(mkv.clone(), limits::Limits::new()), Ok(()));
+    assert_eq!(try_run(mkv.clone(), limits::Limits { max_metadata_block: 33, ..limits::Limits::new() }), Err(limits::MetadataBlock(34)));
+    assert_eq!(try_run(mkv, limits::Limits { max_channels: 1, ..limits::Limits::new() }), Err(limits::Channels(2)));
+  }
+
+  // A size close to 2^56 that would wrap past the length if it were added
+  #[test]
+  #[should_fail]
+  fn test_huge_element() {
+    let mut data = vec![0xEC, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE];
+    data.push_all(&[0x00, ..16]);
+
+    super::children(data.as_slice());
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_negative_lacing() {
+    // Sizes 4, then 4 - 63 which is below zero
+    let mut ebml = vec![0x02, 0x84, 0x80];
+    ebml.push_all(&[0x00, ..16]);
+
+    super::frames(0x06, ebml.as_slice());
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_seek_huge_element() {
+    let header = master(0x1A45DFA3, &[element(0x4282, b"matroska")], true);
+
+    let mut data = header.clone();
+    data.push_all(master(0x18538067, &[], false).as_slice());
+    data.push_all(&[0x15, 0x49, 0xA9, 0x66, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE]);
+
+    super::seek(&mut std::io::MemReader::new(data), 0).unwrap();
+  }
+}
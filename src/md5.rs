@@ -0,0 +1,209 @@
+// MD5, which STREAMINFO has a signature of the decoded samples in. See
+// RFC 1321.
+
+use prelude::*;
+
+use std::cmp;
+
+static SHIFTS: [uint, ..64] = [
+  7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+  5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+  4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+  6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21
+];
+
+// Integer part of |sin(i + 1)| * 2^32
+static SINES: [u32, ..64] = [
+  0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee,
+  0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+  0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be,
+  0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+  0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa,
+  0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+  0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed,
+  0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+  0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c,
+  0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+  0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05,
+  0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+  0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039,
+  0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+  0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1,
+  0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391
+];
+
+fn process(state: &mut [u32, ..4], block: &[u8]) {
+  let mut m = [0u32, ..16];
+
+  for (i, word) in m.iter_mut().enumerate() {
+    *word = block[4 * i] as u32 | (block[4 * i + 1] as u32 << 8) | (block[4 * i + 2] as u32 << 16) | (block[4 * i + 3] as u32 << 24);
+  }
+
+  let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+
+  for i in range(0u, 64) {
+    let (f, g) = match i / 16 {
+      0 => ((b & c) | (!b & d), i),
+      1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+      2 => (b ^ c ^ d, (3 * i + 5) % 16),
+      _ => (c ^ (b | !d), (7 * i) % 16)
+    };
+
+    let rotated = (a + f + SINES[i] + m[g]).rotate_left(SHIFTS[i]);
+
+    a = d;
+    d = c;
+    c = b;
+    b = b + rotated;
+  }
+
+  state[0] += a;
+  state[1] += b;
+  state[2] += c;
+  state[3] += d;
+}
+
+pub struct Context {
+  state: [u32, ..4],
+  buffer: [u8, ..64],
+  buffered: uint,
+  length: u64
+}
+
+// Copying a context lets a digest be taken part of the way through
+impl Clone for Context {
+  fn clone(&self) -> Context {
+    return Context { state: self.state, buffer: self.buffer, buffered: self.buffered, length: self.length };
+  }
+}
+
+impl Context {
+  pub fn new() -> Context {
+    return Context { state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476], buffer: [0x00, ..64], buffered: 0, length: 0 };
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    let mut data = data;
+
+    self.length += data.len() as u64;
+
+    if self.buffered > 0 {
+      let n = cmp::min(64 - self.buffered, data.len());
+
+      for i in range(0, n) {
+        self.buffer[self.buffered + i] = data[i];
+      }
+
+      self.buffered += n;
+      data = data.slice_from(n);
+
+      if self.buffered < 64 {
+        return;
+      }
+
+      let buffer = self.buffer;
+
+      process(&mut self.state, &buffer);
+      self.buffered = 0;
+    }
+
+    while data.len() >= 64 {
+      process(&mut self.state, data.slice_to(64));
+      data = data.slice_from(64);
+    }
+
+    for (i, &byte) in data.iter().enumerate() {
+      self.buffer[i] = byte;
+    }
+
+    self.buffered = data.len();
+  }
+
+  // Samples the way FLAC signs them, interleaved and little-endian, in as
+  // many bytes as the bit depth needs
+  pub fn update_samples(&mut self, samples: &[i32], bits: u8) {
+    let bytes = (bits as uint + 7) / 8;
+    let mut data = [0x00u8, ..1024];
+    let mut length = 0;
+
+    for &sample in samples.iter() {
+      for i in range(0, bytes) {
+        data[length + i] = (sample >> (8 * i)) as u8;
+      }
+
+      length += bytes;
+
+      if length + 4 > data.len() {
+        self.update(data.slice_to(length));
+        length = 0;
+      }
+    }
+
+    self.update(data.slice_to(length));
+  }
+
+  pub fn finish(mut self) -> [u8, ..16] {
+    let bits = 8 * self.length;
+
+    self.update(&[0x80]);
+
+    while self.buffered != 56 {
+      self.update(&[0x00]);
+    }
+
+    let mut length = [0x00u8, ..8];
+
+    for (i, byte) in length.iter_mut().enumerate() {
+      *byte = (bits >> (8 * i)) as u8;
+    }
+
+    self.update(&length);
+
+    let mut digest = [0x00u8, ..16];
+
+    for (i, byte) in digest.iter_mut().enumerate() {
+      *byte = (self.state[i / 4] >> (8 * (i % 4))) as u8;
+    }
+
+    return digest;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  fn md5(data: &[u8]) -> [u8, ..16] {
+    let mut context = super::Context::new();
+
+    context.update(data);
+
+    return context.finish();
+  }
+
+  #[test]
+  fn test_rfc_1321() {
+    assert_eq!(md5(b"").as_slice(), [0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8, 0x42, 0x7e].as_slice());
+    assert_eq!(md5(b"abc").as_slice(), [0x90, 0x01, 0x50, 0x98, 0x3c, 0xd2, 0x4f, 0xb0, 0xd6, 0x96, 0x3f, 0x7d, 0x28, 0xe1, 0x7f, 0x72].as_slice());
+    assert_eq!(md5(b"The quick brown fox jumps over the lazy dog").as_slice(), [0x9e, 0x10, 0x7d, 0x9d, 0x37, 0x2b, 0xb6, 0x82, 0x6b, 0xd8, 0x1d, 0x35, 0x42, 0xa4, 0x19, 0xd6].as_slice());
+  }
+
+  #[test]
+  fn test_chunks() {
+    let data = Vec::from_fn(1000, |i| (i * 7) as u8);
+
+    let mut context = super::Context::new();
+
+    for chunk in data.as_slice().chunks(37) {
+      context.update(chunk);
+    }
+
+    assert_eq!(context.finish().as_slice(), md5(data.as_slice()).as_slice());
+  }
+
+  #[test]
+  fn test_samples() {
+    let mut context = super::Context::new();
+    context.update_samples(&[1, -2, 0x123456], 24);
+
+    assert_eq!(context.finish().as_slice(), md5(&[0x01, 0x00, 0x00, 0xFE, 0xFF, 0xFF, 0x56, 0x34, 0x12]).as_slice());
+  }
+}
@@ -0,0 +1,178 @@
+use std::io::IoResult;
+
+use bitstream::SliceReader;
+use decoder::parallel::Splitter;
+use frame::header;
+use metadata;
+use metadata::stream_info;
+use metadata::seek_table;
+
+// Copies a native FLAC stream from input to output with a new seek table in
+// place of the old one, frames are kept as they are. The frames have to be
+// in memory to know their offsets before the table is written.
+pub fn add_seek_table<R: Reader, W: Writer>(input: &mut R, output: &mut W, spacing: &seek_table::Spacing) -> IoResult<()> {
+  let fourcc = try!(input.read_exact(4));
+
+  if fourcc.as_slice() != b"fLaC" {
+    panic!("flac::editor: Input doesn't start with fLaC (INPUT)");
+  }
+
+  let mut blocks = Vec::new();
+  let mut stream_info = None;
+  let mut last = false;
+
+  while !last {
+    let header = try!(input.read_u8());
+    let length = try!(input.read_be_uint_n(3)) as uint;
+    let data = try!(input.read_exact(length));
+
+    let ty = header & 0x7F;
+    last = header & 0x80 != 0;
+
+    match ty {
+      0 => stream_info = Some(valid!(stream_info::read(&data))),
+      3 => continue,
+      _ => ()
+    }
+
+    blocks.push((ty, data));
+  }
+
+  let stream_info = match stream_info {
+    Some(stream_info) => stream_info,
+    None => panic!("flac::editor: Metadata doesn't contain a stream info (INPUT)")
+  };
+
+  let frames = try!(input.read_to_end());
+
+  let (block_size, _) = stream_info.block_size;
+
+  let mut builder = seek_table::Builder::new(spacing, stream_info.sample_rate, Some(stream_info.samples));
+  let mut splitter = Splitter::new(&[], 1);
+  let mut offset = 0u64;
+
+  splitter.push(frames.as_slice());
+
+  loop {
+    let frame = match splitter.next(true) {
+      Some(frame) => frame,
+      None => break
+    };
+
+    if header::check(frame.as_slice()).is_none() {
+      panic!("flac::editor: Frame data doesn't start with a frame header (INPUT)");
+    }
+
+    let header = valid!(header::Header::from(&mut SliceReader::new(frame.as_slice())));
+
+    let sample_number = match (header.sample_number, header.frame_number) {
+      (Some(sample_number), _) => sample_number,
+      (None, Some(frame_number)) => frame_number as u64 * block_size as u64,
+      (None, None) => panic!("flac::editor: Frame header without a number?! (BUG)")
+    };
+
+    builder.frame(sample_number, offset, header.block_size);
+
+    offset += frame.len() as u64;
+  }
+
+  let points = seek_table::write(builder.points().as_slice());
+
+  try!(output.write(b"fLaC"));
+
+  // STREAMINFO has to come first, the seek table goes right after it, so
+  // it's only the last block when STREAMINFO is the only other one
+  for (i, &(ty, ref data)) in blocks.iter().enumerate() {
+    let last = i == blocks.len() - 1 && (i > 0 || builder.len() == 0);
+
+    try!(output.write(&metadata::header(last, ty, data.len())));
+    try!(output.write(data.as_slice()));
+
+    if i == 0 && builder.len() > 0 {
+      try!(output.write(&metadata::header(blocks.len() == 1, 3, points.len())));
+      try!(output.write(points.as_slice()));
+    }
+  }
+
+  try!(output.write(frames.as_slice()));
+
+  return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+  use std;
+
+  use encoder;
+  use metadata;
+  use metadata::seek_table;
+  use test_util::{encode, noise};
+
+  fn rewrite(data: Vec<u8>, spacing: seek_table::Spacing) -> Vec<u8> {
+    let mut reader = std::io::MemReader::new(data);
+    let mut writer = std::io::MemWriter::new();
+
+    super::add_seek_table(&mut reader, &mut writer, &spacing).unwrap();
+
+    return writer.get_ref().to_vec();
+  }
+
+  #[test]
+  fn test_matches_encoder_seek_table() {
+    let noise = noise(2 * 60000, 6, 9);
+    let mut samples = Vec::new();
+
+    for i in range(0, 60000i32) {
+      samples.push((i % 500) * 20 + noise[2 * i as uint]);
+      samples.push(-(i % 300) * 30 + noise[2 * i as uint + 1]);
+    }
+
+    for block_size in [encoder::Fixed(4096), encoder::Variable(4608, 576)].iter() {
+      let mut parameters = encoder::Parameters::new(44100, 2, 16);
+      parameters.block_size = *block_size;
+
+      let plain = encode(parameters.clone(), samples.as_slice());
+
+      parameters.seek_table = Some(seek_table::EverySeconds(1));
+      parameters.samples = Some(60000);
+
+      let expected = encode(parameters.clone(), samples.as_slice());
+
+      assert_eq!(rewrite(plain.clone(), seek_table::EverySeconds(1)), expected);
+
+      // An existing seek table gets replaced
+      assert_eq!(rewrite(expected.clone(), seek_table::EverySeconds(1)), expected);
+
+      // Blocks after STREAMINFO stay after the seek table, and the last of
+      // them keeps the flag
+      let padded = with_padding(expected.as_slice());
+
+      assert_eq!(rewrite(with_padding(plain.as_slice()), seek_table::EverySeconds(1)), padded);
+      assert_eq!(rewrite(padded.clone(), seek_table::EverySeconds(1)), padded);
+    }
+  }
+
+  // The stream with a PADDING block after its metadata, which becomes the
+  // last block
+  fn with_padding(data: &[u8]) -> Vec<u8> {
+    let mut result = data.slice_to(4).to_vec();
+    let mut start = 4;
+    let mut last = false;
+
+    while !last {
+      let length = (data[start + 1] as uint << 16) | (data[start + 2] as uint << 8) | data[start + 3] as uint;
+      last = data[start] & 0x80 != 0;
+
+      result.push(data[start] & 0x7F);
+      result.push_all(data.slice(start + 1, start + 4 + length));
+
+      start += 4 + length;
+    }
+
+    result.push_all(&metadata::header(true, 1, 10));
+    result.push_all(&[0u8, ..10]);
+    result.push_all(data.slice_from(start));
+
+    return result;
+  }
+}
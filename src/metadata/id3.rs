@@ -0,0 +1,199 @@
+// ID3v2 tags that taggers put in front of the fLaC marker. They aren't FLAC
+// metadata, so the demuxer skips them, and can pass their frames on as an Id3
+// block.
+
+use prelude::*;
+
+use invalid::Invalid;
+
+#[deriving(Show,PartialEq,Clone)]
+pub struct Frame {
+  pub id: Vec<u8>,
+  pub flags: u16,
+  pub data: Vec<u8>
+}
+
+pub const HEADER_LENGTH: uint = 10;
+
+fn synchsafe(data: &[u8]) -> uint {
+  return data.iter().fold(0, |a, &b| (a << 7) | (b & 0x7F) as uint);
+}
+
+fn be(data: &[u8]) -> uint {
+  return data.iter().fold(0, |a, &b| (a << 8) | b as uint);
+}
+
+// Undoes unsynchronisation, which puts a 0x00 after every 0xFF
+fn resynchronise(data: &[u8]) -> Vec<u8> {
+  let mut result = Vec::with_capacity(data.len());
+
+  for (i, &byte) in data.iter().enumerate() {
+    if byte == 0x00 && i > 0 && data[i - 1] == 0xFF {
+      continue;
+    }
+
+    result.push(byte);
+  }
+
+  return result;
+}
+
+pub fn is_tag(data: &[u8]) -> bool {
+  return data.len() >= 3 && data.slice_to(3) == b"ID3";
+}
+
+// Length of the whole tag from its header, footer included
+pub fn length(header: &[u8]) -> Result<uint, Invalid> {
+  if header.len() < HEADER_LENGTH || !is_tag(header) {
+    return Err(Invalid("flac::metadata::id3: Tag doesn't start with an ID3 header"));
+  }
+
+  if header[3] < 2 || header[3] > 4 || header.slice(6, 10).iter().any(|&b| b & 0x80 != 0) {
+    return Err(Invalid("flac::metadata::id3: Unsupported version or invalid size"));
+  }
+
+  let footer = if header[5] & 0x10 != 0 { HEADER_LENGTH } else { 0 };
+
+  return Ok(HEADER_LENGTH + synchsafe(header.slice(6, 10)) + footer);
+}
+
+// Frames of a whole tag, header included. Version 2.2 frames have three
+// character IDs, and no flags.
+pub fn frames(tag: &[u8]) -> Result<Vec<Frame>, Invalid> {
+  let length = try!(length(tag));
+
+  if tag.len() < length {
+    return Err(Invalid("flac::metadata::id3: Tag is shorter than its header says"));
+  }
+
+  let version = tag[3];
+  let flags = tag[5];
+  let size = synchsafe(tag.slice(6, 10));
+
+  let mut body = tag.slice(HEADER_LENGTH, HEADER_LENGTH + size).to_vec();
+
+  if flags & 0x80 != 0 && version < 4 {
+    body = resynchronise(body.as_slice());
+  }
+
+  let mut i = 0;
+
+  if flags & 0x40 != 0 && version > 2 {
+    if body.len() < 4 {
+      return Err(Invalid("flac::metadata::id3: Extended header is cut off"));
+    }
+
+    i = if version == 3 { 4 + be(body.slice_to(4)) } else { synchsafe(body.slice_to(4)) };
+  }
+
+  let (id_length, size_length, flags_length) = if version == 2 { (3, 3, 0) } else { (4, 4, 2) };
+  let header_length = id_length + size_length + flags_length;
+
+  let mut frames = Vec::new();
+
+  while i + header_length <= body.len() && body[i] != 0x00 {
+    let id = body.slice(i, i + id_length).to_vec();
+
+    let sizes = body.slice(i + id_length, i + id_length + size_length);
+    let size = if version == 4 { synchsafe(sizes) } else { be(sizes) };
+
+    let flags = be(body.slice(i + id_length + size_length, i + header_length)) as u16;
+
+    let start = i + header_length;
+
+    if start + size > body.len() {
+      return Err(Invalid("flac::metadata::id3: Frame is longer than the tag"));
+    }
+
+    let data = if version == 4 && flags & 0x0002 != 0 {
+      resynchronise(body.slice(start, start + size))
+    } else {
+      body.slice(start, start + size).to_vec()
+    };
+
+    frames.push(Frame { id: id, flags: flags, data: data });
+
+    i = start + size;
+  }
+
+  return Ok(frames);
+}
+
+#[cfg(test)]
+pub mod tests {
+  fn synchsafe(value: uint) -> [u8, ..4] {
+    return [(value >> 21) as u8 & 0x7F, (value >> 14) as u8 & 0x7F, (value >> 7) as u8 & 0x7F, value as u8 & 0x7F];
+  }
+
+  // A version 2.4 tag with a title and some padding, and a footer if asked
+  pub fn tag(title: &str, footer: bool) -> Vec<u8> {
+    let mut body = b"TIT2".to_vec();
+    body.push_all(&synchsafe(title.len() + 1));
+    body.push_all(&[0x00, 0x00, 0x03]);
+    body.push_all(title.as_bytes());
+    body.grow(32, 0x00);
+
+    let flags = if footer { 0x10 } else { 0x00 };
+
+    let mut tag = b"ID3\x04\x00".to_vec();
+    tag.push(flags);
+    tag.push_all(&synchsafe(body.len()));
+    tag.push_all(body.as_slice());
+
+    if footer {
+      tag.push_all(b"3DI\x04\x00");
+      tag.push(flags);
+      tag.push_all(&synchsafe(body.len()));
+    }
+
+    return tag;
+  }
+
+  #[test]
+  fn test_frames_2_4() {
+    for &footer in [false, true].iter() {
+      let tag = tag("Bad Apple!!", footer);
+
+      assert_eq!(super::length(tag.as_slice()), Ok(tag.len()));
+
+      let mut data = vec![0x03];
+      data.push_all(b"Bad Apple!!");
+
+      assert_eq!(super::frames(tag.as_slice()), Ok(vec![super::Frame { id: b"TIT2".to_vec(), flags: 0, data: data }]));
+
+      // Cut off
+      assert!(super::frames(tag.slice_to(tag.len() - 1)).is_err());
+    }
+  }
+
+  #[test]
+  fn test_frames_2_3_unsynchronised() {
+    // A picture frame with 0xFF bytes that got a 0x00 after them, and an
+    // extended header
+    let mut body = vec![0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    body.push_all(b"APIC");
+    body.push_all(&[0x00, 0x00, 0x00, 0x04, 0x00, 0x00]);
+    body.push_all(&[0xFF, 0x00, 0xD8, 0xFF, 0x00, 0xE0]);
+    body.push_all(b"TPE1");
+    body.push_all(&[0x00, 0x00, 0x00, 0x03, 0x00, 0x00]);
+    body.push_all(&[0x00, b'Z', b'N']);
+
+    let mut tag = b"ID3\x03\x00\xC0".to_vec();
+    tag.push_all(&synchsafe(body.len()));
+    tag.push_all(body.as_slice());
+
+    assert_eq!(super::frames(tag.as_slice()), Ok(vec![
+      super::Frame { id: b"APIC".to_vec(), flags: 0, data: vec![0xFF, 0xD8, 0xFF, 0xE0] },
+      super::Frame { id: b"TPE1".to_vec(), flags: 0, data: vec![0x00, b'Z', b'N'] }
+    ]));
+  }
+
+  #[test]
+  fn test_frames_2_2() {
+    let mut tag = b"ID3\x02\x00\x00".to_vec();
+    tag.push_all(&synchsafe(6 + 3));
+    tag.push_all(b"TT2\x00\x00\x03\x00Hi");
+
+    assert_eq!(super::frames(tag.as_slice()), Ok(vec![super::Frame { id: b"TT2".to_vec(), flags: 0, data: b"\x00Hi".to_vec() }]));
+  }
+}
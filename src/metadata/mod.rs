@@ -1,53 +1,185 @@
+use prelude::*;
+
+#[cfg(feature = "std")]
 use aurora;
 
+#[cfg(feature = "std")]
+use limits::{Limits, Exceeded};
+
+use invalid::Invalid;
+
 pub mod stream_info;
+pub mod seek_table;
+#[cfg(feature = "std")]
+pub mod editor;
+pub mod id3;
 
-#[deriving(Show,PartialEq)]
+#[deriving(Show,PartialEq,Clone)]
 pub enum Ty {
-  StreamInfo(stream_info::StreamInfo), Unknown
+  StreamInfo(stream_info::StreamInfo), SeekTable(Vec<seek_table::SeekPoint>), Application([u8, ..4]), Unknown,
+
+  // Frames of an ID3v2 tag in front of the stream, which isn't a FLAC block
+  Id3(Vec<id3::Frame>)
 }
 
 #[deriving(Show,PartialEq)]
 pub struct Metadata {
   pub ty: Ty,
-  pub data: Vec<u8>
+  pub data: Vec<u8>,
+  pub last: bool
 }
 
+#[cfg(feature = "std")]
 impl aurora::Initialize for Metadata {
   fn initialize() -> Metadata {
-    return Metadata { ty: Unknown, data: Vec::with_capacity(4096) }
+    return Metadata { ty: Unknown, data: Vec::with_capacity(4096), last: false }
   }
 
   fn reinitialize(&mut self) {
     self.ty = Unknown;
     self.data.truncate(0);
+    self.last = false;
   }
 }
 
-pub fn transfer(stream: &mut aurora::stream::Stream, result: &mut Metadata) -> bool {
-  let header = stream.read_u8();
-  let length = stream.read_be_uint_n(3);
-
-  let last = header & 0x80 != 0;
-  let ty = header & 0x7F;
+pub fn header(last: bool, ty: u8, length: uint) -> [u8, ..4] {
+  let flag = if last { 0x80 } else { 0x00 };
 
-  result.data.grow(length as uint, 0x00u8);
-  stream.read(result.data.as_mut_slice());
+  return [flag | ty, (length >> 16) as u8, (length >> 8) as u8, length as u8];
+}
 
+fn parse(ty: u8, last: bool, result: &mut Metadata) -> Result<bool, Invalid> {
   match ty {
     0 => {
-      result.ty = StreamInfo(stream_info::read(&result.data))
+      result.ty = StreamInfo(try!(stream_info::read(&result.data)))
+    }
+    2 if result.data.len() >= 4 => {
+      let id = [result.data[0], result.data[1], result.data[2], result.data[3]];
+
+      result.ty = Application(id)
+    }
+    3 => {
+      result.ty = SeekTable(try!(seek_table::read(&result.data)))
     }
     _ => {
       result.ty = Unknown;
     }
   }
 
-  return last;
+  result.last = last;
+
+  return Ok(last);
+}
+
+#[cfg(feature = "std")]
+pub fn transfer(stream: &mut aurora::stream::Stream, result: &mut Metadata) -> bool {
+  let mut total = 0;
+
+  return transfer_within(stream, result, &Limits::new(), &mut total).unwrap();
+}
+
+// Same as transfer, unless the block doesn't fit the limits after total bytes
+// of metadata, which it adds its length to. Then nothing but its header is
+// read.
+#[cfg(feature = "std")]
+pub fn transfer_within(stream: &mut aurora::stream::Stream, result: &mut Metadata, limits: &Limits, total: &mut uint) -> Result<bool, Exceeded> {
+  let header = stream.read_u8();
+  let length = stream.read_be_uint_n(3) as uint;
+
+  let last = header & 0x80 != 0;
+  let ty = header & 0x7F;
+
+  try!(limits.check_block(ty, length, *total));
+
+  *total += length;
+
+  result.data.grow(length, 0x00u8);
+  stream.read(result.data.as_mut_slice());
+
+  return Ok(valid!(parse(ty, last, result)));
+}
+
+// Same as transfer, for a whole block, header included, that is already in
+// memory. Returns an error instead of panicking.
+pub fn from_bytes(block: &[u8], result: &mut Metadata) -> Result<bool, Invalid> {
+  if block.len() < 4 {
+    return Err(Invalid("flac::metadata: Block is shorter than its header"));
+  }
+
+  let length = (block[1] as uint << 16) | (block[2] as uint << 8) | block[3] as uint;
+
+  if block.len() != 4 + length {
+    return Err(Invalid("flac::metadata: Block isn't as long as its header says"));
+  }
+
+  result.data.truncate(0);
+  result.data.push_all(block.slice_from(4));
+
+  return parse(block[0] & 0x7F, block[0] & 0x80 != 0, result);
 }
 
 #[cfg(test)]
 mod tests {
+  // STREAMINFO of Bad Apple, header included
+  static STREAM_INFO: [u8, ..38] = [
+    0x00, 0x00, 0x00, 0x22, 0x10, 0x00, 0x10, 0x00, 0x00, 0x05, 0x2C, 0x00, 0x36, 0x18, 0x0A, 0xC4, 0x42, 0xF0, 0x00,
+    0xD4, 0xB7, 0x9A, 0x07, 0x02, 0x55, 0xE5, 0xCE, 0x94, 0x69, 0xED, 0xC6, 0x23, 0xCD, 0x9E, 0x8E, 0xB3, 0xE2, 0x21
+  ];
+
+  // Its last block, a Vorbis comment
+  static VORBIS_COMMENT: &'static [u8] = include_bin!("../../test-vectors/metadata/bad_apple.vorbis_comment");
+
+  fn empty() -> super::Metadata {
+    return super::Metadata { ty: super::Unknown, data: Vec::new(), last: false };
+  }
+
+  #[test]
+  fn test_from_bytes_1() {
+    let mut metadata = empty();
+
+    let last = super::from_bytes(STREAM_INFO.as_slice(), &mut metadata).unwrap();
+
+    assert_eq!(last, false);
+
+    assert_eq!(metadata.ty, super::StreamInfo(super::stream_info::StreamInfo {
+      block_size: (4096, 4096),
+      frame_size: (1324, 13848),
+      sample_rate: 44100,
+      channels: 2,
+      bits_per_sample: 16,
+      samples: 13940634,
+      signature: super::stream_info::MD5([0x07, 0x02, 0x55, 0xE5, 0xCE, 0x94, 0x69, 0xED, 0xC6, 0x23, 0xCD, 0x9E, 0x8E, 0xB3, 0xE2, 0x21])
+    }));
+
+    assert_eq!(metadata.data.len(), 34);
+  }
+
+  #[test]
+  fn test_from_bytes_2() {
+    let mut metadata = empty();
+
+    let last = super::from_bytes(VORBIS_COMMENT, &mut metadata).unwrap();
+
+    assert_eq!(last, true);
+    assert_eq!(metadata.ty, super::Unknown);
+    assert_eq!(metadata.data.len(), 315);
+  }
+
+  #[test]
+  fn test_from_bytes_of_a_cut_block() {
+    assert!(super::from_bytes(STREAM_INFO.slice_to(37), &mut empty()).is_err());
+
+    // A STREAMINFO that says it's shorter than it has to be
+    let mut short = STREAM_INFO.slice_to(37).to_vec();
+    short.as_mut_slice()[3] = 33;
+
+    assert!(super::from_bytes(short.as_slice(), &mut empty()).is_err());
+  }
+}
+
+// The same blocks read from a stream, through aurora
+#[cfg(all(test, feature = "std"))]
+mod aurora_tests {
   use std;
   use aurora;
 
@@ -0,0 +1,228 @@
+use std;
+
+use prelude::*;
+
+use invalid::Invalid;
+
+pub const PLACEHOLDER: u64 = 0xFFFFFFFFFFFFFFFF;
+
+#[deriving(Show,PartialEq,Clone)]
+pub struct SeekPoint {
+  pub sample_number: u64,
+  pub offset: u64, // From the first byte of the first frame
+  pub samples: u16
+}
+
+impl SeekPoint {
+  pub fn is_placeholder(&self) -> bool {
+    return self.sample_number == PLACEHOLDER;
+  }
+}
+
+fn read_be(data: &[u8]) -> u64 {
+  return data.iter().fold(0u64, |a, &b| (a << 8) | b as u64);
+}
+
+pub fn read(data: &Vec<u8>) -> Result<Vec<SeekPoint>, Invalid> {
+  if data.len() % 18 != 0 {
+    return Err(Invalid("flac::SeekTable: Length of block isn't a multiple of 18"));
+  }
+
+  return Ok(data.as_slice().chunks(18).map(|point| SeekPoint {
+    sample_number: read_be(point.slice(0, 8)),
+    offset: read_be(point.slice(8, 16)),
+    samples: read_be(point.slice(16, 18)) as u16
+  }).collect());
+}
+
+pub fn write(points: &[SeekPoint]) -> Vec<u8> {
+  let mut data = Vec::with_capacity(18 * points.len());
+
+  for point in points.iter() {
+    for i in range(0, 8u).rev() {
+      data.push((point.sample_number >> (8 * i)) as u8);
+    }
+
+    for i in range(0, 8u).rev() {
+      data.push((point.offset >> (8 * i)) as u8);
+    }
+
+    data.push((point.samples >> 8) as u8);
+    data.push(point.samples as u8);
+  }
+
+  return data;
+}
+
+pub fn placeholder() -> SeekPoint {
+  return SeekPoint { sample_number: PLACEHOLDER, offset: 0, samples: 0 };
+}
+
+#[deriving(Show,PartialEq,Clone)]
+pub enum Spacing {
+  EverySeconds(u32),
+  EverySamples(u64),
+  AtSamples(Vec<u64>)
+}
+
+// Turns target sample numbers into seek points as frames go by, the table
+// always has one point per target so its size is known before any frame is
+// written, targets that land in an already used frame become placeholders
+pub struct Builder {
+  targets: Vec<u64>,
+  next: uint,
+  points: Vec<SeekPoint>
+}
+
+impl Builder {
+  // samples is the total length of the stream, which the interval spacings
+  // need to know how many points there will be
+  pub fn new(spacing: &Spacing, sample_rate: u32, samples: Option<u64>) -> Builder {
+    let interval = match *spacing {
+      EverySeconds(seconds) => Some(seconds as u64 * sample_rate as u64),
+      EverySamples(n) => Some(n),
+      AtSamples(_) => None
+    };
+
+    let targets: Vec<u64> = match (interval, samples) {
+      (Some(0), _) => panic!("flac::SeekTable: Seek points need a spacing of at least one sample (INPUT)"),
+      (Some(n), Some(total)) => std::iter::range_step(0, total, n).collect(),
+      (Some(_), None) => panic!("flac::SeekTable: Evenly spaced seek points need the total number of samples (INPUT)"),
+      (None, _) => {
+        let mut targets = match *spacing {
+          AtSamples(ref targets) => targets.clone(),
+          _ => panic!("flac::SeekTable: Undefined spacing?! (BUG)")
+        };
+
+        targets.sort();
+        targets.dedup();
+
+        targets
+      }
+    };
+
+    return Builder { points: Vec::with_capacity(targets.len()), targets: targets, next: 0 };
+  }
+
+  pub fn len(&self) -> uint {
+    return self.targets.len();
+  }
+
+  // Called for every frame in order, offset is from the start of the first
+  pub fn frame(&mut self, sample_number: u64, offset: u64, block_size: u32) {
+    let end = sample_number + block_size as u64;
+
+    while self.next < self.targets.len() && self.targets[self.next] < end {
+      let target = self.targets[self.next];
+      let used = self.points.last().map_or(false, |p| p.sample_number == sample_number);
+
+      // The field can't hold 65536, like STREAMINFO's, which takes 65535 for
+      // it
+      if target >= sample_number && !used {
+        self.points.push(SeekPoint { sample_number: sample_number, offset: offset, samples: std::cmp::min(block_size, 65535) as u16 });
+      }
+
+      self.next += 1;
+    }
+  }
+
+  pub fn points(&self) -> Vec<SeekPoint> {
+    let mut points = self.points.clone();
+
+    while points.len() < self.targets.len() {
+      points.push(placeholder());
+    }
+
+    return points;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn test_read() {
+    let data = vec![
+      0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00,
+      0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12, 0x34, 0x56, 0x10, 0x00,
+      0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ];
+
+    let points = super::read(&data).unwrap();
+
+    assert_eq!(points.len(), 3);
+    assert_eq!(points[0], super::SeekPoint { sample_number: 0, offset: 0, samples: 4096 });
+    assert_eq!(points[1], super::SeekPoint { sample_number: 0x62000, offset: 0x123456, samples: 4096 });
+    assert!(points[2].is_placeholder());
+  }
+
+  #[test]
+  fn test_write_round_trip() {
+    let points = vec![
+      super::SeekPoint { sample_number: 0, offset: 0, samples: 4096 },
+      super::SeekPoint { sample_number: 0x62000, offset: 0x123456, samples: 4096 },
+      super::placeholder()
+    ];
+
+    let data = super::write(points.as_slice());
+
+    assert_eq!(data.len(), 54);
+    assert_eq!(super::read(&data), Ok(points));
+    assert!(super::read(&data.slice_to(53).to_vec()).is_err());
+  }
+
+  #[test]
+  fn test_builder_every_samples() {
+    let mut builder = super::Builder::new(&super::EverySamples(10000), 44100, Some(30000));
+
+    assert_eq!(builder.len(), 3);
+
+    for n in range(0, 8u64) {
+      builder.frame(4096 * n, 1000 * n, 4096);
+    }
+
+    assert_eq!(builder.points(), vec![
+      super::SeekPoint { sample_number: 0, offset: 0, samples: 4096 },
+      super::SeekPoint { sample_number: 8192, offset: 2000, samples: 4096 },
+      super::SeekPoint { sample_number: 16384, offset: 4000, samples: 4096 }
+    ]);
+  }
+
+  #[test]
+  fn test_builder_at_samples() {
+    // 100 and 200 fall into the same frame, 1000000 is past the end
+    let mut builder = super::Builder::new(&super::AtSamples(vec![200, 5000, 100, 1000000]), 44100, None);
+
+    assert_eq!(builder.len(), 4);
+
+    builder.frame(0, 0, 4096);
+    builder.frame(4096, 1500, 4096);
+    builder.frame(8192, 3000, 1000);
+
+    let points = builder.points();
+
+    assert_eq!(points[0], super::SeekPoint { sample_number: 0, offset: 0, samples: 4096 });
+    assert_eq!(points[1], super::SeekPoint { sample_number: 4096, offset: 1500, samples: 4096 });
+    assert!(points[2].is_placeholder());
+    assert!(points[3].is_placeholder());
+  }
+
+  #[test]
+  fn test_builder_block_of_65536() {
+    let mut builder = super::Builder::new(&super::EverySamples(65536), 44100, Some(65536 * 2));
+
+    builder.frame(0, 0, 65536);
+    builder.frame(65536, 9000, 65536);
+
+    assert_eq!(builder.points(), vec![
+      super::SeekPoint { sample_number: 0, offset: 0, samples: 65535 },
+      super::SeekPoint { sample_number: 65536, offset: 9000, samples: 65535 }
+    ]);
+  }
+
+  #[test]
+  fn test_builder_every_seconds() {
+    let builder = super::Builder::new(&super::EverySeconds(10), 48000, Some(48000 * 35));
+
+    assert_eq!(builder.len(), 4);
+  }
+}
@@ -1,8 +1,12 @@
 use std::fmt;
 use std::cmp;
 
-use aurora;
+use prelude::*;
 
+use bitstream::{BitRead, SliceReader};
+use invalid::Invalid;
+
+#[deriving(Clone)]
 pub struct MD5(pub [u8, ..16]);
 
 impl fmt::Show for MD5 {
@@ -28,7 +32,7 @@ impl cmp::PartialEq for MD5 {
   }
 }
 
-#[deriving(Show,PartialEq)]
+#[deriving(Show,PartialEq,Clone)]
 pub struct StreamInfo {
   pub block_size: (u16, u16),
   pub frame_size: (u32, u32),
@@ -39,38 +43,38 @@ pub struct StreamInfo {
   pub signature: MD5
 }
 
-pub fn read(data: &Vec<u8>) -> StreamInfo {
-  if data.len() != 34 {
-    panic!("StreamInfo: Length of block isn't 34 (is {}), which it should be (INPUT)", data.len());
-  }
-
-  let data = data.as_slice().to_vec();
+// Largest block size of a stream. STREAMINFO can't hold 65536, which a frame
+// can have, so its largest value stands for that as well.
+pub fn max_block_size(stream_info: &StreamInfo) -> uint {
+  let (_, max_block_size) = stream_info.block_size;
 
-  let (sink, mut source) = aurora::channel::create::<aurora::Binary>(1);
-
-  spawn(proc() {
-    aurora::buffer::Buffer::new(data, 4096, sink).run();
-  });
+  return if max_block_size == 65535 { 65536 } else { max_block_size as uint };
+}
 
-  let mut stream = aurora::stream::Stream::new(&mut source);
+pub fn read(data: &Vec<u8>) -> Result<StreamInfo, Invalid> {
+  if data.len() != 34 {
+    return Err(Invalid("flac::StreamInfo: Length of block isn't 34"));
+  }
 
-  let block_size = (stream.read_be_u16(), stream.read_be_u16());
-  let frame_size = (stream.read_be_uint_n(3) as u32, stream.read_be_uint_n(3) as u32);
+  let mut reader = SliceReader::new(data.as_slice());
 
-  let ex = stream.read_be_u64();
+  let block_size = (try!(reader.read_n(16)) as u16, try!(reader.read_n(16)) as u16);
+  let frame_size = (try!(reader.read_n(24)), try!(reader.read_n(24)));
 
-  let sample_rate =     ((ex & 0xFFFFF00000000000) >> 44) as u32;
-  let channels =        ((ex & 0x00000E0000000000) >> 41) as u8 + 1;
-  let bits_per_sample = ((ex & 0x000001F000000000) >> 36) as u8 + 1;
-  let samples =          (ex & 0x0000000FFFFFFFFF) >> 0;
+  let sample_rate = try!(reader.read_n(20));
+  let channels = try!(reader.read_n(3)) as u8 + 1;
+  let bits_per_sample = try!(reader.read_n(5)) as u8 + 1;
+  let samples = (try!(reader.read_n(4)) as u64 << 32) | try!(reader.read_n(32)) as u64;
 
   let mut sig = [0x00u8, ..16];
 
-  stream.read(&mut sig);
+  for byte in sig.iter_mut() {
+    *byte = try!(reader.read_n(8)) as u8;
+  }
 
   let signature = MD5(sig);
 
-  return StreamInfo {
+  return Ok(StreamInfo {
     block_size: block_size,
     frame_size: frame_size,
     sample_rate: sample_rate,
@@ -78,5 +82,55 @@ pub fn read(data: &Vec<u8>) -> StreamInfo {
     bits_per_sample: bits_per_sample,
     samples: samples,
     signature: signature
-  };
+  });
+}
+
+fn push_be(data: &mut Vec<u8>, value: u64, bytes: uint) {
+  for i in range(0, bytes).rev() {
+    data.push((value >> (8 * i)) as u8);
+  }
+}
+
+pub fn write(stream_info: &StreamInfo) -> Vec<u8> {
+  let mut data = Vec::with_capacity(34);
+
+  let (min_block_size, max_block_size) = stream_info.block_size;
+  let (min_frame_size, max_frame_size) = stream_info.frame_size;
+
+  push_be(&mut data, min_block_size as u64, 2);
+  push_be(&mut data, max_block_size as u64, 2);
+  push_be(&mut data, min_frame_size as u64, 3);
+  push_be(&mut data, max_frame_size as u64, 3);
+
+  let ex = (stream_info.sample_rate as u64 << 44)
+         | ((stream_info.channels as u64 - 1) << 41)
+         | ((stream_info.bits_per_sample as u64 - 1) << 36)
+         | (stream_info.samples & 0x0000000FFFFFFFFF);
+
+  push_be(&mut data, ex, 8);
+
+  let MD5(signature) = stream_info.signature;
+
+  data.push_all(signature.as_slice());
+
+  return data;
+}
+
+#[cfg(test)]
+mod tests {
+  static BAD_APPLE: &'static [u8] = include_bin!("../../test-vectors/metadata/bad_apple.stream_info");
+
+  #[test]
+  fn test_write_round_trip() {
+    let body = BAD_APPLE.slice_from(4).to_vec();
+
+    let stream_info = super::read(&body).unwrap();
+
+    assert_eq!(super::write(&stream_info), body);
+  }
+
+  #[test]
+  fn test_read_of_a_cut_block() {
+    assert!(super::read(&BAD_APPLE.slice(4, 37).to_vec()).is_err());
+  }
 }
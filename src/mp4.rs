@@ -0,0 +1,1007 @@
+use std::cmp;
+use std::mem;
+use std::uint;
+
+use aurora;
+
+use limits;
+use limits::{Limits, Exceeded};
+use metadata;
+
+// Demuxes the first FLAC track of an MP4 file, plain or fragmented, following
+// the FLAC in ISO BMFF mapping: the metadata blocks are in the dfLa box of the
+// fLaC sample entry, and every sample is one frame. Top-level boxes are read
+// one at a time, the moov and moof boxes whole, and samples are written as
+// soon as their data has come in. Only what's still needed is kept, which is
+// everything up to the moov, since samples can point anywhere before it.
+
+struct Atom<'a> {
+  ty: &'a [u8],
+  offset: uint, // Of the whole box in the file
+  body: &'a [u8]
+}
+
+fn be(data: &[u8]) -> u64 {
+  return data.iter().fold(0u64, |a, &b| (a << 8) | b as u64);
+}
+
+fn field(data: &[u8], offset: uint, bytes: uint) -> u64 {
+  if data.len() < offset + bytes {
+    panic!("flac::mp4::Demuxer: Box is too short (INPUT)");
+  }
+
+  return be(data.slice(offset, offset + bytes));
+}
+
+// A count of entries of entry bytes each, checked against what's left of the
+// box after it before anything is allocated for them
+fn count(data: &[u8], offset: uint, entry: uint) -> uint {
+  let count = field(data, offset, 4);
+
+  if count > ((data.len() - offset - 4) / entry) as u64 {
+    panic!("flac::mp4::Demuxer: Box has room for fewer than {} entries (INPUT)", count);
+  }
+
+  return count as uint;
+}
+
+// Size and header length of the box at the start of data, None for a box that
+// goes on to the end of the file
+fn size_of(data: &[u8]) -> (Option<u64>, uint) {
+  return match field(data, 0, 4) {
+    0 => (None, 8),
+    1 => (Some(field(data, 8, 8)), 16),
+    size => (Some(size), 8)
+  };
+}
+
+// The boxes in data, which starts at offset in the file
+fn atoms<'a>(data: &'a [u8], offset: uint) -> Vec<Atom<'a>> {
+  let mut atoms = Vec::new();
+  let mut i = 0;
+
+  while i + 8 <= data.len() {
+    let (size, header) = match size_of(data.slice_from(i)) {
+      (Some(size), header) => (size, header),
+      (None, header) => ((data.len() - i) as u64, header)
+    };
+
+    if size < header as u64 || size > (data.len() - i) as u64 {
+      panic!("flac::mp4::Demuxer: Box size {} is out of range (INPUT)", size);
+    }
+
+    let size = size as uint;
+
+    atoms.push(Atom { ty: data.slice(i + 4, i + 8), offset: offset + i, body: data.slice(i + header, i + size) });
+
+    i += size;
+  }
+
+  return atoms;
+}
+
+fn find<'a>(atoms: &[Atom<'a>], ty: &[u8]) -> Option<Atom<'a>> {
+  return atoms.iter().find(|a| a.ty == ty).map(|a| Atom { ty: a.ty, offset: a.offset, body: a.body });
+}
+
+fn path<'a>(atom: &Atom<'a>, types: &[&[u8]]) -> Option<Atom<'a>> {
+  let mut current = Atom { ty: atom.ty, offset: atom.offset, body: atom.body };
+
+  for ty in types.iter() {
+    let children = atoms(current.body, current.offset + 8);
+
+    current = match find(children.as_slice(), *ty) {
+      Some(child) => child,
+      None => return None
+    };
+  }
+
+  return Some(current);
+}
+
+struct Track {
+  id: u32,
+  blocks: Vec<u8>,
+  default_size: u32,
+  runs: Vec<Run>
+}
+
+// Samples one after another from position, all of size or each of the size in
+// sizes. Counts in a box can be much larger than the box, so samples are kept
+// like this instead of one by one.
+struct Run {
+  position: uint,
+  count: uint,
+  size: uint,
+  sizes: Vec<uint>,
+  after: uint // Lowest position of the runs after this one
+}
+
+impl Run {
+  fn new(position: u64, count: uint, size: uint, sizes: Vec<uint>) -> Run {
+    let total = if sizes.is_empty() { count as u64 * size as u64 } else { sizes.iter().fold(0u64, |a, &s| a + s as u64) };
+
+    if position > uint::MAX as u64 || total > (uint::MAX - position as uint) as u64 {
+      panic!("flac::mp4::Demuxer: Samples go past the largest offset there can be (INPUT)");
+    }
+
+    return Run { position: position as uint, count: count, size: size, sizes: sizes, after: uint::MAX };
+  }
+}
+
+// The metadata blocks of a track with a fLaC sample entry
+fn flac_blocks(stsd: &Atom) -> Option<Vec<u8>> {
+  if stsd.body.len() < 8 || field(stsd.body, 4, 4) < 1 {
+    return None;
+  }
+
+  let entries = atoms(stsd.body.slice_from(8), stsd.offset + 16);
+
+  let entry = match find(entries.as_slice(), b"fLaC") {
+    Some(entry) => entry,
+    None => return None
+  };
+
+  // An audio sample entry has 28 bytes before its boxes
+  if entry.body.len() < 28 {
+    panic!("flac::mp4::Demuxer: fLaC sample entry is too short (INPUT)");
+  }
+
+  let children = atoms(entry.body.slice_from(28), entry.offset + 36);
+
+  return match find(children.as_slice(), b"dfLa") {
+    Some(dfla) if dfla.body.len() >= 4 => Some(dfla.body.slice_from(4).to_vec()),
+    _ => panic!("flac::mp4::Demuxer: fLaC sample entry without a dfLa box (INPUT)")
+  };
+}
+
+// Samples of the sample table, from stsz, stsc and stco or co64, a run for
+// each chunk
+fn table_runs(stbl: &Atom) -> Vec<Run> {
+  let children = atoms(stbl.body, stbl.offset + 8);
+
+  let (size, samples, sizes): (uint, uint, Vec<uint>) = match find(children.as_slice(), b"stsz") {
+    Some(stsz) => {
+      let size = field(stsz.body, 4, 4) as uint;
+
+      if size == 0 {
+        let count = count(stsz.body, 8, 4);
+
+        (0, count, range(0, count).map(|i| field(stsz.body, 12 + 4 * i, 4) as uint).collect())
+      } else {
+        (size, field(stsz.body, 8, 4) as uint, Vec::new())
+      }
+    },
+    None => (0, 0, Vec::new())
+  };
+
+  let chunks: Vec<u64> = match (find(children.as_slice(), b"stco"), find(children.as_slice(), b"co64")) {
+    (Some(stco), _) => range(0, count(stco.body, 4, 4)).map(|i| field(stco.body, 8 + 4 * i, 4)).collect(),
+    (None, Some(co64)) => range(0, count(co64.body, 4, 8)).map(|i| field(co64.body, 8 + 8 * i, 8)).collect(),
+    (None, None) => Vec::new()
+  };
+
+  // First chunk, counting from 1, and samples per chunk from there on
+  let runs: Vec<(uint, uint)> = match find(children.as_slice(), b"stsc") {
+    Some(stsc) => range(0, count(stsc.body, 4, 12)).map(|i| {
+      (field(stsc.body, 8 + 12 * i, 4) as uint, field(stsc.body, 12 + 12 * i, 4) as uint)
+    }).collect(),
+    None => Vec::new()
+  };
+
+  let mut table = Vec::with_capacity(chunks.len());
+  let mut sample = 0;
+  let mut run = 0;
+
+  for (c, &offset) in chunks.iter().enumerate() {
+    loop {
+      match runs.get(run + 1) {
+        Some(&(first, _)) if first <= c + 1 => run += 1,
+        _ => break
+      }
+    }
+
+    let per_chunk = match runs.get(run) {
+      Some(&(_, per_chunk)) => per_chunk,
+      None => 0
+    };
+
+    let n = cmp::min(per_chunk, samples - sample);
+
+    if n > 0 {
+      let chunk_sizes = if size == 0 { sizes.slice(sample, sample + n).to_vec() } else { Vec::new() };
+
+      table.push(Run::new(offset, n, size, chunk_sizes));
+      sample += n;
+    }
+  }
+
+  return table;
+}
+
+fn read_track(trak: &Atom) -> Option<Track> {
+  let stsd = match path(trak, &[b"mdia", b"minf", b"stbl", b"stsd"]) {
+    Some(stsd) => stsd,
+    None => return None
+  };
+
+  let blocks = match flac_blocks(&stsd) {
+    Some(blocks) => blocks,
+    None => return None
+  };
+
+  let id = match path(trak, &[b"tkhd"]) {
+    Some(tkhd) if tkhd.body.len() > 0 && tkhd.body[0] == 1 => field(tkhd.body, 20, 4) as u32,
+    Some(tkhd) => field(tkhd.body, 12, 4) as u32,
+    None => panic!("flac::mp4::Demuxer: Track without a tkhd box (INPUT)")
+  };
+
+  let stbl = path(trak, &[b"mdia", b"minf", b"stbl"]).unwrap();
+
+  return Some(Track { id: id, blocks: blocks, default_size: 0, runs: table_runs(&stbl) });
+}
+
+// Samples of a track fragment, from tfhd and trun, a run for each trun
+fn fragment_runs(moof: &Atom, traf: &Atom, track: &Track, runs: &mut Vec<Run>) {
+  let children = atoms(traf.body, traf.offset + 8);
+
+  let tfhd = match find(children.as_slice(), b"tfhd") {
+    Some(tfhd) => tfhd,
+    None => panic!("flac::mp4::Demuxer: Track fragment without a tfhd box (INPUT)")
+  };
+
+  if field(tfhd.body, 4, 4) as u32 != track.id {
+    return;
+  }
+
+  let flags = field(tfhd.body, 1, 3);
+  let mut i = 8;
+
+  let mut base = moof.offset as u64;
+  let mut default_size = track.default_size as uint;
+
+  if flags & 0x000001 != 0 {
+    base = field(tfhd.body, i, 8);
+    i += 8;
+  }
+
+  if flags & 0x000002 != 0 {
+    i += 4;
+  }
+
+  if flags & 0x000008 != 0 {
+    i += 4;
+  }
+
+  if flags & 0x000010 != 0 {
+    default_size = field(tfhd.body, i, 4) as uint;
+  }
+
+  let mut position = base;
+
+  for trun in children.iter().filter(|a| a.ty == b"trun") {
+    let flags = field(trun.body, 1, 3);
+    let mut i = 8;
+
+    if flags & 0x000001 != 0 {
+      let offset = field(trun.body, i, 4) as i32 as i64;
+
+      if offset < 0 && (-offset) as u64 > base {
+        panic!("flac::mp4::Demuxer: Data offset is before the start of the file (INPUT)");
+      }
+
+      position = (base as i64 + offset) as u64;
+      i += 4;
+    }
+
+    if flags & 0x000004 != 0 {
+      i += 4;
+    }
+
+    // Fields that every sample has, of which only the size matters
+    let entry = [0x000100, 0x000200, 0x000400, 0x000800].iter().filter(|&&f| flags & f != 0).count() * 4;
+
+    let count = if entry == 0 { field(trun.body, 4, 4) as uint } else { count(trun.body, 4, entry) };
+    let mut sizes = Vec::new();
+
+    if flags & 0x000200 != 0 {
+      let before = if flags & 0x000100 != 0 { 4 } else { 0 };
+
+      sizes = range(0, count).map(|n| field(trun.body, i + n * entry + before, 4) as uint).collect();
+    }
+
+    let run = Run::new(position, count, default_size, sizes);
+
+    position = run.position as u64 + if run.sizes.is_empty() {
+      run.count as u64 * run.size as u64
+    } else {
+      run.sizes.iter().fold(0u64, |a, &s| a + s as u64)
+    };
+
+    runs.push(run);
+  }
+}
+
+// The part of the input that's still needed, from base up to the bytes that
+// have been received
+struct Input {
+  data: Vec<u8>,
+  start: uint, // Of base in data
+  base: uint,
+  received: uint,
+  finished: bool
+}
+
+impl Input {
+  fn slice(&self, position: uint, size: uint) -> &[u8] {
+    let start = self.start + position - self.base;
+
+    return self.data.slice(start, start + size);
+  }
+
+  fn push(&mut self, data: &[u8]) {
+    let offset = self.received;
+
+    self.received += data.len();
+
+    if self.received > self.base {
+      self.data.push_all(data.slice_from(cmp::max(self.base, offset) - offset));
+    }
+  }
+
+  // Lets go of everything before position
+  fn discard(&mut self, position: uint) {
+    if position <= self.base {
+      return;
+    }
+
+    if position >= self.received {
+      self.data.truncate(0);
+      self.start = 0;
+    } else {
+      self.start += position - self.base;
+
+      // Moved down once half of it isn't needed, so each byte is moved about
+      // once on average
+      if self.start > self.data.len() / 2 {
+        let rest = self.data.slice_from(self.start).to_vec();
+
+        self.data = rest;
+        self.start = 0;
+      }
+    }
+
+    self.base = position;
+  }
+}
+
+// Samples still to be written, in the order they're in the tables
+struct Samples {
+  runs: Vec<Run>,
+  run: uint,
+  sample: uint,
+  position: uint
+}
+
+impl Samples {
+  // The runs of a moov or moof, which may point before ones still waiting
+  fn push(&mut self, runs: Vec<Run>) {
+    let mut runs: Vec<Run> = runs.into_iter().filter(|r| r.count > 0).collect();
+
+    if runs.is_empty() {
+      return;
+    }
+
+    let mut after = uint::MAX;
+
+    for run in runs.iter_mut().rev() {
+      run.after = after;
+      after = cmp::min(after, run.position);
+    }
+
+    for i in range(self.run, self.runs.len()) {
+      let run = &mut self.runs.as_mut_slice()[i];
+
+      run.after = cmp::min(run.after, after);
+    }
+
+    if self.run == self.runs.len() {
+      self.position = runs[0].position;
+    }
+
+    for run in runs.into_iter() {
+      self.runs.push(run);
+    }
+  }
+
+  // Position and size of the next sample
+  fn peek(&self) -> Option<(uint, uint)> {
+    if self.run == self.runs.len() {
+      return None;
+    }
+
+    let run = &self.runs[self.run];
+
+    return Some((self.position, if run.sizes.is_empty() { run.size } else { run.sizes[self.sample] }));
+  }
+
+  fn advance(&mut self, size: uint) {
+    self.position += size;
+    self.sample += 1;
+
+    if self.sample == self.runs[self.run].count {
+      self.run += 1;
+      self.sample = 0;
+
+      if self.run == self.runs.len() {
+        self.runs.truncate(0);
+        self.run = 0;
+      } else {
+        self.position = self.runs[self.run].position;
+      }
+    }
+  }
+
+  // Lowest position a sample still to be written has
+  fn floor(&self) -> uint {
+    return match self.peek() {
+      Some((position, _)) => cmp::min(position, self.runs[self.run].after),
+      None => uint::MAX
+    };
+  }
+}
+
+pub struct Demuxer {
+  source: aurora::channel::Source<aurora::Binary>,
+  sink: aurora::channel::Sink<aurora::Binary>,
+  metadata_sink: aurora::channel::Sink<metadata::Metadata>,
+  limits: Limits
+}
+
+impl Demuxer {
+  pub fn new(source: aurora::channel::Source<aurora::Binary>, sink: aurora::channel::Sink<aurora::Binary>, metadata_sink: aurora::channel::Sink<metadata::Metadata>) -> Demuxer {
+    return Demuxer {
+      source: source,
+      sink: sink,
+      metadata_sink: metadata_sink,
+      limits: Limits::new()
+    }
+  }
+
+  // Metadata blocks and STREAMINFO that exceed the limits fail the demuxer,
+  // and so do moov and moof boxes larger than a metadata block can be, since
+  // they're read whole. See try_run.
+  pub fn limit(&mut self, limits: Limits) {
+    self.limits = limits;
+  }
+
+  fn write_metadata(&mut self, blocks: &[u8]) -> Result<(), Exceeded> {
+    let mut offset = 0;
+    let mut total = 0;
+    let mut last = false;
+
+    while !last {
+      let block = blocks.slice_from(offset);
+
+      if block.len() < 4 {
+        panic!("flac::mp4::Demuxer: dfLa box ended before the last metadata block (INPUT)");
+      }
+
+      let length = 4 + be(block.slice(1, 4)) as uint;
+
+      if block.len() < length {
+        panic!("flac::mp4::Demuxer: Metadata block is longer than the dfLa box (INPUT)");
+      }
+
+      try!(self.limits.check_block(block[0] & 0x7F, length - 4, total));
+      total += length - 4;
+
+      let limits = &self.limits;
+      let mut result = Ok(());
+
+      self.metadata_sink.write(|metadata| {
+        last = valid!(metadata::from_bytes(block.slice_to(length), metadata));
+
+        result = match metadata.ty {
+          metadata::StreamInfo(ref stream_info) => limits.check_stream_info(stream_info),
+          _ => Ok(())
+        };
+      });
+
+      try!(result);
+
+      offset += length;
+    }
+
+    return Ok(());
+  }
+
+  // Writes the samples whose data has come in, and then lets go of the input
+  // before keep that no sample needs, if there is a keep
+  fn write_samples(&mut self, input: &mut Input, samples: &mut Samples, keep: Option<uint>) {
+    loop {
+      let (position, size) = match samples.peek() {
+        Some(sample) => sample,
+        None => break
+      };
+
+      if position < input.base {
+        panic!("flac::mp4::Demuxer: Sample points back at data that has been passed (INPUT)");
+      }
+
+      if position > input.received || size > input.received - position {
+        break;
+      }
+
+      let frame = input.slice(position, size);
+
+      self.sink.write(|binary| {
+        binary.data.truncate(0);
+        binary.data.push_all(frame);
+        binary.last = false;
+      });
+
+      samples.advance(size);
+    }
+
+    match keep {
+      Some(keep) => input.discard(cmp::min(keep, samples.floor())),
+      None => ()
+    }
+  }
+
+  // Reads input until there is data up to end, or there isn't any more
+  fn fill(&mut self, input: &mut Input, samples: &mut Samples, end: uint, keep: Option<uint>) {
+    while input.received < end && !input.finished {
+      self.source.read(|binary| {
+        input.push(binary.data.as_slice());
+        input.finished = binary.last;
+      });
+
+      self.write_samples(input, samples, keep);
+    }
+  }
+
+  pub fn run(&mut self) {
+    match self.try_run() {
+      Ok(()) => (),
+      Err(exceeded) => panic!("flac::mp4::Demuxer: Input exceeds the limits, {} (INPUT)", exceeded)
+    }
+  }
+
+  // Same as run, but metadata that exceeds the limits stops it with an error
+  // instead of a panic. The sinks are dropped without a last buffer then.
+  pub fn try_run(&mut self) -> Result<(), Exceeded> {
+    let mut input = Input { data: Vec::new(), start: 0, base: 0, received: 0, finished: false };
+    let mut samples = Samples { runs: Vec::new(), run: 0, sample: 0, position: 0 };
+    let mut track: Option<Track> = None;
+    let mut next = 0u;
+
+    loop {
+      // Nothing can be let go of before the moov says where the samples are
+      let keep = track.as_ref().map(|_| next);
+
+      self.fill(&mut input, &mut samples, next + 16, keep);
+
+      if input.received <= next {
+        break;
+      }
+
+      let header = input.slice(next, cmp::min(16, input.received - next)).to_vec();
+
+      if header.len() < 8 {
+        panic!("flac::mp4::Demuxer: Box header is cut off (INPUT)");
+      }
+
+      let (size, length) = size_of(header.as_slice());
+      let ty = header.slice(4, 8);
+
+      let size = match size {
+        Some(size) if size < length as u64 => panic!("flac::mp4::Demuxer: Box size {} is out of range (INPUT)", size),
+        Some(size) if size > (uint::MAX - next) as u64 => panic!("flac::mp4::Demuxer: Box size {} is out of range (INPUT)", size),
+        Some(size) => Some(size as uint),
+        None => None
+      };
+
+      if ty != b"moov" && ty != b"moof" {
+        match size {
+          Some(size) => next += size,
+          None => break
+        }
+
+        continue;
+      }
+
+      let end = match size {
+        Some(size) => next + size,
+        None => uint::MAX
+      };
+
+      // A box of unknown size is only read up to where it would exceed them
+      let max = self.limits.max_metadata_block;
+
+      match size {
+        Some(size) if size > max => return Err(limits::MetadataBlock(size)),
+        _ => ()
+      }
+
+      self.fill(&mut input, &mut samples, if max < uint::MAX - next { cmp::min(end, next + max + 1) } else { end }, keep);
+
+      if input.received - next > max && size.is_none() {
+        return Err(limits::MetadataBlock(input.received - next));
+      }
+
+      let end = cmp::min(end, input.received);
+
+      if size.map_or(false, |size| next + size > end) {
+        panic!("flac::mp4::Demuxer: Box is longer than the input (INPUT)");
+      }
+
+      let data = input.slice(next, end - next).to_vec();
+      let atom = Atom { ty: data.slice(4, 8), offset: next, body: data.slice_from(length) };
+
+      if atom.ty == b"moov" {
+        if track.is_some() {
+          panic!("flac::mp4::Demuxer: File has more than one moov box (INPUT)");
+        }
+
+        let children = atoms(atom.body, atom.offset + 8);
+
+        let mut flac = match children.iter().filter(|a| a.ty == b"trak").filter_map(|trak| read_track(trak)).next() {
+          Some(flac) => flac,
+          None => panic!("flac::mp4::Demuxer: File has no FLAC track (INPUT)")
+        };
+
+        match path(&atom, &[b"mvex"]) {
+          Some(mvex) => {
+            for trex in atoms(mvex.body, mvex.offset + 8).iter().filter(|a| a.ty == b"trex") {
+              if field(trex.body, 4, 4) as u32 == flac.id {
+                flac.default_size = field(trex.body, 16, 4) as u32;
+              }
+            }
+          },
+          None => ()
+        }
+
+        try!(self.write_metadata(flac.blocks.as_slice()));
+
+        let runs = mem::replace(&mut flac.runs, Vec::new());
+
+        samples.push(runs);
+        track = Some(flac);
+      } else {
+        let flac = match track {
+          Some(ref flac) => flac,
+          None => panic!("flac::mp4::Demuxer: Fragment came before the moov box (INPUT)")
+        };
+
+        let mut runs = Vec::new();
+
+        for traf in atoms(atom.body, atom.offset + 8).iter().filter(|a| a.ty == b"traf") {
+          fragment_runs(&atom, traf, flac, &mut runs);
+        }
+
+        samples.push(runs);
+      }
+
+      next = end;
+
+      self.write_samples(&mut input, &mut samples, Some(next));
+    }
+
+    if track.is_none() {
+      panic!("flac::mp4::Demuxer: File has no moov box (INPUT)");
+    }
+
+    // The rest of the input, for samples past the last box
+    self.fill(&mut input, &mut samples, uint::MAX, Some(uint::MAX));
+
+    if samples.peek().is_some() {
+      panic!("flac::mp4::Demuxer: Sample is outside of the file (INPUT)");
+    }
+
+    self.sink.write(|binary| {
+      binary.data.truncate(0);
+      binary.last = true;
+    });
+
+    return Ok(());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use aurora;
+
+  use decoder;
+  use demuxer;
+  use encoder;
+  use limits;
+  use metadata;
+
+  use decoder::parallel::Splitter;
+  use test_util;
+  use test_util::noise;
+
+  fn push_be(data: &mut Vec<u8>, value: u64, bytes: uint) {
+    for i in range(0, bytes).rev() {
+      data.push((value >> (8 * i)) as u8);
+    }
+  }
+
+  fn atom(ty: &[u8], children: &[Vec<u8>]) -> Vec<u8> {
+    let length = children.iter().fold(0, |a, c| a + c.len());
+    let mut data = Vec::new();
+
+    push_be(&mut data, 8 + length as u64, 4);
+    data.push_all(ty);
+
+    for child in children.iter() {
+      data.push_all(child.as_slice());
+    }
+
+    return data;
+  }
+
+  fn full(ty: &[u8], flags: u32, fields: &[(u64, uint)]) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    push_be(&mut body, flags as u64, 4);
+
+    for &(value, bytes) in fields.iter() {
+      push_be(&mut body, value, bytes);
+    }
+
+    return atom(ty, &[body]);
+  }
+
+  fn encode() -> Vec<u8> {
+    let mut parameters = encoder::Parameters::new(48000, 2, 16);
+    parameters.block_size = encoder::Fixed(2048);
+
+    return test_util::encode(parameters, noise(2 * 20000, 14, 19).as_slice());
+  }
+
+  // The metadata and the frames of a native stream
+  fn split(flac: &[u8]) -> (Vec<u8>, Vec<Vec<u8>>) {
+    let blocks = flac.slice(4, 42).to_vec();
+
+    let mut splitter = Splitter::new(&[], 1);
+    let mut frames = Vec::new();
+
+    splitter.push(flac.slice_from(42));
+
+    loop {
+      match splitter.next(true) {
+        Some(frame) => frames.push(frame),
+        None => return (blocks, frames)
+      }
+    }
+  }
+
+  fn moov(blocks: &[u8], stbl: &[Vec<u8>], fragmented: bool) -> Vec<u8> {
+    let mut dfla = vec![0x00, 0x00, 0x00, 0x00];
+    dfla.push_all(blocks);
+
+    let mut entry = Vec::from_elem(28, 0x00u8);
+    entry.as_mut_slice()[7] = 1;
+    entry.push_all(atom(b"dfLa", &[dfla]).as_slice());
+
+    let stsd = atom(b"stsd", &[vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01], atom(b"fLaC", &[entry])]);
+
+    let mut tables = vec![stsd];
+    tables.push_all(stbl);
+
+    let trak = atom(b"trak", &[
+      full(b"tkhd", 0, &[(0, 4), (0, 4), (7, 4), (0, 4), (0, 4)]),
+      atom(b"mdia", &[
+        full(b"hdlr", 0, &[(0, 4), (0x736F756E, 4), (0, 4), (0, 4), (0, 4), (0, 1)]),
+        atom(b"minf", &[atom(b"stbl", tables.as_slice())])
+      ])
+    ]);
+
+    let mut children = vec![atom(b"mvhd", &[Vec::from_elem(100, 0x00u8)]), trak];
+
+    if fragmented {
+      children.push(atom(b"mvex", &[full(b"trex", 0, &[(7, 4), (1, 4), (0, 4), (0, 4), (0, 4)])]));
+    }
+
+    return atom(b"moov", children.as_slice());
+  }
+
+  // With the moov in front of the mdat, or after it
+  fn mp4(flac: &[u8], moov_first: bool) -> Vec<u8> {
+    let (blocks, frames) = split(flac);
+
+    let ftyp = atom(b"ftyp", &[b"isom\x00\x00\x02\x00isommp41".to_vec()]);
+
+    // Two frames per chunk
+    let mut sizes = vec![(0, 4), (frames.len() as u64, 4)];
+
+    for frame in frames.iter() {
+      sizes.push((frame.len() as u64, 4));
+    }
+
+    let chunks = (frames.len() + 1) / 2;
+    let placeholder = moov(blocks.as_slice(), &[
+      full(b"stsz", 0, sizes.as_slice()),
+      full(b"stsc", 0, &[(1, 4), (1, 4), (2, 4), (1, 4)]),
+      full(b"stco", 0, Vec::from_elem(chunks + 1, (0, 4)).as_slice())
+    ], false);
+
+    let mdat_start = ftyp.len() + if moov_first { placeholder.len() } else { 0 } + 8;
+
+    let mut offsets = vec![(chunks as u64, 4)];
+    let mut position = mdat_start;
+
+    for (i, frame) in frames.iter().enumerate() {
+      if i % 2 == 0 {
+        offsets.push((position as u64, 4));
+      }
+
+      position += frame.len();
+    }
+
+    let moov = moov(blocks.as_slice(), &[
+      full(b"stsz", 0, sizes.as_slice()),
+      full(b"stsc", 0, &[(1, 4), (1, 4), (2, 4), (1, 4)]),
+      full(b"stco", 0, offsets.as_slice())
+    ], false);
+
+    let mut data = ftyp;
+
+    if moov_first {
+      data.push_all(moov.as_slice());
+      data.push_all(atom(b"mdat", frames.as_slice()).as_slice());
+    } else {
+      data.push_all(atom(b"mdat", frames.as_slice()).as_slice());
+      data.push_all(moov.as_slice());
+    }
+
+    return data;
+  }
+
+  fn fragmented_mp4(flac: &[u8]) -> Vec<u8> {
+    let (blocks, frames) = split(flac);
+
+    let empty = [
+      full(b"stts", 0, &[(0, 4)]),
+      full(b"stsc", 0, &[(0, 4)]),
+      full(b"stsz", 0, &[(0, 4), (0, 4)]),
+      full(b"stco", 0, &[(0, 4)])
+    ];
+
+    let mut data = atom(b"ftyp", &[b"iso6\x00\x00\x02\x00iso6dash".to_vec()]);
+    data.push_all(moov(blocks.as_slice(), &empty, true).as_slice());
+
+    // Three frames per fragment, with sizes in the trun and the data offset
+    // from the start of the moof
+    for (n, fragment) in frames.as_slice().chunks(3).enumerate() {
+      let mut trun = vec![(fragment.len() as u64, 4), (0, 4)];
+
+      for frame in fragment.iter() {
+        trun.push((frame.len() as u64, 4));
+      }
+
+      let moof = |trun: &[(u64, uint)]| {
+        atom(b"moof", &[
+          full(b"mfhd", 0, &[(n as u64 + 1, 4)]),
+          atom(b"traf", &[full(b"tfhd", 0x020000, &[(7, 4)]), full(b"trun", 0x000201, trun)])
+        ])
+      };
+
+      let size = moof(trun.as_slice()).len();
+
+      trun.as_mut_slice()[1] = ((size + 8) as u64, 4);
+
+      data.push_all(moof(trun.as_slice()).as_slice());
+      data.push_all(atom(b"mdat", fragment).as_slice());
+    }
+
+    return data;
+  }
+
+  fn decode(data: Vec<u8>, is_mp4: bool) -> Vec<u8> {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, mut source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      if is_mp4 {
+        super::Demuxer::new(source_0, sink_1, sink_md).run();
+      } else {
+        demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+      }
+    });
+
+    spawn(proc() {
+      decoder::Decoder::new(source_1, source_md, sink_a).run();
+    });
+
+    let mut output = Vec::new();
+    let mut last = false;
+
+    while !last {
+      source_a.read(|audio| {
+        output.push_all(audio.data.as_slice());
+        last = audio.last;
+      });
+    }
+
+    return output;
+  }
+
+  #[test]
+  fn test_sample_table() {
+    let flac = encode();
+
+    let expected = decode(flac.clone(), false);
+
+    assert_eq!(decode(mp4(flac.as_slice(), true), true), expected);
+    assert_eq!(decode(mp4(flac.as_slice(), false), true), expected);
+  }
+
+  #[test]
+  fn test_fragments() {
+    let flac = encode();
+
+    assert_eq!(decode(fragmented_mp4(flac.as_slice()), true), decode(flac, false));
+  }
+
+  // A sample count that the stsz box has no room for, which would otherwise
+  // be allocated for
+  #[test]
+  #[should_fail]
+  fn test_sample_count() {
+    let stbl = atom(b"stbl", &[full(b"stsz", 0, &[(0, 4), (0xFFFFFFFF, 4), (100, 4)])]);
+
+    super::table_runs(&super::Atom { ty: b"stbl", offset: 0, body: stbl.slice_from(8) });
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_sample_past_the_largest_offset() {
+    let stbl = atom(b"stbl", &[
+      full(b"stsz", 0, &[(0xFFFFFFFF, 4), (0xFFFFFFFF, 4)]),
+      full(b"stsc", 0, &[(1, 4), (1, 4), (0xFFFFFFFF, 4), (1, 4)]),
+      full(b"co64", 0, &[(1, 4), (0xFFFFFFFFFFFFFF00, 8)])
+    ]);
+
+    super::table_runs(&super::Atom { ty: b"stbl", offset: 0, body: stbl.slice_from(8) });
+  }
+
+  fn try_run(data: Vec<u8>, limits: limits::Limits) -> Result<(), limits::Exceeded> {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, _source_1) = aurora::channel::create::<aurora::Binary>(256);
+    let (sink_md, _source_md) = aurora::channel::create::<metadata::Metadata>(16);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    let mut demuxer = super::Demuxer::new(source_0, sink_1, sink_md);
+
+    demuxer.limit(limits);
+
+    return demuxer.try_run();
+  }
+
+  #[test]
+  fn test_limits() {
+    let flac = encode();
+    let data = mp4(flac.as_slice(), true);
+
+    let ftyp = 8 + 16;
+    let moov = (data[ftyp] as uint << 24) | (data[ftyp + 1] as uint << 16) | (data[ftyp + 2] as uint << 8) | data[ftyp + 3] as uint;
+
+    assert_eq!(try_run(data.clone(), limits::Limits::new()), Ok(()));
+    assert_eq!(try_run(data.clone(), limits::Limits { max_metadata_block: moov - 1, ..limits::Limits::new() }), Err(limits::MetadataBlock(moov)));
+    assert_eq!(try_run(data.clone(), limits::Limits { max_metadata: 33, ..limits::Limits::new() }), Err(limits::Metadata(34)));
+    assert_eq!(try_run(data, limits::Limits { max_channels: 1, ..limits::Limits::new() }), Err(limits::Channels(2)));
+  }
+}
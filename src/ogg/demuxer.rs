@@ -0,0 +1,231 @@
+use aurora;
+
+use metadata;
+
+use super::{Page, check};
+
+fn read_page(stream: &mut aurora::stream::Stream) -> Option<Page> {
+  let mut data = Vec::from_elem(27, 0x00u8);
+
+  match stream.try_read(data.slice_mut(0, 1)) {
+    Some(1) => (),
+    _ => return None
+  }
+
+  stream.read(data.slice_mut(1, 27));
+
+  if data.slice_to(4) != b"OggS" {
+    panic!("flac::ogg::Demuxer: Lost sync, page didn't start with 'OggS' (INPUT)");
+  }
+
+  let segments = data[26] as uint;
+
+  data.grow(segments, 0x00);
+  stream.read(data.slice_mut(27, 27 + segments));
+
+  let length = data.slice_from(27).iter().fold(0, |a, &l| a + l as uint);
+
+  data.grow(length, 0x00);
+  stream.read(data.slice_mut(27 + segments, 27 + segments + length));
+
+  if check(data.as_slice()).is_none() {
+    panic!("flac::ogg::Demuxer: CRC of page didn't match (INPUT)");
+  }
+
+  return Some(Page::from(data.as_slice()));
+}
+
+// Demuxes FLAC streams from Ogg, into the same channels as the native
+// Demuxer. Pages of other streams are skipped, and chained streams follow one
+// another with the metadata of each after the frames of the one before, ended
+// by an Unknown last block, which is what Decoder::chained expects.
+pub struct Demuxer {
+  source: aurora::channel::Source<aurora::Binary>,
+  sink: aurora::channel::Sink<aurora::Binary>,
+  metadata_sink: aurora::channel::Sink<metadata::Metadata>
+}
+
+impl Demuxer {
+  pub fn new(source: aurora::channel::Source<aurora::Binary>, sink: aurora::channel::Sink<aurora::Binary>, metadata_sink: aurora::channel::Sink<metadata::Metadata>) -> Demuxer {
+    return Demuxer {
+      source: source,
+      sink: sink,
+      metadata_sink: metadata_sink
+    }
+  }
+
+  pub fn run(&mut self) {
+    let mut stream = aurora::stream::Stream::new(&mut self.source);
+
+    let mut serial = None;
+    let mut headers = false;
+    let mut packet = Vec::new();
+
+    loop {
+      let page = match read_page(&mut stream) {
+        Some(page) => page,
+        None => break
+      };
+
+      if page.first && serial.is_none() && page.body.len() >= 5 && page.body.slice_to(5) == b"\x7FFLAC" {
+        serial = Some(page.serial);
+        headers = true;
+      }
+
+      if serial != Some(page.serial) {
+        continue;
+      }
+
+      // A packet that doesn't go on on this page lost its end
+      if !page.continued {
+        packet.truncate(0);
+      }
+
+      for &(data, complete) in page.packets().iter() {
+        packet.push_all(data);
+
+        if !complete {
+          continue;
+        }
+
+        if page.first {
+          if packet.len() < 13 + 4 + 34 || packet[5] != 1 || packet.slice(9, 13) != b"fLaC" {
+            panic!("flac::ogg::Demuxer: Mapping header isn't one for FLAC 1.x (INPUT)");
+          }
+
+          let block = packet.slice_from(13);
+
+          self.metadata_sink.write(|metadata| {
+            headers = !valid!(metadata::from_bytes(block, metadata));
+          });
+        } else if headers {
+          let block = packet.as_slice();
+
+          self.metadata_sink.write(|metadata| {
+            headers = !valid!(metadata::from_bytes(block, metadata));
+          });
+        } else {
+          let frame = packet.as_slice();
+
+          self.sink.write(|binary| {
+            binary.data.truncate(0);
+            binary.data.push_all(frame);
+            binary.last = false;
+          });
+        }
+
+        packet.truncate(0);
+      }
+
+      if page.last {
+        serial = None;
+      }
+    }
+
+    self.metadata_sink.write(|metadata| {
+      metadata.ty = metadata::Unknown;
+      metadata.data.truncate(0);
+      metadata.last = true;
+    });
+
+    self.sink.write(|binary| {
+      binary.data.truncate(0);
+      binary.last = true;
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use aurora;
+
+  use decoder;
+  use demuxer;
+  use metadata;
+
+  use super::super::tests::mux;
+  use test_util::{encode, noise, parameters};
+
+  fn decode(data: Vec<u8>, ogg: bool, chained: bool) -> Vec<u8> {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, mut source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      if ogg {
+        super::Demuxer::new(source_0, sink_1, sink_md).run();
+      } else {
+        demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+      }
+    });
+
+    spawn(proc() {
+      if chained {
+        decoder::Decoder::chained(source_1, source_md, sink_a).run();
+      } else {
+        decoder::Decoder::new(source_1, source_md, sink_a).run();
+      }
+    });
+
+    let mut output = Vec::new();
+    let mut last = false;
+
+    while !last {
+      source_a.read(|audio| {
+        output.push_all(audio.data.as_slice());
+        last = audio.last;
+      });
+    }
+
+    return output;
+  }
+
+  #[test]
+  fn test_matches_native_stream() {
+    let flac = encode(parameters(2, 1152), noise(2 * 10000, 16, 5).as_slice());
+
+    let expected = decode(flac.clone(), false, false);
+
+    assert_eq!(expected.len(), 4 * 10000);
+    assert_eq!(decode(mux(flac.as_slice(), 1), true, false), expected);
+  }
+
+  #[test]
+  fn test_chained_streams() {
+    let first = encode(parameters(2, 4096), noise(2 * 5000, 16, 6).as_slice());
+    let second = encode(parameters(1, 576), noise(3000, 16, 7).as_slice());
+
+    let mut ogg = mux(first.as_slice(), 10);
+    ogg.push_all(mux(second.as_slice(), 11).as_slice());
+
+    let mut expected = decode(first, false, false);
+    expected.push_all(decode(second, false, false).as_slice());
+
+    assert_eq!(decode(ogg, true, true), expected);
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_page_crc_mismatch() {
+    let flac = encode(parameters(1, 4096), noise(10000, 16, 8).as_slice());
+    let mut ogg = mux(flac.as_slice(), 1);
+
+    let n = ogg.len();
+    ogg.as_mut_slice()[n - 10] ^= 0x01;
+
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, _source_1) = aurora::channel::create::<aurora::Binary>(16);
+    let (sink_md, _source_md) = aurora::channel::create::<metadata::Metadata>(16);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(ogg, 4096, sink_0).run();
+    });
+
+    super::Demuxer::new(source_0, sink_1, sink_md).run();
+  }
+}
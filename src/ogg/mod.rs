@@ -0,0 +1,362 @@
+use std::io::{IoResult, Seek, SeekSet, SeekEnd};
+use std::io::util::LimitReader;
+
+pub use self::demuxer::Demuxer;
+pub use self::muxer::Muxer;
+
+pub mod demuxer;
+pub mod muxer;
+
+pub const MAX_PAGE: uint = 27 + 255 + 255 * 255;
+
+// Pages that don't finish a packet have no granule position
+pub const NO_GRANULE: u64 = 0xFFFFFFFFFFFFFFFF;
+
+// CRC-32 of a page with the CRC field zeroed, polynomial 0x04C11DB7 without
+// any reflection or inversion
+pub fn crc(data: &[u8]) -> u32 {
+  let mut crc = 0u32;
+
+  for &byte in data.iter() {
+    crc ^= (byte as u32) << 24;
+
+    for _ in range(0u, 8) {
+      crc = if crc & 0x80000000 != 0 { (crc << 1) ^ 0x04C11DB7 } else { crc << 1 };
+    }
+  }
+
+  return crc;
+}
+
+fn le(data: &[u8]) -> u64 {
+  return data.iter().rev().fold(0u64, |a, &b| (a << 8) | b as u64);
+}
+
+fn push_le(data: &mut Vec<u8>, value: u64, bytes: uint) {
+  for i in range(0, bytes) {
+    data.push((value >> (8 * i)) as u8);
+  }
+}
+
+#[deriving(Show,Clone,PartialEq)]
+pub struct Page {
+  pub continued: bool,
+  pub first: bool,
+  pub last: bool,
+  pub granule: u64,
+  pub serial: u32,
+  pub sequence: u32,
+  pub lacing: Vec<u8>,
+  pub body: Vec<u8>
+}
+
+impl Page {
+  // Takes a page that check said was valid
+  pub fn from(data: &[u8]) -> Page {
+    let segments = data[26] as uint;
+
+    return Page {
+      continued: data[5] & 0x01 != 0,
+      first: data[5] & 0x02 != 0,
+      last: data[5] & 0x04 != 0,
+      granule: le(data.slice(6, 14)),
+      serial: le(data.slice(14, 18)) as u32,
+      sequence: le(data.slice(18, 22)) as u32,
+      lacing: data.slice(27, 27 + segments).to_vec(),
+      body: data.slice_from(27 + segments).to_vec()
+    };
+  }
+
+  pub fn len(&self) -> uint {
+    return 27 + self.lacing.len() + self.body.len();
+  }
+
+  pub fn write(&self) -> Vec<u8> {
+    let mut data = Vec::with_capacity(self.len());
+
+    let flags = (self.continued as u8) | (self.first as u8 << 1) | (self.last as u8 << 2);
+
+    data.push_all(b"OggS");
+    data.push(0);
+    data.push(flags);
+    push_le(&mut data, self.granule, 8);
+    push_le(&mut data, self.serial as u64, 4);
+    push_le(&mut data, self.sequence as u64, 4);
+    push_le(&mut data, 0, 4);
+    data.push(self.lacing.len() as u8);
+    data.push_all(self.lacing.as_slice());
+    data.push_all(self.body.as_slice());
+
+    let crc = crc(data.as_slice());
+
+    for i in range(0, 4u) {
+      data.as_mut_slice()[22 + i] = (crc >> (8 * i)) as u8;
+    }
+
+    return data;
+  }
+
+  // Splits the body into packets, the bool says whether the packet ends on
+  // this page
+  pub fn packets(&self) -> Vec<(&[u8], bool)> {
+    let mut packets = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+
+    for &length in self.lacing.iter() {
+      end += length as uint;
+
+      if length < 255 {
+        packets.push((self.body.slice(start, end), true));
+        start = end;
+      }
+    }
+
+    if self.lacing.last().map_or(false, |&l| l == 255) {
+      packets.push((self.body.slice(start, end), false));
+    }
+
+    return packets;
+  }
+}
+
+// Length of the page at the start of data, if it is complete and its CRC
+// matches
+pub fn check(data: &[u8]) -> Option<uint> {
+  if data.len() < 27 || data.slice_to(4) != b"OggS" || data[4] != 0 {
+    return None;
+  }
+
+  let segments = data[26] as uint;
+
+  if data.len() < 27 + segments {
+    return None;
+  }
+
+  let length = 27 + segments + data.slice(27, 27 + segments).iter().fold(0, |a, &l| a + l as uint);
+
+  if data.len() < length {
+    return None;
+  }
+
+  let expected = le(data.slice(22, 26)) as u32;
+
+  let mut page = data.slice_to(length).to_vec();
+
+  for i in range(22, 26u) {
+    page.as_mut_slice()[i] = 0;
+  }
+
+  if crc(page.as_slice()) != expected {
+    return None;
+  }
+
+  return Some(length);
+}
+
+// First page of the stream with a granule position that starts at or after
+// from, skipping anything that isn't a valid page
+fn find_page<R: Reader + Seek>(input: &mut R, mut from: u64, serial: u32) -> IoResult<Option<(u64, Page)>> {
+  loop {
+    try!(input.seek(from as i64, SeekSet));
+
+    let window = try!(LimitReader::new(input.by_ref(), 2 * MAX_PAGE).read_to_end());
+    let mut i = 0;
+
+    while i + 27 <= window.len() {
+      if window[i] != b'O' {
+        i += 1;
+        continue;
+      }
+
+      match check(window.slice_from(i)) {
+        Some(length) => {
+          let page = Page::from(window.slice(i, i + length));
+
+          if page.serial == serial && page.granule != NO_GRANULE {
+            return Ok(Some((from + i as u64, page)));
+          }
+
+          i += length;
+        },
+        None if window.len() - i < MAX_PAGE && window.len() == 2 * MAX_PAGE => break,
+        None => i += 1
+      }
+    }
+
+    if window.len() < 2 * MAX_PAGE {
+      return Ok(None);
+    }
+
+    from += i as u64;
+  }
+}
+
+// Finds where to start demuxing to get to a sample, by bisection over the
+// granule positions of the pages. This is the offset and granule position of
+// the last page at or before the sample, the packets that end on it end at
+// its granule position and the next packet starts there.
+pub fn seek<R: Reader + Seek>(input: &mut R, serial: u32, sample: u64) -> IoResult<Option<(u64, u64)>> {
+  try!(input.seek(0, SeekEnd));
+
+  let mut low = 0u64;
+  let mut high = try!(input.tell());
+
+  while high - low > 2 * MAX_PAGE as u64 {
+    let middle = low + (high - low) / 2;
+
+    match try!(find_page(input, middle, serial)) {
+      Some((offset, ref page)) if page.granule <= sample => low = offset,
+      _ => high = middle
+    }
+  }
+
+  let mut best = None;
+  let mut position = low;
+
+  loop {
+    match try!(find_page(input, position, serial)) {
+      Some((offset, ref page)) if page.granule <= sample => {
+        best = Some((offset, page.granule));
+        position = offset + page.len() as u64;
+      },
+      _ => return Ok(best)
+    }
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use std;
+
+  use bitstream;
+  use decoder;
+  use frame;
+  use test_util::{encode, noise, parameters};
+
+  // Ogg FLAC with every packet on a page of its own
+  pub fn mux(flac: &[u8], serial: u32) -> Vec<u8> {
+    let mut blocks = Vec::new();
+    let mut offset = 4;
+    let mut last = false;
+
+    while !last {
+      let length = (flac[offset + 1] as uint << 16) | (flac[offset + 2] as uint << 8) | flac[offset + 3] as uint;
+
+      last = flac[offset] & 0x80 != 0;
+      blocks.push(flac.slice(offset, offset + 4 + length).to_vec());
+      offset += 4 + length;
+    }
+
+    let mut packets = Vec::new();
+    let mut first = b"\x7FFLAC\x01\x00".to_vec();
+
+    first.push(0);
+    first.push((blocks.len() - 1) as u8);
+    first.push_all(b"fLaC");
+    first.push_all(blocks[0].as_slice());
+
+    packets.push((first, 0));
+
+    for block in blocks.slice_from(1).iter() {
+      packets.push((block.clone(), 0));
+    }
+
+    let mut splitter = decoder::parallel::Splitter::new(&[], 1);
+    let mut granule = 0;
+
+    splitter.push(flac.slice_from(offset));
+
+    loop {
+      match splitter.next(true) {
+        Some(frame) => {
+          granule += frame::header::Header::from(&mut bitstream::SliceReader::new(frame.as_slice())).unwrap().block_size as u64;
+          packets.push((frame, granule));
+        },
+        None => break
+      }
+    }
+
+    let mut data = Vec::new();
+    let n = packets.len();
+
+    for (i, &(ref packet, granule)) in packets.iter().enumerate() {
+      let mut lacing = Vec::from_elem(packet.len() / 255, 255u8);
+      lacing.push((packet.len() % 255) as u8);
+
+      let page = super::Page {
+        continued: false, first: i == 0, last: i == n - 1,
+        granule: granule, serial: serial, sequence: i as u32,
+        lacing: lacing, body: packet.clone()
+      };
+
+      data.push_all(page.write().as_slice());
+    }
+
+    return data;
+  }
+
+  #[test]
+  fn test_page_round_trip() {
+    let page = super::Page {
+      continued: true, first: false, last: true,
+      granule: 123456789, serial: 0xDEADBEEF, sequence: 7,
+      lacing: vec![255, 255, 10], body: Vec::from_elem(520, 0x55u8)
+    };
+
+    let data = page.write();
+
+    assert_eq!(super::check(data.as_slice()), Some(27 + 3 + 520));
+    assert_eq!(super::Page::from(data.as_slice()), page);
+
+    let mut corrupt = data.clone();
+    corrupt.as_mut_slice()[100] ^= 0x01;
+
+    assert_eq!(super::check(corrupt.as_slice()), None);
+    assert_eq!(super::check(data.slice_to(300)), None);
+  }
+
+  #[test]
+  fn test_packets() {
+    let page = super::Page {
+      continued: false, first: false, last: false,
+      granule: 0, serial: 1, sequence: 0,
+      lacing: vec![3, 255, 0, 255], body: Vec::from_fn(513, |i| i as u8)
+    };
+
+    let packets = page.packets();
+
+    assert_eq!(packets.len(), 3);
+    assert_eq!(packets[0], (page.body.slice(0, 3), true));
+    assert_eq!(packets[1], (page.body.slice(3, 258), true));
+    assert_eq!(packets[2], (page.body.slice(258, 513), false));
+  }
+
+  #[test]
+  fn test_seek() {
+    let samples = noise(400000, 16, 3);
+    let ogg = mux(encode(parameters(1, 1000), samples.as_slice()).as_slice(), 42);
+
+    assert!(ogg.len() > 4 * super::MAX_PAGE);
+
+    let mut pages = Vec::new();
+    let mut offset = 0;
+
+    while offset < ogg.len() {
+      let length = super::check(ogg.slice_from(offset)).unwrap();
+
+      pages.push((offset as u64, super::Page::from(ogg.slice(offset, offset + length)).granule));
+      offset += length;
+    }
+
+    let mut input = std::io::MemReader::new(ogg);
+
+    for &sample in [0u64, 999, 1000, 123456, 250500, 399999, 400000, 1000000].iter() {
+      let expected = pages.iter().filter(|&&(_, g)| g != super::NO_GRANULE && g <= sample).last().map(|&p| p);
+
+      assert_eq!(super::seek(&mut input, 42, sample).unwrap(), expected);
+    }
+
+    assert_eq!(super::seek(&mut input, 43, 1000).unwrap(), None);
+  }
+}
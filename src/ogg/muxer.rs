@@ -0,0 +1,291 @@
+use std::io::IoResult;
+
+use bitstream::SliceReader;
+use decoder::parallel::Splitter;
+use frame::header;
+use metadata;
+use metadata::stream_info;
+
+use super::{Page, NO_GRANULE};
+
+// Puts FLAC into Ogg pages, following the mapping: a first page with only the
+// mapping header packet and STREAMINFO, one packet per metadata block, and one
+// per frame with granule positions counting samples. Pages are cut once their
+// body reaches page_size bytes, so packets may go on over several pages.
+pub struct Muxer<W> {
+  writer: W,
+  serial: u32,
+  page_size: uint,
+  sequence: u32,
+  samples: u64,
+  first: bool,
+  continued: bool,
+  granule: u64,
+  lacing: Vec<u8>,
+  body: Vec<u8>
+}
+
+impl<W: Writer> Muxer<W> {
+  pub fn new(writer: W, serial: u32, page_size: uint) -> Muxer<W> {
+    if page_size < 1 || page_size > 255 * 255 {
+      panic!("flac::ogg::Muxer: Page size {} is out of range (INPUT)", page_size);
+    }
+
+    return Muxer {
+      writer: writer, serial: serial, page_size: page_size, sequence: 0, samples: 0,
+      first: true, continued: false, granule: NO_GRANULE, lacing: Vec::new(), body: Vec::new()
+    };
+  }
+
+  fn flush(&mut self, last: bool) -> IoResult<()> {
+    let page = Page {
+      continued: self.continued, first: self.first, last: last,
+      granule: self.granule, serial: self.serial, sequence: self.sequence,
+      lacing: self.lacing.clone(), body: self.body.clone()
+    };
+
+    try!(self.writer.write(page.write().as_slice()));
+
+    // The page ended in the middle of a packet if its last segment is full
+    self.continued = self.lacing.last().map_or(false, |&l| l == 255);
+    self.first = false;
+    self.granule = NO_GRANULE;
+    self.sequence += 1;
+    self.lacing.truncate(0);
+    self.body.truncate(0);
+
+    return Ok(());
+  }
+
+  fn push_packet(&mut self, packet: &[u8], granule: u64) -> IoResult<()> {
+    let segments = packet.len() / 255 + 1;
+
+    for s in range(0, segments) {
+      if self.lacing.len() == 255 || self.body.len() >= self.page_size {
+        try!(self.flush(false));
+      }
+
+      let start = 255 * s;
+      let end = if s == segments - 1 { packet.len() } else { start + 255 };
+
+      self.lacing.push((end - start) as u8);
+      self.body.push_all(packet.slice(start, end));
+    }
+
+    self.granule = granule;
+
+    return Ok(());
+  }
+
+  // Blocks are types and contents of the metadata after STREAMINFO
+  pub fn write_header(&mut self, stream_info: &stream_info::StreamInfo, blocks: &[(u8, Vec<u8>)]) -> IoResult<()> {
+    if !self.first {
+      panic!("flac::ogg::Muxer: Header was already written (INPUT)");
+    }
+
+    let mut mapping = b"\x7FFLAC\x01\x00".to_vec();
+
+    mapping.push((blocks.len() >> 8) as u8);
+    mapping.push(blocks.len() as u8);
+    mapping.push_all(b"fLaC");
+    mapping.push_all(&metadata::header(blocks.is_empty(), 0, 34));
+    mapping.push_all(stream_info::write(stream_info).as_slice());
+
+    // The first page has the mapping header to itself, and the audio starts
+    // on a page of its own
+    try!(self.push_packet(mapping.as_slice(), 0));
+    try!(self.flush(false));
+
+    for (i, &(ty, ref data)) in blocks.iter().enumerate() {
+      let mut packet = metadata::header(i == blocks.len() - 1, ty, data.len()).to_vec();
+      packet.push_all(data.as_slice());
+
+      try!(self.push_packet(packet.as_slice(), 0));
+    }
+
+    if !self.lacing.is_empty() {
+      try!(self.flush(false));
+    }
+
+    return Ok(());
+  }
+
+  pub fn write_frame(&mut self, frame: &[u8]) -> IoResult<()> {
+    if self.first {
+      panic!("flac::ogg::Muxer: Frame came before the header (INPUT)");
+    }
+
+    if header::check(frame).is_none() {
+      panic!("flac::ogg::Muxer: Frame doesn't start with a frame header (INPUT)");
+    }
+
+    self.samples += valid!(header::Header::from(&mut SliceReader::new(frame))).block_size as u64;
+
+    let samples = self.samples;
+
+    return self.push_packet(frame, samples);
+  }
+
+  pub fn finish(mut self) -> IoResult<W> {
+    if self.lacing.is_empty() {
+      self.granule = self.samples;
+    }
+
+    try!(self.flush(true));
+
+    return Ok(self.writer);
+  }
+}
+
+// Wraps a native FLAC stream in Ogg, the seek table is left out since its
+// offsets don't mean anything in Ogg
+pub fn remux<R: Reader, W: Writer>(input: &mut R, output: W, serial: u32, page_size: uint) -> IoResult<W> {
+  let fourcc = try!(input.read_exact(4));
+
+  if fourcc.as_slice() != b"fLaC" {
+    panic!("flac::ogg::remux: Input doesn't start with fLaC (INPUT)");
+  }
+
+  let mut stream_info = None;
+  let mut blocks = Vec::new();
+  let mut last = false;
+
+  while !last {
+    let header = try!(input.read_u8());
+    let length = try!(input.read_be_uint_n(3)) as uint;
+    let data = try!(input.read_exact(length));
+
+    last = header & 0x80 != 0;
+
+    match header & 0x7F {
+      0 => stream_info = Some(valid!(stream_info::read(&data))),
+      3 => (),
+      ty => blocks.push((ty, data))
+    }
+  }
+
+  let stream_info = match stream_info {
+    Some(stream_info) => stream_info,
+    None => panic!("Metadata didn't contain a stream info, and it has to according to the spec")
+  };
+
+  let mut muxer = Muxer::new(output, serial, page_size);
+
+  try!(muxer.write_header(&stream_info, blocks.as_slice()));
+
+  let mut splitter = Splitter::new(&[], 1);
+
+  splitter.push(try!(input.read_to_end()).as_slice());
+
+  loop {
+    match splitter.next(true) {
+      Some(frame) => try!(muxer.write_frame(frame.as_slice())),
+      None => break
+    }
+  }
+
+  return muxer.finish();
+}
+
+#[cfg(test)]
+mod tests {
+  use std;
+  use aurora;
+
+  use decoder;
+  use demuxer;
+  use metadata;
+  use ogg;
+
+  use test_util::{encode, noise, parameters};
+
+  fn decode(data: Vec<u8>, is_ogg: bool) -> Vec<u8> {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, mut source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      if is_ogg {
+        ogg::Demuxer::new(source_0, sink_1, sink_md).run();
+      } else {
+        demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+      }
+    });
+
+    spawn(proc() {
+      decoder::Decoder::new(source_1, source_md, sink_a).run();
+    });
+
+    let mut output = Vec::new();
+    let mut last = false;
+
+    while !last {
+      source_a.read(|audio| {
+        output.push_all(audio.data.as_slice());
+        last = audio.last;
+      });
+    }
+
+    return output;
+  }
+
+  fn pages(data: &[u8]) -> Vec<ogg::Page> {
+    let mut pages = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+      let length = ogg::check(data.slice_from(offset)).unwrap();
+
+      pages.push(ogg::Page::from(data.slice(offset, offset + length)));
+      offset += length;
+    }
+
+    return pages;
+  }
+
+  #[test]
+  fn test_remux() {
+    let flac = encode(parameters(2, 1152), noise(2 * 20000, 16, 21).as_slice());
+
+    for &page_size in [255u, 4096, 1 << 15].iter() {
+      let data = super::remux(&mut std::io::MemReader::new(flac.clone()), std::io::MemWriter::new(), 0x1234, page_size).unwrap().get_ref().to_vec();
+
+      let pages = pages(data.as_slice());
+      let n = pages.len();
+
+      assert!(pages[0].first && !pages[0].continued);
+      assert_eq!(pages[0].body.len(), 51);
+      assert!(pages[n - 1].last);
+
+      let mut frames = 0u64;
+
+      for (i, page) in pages.iter().enumerate() {
+        assert_eq!(page.serial, 0x1234);
+        assert_eq!(page.sequence, i as u32);
+        assert!(page.body.len() < page_size + 255);
+
+        if i == 0 {
+          continue;
+        }
+
+        let complete = page.packets().iter().filter(|&&(_, complete)| complete).count() as u64;
+
+        frames += complete;
+
+        if complete > 0 {
+          assert_eq!(page.granule, std::cmp::min(1152 * frames, 20000));
+        } else {
+          assert_eq!(page.granule, ogg::NO_GRANULE);
+        }
+      }
+
+      assert_eq!(pages[n - 1].granule, 20000);
+      assert_eq!(decode(data, true), decode(flac.clone(), false));
+    }
+  }
+}
@@ -0,0 +1,390 @@
+use bitstream::SliceReader;
+use crc;
+use frame;
+use frame::header;
+use invalid::Invalid;
+use limits::{Limits, Exceeded};
+use metadata::stream_info;
+use metadata::stream_info::StreamInfo;
+use reader::Block;
+
+#[deriving(Show,PartialEq)]
+pub enum ParseError {
+  // Bytes that weren't part of a frame, skipped to get to the next header
+  Garbage(uint),
+
+  // The input ended in the middle of a frame
+  CutOff,
+
+  // A frame with more channels or samples than the limits allow, which is
+  // skipped
+  Limit(Exceeded),
+
+  // A frame whose CRC-16 matches but that doesn't decode, like one with a
+  // reserved subframe type or that leaves its sample rate to a STREAMINFO
+  // there isn't, which is skipped
+  Corrupt(Invalid)
+}
+
+pub enum Parsed {
+  NeedMoreData, Frame(Block), Error(ParseError)
+}
+
+// Parses frames out of chunks of input as they come in, like from a network
+// source, without ever waiting for more. Data before the first frame header,
+// say when joining a live stream, is skipped.
+//
+// Where a frame ends isn't in its header, so a frame is parsed once the
+// header of the next one is in, and the CRC-16 up to it matches. Frames are
+// decoded where they are and moved past, like the Splitter does it, so data
+// is copied about once.
+pub struct FrameParser {
+  data: Vec<u8>,
+  start: uint, // What's before it is parsed already
+  scan: uint,
+  synced: bool,
+  stream_info: Option<StreamInfo>,
+  limits: Limits,
+  limit: uint
+}
+
+impl FrameParser {
+  // Frames can leave their sample rate and size to STREAMINFO, which also
+  // says how large frames get. Without one, they're as large as the format
+  // allows, so a corrupted frame takes that much input to notice.
+  pub fn new(stream_info: Option<StreamInfo>) -> FrameParser {
+    return match FrameParser::with_limits(stream_info, Limits::new()) {
+      Ok(parser) => parser,
+      Err(exceeded) => panic!("flac::FrameParser: Limits::new() was exceeded with {} (BUG)", exceeded)
+    };
+  }
+
+  // Same as new, but STREAMINFO and every frame header is checked against the
+  // limits, and without STREAMINFO they say how large frames get
+  pub fn with_limits(stream_info: Option<StreamInfo>, limits: Limits) -> Result<FrameParser, Exceeded> {
+    let frame_size = match stream_info {
+      Some(ref stream_info) => {
+        try!(limits.check_stream_info(stream_info));
+
+        match stream_info.frame_size.1 {
+          0 => frame::max_size(stream_info::max_block_size(stream_info), stream_info.channels as uint, stream_info.bits_per_sample),
+          n => n as uint
+        }
+      },
+      None => frame::max_size(limits.max_block_size as uint, limits.max_channels as uint, 32)
+    };
+
+    return Ok(FrameParser {
+      data: Vec::new(),
+      start: 0,
+      scan: 0,
+      synced: false,
+      stream_info: stream_info,
+      limits: limits,
+      limit: frame_size + header::MAX_LENGTH
+    });
+  }
+
+  // Bytes fed that aren't parsed yet
+  fn len(&self) -> uint {
+    return self.data.len() - self.start;
+  }
+
+  // Where the n bytes that are cut out are in data
+  fn cut(&mut self, n: uint) -> (uint, uint) {
+    let frame = (self.start, self.start + n);
+
+    self.start += n;
+    self.scan = 0;
+
+    return frame;
+  }
+
+  // First frame header from start on
+  fn find(&mut self, start: uint, finished: bool, crc: bool) -> Option<uint> {
+    let data = self.data.slice_from(self.start);
+    let mut i = if self.scan > start { self.scan } else { start };
+
+    while i < data.len() {
+      if !finished && i + header::MAX_LENGTH > data.len() {
+        break;
+      }
+
+      if data[i] == 0xFF && header::check(data.slice_from(i)).is_some() && (!crc || crc::crc16(data.slice_to(i)) == 0) {
+        self.scan = i + 1;
+        return Some(i);
+      }
+
+      i += 1;
+    }
+
+    self.scan = i;
+
+    return None;
+  }
+
+  fn decode(&self, frame: (uint, uint)) -> Parsed {
+    let (start, end) = frame;
+    let data = self.data.slice(start, end);
+
+    let header = match header::Header::from(&mut SliceReader::new(data)) {
+      Ok(header) => header,
+      Err(invalid) => return Error(Corrupt(invalid))
+    };
+
+    match self.limits.check_frame(frame::channels(header.channel_assignment) as u8, header.block_size) {
+      Ok(()) => (),
+      Err(exceeded) => return Error(Limit(exceeded))
+    }
+
+    return match frame::decode(&mut SliceReader::new(data), self.stream_info.as_ref()) {
+      Ok((header, channels)) => Frame(Block { header: header, channels: channels }),
+      Err(invalid) => Error(Corrupt(invalid))
+    };
+  }
+
+  fn next(&mut self, finished: bool) -> Parsed {
+    if !self.synced {
+      match self.find(0, finished, false) {
+        Some(0) => {
+          self.synced = true;
+          self.scan = 0;
+        },
+        Some(n) => {
+          self.cut(n);
+          self.synced = true;
+
+          return Error(Garbage(n));
+        },
+        None if finished && self.len() > 0 => {
+          let n = self.len();
+          self.cut(n);
+
+          return Error(Garbage(n));
+        },
+        None => {
+          return NeedMoreData;
+        }
+      }
+    }
+
+    match self.find(1, finished, true) {
+      Some(n) => {
+        let frame = self.cut(n);
+
+        return self.decode(frame);
+      },
+      None if finished && self.len() == 0 => {
+        return NeedMoreData;
+      },
+      None if finished => {
+        let n = self.len();
+        let (start, end) = self.cut(n);
+
+        if crc::crc16(self.data.slice(start, end)) != 0 {
+          return Error(CutOff);
+        }
+
+        return self.decode((start, end));
+      },
+      None if self.len() > self.limit => {
+        // Longer than any frame, so it's corrupted, and parsing picks up
+        // again at the next header after its start
+        self.synced = false;
+
+        match self.find(1, false, false) {
+          Some(n) => {
+            self.cut(n);
+            self.synced = true;
+
+            return Error(Garbage(n));
+          },
+          None => {
+            // The end could still be the start of a header
+            let n = self.len() - header::MAX_LENGTH;
+            self.cut(n);
+
+            return Error(Garbage(n));
+          }
+        }
+      },
+      None => {
+        return NeedMoreData;
+      }
+    }
+  }
+
+  // Takes the next chunk of input, and returns the next frame there is. An
+  // empty chunk returns the frames that are already buffered.
+  pub fn feed(&mut self, data: &[u8]) -> Parsed {
+    let remaining = self.len();
+
+    if self.start > 0 && self.start >= remaining {
+      for i in range(0, remaining) {
+        self.data.as_mut_slice()[i] = self.data[self.start + i];
+      }
+
+      self.data.truncate(remaining);
+      self.start = 0;
+    }
+
+    self.data.push_all(data);
+
+    return self.next(false);
+  }
+
+  // Once the input has ended, returns what's left in the buffer, one frame at
+  // a time, and NeedMoreData once it's empty
+  pub fn finish(&mut self) -> Parsed {
+    return self.next(true);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std;
+
+  use frame;
+  use frame::header;
+  use limits;
+  use reader;
+
+  use test_util;
+  use test_util::noise;
+
+  fn encode() -> Vec<u8> {
+    let mut parameters = test_util::parameters(2, 576);
+    parameters.samples = Some(5000);
+
+    return test_util::encode(parameters, noise(2 * 5000, 16, 46).as_slice());
+  }
+
+  fn expected(flac: Vec<u8>) -> (Vec<reader::Block>, uint) {
+    let mut reader = reader::FlacReader::new(std::io::MemReader::new(flac)).unwrap();
+
+    let length = reader.metadata().iter().fold(4, |n, block| n + 4 + block.data.len());
+    let blocks = reader.blocks().map(|b| b.unwrap()).collect();
+
+    return (blocks, length);
+  }
+
+  fn parse(parser: &mut super::FrameParser, data: &[u8], chunk: uint) -> (Vec<reader::Block>, Vec<super::ParseError>) {
+    let mut blocks = Vec::new();
+    let mut errors = Vec::new();
+
+    {
+      let mut handle = |parsed: super::Parsed| -> bool {
+        match parsed {
+          super::Frame(block) => { blocks.push(block); true },
+          super::Error(error) => { errors.push(error); true },
+          super::NeedMoreData => false
+        }
+      };
+
+      for piece in data.chunks(chunk) {
+        let mut next = parser.feed(piece);
+
+        while handle(next) {
+          next = parser.feed(&[]);
+        }
+      }
+
+      while handle(parser.finish()) {}
+    }
+
+    return (blocks, errors);
+  }
+
+  #[test]
+  fn test_chunks() {
+    let flac = encode();
+    let (expected, start) = expected(flac.clone());
+
+    for &chunk in [1u, 7, 100, 4096].iter() {
+      let mut parser = super::FrameParser::new(None);
+
+      let (blocks, errors) = parse(&mut parser, flac.slice_from(start), chunk);
+
+      assert_eq!(errors, vec![]);
+      assert_eq!(blocks.len(), expected.len());
+
+      for (block, expected) in blocks.iter().zip(expected.iter()) {
+        assert_eq!(block.header, expected.header);
+        assert_eq!(block.channels, expected.channels);
+      }
+    }
+  }
+
+  #[test]
+  fn test_joining_and_cut_off() {
+    let flac = encode();
+    let (expected, start) = expected(flac.clone());
+
+    // Joins in the middle of the first frame and stops in the middle of the
+    // last one
+    let data = flac.slice(start + 100, flac.len() - 10);
+
+    let mut parser = super::FrameParser::new(None);
+
+    let (blocks, errors) = parse(&mut parser, data, 333);
+
+    assert_eq!(blocks.len(), expected.len() - 2);
+    assert_eq!(blocks[0].channels, expected[1].channels);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[1], super::CutOff);
+
+    match errors[0] {
+      super::Garbage(n) => assert!(n > 0),
+      _ => panic!("Didn't skip to the second frame")
+    }
+  }
+
+  #[test]
+  fn test_corrupt_frames() {
+    let flac = encode();
+    let (expected, start) = expected(flac.clone());
+
+    let data = flac.slice_from(start);
+    let length = header::check(data).unwrap();
+
+    // A reserved subframe type in the first frame, and a first frame that
+    // leaves its sample rate to a STREAMINFO the parser doesn't have, under
+    // CRCs that still match
+    for &(i, value) in [(length, 0x02 << 1), (2, data[2] & 0xF0)].iter() {
+      let mut corrupt = data.to_vec();
+      frame::tests::rewrite(corrupt.as_mut_slice(), i, value);
+
+      let mut parser = super::FrameParser::new(None);
+
+      let (blocks, errors) = parse(&mut parser, corrupt.as_slice(), 100);
+
+      assert_eq!(blocks.len(), expected.len() - 1);
+      assert_eq!(blocks[0].channels, expected[1].channels);
+      assert_eq!(errors.len(), 1);
+
+      match errors[0] {
+        super::Corrupt(_) => (),
+        _ => panic!("Decoded a frame that doesn't decode")
+      }
+    }
+  }
+
+  #[test]
+  fn test_limits() {
+    let flac = encode();
+    let (expected, start) = expected(flac.clone());
+
+    let limits = limits::Limits { max_block_size: 512, ..limits::Limits::new() };
+    let mut parser = super::FrameParser::with_limits(None, limits.clone()).unwrap();
+
+    let (blocks, errors) = parse(&mut parser, flac.slice_from(start), 1000);
+
+    // Only the last frame is short enough
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].channels, expected[expected.len() - 1].channels);
+    assert_eq!(errors.len(), expected.len() - 1);
+    assert!(errors.iter().all(|e| *e == super::Limit(limits::BlockSize(576))));
+
+    assert_eq!(super::FrameParser::with_limits(Some(reader::FlacReader::new(std::io::MemReader::new(flac)).unwrap().stream_info().clone()), limits).err(), Some(limits::BlockSize(576)));
+  }
+}
@@ -0,0 +1,441 @@
+use std::io::{IoError, IoResult, EndOfFile, InvalidInput};
+
+use bitstream::SliceReader;
+use crc;
+use decoder::parallel::Splitter;
+use frame;
+use frame::Sample;
+use frame::header;
+use frame::header::Header;
+use invalid::Invalid;
+use limits::{Limits, Exceeded};
+use metadata;
+use metadata::id3;
+use metadata::stream_info;
+use metadata::stream_info::StreamInfo;
+
+// Decodes in the calling thread, without aurora channels. Frames are cut out
+// of the input the same way the parallel decoder does it, and decoded from
+// memory, so a stream that is cut off ends in an error instead of a decoder
+// reading past its input.
+pub struct FlacReader<R> {
+  input: R,
+  splitter: Splitter,
+  finished: bool,
+  stream_info: StreamInfo,
+  metadata: Vec<metadata::Metadata>,
+  limits: Limits,
+  max_frame_size: uint
+}
+
+// Samples of one frame, a Vec for each channel
+pub struct Block {
+  pub header: Header,
+  pub channels: Vec<Vec<i32>>
+}
+
+impl Block {
+  // See frame::planar
+  pub fn planar<S: Sample>(&self, buffers: &mut [&mut [S]]) -> uint {
+    return frame::planar(&self.header, self.channels.as_slice(), buffers);
+  }
+
+  // See frame::interleaved
+  pub fn interleaved<S: Sample>(&self, output: &mut [S]) -> uint {
+    return frame::interleaved(&self.header, self.channels.as_slice(), output);
+  }
+}
+
+// Opening a reader or reading a block can fail either way
+#[deriving(Show)]
+pub enum Error {
+  Io(IoError),
+  Limit(Exceeded)
+}
+
+// try! for I/O in functions that return an Error
+macro_rules! io(
+  ($e:expr) => (match $e { Ok(value) => value, Err(error) => return Err(Io(error)) })
+)
+
+macro_rules! limit(
+  ($e:expr) => (match $e { Ok(()) => (), Err(exceeded) => return Err(Limit(exceeded)) })
+)
+
+// What the parsers reject is InvalidInput, like a frame whose CRC doesn't
+// match
+pub fn invalid_input(invalid: Invalid) -> Error {
+  return Io(IoError { kind: InvalidInput, desc: invalid.desc(), detail: None });
+}
+
+impl<R: Reader> FlacReader<R> {
+  // Reads up to the first frame, skipping ID3v2 tags in front of fLaC
+  pub fn new(input: R) -> IoResult<FlacReader<R>> {
+    return match FlacReader::with_limits(input, Limits::new()) {
+      Ok(reader) => Ok(reader),
+      Err(Io(error)) => Err(error),
+      Err(Limit(exceeded)) => panic!("flac::FlacReader: Limits::new() was exceeded with {} (BUG)", exceeded)
+    };
+  }
+
+  // Same as new, but every tag and block is checked against the limits
+  // before it's read, and STREAMINFO before any frame is. Frames that exceed
+  // them come up as Limit errors.
+  pub fn with_limits(mut input: R, limits: Limits) -> Result<FlacReader<R>, Error> {
+    let mut total = 0;
+    let mut fourcc = io!(input.read_exact(4));
+
+    while id3::is_tag(fourcc.as_slice()) {
+      fourcc.push_all(io!(input.read_exact(id3::HEADER_LENGTH - 4)).as_slice());
+
+      let length = try!(id3::length(fourcc.as_slice()).map_err(invalid_input));
+
+      limit!(limits.check_metadata(length, total));
+      total += length;
+
+      io!(input.read_exact(length - id3::HEADER_LENGTH));
+
+      fourcc = io!(input.read_exact(4));
+    }
+
+    if fourcc.as_slice() != b"fLaC" {
+      return Err(Io(IoError { kind: InvalidInput, desc: "flac::FlacReader: Input doesn't start with fLaC", detail: None }));
+    }
+
+    let mut blocks = Vec::new();
+    let mut last = false;
+
+    while !last {
+      let mut block = io!(input.read_exact(4));
+      let length = (block[1] as uint << 16) | (block[2] as uint << 8) | block[3] as uint;
+
+      if blocks.is_empty() && block[0] & 0x7F != 0 {
+        return Err(Io(IoError { kind: InvalidInput, desc: "flac::FlacReader: Metadata doesn't start with STREAMINFO", detail: None }));
+      }
+
+      limit!(limits.check_block(block[0] & 0x7F, length, total));
+      total += length;
+
+      block.push_all(io!(input.read_exact(length)).as_slice());
+
+      let mut metadata = metadata::Metadata { ty: metadata::Unknown, data: Vec::new(), last: false };
+
+      last = try!(metadata::from_bytes(block.as_slice(), &mut metadata).map_err(invalid_input));
+      blocks.push(metadata);
+    }
+
+    let stream_info = match blocks[0].ty {
+      metadata::StreamInfo(ref stream_info) => stream_info.clone(),
+      _ => panic!("flac::FlacReader: Block of type 0 isn't STREAMINFO (BUG)")
+    };
+
+    limit!(limits.check_stream_info(&stream_info));
+
+    let max_frame_size = frame::max_size(stream_info::max_block_size(&stream_info), stream_info.channels as uint, stream_info.bits_per_sample);
+
+    return Ok(FlacReader {
+      input: input,
+      splitter: Splitter::new(&[], 1),
+      finished: false,
+      stream_info: stream_info,
+      metadata: blocks,
+      limits: limits,
+      max_frame_size: max_frame_size
+    });
+  }
+
+  pub fn stream_info(&self) -> &StreamInfo {
+    return &self.stream_info;
+  }
+
+  // All metadata blocks, STREAMINFO first
+  pub fn metadata(&self) -> &[metadata::Metadata] {
+    return self.metadata.as_slice();
+  }
+
+  // None once the input ends
+  pub fn next_block(&mut self) -> Option<Result<Block, Error>> {
+    loop {
+      match self.splitter.next(self.finished) {
+        Some(frame) => {
+          if crc::crc16(frame.as_slice()) != 0 {
+            let last = self.finished && self.splitter.is_empty();
+
+            return Some(Err(Io(if frame::is_cut_off(frame.as_slice(), last) {
+              IoError { kind: EndOfFile, desc: "flac::FlacReader: Input ended in the middle of a frame", detail: None }
+            } else {
+              IoError { kind: InvalidInput, desc: "flac::FlacReader: Frame CRC-16 doesn't match", detail: None }
+            })));
+          }
+
+          return Some(self.decode(frame.as_slice()));
+        },
+        None if self.finished => return None,
+        // Up to the largest frame there can be, and the header after it
+        None if self.splitter.len() > self.max_frame_size + header::MAX_LENGTH => {
+          self.finished = true;
+          self.splitter = Splitter::new(&[], 1);
+
+          return Some(Err(Io(IoError { kind: InvalidInput, desc: "flac::FlacReader: Frame is larger than STREAMINFO allows", detail: None })));
+        },
+        None => {
+          let mut buffer = [0x00u8, ..4096];
+
+          match self.input.read(buffer) {
+            Ok(n) => self.splitter.push(buffer.slice_to(n)),
+            Err(ref error) if error.kind == EndOfFile => self.finished = true,
+            Err(error) => return Some(Err(Io(error)))
+          }
+        }
+      }
+    }
+  }
+
+  // Of a frame whose CRC-16 matches
+  fn decode(&self, frame: &[u8]) -> Result<Block, Error> {
+    let header = try!(Header::from(&mut SliceReader::new(frame)).map_err(invalid_input));
+
+    limit!(self.limits.check_frame(frame::channels(header.channel_assignment) as u8, header.block_size));
+
+    let (header, channels) = try!(frame::decode(&mut SliceReader::new(frame), Some(&self.stream_info)).map_err(invalid_input));
+
+    return Ok(Block { header: header, channels: channels });
+  }
+
+  pub fn blocks<'a>(&'a mut self) -> Blocks<'a, R> {
+    return Blocks { reader: self };
+  }
+}
+
+pub struct Blocks<'a, R: 'a> {
+  reader: &'a mut FlacReader<R>
+}
+
+impl<'a, R: Reader> Iterator<Result<Block, Error>> for Blocks<'a, R> {
+  fn next(&mut self) -> Option<Result<Block, Error>> {
+    return self.reader.next_block();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std;
+  use aurora;
+
+  use decoder;
+  use demuxer;
+  use frame;
+  use frame::header;
+  use limits;
+  use metadata;
+
+  use metadata::id3;
+  use metadata::seek_table;
+  use test_util;
+  use test_util::noise;
+
+  fn encode(samples: &[i32]) -> Vec<u8> {
+    let mut parameters = test_util::parameters(2, 1152);
+    parameters.seek_table = Some(seek_table::EverySamples(4096));
+    parameters.samples = Some(samples.len() as u64 / 2);
+
+    return test_util::encode(parameters, samples);
+  }
+
+  fn decode(data: Vec<u8>) -> Vec<u8> {
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, mut source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+    });
+
+    spawn(proc() {
+      decoder::Decoder::new(source_1, source_md, sink_a).run();
+    });
+
+    let mut output = Vec::new();
+    let mut last = false;
+
+    while !last {
+      source_a.read(|audio| {
+        output.push_all(audio.data.as_slice());
+        last = audio.last;
+      });
+    }
+
+    return output;
+  }
+
+  #[test]
+  fn test_matches_decoder() {
+    let flac = encode(noise(2 * 10000, 16, 41).as_slice());
+
+    let mut reader = super::FlacReader::new(std::io::MemReader::new(flac.clone())).unwrap();
+
+    assert_eq!(reader.stream_info().samples, 10000);
+    assert_eq!(reader.metadata().len(), 2);
+
+    match reader.metadata()[1].ty {
+      metadata::SeekTable(ref points) => assert_eq!(points.len(), 3),
+      _ => panic!("Second block isn't the seek table")
+    }
+
+    let mut output = Vec::new();
+    let mut samples = [0i16, ..2 * 1152];
+
+    for block in reader.blocks() {
+      let n = block.unwrap().interleaved(samples.as_mut_slice());
+
+      for &sample in samples.slice_to(n).iter() {
+        output.push((sample >> 8) as u8);
+        output.push(sample as u8);
+      }
+    }
+
+    assert_eq!(output, decode(flac));
+  }
+
+  #[test]
+  fn test_id3_and_cut_off_input() {
+    let flac = encode(noise(2 * 10000, 16, 42).as_slice());
+
+    let mut data = id3::tests::tag("Cut", true);
+    data.push_all(flac.slice_to(flac.len() - 100));
+
+    let mut reader = super::FlacReader::new(std::io::MemReader::new(data)).unwrap();
+
+    let blocks: Vec<Result<super::Block, super::Error>> = reader.blocks().collect();
+    let n = blocks.len();
+
+    assert_eq!(n, (10000 + 1151) / 1152);
+    assert!(blocks.slice_to(n - 1).iter().all(|b| b.is_ok()));
+
+    match blocks[n - 1] {
+      Err(super::Io(ref error)) => assert_eq!(error.kind, std::io::EndOfFile),
+      _ => panic!("Cut off frame decoded")
+    }
+  }
+
+  #[test]
+  fn test_corrupt_frame() {
+    let mut flac = encode(noise(2 * 10000, 16, 43).as_slice());
+
+    let i = flac.len() / 2;
+    flac.as_mut_slice()[i] ^= 0x10;
+
+    let mut reader = super::FlacReader::new(std::io::MemReader::new(flac)).unwrap();
+
+    let blocks: Vec<Result<super::Block, super::Error>> = reader.blocks().collect();
+
+    match blocks.last() {
+      Some(&Err(super::Io(ref error))) => assert_eq!(error.kind, std::io::InvalidInput),
+      _ => panic!("Corrupt frame decoded")
+    }
+
+    // A reserved subframe type in the first frame, which is right after
+    // STREAMINFO and the seek table, under CRCs that still match
+    let mut flac = encode(noise(2 * 10000, 16, 43).as_slice());
+    let start = 4 + 4 + 34 + 4 + 3 * 18;
+    let length = header::check(flac.slice_from(start)).unwrap();
+
+    frame::tests::rewrite(flac.slice_from_mut(start), length, 0x02 << 1);
+
+    let mut reader = super::FlacReader::new(std::io::MemReader::new(flac)).unwrap();
+
+    match reader.next_block() {
+      Some(Err(super::Io(ref error))) => assert_eq!(error.kind, std::io::InvalidInput),
+      _ => panic!("Frame with a reserved subframe type decoded")
+    }
+
+    assert!(reader.next_block().unwrap().is_ok());
+  }
+
+  fn invalid(data: Vec<u8>) {
+    match super::FlacReader::new(std::io::MemReader::new(data)) {
+      Err(ref error) => assert_eq!(error.kind, std::io::InvalidInput),
+      Ok(_) => panic!("Invalid input opened")
+    }
+  }
+
+  #[test]
+  fn test_invalid_start() {
+    let flac = encode(noise(2 * 100, 16, 45).as_slice());
+
+    let mut not_flac = flac.clone();
+    not_flac.as_mut_slice()[3] = b'c';
+
+    invalid(not_flac);
+
+    // A padding block before STREAMINFO
+    let mut padding_first = b"fLaC".to_vec();
+    padding_first.push_all(&metadata::header(false, 1, 0));
+    padding_first.push_all(flac.slice_from(4));
+
+    invalid(padding_first);
+
+    // STREAMINFO that says it's 33 bytes
+    let mut short = flac.slice_to(4 + 4 + 33).to_vec();
+    short.as_mut_slice()[7] = 33;
+    short.push_all(flac.slice_from(4 + 4 + 34));
+
+    invalid(short);
+  }
+
+  fn open(data: Vec<u8>, limits: limits::Limits) -> Option<limits::Exceeded> {
+    return match super::FlacReader::with_limits(std::io::MemReader::new(data), limits) {
+      Ok(_) => None,
+      Err(super::Limit(exceeded)) => Some(exceeded),
+      Err(super::Io(error)) => panic!("{}", error)
+    };
+  }
+
+  #[test]
+  fn test_limits() {
+    // STREAMINFO of 34 bytes, and a seek table of 3 points
+    let flac = encode(noise(2 * 10000, 16, 44).as_slice());
+
+    assert_eq!(open(flac.clone(), limits::Limits::new()), None);
+    assert_eq!(open(flac.clone(), limits::Limits { max_metadata_block: 34, ..limits::Limits::new() }), Some(limits::MetadataBlock(54)));
+    assert_eq!(open(flac.clone(), limits::Limits { max_metadata: 87, ..limits::Limits::new() }), Some(limits::Metadata(88)));
+    assert_eq!(open(flac.clone(), limits::Limits { max_channels: 1, ..limits::Limits::new() }), Some(limits::Channels(2)));
+    assert_eq!(open(flac.clone(), limits::Limits { max_block_size: 1024, ..limits::Limits::new() }), Some(limits::BlockSize(1152)));
+
+    // A block that says it's 16 MiB, with nothing after it
+    let mut huge = b"fLaC".to_vec();
+    huge.push_all(&metadata::header(true, 4, (1 << 24) - 1));
+
+    assert_eq!(open(huge, limits::Limits { max_metadata_block: 1 << 20, ..limits::Limits::new() }), Some(limits::MetadataBlock((1 << 24) - 1)));
+
+    // Frames of more samples than STREAMINFO says
+    let limits = limits::Limits { max_block_size: 1024, ..limits::Limits::new() };
+
+    match lying(encode(Vec::from_elem(2 * 10000, 0i32).as_slice()), limits.clone()) {
+      Some(Err(super::Limit(exceeded))) => assert_eq!(exceeded, limits::BlockSize(1152)),
+      _ => panic!("Frame over the limits decoded")
+    }
+
+    // And larger than STREAMINFO allows, which isn't buffered to the end
+    match lying(flac, limits) {
+      Some(Err(super::Io(ref error))) => assert_eq!(error.kind, std::io::InvalidInput),
+      _ => panic!("Frame larger than STREAMINFO allows decoded")
+    }
+  }
+
+  // Says the largest block is 1024 samples
+  fn lying(flac: Vec<u8>, limits: limits::Limits) -> Option<Result<super::Block, super::Error>> {
+    let mut lying = flac;
+    lying.as_mut_slice()[8 + 2] = 0x04;
+    lying.as_mut_slice()[8 + 3] = 0x00;
+
+    let mut reader = super::FlacReader::with_limits(std::io::MemReader::new(lying), limits).unwrap();
+
+    return reader.next_block();
+  }
+}
\ No newline at end of file
@@ -0,0 +1,115 @@
+// Which vector instructions the CPU has, for the LPC and Rice kernels that
+// are written for each of them. Detected once at runtime, so the crate runs
+// the same build on CPUs with and without them.
+
+use prelude::*;
+
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, Relaxed};
+#[cfg(not(feature = "std"))]
+use core::atomic::{AtomicUint, INIT_ATOMIC_UINT, Relaxed};
+
+#[deriving(Show,PartialEq)]
+pub enum Kernel {
+  Scalar,
+  Sse41, // x86_64, 128 bit vectors
+  Avx2,  // x86_64, 256 bit vectors
+  Neon   // aarch64, 128 bit vectors
+}
+
+static KERNEL: AtomicUint = INIT_ATOMIC_UINT;
+
+static X86_64: [Kernel, ..3] = [Scalar, Sse41, Avx2];
+static AARCH64: [Kernel, ..2] = [Scalar, Neon];
+
+#[cfg(target_arch = "x86_64")]
+fn cpuid(leaf: u32) -> (u32, u32, u32) {
+  let eax: u32;
+  let ebx: u32;
+  let ecx: u32;
+
+  unsafe {
+    asm!("cpuid" : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx) : "{eax}"(leaf), "{ecx}"(0u32) : "edx" : "volatile");
+  }
+
+  return (eax, ebx, ecx);
+}
+
+// Which register state the OS saves on context switches, bit 1 for XMM and
+// bit 2 for YMM
+#[cfg(target_arch = "x86_64")]
+fn xgetbv() -> u32 {
+  let eax: u32;
+
+  unsafe {
+    asm!("xgetbv" : "={eax}"(eax) : "{ecx}"(0u32) : "edx" : "volatile");
+  }
+
+  return eax;
+}
+
+#[cfg(target_arch = "x86_64")]
+fn detect() -> Kernel {
+  let (max_leaf, _, _) = cpuid(0);
+  let (_, _, features) = cpuid(1);
+
+  // AVX2 is in leaf 7, and needs the OS to save the YMM registers, which
+  // XGETBV says once OSXSAVE says it can be used
+  let avx2 = max_leaf >= 7 && {
+    let (_, extended, _) = cpuid(7);
+
+    extended & (1 << 5) != 0
+  };
+
+  let ymm = features & (1 << 27) != 0 && features & (1 << 28) != 0 && xgetbv() & 0b110 == 0b110;
+
+  if avx2 && ymm {
+    return Avx2;
+  }
+
+  // PMULLD, for 32 bit products, is SSE4.1
+  if features & (1 << 19) != 0 {
+    return Sse41;
+  }
+
+  return Scalar;
+}
+
+// NEON is always there on aarch64
+#[cfg(target_arch = "aarch64")]
+fn detect() -> Kernel {
+  return Neon;
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn detect() -> Kernel {
+  return Scalar;
+}
+
+// Best kernel for this CPU, detected once
+pub fn kernel() -> Kernel {
+  return match KERNEL.load(Relaxed) {
+    1 => Scalar,
+    2 => Sse41,
+    3 => Avx2,
+    4 => Neon,
+    _ => {
+      let kernel = detect();
+
+      KERNEL.store(match kernel { Scalar => 1, Sse41 => 2, Avx2 => 3, Neon => 4 }, Relaxed);
+
+      kernel
+    }
+  };
+}
+
+// Every kernel this CPU can run, the best last, for the tests to check each
+// of them against Scalar
+pub fn kernels() -> &'static [Kernel] {
+  return match kernel() {
+    Scalar => X86_64.slice_to(1),
+    Sse41 => X86_64.slice_to(2),
+    Avx2 => X86_64.as_slice(),
+    Neon => AARCH64.as_slice()
+  };
+}
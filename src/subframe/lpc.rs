@@ -0,0 +1,261 @@
+// Restores LPC subframes from their residual. Predictions for the orders
+// encoders use most are vectorized, when they fit 32 bit lanes, with a kernel
+// for each of SSE4.1 and AVX2 on x86_64 and NEON on aarch64, picked by what
+// simd::kernel() detects. The vectors are loaded straight from the
+// coefficients and samples.
+
+use prelude::*;
+
+use simd;
+use simd::{Kernel, Scalar};
+
+fn scalar(coefficients: &[i32], shift: uint, samples: &mut [i32]) {
+  let order = coefficients.len();
+
+  for n in range(order, samples.len()) {
+    let mut sum = 0i64;
+
+    for (j, &c) in coefficients.iter().enumerate() {
+      sum += c as i64 * samples[n - 1 - j] as i64;
+    }
+
+    samples[n] = ((sum >> shift) + samples[n] as i64) as i32;
+  }
+}
+
+// The predictors sum the products of order reversed coefficients and the
+// order samples before the one predicted, order is a multiple of 4
+type Predictor = unsafe fn(*const i32, *const i32, uint) -> i32;
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn predict_sse41(reversed: *const i32, window: *const i32, order: uint) -> i32 {
+  let sum: i32;
+  let mut reversed = reversed;
+  let mut window = window;
+  let mut order = order;
+
+  asm!("pxor %xmm0, %xmm0
+      1:
+        movdqu ($1), %xmm1
+        movdqu ($2), %xmm2
+        pmulld %xmm2, %xmm1
+        paddd %xmm1, %xmm0
+        add $$16, $1
+        add $$16, $2
+        sub $$4, $3
+        jnz 1b
+        pshufd $$0x4E, %xmm0, %xmm1
+        paddd %xmm1, %xmm0
+        pshufd $$0xB1, %xmm0, %xmm1
+        paddd %xmm1, %xmm0
+        movd %xmm0, $0"
+    : "=r"(sum), "+r"(reversed), "+r"(window), "+r"(order)
+    :
+    : "xmm0", "xmm1", "xmm2", "cc");
+
+  return sum;
+}
+
+// 8 products at a time, then 4 for orders like 12
+#[cfg(target_arch = "x86_64")]
+unsafe fn predict_avx2(reversed: *const i32, window: *const i32, order: uint) -> i32 {
+  let sum: i32;
+  let mut reversed = reversed;
+  let mut window = window;
+  let mut order = order;
+
+  asm!("vpxor %ymm0, %ymm0, %ymm0
+        cmp $$8, $3
+        jb 2f
+      1:
+        vmovdqu ($1), %ymm1
+        vpmulld ($2), %ymm1, %ymm1
+        vpaddd %ymm1, %ymm0, %ymm0
+        add $$32, $1
+        add $$32, $2
+        sub $$8, $3
+        cmp $$8, $3
+        jae 1b
+      2:
+        test $3, $3
+        jz 3f
+        vmovdqu ($1), %xmm1
+        vpmulld ($2), %xmm1, %xmm1
+        vpaddd %ymm1, %ymm0, %ymm0
+      3:
+        vextracti128 $$1, %ymm0, %xmm1
+        vpaddd %xmm1, %xmm0, %xmm0
+        vpshufd $$0x4E, %xmm0, %xmm1
+        vpaddd %xmm1, %xmm0, %xmm0
+        vpshufd $$0xB1, %xmm0, %xmm1
+        vpaddd %xmm1, %xmm0, %xmm0
+        vmovd %xmm0, $0
+        vzeroupper"
+    : "=r"(sum), "+r"(reversed), "+r"(window), "+r"(order)
+    :
+    : "xmm0", "xmm1", "cc");
+
+  return sum;
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn predict_neon(reversed: *const i32, window: *const i32, order: uint) -> i32 {
+  let sum: i32;
+  let mut reversed = reversed;
+  let mut window = window;
+  let mut order = order;
+
+  asm!("movi v0.4s, #0
+      1:
+        ld1 {v1.4s}, [$1], #16
+        ld1 {v2.4s}, [$2], #16
+        mla v0.4s, v1.4s, v2.4s
+        subs $3, $3, #4
+        b.ne 1b
+        addv s0, v0.4s
+        umov ${0:w}, v0.s[0]"
+    : "=r"(sum), "+r"(reversed), "+r"(window), "+r"(order)
+    :
+    : "v0", "v1", "v2", "cc");
+
+  return sum;
+}
+
+#[cfg(target_arch = "x86_64")]
+fn predictor(kernel: Kernel) -> Predictor {
+  return match kernel {
+    simd::Sse41 => predict_sse41,
+    simd::Avx2 => predict_avx2,
+    _ => panic!("flac::lpc: No {} kernel on x86_64 (BUG)", kernel)
+  };
+}
+
+#[cfg(target_arch = "aarch64")]
+fn predictor(kernel: Kernel) -> Predictor {
+  return match kernel {
+    simd::Neon => predict_neon,
+    _ => panic!("flac::lpc: No {} kernel on aarch64 (BUG)", kernel)
+  };
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn predictor(kernel: Kernel) -> Predictor {
+  panic!("flac::lpc: No {} kernel on this target (BUG)", kernel);
+}
+
+// Same as restore, with the kernel given, which has to be one of
+// simd::kernels()
+pub fn restore_with(kernel: Kernel, coefficients: &[i32], shift: uint, samples: &mut [i32]) {
+  let order = coefficients.len();
+
+  if kernel == Scalar || order == 0 || order % 4 != 0 {
+    return scalar(coefficients, shift, samples);
+  }
+
+  let predict = predictor(kernel);
+
+  // Reversed, so they line up with the samples before n
+  let mut reversed = [0i32, ..32];
+
+  for (j, &c) in coefficients.iter().enumerate() {
+    reversed[order - 1 - j] = c;
+  }
+
+  for n in range(order, samples.len()) {
+    let prediction = unsafe { predict(reversed.as_ptr(), samples.as_ptr().offset((n - order) as int), order) };
+
+    samples[n] = (prediction >> shift) + samples[n];
+  }
+}
+
+// Samples after the warm-up ones hold the residual, and get restored in place.
+// Predictions are summed in 32 bits only when bits, precision and order say
+// they can't overflow.
+pub fn restore(coefficients: &[i32], shift: uint, bits: u8, precision: u8, samples: &mut [i32]) {
+  let order = coefficients.len();
+  let order_bits = match order { 0...1 => 0u, 2 => 1, 3...4 => 2, 5...8 => 3, 9...16 => 4, _ => 5 };
+
+  let fits = bits as uint + precision as uint + order_bits <= 32;
+
+  let kernel = match order {
+    8 | 12 | 32 if fits => simd::kernel(),
+    _ => Scalar
+  };
+
+  restore_with(kernel, coefficients, shift, samples);
+}
+
+#[cfg(test)]
+mod tests {
+  use test;
+
+  use test_util::noise;
+
+  // Residual sized noise after warm-up samples, and coefficients that keep
+  // the sums in 32 bits
+  fn subframe(order: uint, seed: u32) -> (Vec<i32>, Vec<i32>) {
+    let samples: Vec<i32> = noise(4096, 16, seed).iter().map(|&n| n >> 4).collect();
+    let coefficients: Vec<i32> = noise(order, 16, seed + 1).iter().map(|&n| n >> 12).collect();
+
+    return (samples, coefficients);
+  }
+
+  #[test]
+  fn test_kernels_match_scalar() {
+    for &order in [1u, 4, 8, 12, 16, 31, 32].iter() {
+      let (samples, coefficients) = subframe(order, order as u32);
+
+      let mut expected = samples.clone();
+      super::restore_with(::simd::Scalar, coefficients.as_slice(), 9, expected.as_mut_slice());
+
+      for &kernel in ::simd::kernels().iter() {
+        let mut restored = samples.clone();
+        super::restore_with(kernel, coefficients.as_slice(), 9, restored.as_mut_slice());
+
+        assert_eq!(restored, expected);
+      }
+
+      let mut restored = samples.clone();
+      super::restore(coefficients.as_slice(), 9, 12, 12, restored.as_mut_slice());
+
+      assert_eq!(restored, expected);
+    }
+  }
+
+  #[test]
+  fn test_wide_sums_stay_scalar() {
+    // 24 bit samples with 15 bit coefficients overflow 32 bits
+    let samples: Vec<i32> = noise(1024, 16, 50).iter().map(|&n| n << 8).collect();
+    let coefficients: Vec<i32> = noise(8, 16, 51).iter().map(|&n| n >> 1).collect();
+
+    let mut expected = samples.clone();
+    super::restore_with(::simd::Scalar, coefficients.as_slice(), 14, expected.as_mut_slice());
+
+    let mut restored = samples.clone();
+    super::restore(coefficients.as_slice(), 14, 24, 15, restored.as_mut_slice());
+
+    assert_eq!(restored, expected);
+  }
+
+  fn bench(b: &mut test::Bencher, kernel: ::simd::Kernel) {
+    let (samples, coefficients) = subframe(12, 52);
+    let mut restored = samples.clone();
+
+    b.bytes = 4 * samples.len() as u64;
+
+    b.iter(|| {
+      restored.clone_from(&samples);
+      super::restore_with(kernel, coefficients.as_slice(), 9, restored.as_mut_slice());
+    });
+  }
+
+  #[bench]
+  fn bench_order_12_scalar(b: &mut test::Bencher) {
+    bench(b, ::simd::Scalar);
+  }
+
+  #[bench]
+  fn bench_order_12_detected(b: &mut test::Bencher) {
+    bench(b, ::simd::kernel());
+  }
+}
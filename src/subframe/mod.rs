@@ -1,5 +1,12 @@
-use std;
-use aurora;
+use prelude::*;
+
+#[cfg(test)]
+use bitstream::SliceReader;
+
+use bitstream::BitRead;
+use invalid::Invalid;
+
+pub mod lpc;
 
 #[deriving(Show,PartialEq)]
 enum Ty {
@@ -12,78 +19,91 @@ pub struct Header {
 }
 
 impl Header {
-  pub fn from(stream: &mut aurora::stream::Bitstream) -> Header {
-    assert_eq!(stream.read_n(1), 0);
+  pub fn from<B: BitRead>(stream: &mut B) -> Result<Header, Invalid> {
+    if try!(stream.read_n(1)) != 0 {
+      return Err(Invalid("flac::Decoder: Subframe doesn't start with a zero bit"));
+    }
 
-    let ty_code = stream.read_n(6);
+    let ty_code = try!(stream.read_n(6));
 
     let ty = if ty_code & 0b100000u32 != 0 {
       LPC((ty_code as u8 & 0b011111u8) + 1)
-    } else if ty_code & 0b001000u32 != 0 {
+    } else if ty_code & 0b001000u32 != 0 && ty_code & 0b000111u32 <= 4 {
       Fixed(ty_code as u8 & 0b000111u8)
     } else if ty_code == 0b000001u32 {
       Verbatim
     } else if ty_code == 0b000000u32 {
       Constant
     } else {
-      panic!("flac::Decoder: Value is reserved");
+      return Err(Invalid("flac::Decoder: Subframe type is reserved"));
     };
 
-    let wasted = if stream.read_n(1) == 1 {
-      let mut n = 1u8;
-      while stream.read_n(1) == 0 {
-        n += 1;
-      }
-      n
+    let wasted = if try!(stream.read_n(1)) == 1 {
+      try!(stream.read_unary()) + 1
     } else {
       0
     };
 
-    return Header {
+    // Samples are at most 32 bits, so this is more than read_into allows
+    if wasted > 32 {
+      return Err(Invalid("flac::Decoder: More wasted bits in a subframe than it has"));
+    }
+
+    return Ok(Header {
       ty: ty,
-      wasted_bits: wasted
-    };
+      wasted_bits: wasted as u8
+    });
   }
 }
 
 #[deriving(Show,PartialEq)]
 pub struct LPCSubframe {
-  warmup: Vec<uint>,
+  warmup: Vec<i32>,
   precision: u8,
   shift: i8,
-  coefficients: Vec<int>
+  coefficients: Vec<i32>
 }
 
 impl LPCSubframe {
-  pub fn from(frame_header: &::frame::header::Header, subframe_header: &Header, stream: &mut aurora::stream::Bitstream) -> LPCSubframe {
-    let bits_per_sample = frame_header.sample_size;
+  pub fn from<B: BitRead>(frame_header: &::frame::header::Header, subframe_header: &Header, stream: &mut B) -> Result<LPCSubframe, Invalid> {
+    return LPCSubframe::read(frame_header.sample_size, subframe_header, stream);
+  }
+
+  fn read<B: BitRead>(bits_per_sample: u8, subframe_header: &Header, stream: &mut B) -> Result<LPCSubframe, Invalid> {
     let order = match subframe_header.ty {
       LPC(n) => n,
-      _ => panic!("Cannot extract order from non LPC subframe")
+      _ => panic!("flac::Decoder: Cannot extract order from non LPC subframe (BUG)")
     };
 
     let mut warmup = Vec::new();
 
     for _ in range(0, order) {
-      warmup.push(stream.read_n(bits_per_sample as uint) as uint);
+      warmup.push(try!(stream.read_n_signed(bits_per_sample as uint)));
     }
 
-    let precision = stream.read_n(4) as u8;
+    let precision = match try!(stream.read_n(4)) as u8 {
+      0b1111 => return Err(INVALID_PRECISION),
+      n => n + 1
+    };
+
+    let shift = try!(stream.read_n_signed(5)) as i8;
 
-    let shift = stream.read_n_signed(5) as i8;
+    if shift < 0 {
+      return Err(NEGATIVE_SHIFT);
+    }
 
     let mut coefficients = Vec::new();
 
     for _ in range(0, order) {
-      coefficients.push(stream.read_n_signed(precision as uint) as int);
+      coefficients.push(try!(stream.read_n_signed(precision as uint)));
     }
 
-    return LPCSubframe {
+    return Ok(LPCSubframe {
       warmup: warmup,
       precision: precision,
       shift: shift,
       coefficients: coefficients
-    };
+    });
   }
 }
 
@@ -93,109 +113,210 @@ pub struct VerbatimSubframe {
 }
 
 impl VerbatimSubframe {
-  pub fn from(frame_header: &::frame::header::Header, stream: &mut aurora::stream::Bitstream) -> VerbatimSubframe {
-    let bits_per_sample = frame_header.sample_size;
-    let block_size = frame_header.block_size;
+  pub fn from<B: BitRead>(frame_header: &::frame::header::Header, stream: &mut B) -> Result<VerbatimSubframe, Invalid> {
+    return VerbatimSubframe::read(frame_header.sample_size, frame_header.block_size, stream);
+  }
 
+  fn read<B: BitRead>(bits_per_sample: u8, block_size: u32, stream: &mut B) -> Result<VerbatimSubframe, Invalid> {
     let mut subblocks = Vec::new();
 
     for _ in range(0, block_size) {
-      subblocks.push(stream.read_n(bits_per_sample as uint));
+      subblocks.push(try!(stream.read_n(bits_per_sample as uint)));
     }
 
-    return VerbatimSubframe { subblocks: subblocks };
+    return Ok(VerbatimSubframe { subblocks: subblocks });
   }
 }
 
-pub fn read(frame_header: &::frame::header::Header, bitstream: &mut aurora::stream::Bitstream) -> Vec<u32> {
-  let header = Header::from(bitstream);
+fn extend_sign_bits(value: u32, n: u8) -> i32 {
+  let shift = 32 - n;
 
-  match header.ty {
-    Verbatim => {
-      let subframe = VerbatimSubframe::from(frame_header, bitstream);
+  return (value << shift as uint) as i32 >> (shift as uint);
+}
 
-      return subframe.subblocks;
-    },
-    _ => {
-      panic!("Unsupported");
-    }
+// Samples after the warm-up ones hold the residual, and get restored in place
+fn restore_fixed(order: u8, samples: &mut [i32]) {
+  for n in range(order as uint, samples.len()) {
+    let prediction = match order {
+      0 => 0,
+      1 => samples[n - 1] as i64,
+      2 => 2 * samples[n - 1] as i64 - samples[n - 2] as i64,
+      3 => 3 * samples[n - 1] as i64 - 3 * samples[n - 2] as i64 + samples[n - 3] as i64,
+      4 => 4 * samples[n - 1] as i64 - 6 * samples[n - 2] as i64 + 4 * samples[n - 3] as i64 - samples[n - 4] as i64,
+      _ => panic!("flac::Decoder: Fixed predictor order {} is reserved (BUG)", order)
+    };
+
+    samples[n] = (prediction + samples[n] as i64) as i32;
   }
 }
 
-#[test]
-fn test_header_from_1() {
-  let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
+// Reads the residual of a block into what follows the warm-up samples
+fn read_residual<B: BitRead>(block_size: u32, order: u8, stream: &mut B, residual: &mut [i32]) -> Result<(), Invalid> {
+  let (parameter_bits, escape) = match try!(stream.read_n(2)) {
+    0b00 => (4, 0b1111),
+    0b01 => (5, 0b11111),
+    _ => return Err(Invalid("flac::Decoder: Reserved residual coding method"))
+  };
 
-  spawn(proc() {
-    let path = std::path::Path::new("./test-vectors/subframes/bad_apple.1");
-    let file = std::io::File::open(&path).unwrap();
+  let partition_order = try!(stream.read_n(4)) as uint;
+  let partition_size = block_size as uint >> partition_order;
 
-    aurora::file::Input::new(file, 4096, sink_0).run();
-  });
+  if partition_size << partition_order != block_size as uint || partition_size < order as uint {
+    return Err(Invalid("flac::Decoder: Partition order doesn't fit the block size"));
+  }
 
-  let mut stream = aurora::stream::Stream::new(&mut source_0);
-  let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+  let mut i = 0;
 
-  let header = Header::from(&mut bitstream);
+  for partition in range(0, 1u << partition_order) {
+    let n = if partition == 0 { partition_size - order as uint } else { partition_size };
 
-  assert_eq!(header.ty, LPC(1));
-  assert_eq!(header.wasted_bits, 0);
-}
+    let parameter = try!(stream.read_n(parameter_bits));
 
-#[test]
-fn test_header_from_2() {
-  let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
+    if parameter == escape {
+      let bits = try!(stream.read_n(5)) as uint;
 
-  spawn(proc() {
-    let path = std::path::Path::new("./test-vectors/subframes/bad_apple.2");
-    let file = std::io::File::open(&path).unwrap();
+      for _ in range(0, n) {
+        residual[i] = if bits > 0 { try!(stream.read_n_signed(bits)) } else { 0 };
+        i += 1;
+      }
+    } else {
+      try!(stream.read_rice(parameter as uint, residual.slice_mut(i, i + n)));
+      i += n;
+    }
+  }
 
-    aurora::file::Input::new(file, 4096, sink_0).run();
-  });
+  return Ok(());
+}
 
-  let mut stream = aurora::stream::Stream::new(&mut source_0);
-  let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+pub fn read<B: BitRead>(frame_header: &::frame::header::Header, bits_per_sample: u8, bitstream: &mut B) -> Result<Vec<i32>, Invalid> {
+  let mut samples = Vec::from_elem(frame_header.block_size as uint, 0i32);
 
-  let header = Header::from(&mut bitstream);
+  try!(read_into(frame_header, bits_per_sample, bitstream, samples.as_mut_slice()));
 
-  assert_eq!(header.ty, LPC(1));
-  assert_eq!(header.wasted_bits, 0);
+  return Ok(samples);
 }
 
-#[test]
-fn test_header_from_3() {
-  let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
+static INVALID_PRECISION: Invalid = Invalid("flac::Decoder: Invalid LPC coefficient precision");
+static NEGATIVE_SHIFT: Invalid = Invalid("flac::Decoder: Negative LPC shift");
+static ORDER_EXCEEDS_BLOCK_SIZE: Invalid = Invalid("flac::Decoder: Predictor order exceeds block size");
+
+// Same as read, into the first block size samples of output, without
+// allocating
+pub fn read_into<B: BitRead>(frame_header: &::frame::header::Header, bits_per_sample: u8, bitstream: &mut B, output: &mut [i32]) -> Result<(), Invalid> {
+  let header = try!(Header::from(bitstream));
+
+  if header.wasted_bits >= bits_per_sample {
+    return Err(Invalid("flac::Decoder: More wasted bits in a subframe than it has"));
+  }
+
+  let bits = bits_per_sample - header.wasted_bits;
+  let block_size = frame_header.block_size;
+
+  if output.len() < block_size as uint {
+    return Err(Invalid("flac::Decoder: Block size is larger than the buffer for it"));
+  }
+
+  let samples = output.slice_mut(0, block_size as uint);
+
+  match header.ty {
+    Constant => {
+      let value = try!(bitstream.read_n_signed(bits as uint));
+
+      for sample in samples.iter_mut() {
+        *sample = value;
+      }
+    },
+    Verbatim => {
+      for sample in samples.iter_mut() {
+        *sample = extend_sign_bits(try!(bitstream.read_n(bits as uint)), bits);
+      }
+    },
+    Fixed(order) => {
+      if order as u32 > block_size {
+        return Err(ORDER_EXCEEDS_BLOCK_SIZE);
+      }
+
+      for n in range(0, order as uint) {
+        samples[n] = try!(bitstream.read_n_signed(bits as uint));
+      }
+
+      try!(read_residual(block_size, order, bitstream, samples.slice_from_mut(order as uint)));
+      restore_fixed(order, samples);
+    },
+    LPC(order) => {
+      if order as u32 > block_size {
+        return Err(ORDER_EXCEEDS_BLOCK_SIZE);
+      }
+
+      for n in range(0, order as uint) {
+        samples[n] = try!(bitstream.read_n_signed(bits as uint));
+      }
+
+      let precision = match try!(bitstream.read_n(4)) as u8 {
+        0b1111 => return Err(INVALID_PRECISION),
+        n => n + 1
+      };
 
-  spawn(proc() {
-    let path = std::path::Path::new("./test-vectors/subframes/bad_apple.3");
-    let file = std::io::File::open(&path).unwrap();
+      let shift = try!(bitstream.read_n_signed(5));
 
-    aurora::file::Input::new(file, 4096, sink_0).run();
-  });
+      if shift < 0 {
+        return Err(NEGATIVE_SHIFT);
+      }
 
-  let mut stream = aurora::stream::Stream::new(&mut source_0);
-  let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+      let mut coefficients = [0i32, ..32];
 
-  let header = Header::from(&mut bitstream);
+      for c in coefficients.slice_to_mut(order as uint).iter_mut() {
+        *c = try!(bitstream.read_n_signed(precision as uint));
+      }
 
-  assert_eq!(header.ty, LPC(1));
-  assert_eq!(header.wasted_bits, 0);
+      try!(read_residual(block_size, order, bitstream, samples.slice_from_mut(order as uint)));
+      lpc::restore(coefficients.slice_to(order as uint), shift as uint, bits, precision, samples);
+    }
+  }
+
+  if header.wasted_bits > 0 {
+    for sample in samples.iter_mut() {
+      *sample <<= header.wasted_bits as uint;
+    }
+  }
+
+  return Ok(());
 }
 
-#[test]
-fn test_header_from_bad_apple_verbatim_1() {
-  let (sink_0, mut source_0) = aurora::channel::create::<aurora::Binary>(1);
-  let (sink_r, mut source_r) = aurora::channel::create::<aurora::Binary>(1);
+// The first subframe of the first frames of Bad Apple, and of the same frame
+// stored verbatim
+#[cfg(test)]
+static BAD_APPLE: [&'static [u8], ..3] = [
+  include_bin!("../../test-vectors/subframes/bad_apple.1"),
+  include_bin!("../../test-vectors/subframes/bad_apple.2"),
+  include_bin!("../../test-vectors/subframes/bad_apple.3")
+];
+
+#[cfg(test)]
+static BAD_APPLE_VERBATIM: &'static [u8] = include_bin!("../../test-vectors/subframes/bad_apple_verbatim.1");
+
+// The 1152 big-endian 16 bit samples it holds
+#[cfg(test)]
+static BAD_APPLE_DECODED: &'static [u8] = include_bin!("../../test-vectors/subframes/bad_apple_verbatim.1.decoded");
+
+#[cfg(test)]
+fn decoded(i: uint) -> i32 {
+  return ((BAD_APPLE_DECODED[2 * i] as u16 << 8) | BAD_APPLE_DECODED[2 * i + 1] as u16) as i16 as i32;
+}
 
-  spawn(proc() {
-    let path = std::path::Path::new("./test-vectors/subframes/bad_apple_verbatim.1");
-    let file = std::io::File::open(&path).unwrap();
+#[test]
+fn test_header_from() {
+  for data in BAD_APPLE.iter() {
+    let header = Header::from(&mut SliceReader::new(*data)).unwrap();
 
-    aurora::file::Input::new(file, 4096, sink_0).run();
-  });
+    assert_eq!(header.ty, LPC(1));
+    assert_eq!(header.wasted_bits, 0);
+  }
+}
 
-  let mut stream = aurora::stream::Stream::new(&mut source_0);
-  let mut bitstream = aurora::stream::Bitstream::new(&mut stream);
+#[test]
+fn test_header_from_bad_apple_verbatim_1() {
+  let mut bitstream = SliceReader::new(BAD_APPLE_VERBATIM);
 
   let header = ::frame::header::Header {
     variable_blocksize: false,
@@ -208,27 +329,43 @@ fn test_header_from_bad_apple_verbatim_1() {
     crc: 0xAE
   };
 
-  let subframe_header = Header::from(&mut bitstream);
+  let subframe_header = Header::from(&mut bitstream).unwrap();
 
   assert_eq!(subframe_header.ty, Verbatim);
   assert_eq!(subframe_header.wasted_bits, 0);
 
-  let subframe = VerbatimSubframe::from(&header, &mut bitstream);
+  let subframe = VerbatimSubframe::from(&header, &mut bitstream).unwrap();
+
+  for i in range(0, 1152) {
+    assert_eq!(subframe.subblocks[i], decoded(i) as u16 as u32);
+  }
+}
 
-  spawn(proc() {
-    let decoded_path = std::path::Path::new("./test-vectors/subframes/bad_apple_verbatim.1.decoded");
-    let decoded_file = std::io::File::open(&decoded_path).unwrap();
+#[test]
+fn test_read_bad_apple_1() {
+  let header = ::frame::header::Header {
+    variable_blocksize: false,
+    block_size: 4096,
+    sample_rate: 44100,
+    channel_assignment: 1,
+    sample_size: 16,
+    sample_number: None,
+    frame_number: Some(0),
+    crc: 0xC2
+  };
 
-    aurora::file::Input::new(decoded_file, 4096, sink_r).run();
-  });
+  let samples = read(&header, 16, &mut SliceReader::new(BAD_APPLE[0])).unwrap();
 
-  let mut reference_stream = aurora::stream::Stream::new(&mut source_r);
+  assert_eq!(samples.len(), 4096);
 
+  // The verbatim vector holds the first 1152 samples of the same channel
   for i in range(0, 1152) {
-    let reference_sample = reference_stream.read_be_u16();
-    let decoded_sample = subframe.subblocks[i];
-
-    assert_eq!(decoded_sample, reference_sample as u32);
+    assert_eq!(samples[i], decoded(i));
   }
 
+  // Cut off, or with more wasted bits than it has
+  let data = BAD_APPLE[0];
+
+  assert!(read(&header, 16, &mut SliceReader::new(data.slice_to(data.len() / 2))).is_err());
+  assert!(read(&header, 16, &mut SliceReader::new(&[0x01, 0x00, 0x01, 0x00, 0x00])).is_err());
 }
@@ -0,0 +1,119 @@
+// Signals and an in-memory encoder for the tests, also included by the
+// benchmarks, so it only uses std and the encoder. The core modules' tests
+// only get the signals without std.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+#[cfg(feature = "std")]
+use std::cmp;
+#[cfg(feature = "std")]
+use std::io::{IoError, IoResult, InvalidInput, Seek, SeekStyle, SeekSet, SeekCur, SeekEnd};
+#[cfg(feature = "std")]
+use std::slice::bytes::copy_memory;
+
+#[cfg(feature = "std")]
+use encoder;
+
+// Uniform noise in bits, the same for the same seed
+pub fn noise(n: uint, bits: uint, seed: u32) -> Vec<i32> {
+  let mut seed = seed;
+
+  return Vec::from_fn(n, |_| {
+    seed = seed * 1103515245 + 12345;
+
+    (seed as i32) >> (32 - bits)
+  });
+}
+
+// 44.1 kHz and 16 bits, in blocks of a fixed size
+#[cfg(feature = "std")]
+pub fn parameters(channels: u8, block_size: u32) -> encoder::Parameters {
+  let mut parameters = encoder::Parameters::new(44100, channels, 16);
+
+  parameters.block_size = encoder::Fixed(block_size);
+
+  return parameters;
+}
+
+// Encodes interleaved samples to a whole stream
+#[cfg(feature = "std")]
+pub fn encode(parameters: encoder::Parameters, samples: &[i32]) -> Vec<u8> {
+  let mut encoder = encoder::Encoder::new(Output::new(), parameters).unwrap();
+
+  encoder.write(samples).unwrap();
+
+  return encoder.finish().unwrap().unwrap();
+}
+
+// Grows as it is written to, and can seek back into what is written, like
+// the encoder does to fill in STREAMINFO
+#[cfg(feature = "std")]
+pub struct Output {
+  data: Vec<u8>,
+  position: uint
+}
+
+#[cfg(feature = "std")]
+impl Output {
+  pub fn new() -> Output {
+    return Output { data: Vec::new(), position: 0 };
+  }
+
+  pub fn unwrap(self) -> Vec<u8> {
+    return self.data;
+  }
+}
+
+#[cfg(feature = "std")]
+impl Writer for Output {
+  fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+    let overlap = cmp::min(buf.len(), self.data.len() - self.position);
+
+    copy_memory(self.data.slice_mut(self.position, self.position + overlap), buf.slice_to(overlap));
+    self.data.push_all(buf.slice_from(overlap));
+
+    self.position += buf.len();
+
+    return Ok(());
+  }
+}
+
+#[cfg(feature = "std")]
+impl Seek for Output {
+  fn tell(&self) -> IoResult<u64> {
+    return Ok(self.position as u64);
+  }
+
+  fn seek(&mut self, pos: i64, style: SeekStyle) -> IoResult<()> {
+    let base = match style {
+      SeekSet => 0,
+      SeekCur => self.position as i64,
+      SeekEnd => self.data.len() as i64
+    };
+
+    if base + pos < 0 || base + pos > self.data.len() as i64 {
+      return Err(IoError { kind: InvalidInput, desc: "Seek outside of the buffer", detail: None });
+    }
+
+    self.position = (base + pos) as uint;
+
+    return Ok(());
+  }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_output_seeks_back() {
+  let mut buffer = Output::new();
+
+  buffer.write(b"abcdef").unwrap();
+  buffer.seek(2, SeekSet).unwrap();
+  buffer.write(b"XY").unwrap();
+  buffer.seek(0, SeekEnd).unwrap();
+  buffer.write(b"g").unwrap();
+
+  assert_eq!(buffer.tell().unwrap(), 7);
+  assert!(buffer.seek(1, SeekEnd).is_err());
+  assert_eq!(buffer.unwrap().as_slice(), b"abXYefg");
+}
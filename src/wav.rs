@@ -0,0 +1,355 @@
+use std::u32;
+use std::io::{IoError, IoResult, InvalidInput, Seek, SeekSet, SeekEnd};
+use std::io::Writer as IoWriter;
+
+use aurora;
+
+#[deriving(Show,Clone,PartialEq)]
+struct Format {
+  channels: uint,
+  sample_rate: u32,
+  bits: uint
+}
+
+impl Format {
+  fn bytes_per_sample(&self) -> uint {
+    return (self.bits + 7) / 8;
+  }
+
+  fn block_align(&self) -> uint {
+    return self.channels * self.bytes_per_sample();
+  }
+
+  // Plain PCM can't say which speakers the channels go to, or how many bits
+  // of a sample are used
+  fn is_extensible(&self) -> bool {
+    return self.channels > 2 || self.bits > 16 || self.bits % 8 != 0;
+  }
+
+  // Speaker positions in FLAC channel order
+  fn channel_mask(&self) -> u32 {
+    return match self.channels {
+      1 => 0x0004,
+      2 => 0x0003,
+      3 => 0x0007,
+      4 => 0x0033,
+      5 => 0x0607,
+      6 => 0x060F,
+      7 => 0x070F,
+      8 => 0x063F,
+      _ => 0x0000
+    };
+  }
+}
+
+static PCM: [u8, ..16] = [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71];
+
+fn push_le(data: &mut Vec<u8>, value: u64, bytes: uint) {
+  for i in range(0, bytes) {
+    data.push((value >> (8 * i)) as u8);
+  }
+}
+
+// The header always has the same length, there is a JUNK chunk where an RF64
+// file has its ds64 chunk, so it can be patched in place once the size of the
+// data is known
+fn header(format: &Format, data_size: u64) -> Vec<u8> {
+  let extensible = format.is_extensible();
+  let fmt_size = if extensible { 40 } else { 16 };
+
+  let riff_size = 4 + (8 + 28) + (8 + fmt_size) + 8 + data_size + (data_size & 1);
+  let rf64 = riff_size > u32::MAX as u64;
+
+  let mut data = Vec::with_capacity((8 + riff_size - data_size) as uint);
+
+  if rf64 {
+    data.push_all(b"RF64");
+    push_le(&mut data, 0xFFFFFFFF, 4);
+    data.push_all(b"WAVE");
+    data.push_all(b"ds64");
+    push_le(&mut data, 28, 4);
+    push_le(&mut data, riff_size, 8);
+    push_le(&mut data, data_size, 8);
+    push_le(&mut data, data_size / format.block_align() as u64, 8);
+    push_le(&mut data, 0, 4);
+  } else {
+    data.push_all(b"RIFF");
+    push_le(&mut data, riff_size, 4);
+    data.push_all(b"WAVE");
+    data.push_all(b"JUNK");
+    push_le(&mut data, 28, 4);
+    data.grow(28, 0x00);
+  }
+
+  data.push_all(b"fmt ");
+  push_le(&mut data, fmt_size, 4);
+  push_le(&mut data, if extensible { 0xFFFE } else { 0x0001 }, 2);
+  push_le(&mut data, format.channels as u64, 2);
+  push_le(&mut data, format.sample_rate as u64, 4);
+  push_le(&mut data, (format.sample_rate as uint * format.block_align()) as u64, 4);
+  push_le(&mut data, format.block_align() as u64, 2);
+  push_le(&mut data, 8 * format.bytes_per_sample() as u64, 2);
+
+  if extensible {
+    push_le(&mut data, 22, 2);
+    push_le(&mut data, format.bits as u64, 2);
+    push_le(&mut data, format.channel_mask() as u64, 4);
+    data.push_all(&PCM);
+  }
+
+  data.push_all(b"data");
+  push_le(&mut data, if rf64 { 0xFFFFFFFF } else { data_size }, 4);
+
+  return data;
+}
+
+// Turns big-endian signed samples into little-endian ones with the used bits
+// at the top, and 8 bit samples into unsigned ones
+fn convert(format: &Format, input: &[u8], output: &mut Vec<u8>) {
+  let bytes = format.bytes_per_sample();
+  let shift = 8 * bytes - format.bits;
+
+  for sample in input.chunks(bytes) {
+    let value = sample.iter().fold(0u32, |a, &b| (a << 8) | b as u32) << shift;
+
+    if bytes == 1 {
+      output.push(value as u8 ^ 0x80);
+    } else {
+      push_le(output, value as u64, bytes);
+    }
+  }
+}
+
+fn format_of(audio: &aurora::Audio) -> Format {
+  let bits = match audio.sample_type {
+    aurora::sample_type::Signed(bits) => bits,
+    _ => panic!("flac::wav::Writer: Only signed samples can be written (INPUT)")
+  };
+
+  return Format { channels: audio.channels, sample_rate: audio.sample_rate as u32, bits: bits };
+}
+
+// Writes the audio from a source to a RIFF/WAVE file, the header is written
+// with the first audio and the sizes in it are patched by run once the last
+// audio has been written
+pub struct Writer<W> {
+  source: aurora::channel::Source<aurora::Audio>,
+  writer: W
+}
+
+impl<W: IoWriter + Seek> Writer<W> {
+  pub fn new(source: aurora::channel::Source<aurora::Audio>, writer: W) -> Writer<W> {
+    return Writer { source: source, writer: writer };
+  }
+
+  pub fn run(mut self) -> IoResult<W> {
+    let mut format: Option<Format> = None;
+    let mut data_size = 0u64;
+    let mut buffer = Vec::new();
+    let mut result = Ok(());
+    let mut last = false;
+
+    while !last && result.is_ok() {
+      let writer = &mut self.writer;
+
+      self.source.read(|audio| {
+        last = audio.last;
+
+        // A stream without samples still ends with audio that has its format
+        if audio.data.is_empty() {
+          if format.is_none() && audio.channels > 0 {
+            let current = format_of(audio);
+
+            result = writer.write(header(&current, 0).as_slice());
+            format = Some(current);
+          }
+
+          return;
+        }
+
+        let current = format_of(audio);
+
+        if format.is_none() {
+          result = writer.write(header(&current, 0).as_slice());
+          format = Some(current.clone());
+        } else if format != Some(current.clone()) {
+          panic!("flac::wav::Writer: Format changed from {} to {} in the middle of the stream (INPUT)", format, current);
+        }
+
+        buffer.truncate(0);
+        convert(&current, audio.data.as_slice(), &mut buffer);
+
+        if result.is_ok() {
+          result = writer.write(buffer.as_slice());
+        }
+
+        data_size += buffer.len() as u64;
+      });
+    }
+
+    try!(result);
+
+    let format = match format {
+      Some(format) => format,
+      None => return Err(IoError { kind: InvalidInput, desc: "flac::wav::Writer: There was no audio to write", detail: None })
+    };
+
+    if data_size & 1 != 0 {
+      try!(self.writer.write(&[0x00]));
+    }
+
+    try!(self.writer.seek(0, SeekSet));
+    try!(self.writer.write(header(&format, data_size).as_slice()));
+    try!(self.writer.seek(0, SeekEnd));
+
+    return Ok(self.writer);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use aurora;
+
+  use demuxer;
+  use decoder;
+  use encoder;
+  use metadata;
+  use test_util::{encode, noise, Output};
+
+  fn le(data: &[u8]) -> u64 {
+    return data.iter().rev().fold(0u64, |a, &b| (a << 8) | b as u64);
+  }
+
+  #[test]
+  fn test_header_pcm() {
+    let format = super::Format { channels: 2, sample_rate: 44100, bits: 16 };
+    let data = super::header(&format, 4000);
+
+    assert_eq!(data.len(), 80);
+    assert_eq!(data.slice(0, 4), b"RIFF");
+    assert_eq!(le(data.slice(4, 8)), 72 + 4000);
+    assert_eq!(data.slice(12, 16), b"JUNK");
+    assert_eq!(data.slice(48, 52), b"fmt ");
+    assert_eq!(le(data.slice(52, 56)), 16);
+    assert_eq!(le(data.slice(56, 58)), 1);
+    assert_eq!(le(data.slice(58, 60)), 2);
+    assert_eq!(le(data.slice(60, 64)), 44100);
+    assert_eq!(le(data.slice(64, 68)), 44100 * 4);
+    assert_eq!(le(data.slice(68, 70)), 4);
+    assert_eq!(le(data.slice(70, 72)), 16);
+    assert_eq!(data.slice(72, 76), b"data");
+    assert_eq!(le(data.slice(76, 80)), 4000);
+  }
+
+  #[test]
+  fn test_header_extensible() {
+    let format = super::Format { channels: 6, sample_rate: 96000, bits: 20 };
+    let data = super::header(&format, 0);
+
+    assert_eq!(data.len(), 104);
+    assert_eq!(le(data.slice(52, 56)), 40);
+    assert_eq!(le(data.slice(56, 58)), 0xFFFE);
+    assert_eq!(le(data.slice(68, 70)), 18);
+    assert_eq!(le(data.slice(70, 72)), 24);
+    assert_eq!(le(data.slice(72, 74)), 22);
+    assert_eq!(le(data.slice(74, 76)), 20);
+    assert_eq!(le(data.slice(76, 80)), 0x060F);
+    assert_eq!(data.slice(80, 96), super::PCM.as_slice());
+    assert_eq!(data.slice(96, 100), b"data");
+  }
+
+  #[test]
+  fn test_header_rf64() {
+    let format = super::Format { channels: 2, sample_rate: 48000, bits: 24 };
+    let data_size = 5u64 << 30;
+    let data = super::header(&format, data_size);
+
+    assert_eq!(data.len(), 104);
+    assert_eq!(data.slice(0, 4), b"RF64");
+    assert_eq!(le(data.slice(4, 8)), 0xFFFFFFFF);
+    assert_eq!(data.slice(12, 16), b"ds64");
+    assert_eq!(le(data.slice(20, 28)), 96 + data_size);
+    assert_eq!(le(data.slice(28, 36)), data_size);
+    assert_eq!(le(data.slice(36, 44)), data_size / 6);
+    assert_eq!(le(data.slice(100, 104)), 0xFFFFFFFF);
+  }
+
+  #[test]
+  fn test_convert() {
+    let mut output = Vec::new();
+
+    super::convert(&super::Format { channels: 1, sample_rate: 8000, bits: 8 }, &[0x80, 0xFF, 0x00, 0x7F], &mut output);
+
+    assert_eq!(output, vec![0x00, 0x7F, 0x80, 0xFF]);
+
+    output.truncate(0);
+
+    super::convert(&super::Format { channels: 1, sample_rate: 8000, bits: 12 }, &[0xF8, 0x00, 0x07, 0xFF], &mut output);
+
+    assert_eq!(output, vec![0x00, 0x80, 0xF0, 0x7F]);
+  }
+
+  #[test]
+  fn test_decode_to_wav() {
+    let samples = noise(2 * 5001, 16, 13);
+    let flac = encode(encoder::Parameters::new(44100, 2, 16), samples.as_slice());
+
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(flac, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+    });
+
+    spawn(proc() {
+      decoder::Decoder::new(source_1, source_md, sink_a).run();
+    });
+
+    let wav = super::Writer::new(source_a, Output::new()).run().unwrap().unwrap();
+
+    assert_eq!(wav.len(), 80 + 4 * 5001);
+    assert_eq!(le(wav.slice(4, 8)), 72 + 4 * 5001);
+    assert_eq!(le(wav.slice(76, 80)), 4 * 5001);
+
+    for (i, &sample) in samples.iter().enumerate() {
+      assert_eq!(le(wav.slice(80 + 2 * i, 82 + 2 * i)) as u16, sample as u16);
+    }
+  }
+
+  #[test]
+  fn test_decode_empty_to_wav() {
+    let flac = encode(encoder::Parameters::new(48000, 1, 24), &[]);
+
+    let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+    let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+    let (sink_md, source_md) = aurora::channel::create::<metadata::Metadata>(4);
+    let (sink_a, source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+    spawn(proc() {
+      aurora::buffer::Buffer::new(flac, 4096, sink_0).run();
+    });
+
+    spawn(proc() {
+      demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+    });
+
+    spawn(proc() {
+      decoder::Decoder::new(source_1, source_md, sink_a).run();
+    });
+
+    let wav = super::Writer::new(source_a, Output::new()).run().unwrap().unwrap();
+
+    assert_eq!(wav.len(), 104);
+    assert_eq!(le(wav.slice(58, 60)), 1);
+    assert_eq!(le(wav.slice(60, 64)), 48000);
+    assert_eq!(le(wav.slice(74, 76)), 24);
+    assert_eq!(wav.slice(96, 100), b"data");
+    assert_eq!(le(wav.slice(100, 104)), 0);
+  }
+}
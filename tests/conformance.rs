@@ -0,0 +1,123 @@
+// Decodes the conformance streams, which are written without the crate's
+// encoder, through the reader and, with complete-tests, through the demuxer
+// and decoder after writing them to test-vectors/complete.
+
+#[cfg(feature = "complete-tests")]
+extern crate aurora;
+extern crate flac;
+
+use std::io::MemReader;
+
+use flac::md5;
+use flac::metadata::stream_info::MD5;
+use flac::reader::FlacReader;
+
+mod streams;
+
+#[test]
+fn test_streams_decode() {
+  for stream in streams::streams().iter() {
+    let mut reader = FlacReader::new(MemReader::new(stream.flac.clone())).unwrap();
+
+    assert_eq!(*reader.stream_info(), stream.stream_info);
+
+    let mut decoded = Vec::new();
+
+    for block in reader.blocks() {
+      let block = block.unwrap();
+      let mut output = Vec::from_elem(block.channels.len() * block.header.block_size as uint, 0i32);
+
+      block.interleaved(output.as_mut_slice());
+      decoded.push_all(output.as_slice());
+    }
+
+    assert!(decoded == stream.samples, "{} doesn't decode to its samples", stream.name);
+
+    let mut context = md5::Context::new();
+    context.update_samples(decoded.as_slice(), stream.stream_info.bits_per_sample);
+
+    assert_eq!(MD5(context.finish()), stream.stream_info.signature);
+  }
+}
+
+#[test]
+fn test_codes_covered() {
+  let mut block_size_codes = [false, ..16];
+  let mut sample_rate_codes = [false, ..16];
+  let mut channel_assignments = [false, ..16];
+  let mut sample_size_codes = [false, ..8];
+
+  // The first frame header of each stream, right after STREAMINFO
+  for stream in streams::streams().iter() {
+    let header = stream.flac.slice(4 + 4 + 34, 4 + 4 + 34 + 4);
+
+    block_size_codes[(header[2] >> 4) as uint] = true;
+    sample_rate_codes[(header[2] & 0x0F) as uint] = true;
+    channel_assignments[(header[3] >> 4) as uint] = true;
+    sample_size_codes[((header[3] >> 1) & 0b111) as uint] = true;
+  }
+
+  // Everything but the reserved codes, and 0b1111 for an invalid rate
+  assert!(block_size_codes.slice_from(1).iter().all(|&c| c));
+  assert!(sample_rate_codes.slice_to(15).iter().all(|&c| c));
+  assert!(channel_assignments.slice_to(11).iter().all(|&c| c));
+  assert_eq!(sample_size_codes.as_slice(), [true, true, true, false, true, true, true, false].as_slice());
+}
+
+// The demuxer and decoder output big-endian samples in whole bytes
+#[cfg(feature = "complete-tests")]
+fn decode(data: Vec<u8>) -> Vec<u8> {
+  let (sink_0, source_0) = aurora::channel::create::<aurora::Binary>(1);
+  let (sink_1, source_1) = aurora::channel::create::<aurora::Binary>(4);
+  let (sink_md, source_md) = aurora::channel::create::<flac::metadata::Metadata>(4);
+  let (sink_a, mut source_a) = aurora::channel::create::<aurora::Audio>(4);
+
+  spawn(proc() {
+    aurora::buffer::Buffer::new(data, 4096, sink_0).run();
+  });
+
+  spawn(proc() {
+    flac::demuxer::Demuxer::new(source_0, sink_1, sink_md).run();
+  });
+
+  spawn(proc() {
+    flac::decoder::Decoder::new(source_1, source_md, sink_a).run();
+  });
+
+  let mut output = Vec::new();
+  let mut last = false;
+
+  while !last {
+    source_a.read(|audio| {
+      output.push_all(audio.data.as_slice());
+      last = audio.last;
+    });
+  }
+
+  return output;
+}
+
+#[cfg(feature = "complete-tests")]
+#[test]
+fn test_complete() {
+  let directory = Path::new("./test-vectors/complete");
+  let streams = streams::streams();
+
+  streams::write(&directory, streams.as_slice()).unwrap();
+
+  for stream in streams.iter() {
+    let path = directory.join(format!("{}.flac", stream.name));
+    let flac = std::io::File::open(&path).read_to_end().unwrap();
+
+    let bytes = (stream.stream_info.bits_per_sample as uint + 7) / 8;
+    let mut expected = Vec::new();
+
+    for &sample in stream.samples.iter() {
+      for b in range(0, bytes).rev() {
+        expected.push((sample >> (8 * b)) as u8);
+      }
+    }
+
+    assert!(decode(flac) == expected, "{} doesn't decode to its samples", stream.name);
+  }
+}
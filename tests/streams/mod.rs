@@ -0,0 +1,830 @@
+// Small streams that between them use every subframe type, predictor order,
+// channel assignment, bit depth from 4 to 32, block size and sample rate
+// code, escaped and wide Rice partitions and variable block sizes. Frames are
+// put together by hand, so each one is coded the way its stream is named for
+// instead of the way the encoder would pick. Every stream comes with the
+// samples it has to decode to, and their MD5 in STREAMINFO.
+//
+// Nothing here comes from the crate but MD5 and the StreamInfo it's compared
+// to, the bits, CRCs, predictors and codes are written from the format's
+// tables, so the decoder isn't checked against its own encoder. Shared by the
+// conformance tests and the fuzzer.
+
+#![allow(dead_code)]
+
+use std::cmp;
+use std::f64;
+use std::io::{File, IoResult, USER_RWX};
+use std::io::fs;
+
+use flac::md5;
+use flac::metadata::stream_info::{StreamInfo, MD5};
+
+pub struct Stream {
+  pub name: String,
+  pub flac: Vec<u8>,
+  pub samples: Vec<i32>, // Interleaved
+  pub stream_info: StreamInfo
+}
+
+// Bits from the most significant, in whole bytes once aligned
+struct Bits {
+  data: Vec<u8>,
+  bits: uint
+}
+
+impl Bits {
+  fn new() -> Bits {
+    return Bits { data: Vec::new(), bits: 0 };
+  }
+
+  fn bit(&mut self, one: bool) {
+    if self.bits % 8 == 0 {
+      self.data.push(0x00);
+    }
+
+    if one {
+      let last = self.data.len() - 1;
+      *self.data.get_mut(last) |= 0x80 >> (self.bits % 8);
+    }
+
+    self.bits += 1;
+  }
+
+  fn write(&mut self, value: u64, n: uint) {
+    for i in range(0, n).rev() {
+      self.bit((value >> i) & 1 != 0);
+    }
+  }
+
+  // Two's complement in n bits, where no bits only hold 0
+  fn write_signed(&mut self, value: i64, n: uint) {
+    if n == 0 && value != 0 || n > 0 && n < 64 && (value < -(1i64 << (n - 1)) || value >= 1i64 << (n - 1)) {
+      panic!("flac::conformance: {} doesn't fit in {} bits (BUG)", value, n);
+    }
+
+    self.write(value as u64, n);
+  }
+
+  fn write_unary(&mut self, zeros: u64) {
+    for _ in range(0, zeros) {
+      self.bit(false);
+    }
+
+    self.bit(true);
+  }
+
+  fn align(&mut self) {
+    while self.bits % 8 != 0 {
+      self.bit(false);
+    }
+  }
+}
+
+// x^8 + x^2 + x + 1, from 0, for frame headers
+fn crc8(data: &[u8]) -> u8 {
+  let mut crc = 0u8;
+
+  for &byte in data.iter() {
+    crc ^= byte;
+
+    for _ in range(0u, 8) {
+      crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+    }
+  }
+
+  return crc;
+}
+
+// x^16 + x^15 + x^2 + 1, from 0, for whole frames
+fn crc16(data: &[u8]) -> u16 {
+  let mut crc = 0u16;
+
+  for &byte in data.iter() {
+    crc ^= byte as u16 << 8;
+
+    for _ in range(0u, 8) {
+      crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+    }
+  }
+
+  return crc;
+}
+
+// How a subframe gets coded
+#[deriving(Clone)]
+enum Coding {
+  Constant,
+  Verbatim,
+  Fixed(u8),
+  Lpc(u8),
+  Partitioned(u8, u8), // Fixed order and partition order
+  Escaped(u8),         // Fixed order, every other partition escaped
+  Wasted(u8)           // Wasted bits, fixed order 2 on what's left
+}
+
+// Sines under a little noise, well inside bits
+fn signal(n: uint, bits: u8, seed: u32) -> Vec<i32> {
+  let mut seed = seed;
+  let scale = ((1u64 << (bits as uint - 1)) - 1) as f64;
+  let period = 20.0 + (seed % 50) as f64;
+
+  return Vec::from_fn(n, |i| {
+    seed = seed * 1103515245 + 12345;
+
+    let noise = (seed >> 16) as f64 / 65536.0 - 0.5;
+    let value = 0.7 * (2.0 * f64::consts::PI * i as f64 / period).sin() + 0.2 * noise;
+
+    (value * scale) as i32
+  });
+}
+
+fn min_sample(bits: u8) -> i32 {
+  return (-(1i64 << (bits as uint - 1))) as i32;
+}
+
+fn max_sample(bits: u8) -> i32 {
+  return ((1i64 << (bits as uint - 1)) - 1) as i32;
+}
+
+// A signal that reaches both ends of bits
+fn full_scale(n: uint, bits: u8, seed: u32) -> Vec<i32> {
+  let mut samples = signal(n, bits, seed);
+
+  samples[0] = max_sample(bits);
+  samples[1] = min_sample(bits);
+
+  return samples;
+}
+
+// Signals for predicted subframes stay within 24 bits, so their residual
+// fits in 32
+fn predictable(n: uint, bits: u8, seed: u32) -> Vec<i32> {
+  return signal(n, cmp::min(bits, 24), seed);
+}
+
+// What the fixed predictors of orders 0 to 4 leave, which are the binomial
+// differences of the samples
+fn fixed_residual(samples: &[i32], order: u8) -> Vec<i64> {
+  let weights: &[i64] = match order {
+    0 => &[1],
+    1 => &[1, -1],
+    2 => &[1, -2, 1],
+    3 => &[1, -3, 3, -1],
+    4 => &[1, -4, 6, -4, 1],
+    _ => panic!("flac::conformance: Fixed order {} (BUG)", order)
+  };
+
+  return range(order as uint, samples.len()).map(|i| {
+    weights.iter().enumerate().fold(0i64, |sum, (j, &w)| sum + w * samples[i - j] as i64)
+  }).collect();
+}
+
+fn lpc_residual(samples: &[i32], coefficients: &[i64], shift: uint) -> Vec<i64> {
+  let order = coefficients.len();
+
+  return range(order, samples.len()).map(|i| {
+    let prediction = coefficients.iter().enumerate().fold(0i64, |sum, (j, &c)| sum + c * samples[i - 1 - j] as i64);
+
+    samples[i] as i64 - (prediction >> shift)
+  }).collect();
+}
+
+// Rice codes zig-zag signed values, 0, -1, 1, -2, ...
+fn fold(value: i64) -> u64 {
+  return if value < 0 { (-2 * value - 1) as u64 } else { (2 * value) as u64 };
+}
+
+fn rice_parameter(residual: &[i64]) -> u8 {
+  if residual.len() == 0 {
+    return 0;
+  }
+
+  let sum = residual.iter().fold(0u64, |a, &r| a + r.abs() as u64);
+  let mean = sum / residual.len() as u64;
+
+  let mut k = 0u8;
+
+  while k < 30 && (2u64 << k as uint) <= mean {
+    k += 1;
+  }
+
+  return k;
+}
+
+// Bits a partition needs as two's complement, none if it's all zero
+fn escape_bits(residual: &[i64]) -> u8 {
+  let mut bits = 0u8;
+
+  for &r in residual.iter() {
+    if r != 0 {
+      let magnitude = (if r < 0 { !r } else { r }) as u64;
+
+      bits = cmp::max(bits, (64 - magnitude.leading_zeros()) as u8 + 1);
+    }
+  }
+
+  return bits;
+}
+
+// Largest partition order up to 3 the block size divides into, leaving
+// more than the warm-up samples in the first partition
+fn partition_order(block_size: uint, order: u8) -> u8 {
+  let mut partition_order = 3u;
+
+  while partition_order > 0 && (block_size % (1 << partition_order) != 0 || (block_size >> partition_order) <= order as uint) {
+    partition_order -= 1;
+  }
+
+  return partition_order as u8;
+}
+
+enum Partition {
+  Rice(u8),
+  Escape(u8)
+}
+
+// The residual of a block after order warm-up samples, in 2^partition_order
+// partitions, every other one escaped if asked
+fn write_residual(bits: &mut Bits, residual: &[i64], block_size: uint, order: u8, partition_order: u8, escaped: bool) {
+  let partition_size = block_size >> partition_order as uint;
+  let mut partitions = Vec::new();
+  let mut start = 0;
+
+  for p in range(0, 1u << partition_order as uint) {
+    let end = start + partition_size - (if p == 0 { order as uint } else { 0 });
+    let partition = residual.slice(start, end);
+
+    partitions.push((partition, if escaped && p % 2 == 0 {
+      Escape(escape_bits(partition))
+    } else {
+      Rice(rice_parameter(partition))
+    }));
+
+    start = end;
+  }
+
+  // Parameters past 14 need the method with five bit parameters, where the
+  // escape code is 31 instead of 15
+  let wide = partitions.iter().any(|&(_, ref coding)| match *coding { Rice(k) => k > 14, Escape(_) => false });
+  let parameter_bits = if wide { 5 } else { 4 };
+
+  bits.write(if wide { 0b01 } else { 0b00 }, 2);
+  bits.write(partition_order as u64, 4);
+
+  for &(partition, ref coding) in partitions.iter() {
+    match *coding {
+      Rice(k) => {
+        bits.write(k as u64, parameter_bits);
+
+        for &r in partition.iter() {
+          let folded = fold(r);
+
+          bits.write_unary(folded >> k as uint);
+          bits.write(folded & ((1 << k as uint) - 1), k as uint);
+        }
+      },
+      Escape(n) => {
+        bits.write((1 << parameter_bits) - 1, parameter_bits);
+        bits.write(n as u64, 5);
+
+        for &r in partition.iter() {
+          bits.write_signed(r, n as uint);
+        }
+      }
+    }
+  }
+}
+
+// A subframe of samples that take sample_bits, the header's type and wasted
+// bits included
+fn write_subframe(bits: &mut Bits, samples: &[i32], sample_bits: uint, coding: Coding) {
+  let block_size = samples.len();
+
+  let (samples, sample_bits, coding) = match coding {
+    Wasted(wasted) => {
+      bits.write(0b0, 1);
+      bits.write(0b001000 | 2, 6);
+      bits.write(0b1, 1);
+      bits.write_unary(wasted as u64 - 1);
+
+      let shifted: Vec<i32> = samples.iter().map(|&s| s >> wasted as uint).collect();
+
+      (shifted, sample_bits - wasted as uint, Partitioned(2, partition_order(block_size, 2)))
+    },
+    _ => {
+      let ty = match coding {
+        Constant => 0b000000,
+        Verbatim => 0b000001,
+        Fixed(order) | Partitioned(order, _) | Escaped(order) => 0b001000 | order as u64,
+        Lpc(order) => 0b100000 | (order as u64 - 1),
+        Wasted(_) => unreachable!()
+      };
+
+      bits.write(0b0, 1);
+      bits.write(ty, 6);
+      bits.write(0b0, 1);
+
+      (samples.to_vec(), sample_bits, coding)
+    }
+  };
+
+  let samples = samples.as_slice();
+
+  match coding {
+    Constant => {
+      if samples.iter().any(|&s| s != samples[0]) {
+        panic!("flac::conformance: Constant subframe of samples that differ (BUG)");
+      }
+
+      bits.write_signed(samples[0] as i64, sample_bits);
+    },
+    Verbatim => {
+      for &s in samples.iter() {
+        bits.write_signed(s as i64, sample_bits);
+      }
+    },
+    Fixed(order) | Partitioned(order, _) | Escaped(order) => {
+      let partitions = match coding {
+        Partitioned(_, partition_order) => partition_order,
+        _ => partition_order(block_size, order)
+      };
+
+      let escaped = match coding { Escaped(_) => true, _ => false };
+
+      for &s in samples.slice_to(order as uint).iter() {
+        bits.write_signed(s as i64, sample_bits);
+      }
+
+      write_residual(bits, fixed_residual(samples, order).as_slice(), block_size, order, partitions, escaped);
+    },
+    Lpc(order) => {
+      // Weights falling off with distance that sum to one, so the predictor
+      // is an average of the samples before, in 13 bits shifted by 10
+      let (precision, shift) = (13u, 10u);
+      let total = order as i64 * (order as i64 + 1) / 2;
+
+      let coefficients: Vec<i64> = range(0, order as i64).map(|j| ((order as i64 - j) << shift) / total).collect();
+
+      for &s in samples.slice_to(order as uint).iter() {
+        bits.write_signed(s as i64, sample_bits);
+      }
+
+      bits.write(precision as u64 - 1, 4);
+      bits.write_signed(shift as i64, 5);
+
+      for &c in coefficients.iter() {
+        bits.write_signed(c, precision);
+      }
+
+      let residual = lpc_residual(samples, coefficients.as_slice(), shift);
+
+      write_residual(bits, residual.as_slice(), block_size, order, partition_order(block_size, order), false);
+    },
+    Wasted(_) => unreachable!()
+  }
+}
+
+// Sample numbers and frame numbers are coded like UTF-8, extended to 7 bytes
+fn write_number(bits: &mut Bits, value: u64) {
+  if value < 0x80 {
+    bits.write(value, 8);
+    return;
+  }
+
+  let mut n = 2u;
+
+  while n < 7 && value >> (5 * n + 1) != 0 {
+    n += 1;
+  }
+
+  bits.write(((0xFF00u64 >> n) & 0xFF) | (value >> (6 * (n - 1))), 8);
+
+  for i in range(0, n - 1).rev() {
+    bits.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+  }
+}
+
+fn block_size_code(block_size: uint) -> (u64, uint) {
+  return match block_size {
+    192 => (0b0001, 0),
+    576 => (0b0010, 0),
+    1152 => (0b0011, 0),
+    2304 => (0b0100, 0),
+    4608 => (0b0101, 0),
+    256 => (0b1000, 0),
+    512 => (0b1001, 0),
+    1024 => (0b1010, 0),
+    2048 => (0b1011, 0),
+    4096 => (0b1100, 0),
+    8192 => (0b1101, 0),
+    16384 => (0b1110, 0),
+    32768 => (0b1111, 0),
+    1...256 => (0b0110, 8),
+    _ => (0b0111, 16)
+  };
+}
+
+fn sample_rate_code(sample_rate: u32) -> (u64, u64, uint) {
+  return match sample_rate {
+    88200 => (0b0001, 0, 0),
+    176400 => (0b0010, 0, 0),
+    192000 => (0b0011, 0, 0),
+    8000 => (0b0100, 0, 0),
+    16000 => (0b0101, 0, 0),
+    22050 => (0b0110, 0, 0),
+    24000 => (0b0111, 0, 0),
+    32000 => (0b1000, 0, 0),
+    44100 => (0b1001, 0, 0),
+    48000 => (0b1010, 0, 0),
+    96000 => (0b1011, 0, 0),
+    _ if sample_rate % 1000 == 0 && sample_rate / 1000 < 256 => (0b1100, sample_rate as u64 / 1000, 8),
+    _ if sample_rate < 65536 => (0b1101, sample_rate as u64, 16),
+    _ if sample_rate % 10 == 0 && sample_rate / 10 < 65536 => (0b1110, sample_rate as u64 / 10, 16),
+    _ => (0b0000, 0, 0)
+  };
+}
+
+// 32 bits is only in STREAMINFO
+fn sample_size_code(bits: u8) -> u64 {
+  return match bits {
+    8 => 0b001,
+    12 => 0b010,
+    16 => 0b100,
+    20 => 0b101,
+    24 => 0b110,
+    _ => 0b000
+  };
+}
+
+struct Builder {
+  name: String,
+  sample_rate: u32,
+  channels: u8,
+  bits: u8,
+  variable: bool,
+  frames: Vec<u8>,
+  samples: Vec<i32>,
+  block_sizes: Vec<u32>,
+  frame_size: (u32, u32),
+  number: u64,
+  md5: md5::Context
+}
+
+impl Builder {
+  fn new(name: String, sample_rate: u32, channels: u8, bits: u8, variable: bool) -> Builder {
+    return Builder {
+      name: name,
+      sample_rate: sample_rate,
+      channels: channels,
+      bits: bits,
+      variable: variable,
+      frames: Vec::new(),
+      samples: Vec::new(),
+      block_sizes: Vec::new(),
+      frame_size: (0, 0),
+      number: 0,
+      md5: md5::Context::new()
+    };
+  }
+
+  // One frame of the channels, coded as the channel assignment says with a
+  // coding for each subframe
+  fn frame(&mut self, channels: &[Vec<i32>], channel_assignment: u8, codings: &[Coding]) {
+    let block_size = channels[0].len();
+    let bits = self.bits as uint;
+
+    if channels.len() != self.channels as uint || codings.len() != channels.len() {
+      panic!("flac::conformance: Frame has the wrong number of channels (BUG)");
+    }
+
+    let side = || -> Vec<i32> {
+      channels[0].iter().zip(channels[1].iter()).map(|(&l, &r)| (l as i64 - r as i64) as i32).collect()
+    };
+
+    let subframes = match channel_assignment {
+      0b1000 => vec![(channels[0].clone(), bits), (side(), bits + 1)],
+      0b1001 => vec![(side(), bits + 1), (channels[1].clone(), bits)],
+      0b1010 => {
+        let mid: Vec<i32> = channels[0].iter().zip(channels[1].iter()).map(|(&l, &r)| ((l as i64 + r as i64) >> 1) as i32).collect();
+
+        vec![(mid, bits), (side(), bits + 1)]
+      },
+      _ => channels.iter().map(|c| (c.clone(), bits)).collect()
+    };
+
+    let mut header = Bits::new();
+
+    let (block_size_code, block_size_bits) = block_size_code(block_size);
+    let (sample_rate_code, sample_rate, sample_rate_bits) = sample_rate_code(self.sample_rate);
+
+    header.write(0b11111111111110, 14);
+    header.write(0b0, 1);
+    header.write(if self.variable { 1 } else { 0 }, 1);
+    header.write(block_size_code, 4);
+    header.write(sample_rate_code, 4);
+    header.write(channel_assignment as u64, 4);
+    header.write(sample_size_code(self.bits), 3);
+    header.write(0b0, 1);
+
+    write_number(&mut header, self.number);
+
+    header.write(block_size as u64 - 1, block_size_bits);
+    header.write(sample_rate, sample_rate_bits);
+
+    let crc = crc8(header.data.as_slice());
+    header.write(crc as u64, 8);
+
+    let mut frame = header;
+
+    for (&(ref samples, sample_bits), coding) in subframes.iter().zip(codings.iter()) {
+      write_subframe(&mut frame, samples.as_slice(), sample_bits, coding.clone());
+    }
+
+    frame.align();
+
+    let crc = crc16(frame.data.as_slice());
+    frame.write(crc as u64, 16);
+
+    let data = frame.data;
+    let (min, max) = self.frame_size;
+
+    self.frame_size = match self.block_sizes.len() {
+      0 => (data.len() as u32, data.len() as u32),
+      _ => (cmp::min(min, data.len() as u32), cmp::max(max, data.len() as u32))
+    };
+
+    self.frames.push_all(data.as_slice());
+
+    let start = self.samples.len();
+
+    for s in range(0, block_size) {
+      for channel in channels.iter() {
+        self.samples.push(channel[s]);
+      }
+    }
+
+    self.md5.update_samples(self.samples.slice_from(start), self.bits);
+
+    self.block_sizes.push(block_size as u32);
+    self.number += if self.variable { block_size as u64 } else { 1 };
+  }
+
+  fn finish(self) -> Stream {
+    // The last block may be shorter than the others
+    let sizes = self.block_sizes.as_slice();
+    let others = if sizes.len() > 1 { sizes.slice_to(sizes.len() - 1) } else { sizes };
+
+    let (min_block_size, max_block_size) = match self.variable {
+      true => (*others.iter().min().unwrap(), *sizes.iter().max().unwrap()),
+      false => (sizes[0], sizes[0])
+    };
+
+    let (min_frame_size, max_frame_size) = self.frame_size;
+
+    let stream_info = StreamInfo {
+      block_size: (min_block_size as u16, max_block_size as u16),
+      frame_size: self.frame_size,
+      sample_rate: self.sample_rate,
+      channels: self.channels,
+      bits_per_sample: self.bits,
+      samples: self.samples.len() as u64 / self.channels as u64,
+      signature: MD5(self.md5.finish())
+    };
+
+    let mut block = Bits::new();
+
+    block.write(0b1, 1);
+    block.write(0, 7);
+    block.write(34, 24);
+
+    block.write(min_block_size as u64, 16);
+    block.write(max_block_size as u64, 16);
+    block.write(min_frame_size as u64, 24);
+    block.write(max_frame_size as u64, 24);
+    block.write(self.sample_rate as u64, 20);
+    block.write(self.channels as u64 - 1, 3);
+    block.write(self.bits as u64 - 1, 5);
+    block.write(stream_info.samples, 36);
+
+    let MD5(signature) = stream_info.signature;
+
+    for &byte in signature.iter() {
+      block.write(byte as u64, 8);
+    }
+
+    let mut flac = b"fLaC".to_vec();
+
+    flac.push_all(block.data.as_slice());
+    flac.push_all(self.frames.as_slice());
+
+    return Stream { name: self.name, flac: flac, samples: self.samples, stream_info: stream_info };
+  }
+}
+
+fn mono(name: String, sample_rate: u32, bits: u8, frames: &[(Vec<i32>, Coding)]) -> Stream {
+  let mut builder = Builder::new(name, sample_rate, 1, bits, false);
+
+  for &(ref samples, ref coding) in frames.iter() {
+    builder.frame(&[samples.clone()], 0b0000, &[coding.clone()]);
+  }
+
+  return builder.finish();
+}
+
+fn subframe_streams(streams: &mut Vec<Stream>) {
+  let n = 1024;
+
+  streams.push(mono("subframe-constant".to_string(), 44100, 16, &[
+    (Vec::from_elem(n, -1234i32), Constant),
+    (Vec::from_elem(n, 32767i32), Constant)
+  ]));
+
+  streams.push(mono("subframe-verbatim".to_string(), 44100, 16, &[
+    (full_scale(n, 16, 1), Verbatim),
+    (signal(n, 16, 2), Verbatim)
+  ]));
+
+  for order in range(0u8, 5) {
+    streams.push(mono(format!("subframe-fixed-{}", order), 44100, 16, &[
+      (signal(n, 16, 10 + order as u32), Fixed(order)),
+      (signal(n, 16, 20 + order as u32), Fixed(order))
+    ]));
+  }
+
+  for order in range(1u8, 33) {
+    streams.push(mono(format!("subframe-lpc-{}", order), 44100, 16, &[
+      (signal(n, 16, 30 + order as u32), Lpc(order)),
+      (signal(n, 16, 70 + order as u32), Lpc(order))
+    ]));
+  }
+
+  let shifted = |bits: u8, wasted: u8, seed: u32| -> Vec<i32> {
+    signal(n, bits - wasted, seed).iter().map(|&s| s << wasted as uint).collect()
+  };
+
+  streams.push(mono("subframe-wasted-bits".to_string(), 44100, 16, &[
+    (shifted(16, 1, 3), Wasted(1)),
+    (shifted(16, 3, 4), Wasted(3)),
+    (shifted(16, 12, 5), Wasted(12))
+  ]));
+}
+
+fn channel_streams(streams: &mut Vec<Stream>) {
+  let n = 1152;
+
+  // Independent channels, assignments 0 to 7
+  for channels in range(1u8, 9) {
+    let mut builder = Builder::new(format!("channels-{}", channels), 44100, channels, 16, false);
+
+    for frame in range(0u32, 2) {
+      let samples: Vec<Vec<i32>> = range(0, channels as u32).map(|c| signal(n, 16, 100 + 10 * frame + c)).collect();
+      let codings: Vec<Coding> = range(0, channels).map(|c| Fixed(c % 5)).collect();
+
+      builder.frame(samples.as_slice(), channels - 1, codings.as_slice());
+    }
+
+    streams.push(builder.finish());
+  }
+
+  let assignments = [("left-side", 0b1000u8), ("right-side", 0b1001), ("mid-side", 0b1010)];
+
+  for &(name, channel_assignment) in assignments.iter() {
+    let mut builder = Builder::new(format!("assignment-{}", name), 44100, 2, 16, false);
+
+    for frame in range(0u32, 2) {
+      let left = signal(n, 16, 200 + frame);
+      let right = signal(n, 16, 300 + frame);
+
+      builder.frame(&[left, right], channel_assignment, &[Lpc(8), Fixed(2)]);
+    }
+
+    streams.push(builder.finish());
+  }
+}
+
+fn bit_depth_streams(streams: &mut Vec<Stream>) {
+  let n = 576;
+
+  for bits in range(4u8, 33) {
+    let seed = 1000 + 10 * bits as u32;
+    let mut builder = Builder::new(format!("bits-{}", bits), 44100, 2, bits, false);
+
+    builder.frame(&[full_scale(n, bits, seed), Vec::from_elem(n, min_sample(bits))], 0b0001, &[Verbatim, Constant]);
+
+    // Side channels need a bit more, which 32 bit streams don't have
+    let (left_side, mid_side) = if bits < 32 { (0b1000, 0b1010) } else { (0b0001, 0b0001) };
+
+    builder.frame(&[predictable(n, bits, seed + 1), predictable(n, bits, seed + 2)], left_side, &[Fixed(2), Lpc(4)]);
+    builder.frame(&[predictable(n, bits, seed + 3), predictable(n, bits, seed + 4)], mid_side, &[Lpc(8), Escaped(1)]);
+
+    streams.push(builder.finish());
+  }
+}
+
+fn block_size_streams(streams: &mut Vec<Stream>) {
+  // The sizes with their own code, then ones written in 8 and 16 bits
+  let block_sizes = [192u, 576, 1152, 2304, 4608, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 100, 5000];
+
+  for &block_size in block_sizes.iter() {
+    streams.push(mono(format!("block-size-{}", block_size), 44100, 16, &[
+      (signal(block_size, 16, 400 + block_size as u32), Fixed(1)),
+      (signal(block_size, 16, 500 + block_size as u32), Fixed(1))
+    ]));
+  }
+}
+
+fn sample_rate_streams(streams: &mut Vec<Stream>) {
+  // The rates with their own code, one in kHz, one in Hz, one in tens of Hz
+  // and one that's only in STREAMINFO
+  let sample_rates = [88200u32, 176400, 192000, 8000, 16000, 22050, 24000, 32000, 44100, 48000, 96000, 11000, 11025, 100010, 100001];
+
+  for &sample_rate in sample_rates.iter() {
+    streams.push(mono(format!("sample-rate-{}", sample_rate), sample_rate, 16, &[
+      (signal(192, 16, sample_rate), Fixed(2))
+    ]));
+  }
+}
+
+fn residual_streams(streams: &mut Vec<Stream>) {
+  let n = 4096;
+
+  // A ramp has no second order residual, so its escaped partitions hold
+  // samples of zero bits
+  let ramp = Vec::from_fn(n, |i| 3 * i as i32 - 6000);
+
+  streams.push(mono("residual-escape".to_string(), 44100, 16, &[
+    (signal(n, 16, 600), Escaped(1)),
+    (ramp, Escaped(2))
+  ]));
+
+  // Rice parameters past 14 need the five bit parameters
+  streams.push(mono("residual-wide-parameters".to_string(), 44100, 24, &[
+    (signal(n, 24, 601), Fixed(0)),
+    (signal(n, 24, 602), Fixed(1))
+  ]));
+
+  let partitioned: Vec<(Vec<i32>, Coding)> = range(0u8, 9).map(|partition_order| {
+    (signal(n, 16, 610 + partition_order as u32), Partitioned(2, partition_order))
+  }).collect();
+
+  streams.push(mono("residual-partition-orders".to_string(), 44100, 16, partitioned.as_slice()));
+}
+
+fn variable_block_size_streams(streams: &mut Vec<Stream>) {
+  let block_sizes = [1152u, 17, 4096, 300, 16, 4608];
+  let mut builder = Builder::new("variable-block-size".to_string(), 44100, 2, 16, true);
+
+  for (i, &block_size) in block_sizes.iter().enumerate() {
+    let left = signal(block_size, 16, 700 + i as u32);
+    let right = signal(block_size, 16, 800 + i as u32);
+
+    builder.frame(&[left, right], 0b1010, &[Fixed(2), Verbatim]);
+  }
+
+  streams.push(builder.finish());
+}
+
+pub fn streams() -> Vec<Stream> {
+  let mut streams = Vec::new();
+
+  subframe_streams(&mut streams);
+  channel_streams(&mut streams);
+  bit_depth_streams(&mut streams);
+  block_size_streams(&mut streams);
+  sample_rate_streams(&mut streams);
+  residual_streams(&mut streams);
+  variable_block_size_streams(&mut streams);
+
+  return streams;
+}
+
+// Writes NAME.flac for each stream, with NAME.pcm holding the samples it
+// decodes to the way MD5 sees them, interleaved and little-endian in whole
+// bytes, and NAME.md5 holding their MD5 in hex, to check against the
+// reference decoder
+pub fn write(directory: &Path, streams: &[Stream]) -> IoResult<()> {
+  try!(fs::mkdir_recursive(directory, USER_RWX));
+
+  for stream in streams.iter() {
+    let bits = stream.stream_info.bits_per_sample;
+    let bytes = (bits as uint + 7) / 8;
+
+    let mut pcm = Vec::with_capacity(bytes * stream.samples.len());
+
+    for &sample in stream.samples.iter() {
+      for i in range(0, bytes) {
+        pcm.push((sample >> (8 * i)) as u8);
+      }
+    }
+
+    let MD5(signature) = stream.stream_info.signature;
+    let hex: Vec<String> = signature.iter().map(|b| format!("{:02x}", *b)).collect();
+
+    try!(File::create(&directory.join(format!("{}.flac", stream.name))).write(stream.flac.as_slice()));
+    try!(File::create(&directory.join(format!("{}.pcm", stream.name))).write(pcm.as_slice()));
+    try!(File::create(&directory.join(format!("{}.md5", stream.name))).write_line(hex.concat().as_slice()));
+  }
+
+  return Ok(());
+}